@@ -0,0 +1,513 @@
+//! JSON endpoints of the admin API
+//!
+//! | Method | Path | |
+//! |---|---|---|
+//! | GET | /devices | Enrolled devices |
+//! | GET | /devices/{device_id} | Device, including its groups, applied profiles and drift |
+//! | DELETE | /devices/{device_id} | Forgets the device, it has to enroll again |
+//! | POST | /devices/{device_id}/revoke | Unenrolls the device at its next check-in |
+//! | PUT | /devices/{device_id}/groups | Replaces the groups, eg `["finance"]`, selecting the profiles of the device |
+//! | GET | /devices/{device_id}/commands | Queued commands and their outcome |
+//! | POST | /devices/{device_id}/commands | Queues a command, eg `{"operation": "Get", "uri": "./DevDetail/SwV"}` |
+//! | GET | /commands/{id} | Queued command and its outcome |
+//! | GET | /profiles | Configuration profiles |
+
+use super::Administrator;
+use crate::certificate_authority;
+use crate::command_queue::{self, QueuedCommand};
+use crate::csp::{dm_client, Format};
+use crate::device_store::{self, Device, EnrollmentState};
+use crate::management::Profile;
+use crate::microsoft_protocol::syncml::{Command, Item, ItemCommand, Meta};
+use crate::tenant::Tenant;
+use crate::AppState;
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::info;
+
+pub(super) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/devices", get(list_devices))
+        .route(
+            "/devices/{device_id}",
+            get(get_device).delete(remove_device),
+        )
+        .route("/devices/{device_id}/revoke", post(revoke_device))
+        .route("/devices/{device_id}/groups", put(set_groups))
+        .route(
+            "/devices/{device_id}/commands",
+            get(list_commands).post(queue_command),
+        )
+        .route("/commands/{id}", get(get_command))
+        .route("/profiles", get(list_profiles))
+}
+
+/// Error answered as `{"error": "..."}`.
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn not_found(what: impl std::fmt::Display) -> Self {
+        Self(StatusCode::NOT_FOUND, format!("{what} not found"))
+    }
+
+    fn bad_request(reason: impl std::fmt::Display) -> Self {
+        Self(StatusCode::BAD_REQUEST, reason.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+        (self.0, Json(Body { error: self.1 })).into_response()
+    }
+}
+
+impl From<device_store::Error> for ApiError {
+    fn from(value: device_store::Error) -> Self {
+        eprintln!("Error accessing device store: {}", value);
+        Self(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Server Error".into(),
+        )
+    }
+}
+
+impl From<certificate_authority::Error> for ApiError {
+    fn from(value: certificate_authority::Error) -> Self {
+        eprintln!("Error accessing certificate authority: {}", value);
+        Self(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Server Error".into(),
+        )
+    }
+}
+
+impl From<command_queue::Error> for ApiError {
+    fn from(value: command_queue::Error) -> Self {
+        match value {
+            command_queue::Error::Unsupported(_) => Self::bad_request(value),
+            _ => {
+                eprintln!("Error accessing command queue: {}", value);
+                Self(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal Server Error".into(),
+                )
+            }
+        }
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+fn timestamp(at: &DateTime<Utc>) -> String {
+    at.to_rfc3339()
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceView {
+    device_id: String,
+    hw_dev_id: Option<String>,
+    hostname: Option<String>,
+    os_version: Option<String>,
+    certificate_thumbprint: Option<String>,
+    first_enrolled_at: String,
+    last_enrolled_at: String,
+    last_check_in: Option<String>,
+    state: &'static str,
+    groups: BTreeSet<String>,
+    /// Version of each applied profile
+    profiles: BTreeMap<String, String>,
+    drift: Vec<DriftView>,
+}
+
+#[derive(Debug, Serialize)]
+struct DriftView {
+    uri: String,
+    profile: String,
+    expected: String,
+    actual: Option<String>,
+    detected_at: String,
+}
+
+impl From<Device> for DeviceView {
+    fn from(device: Device) -> Self {
+        Self {
+            first_enrolled_at: timestamp(&device.first_enrolled_at),
+            last_enrolled_at: timestamp(&device.last_enrolled_at),
+            last_check_in: device.last_check_in.as_ref().map(timestamp),
+            state: device.state.as_str(),
+            drift: device
+                .drift
+                .into_iter()
+                .map(|(uri, drift)| DriftView {
+                    uri,
+                    profile: drift.profile,
+                    expected: drift.expected,
+                    actual: drift.actual,
+                    detected_at: timestamp(&drift.detected_at),
+                })
+                .collect(),
+            device_id: device.device_id,
+            hw_dev_id: device.hw_dev_id,
+            hostname: device.hostname,
+            os_version: device.os_version,
+            certificate_thumbprint: device.certificate_thumbprint,
+            groups: device.groups,
+            profiles: device.profiles,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ItemView {
+    uri: Option<String>,
+    format: Option<String>,
+    data: Option<String>,
+}
+
+impl From<&Item> for ItemView {
+    fn from(item: &Item) -> Self {
+        Self {
+            uri: item.loc_uri().map(str::to_string),
+            format: item.meta.as_ref().and_then(|meta| meta.format.clone()),
+            data: item.data.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CommandView {
+    id: i64,
+    device_id: String,
+    operation: &'static str,
+    items: Vec<ItemView>,
+    state: &'static str,
+    status: Option<u16>,
+    results: Vec<ItemView>,
+    created_at: String,
+    sent_at: Option<String>,
+    completed_at: Option<String>,
+    attempts: u32,
+}
+
+impl From<QueuedCommand> for CommandView {
+    fn from(queued: QueuedCommand) -> Self {
+        Self {
+            id: queued.id,
+            operation: queued.command.name(),
+            items: queued.command.items().iter().map(ItemView::from).collect(),
+            state: queued.state.as_str(),
+            status: queued.status,
+            results: queued.results.iter().map(ItemView::from).collect(),
+            created_at: timestamp(&queued.created_at),
+            sent_at: queued.sent_at.as_ref().map(timestamp),
+            completed_at: queued.completed_at.as_ref().map(timestamp),
+            attempts: queued.attempts,
+            device_id: queued.device_id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ProfileView {
+    name: String,
+    description: Option<String>,
+    groups: Vec<String>,
+    version: String,
+    settings: Vec<SettingView>,
+}
+
+#[derive(Debug, Serialize)]
+struct SettingView {
+    uri: String,
+    operation: &'static str,
+    format: String,
+    data: Option<String>,
+}
+
+impl From<&Profile> for ProfileView {
+    fn from(profile: &Profile) -> Self {
+        Self {
+            name: profile.name.clone(),
+            description: profile.description.clone(),
+            groups: profile.groups.clone(),
+            version: profile.version.clone(),
+            settings: profile
+                .settings
+                .iter()
+                .map(|setting| SettingView {
+                    uri: setting.uri.clone(),
+                    operation: setting.command.name(),
+                    format: setting.value.format().to_string(),
+                    data: setting.value.data(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn find_device(tenant: &Tenant, device_id: &str) -> Result<Device, ApiError> {
+    tenant
+        .device_store
+        .get(device_id)?
+        .ok_or_else(|| ApiError::not_found(format!("device {device_id}")))
+}
+
+async fn list_devices(admin: Administrator) -> ApiResult<Vec<DeviceView>> {
+    let devices = admin.tenant.device_store.list()?;
+    Ok(Json(devices.into_iter().map(DeviceView::from).collect()))
+}
+
+async fn get_device(admin: Administrator, Path(device_id): Path<String>) -> ApiResult<DeviceView> {
+    Ok(Json(find_device(&admin.tenant, &device_id)?.into()))
+}
+
+async fn remove_device(
+    admin: Administrator,
+    Path(device_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !admin.tenant.device_store.remove(&device_id)? {
+        return Err(ApiError::not_found(format!("device {device_id}")));
+    }
+    for certificate in admin
+        .tenant
+        .certificate_authority
+        .revoke_device(&device_id)?
+    {
+        info!(
+            "Revoked certificate {} of removed device {device_id}",
+            certificate.thumbprint
+        );
+    }
+    info!("Administrator {} removed device {device_id}", admin.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Queues the unenrollment of the device, the device removes the management account at its next check-in.
+async fn revoke_device(
+    admin: Administrator,
+    Path(device_id): Path<String>,
+) -> Result<(StatusCode, Json<CommandView>), ApiError> {
+    let device = find_device(&admin.tenant, &device_id)?;
+    let command = dm_client::unenroll()
+        .exec(crate::MDM_PROVIDER_ID)
+        .map_err(ApiError::bad_request)?;
+    let queued = admin
+        .tenant
+        .command_queue
+        .push(&device.device_id, command, Utc::now())?;
+    admin
+        .tenant
+        .device_store
+        .set_state(&device.device_id, EnrollmentState::Unenrolled)?;
+    info!(
+        "Administrator {} revoked enrollment of {device_id}",
+        admin.name
+    );
+    Ok((StatusCode::CREATED, Json(queued.into())))
+}
+
+async fn set_groups(
+    admin: Administrator,
+    Path(device_id): Path<String>,
+    Json(groups): Json<BTreeSet<String>>,
+) -> ApiResult<DeviceView> {
+    if !admin.tenant.device_store.set_groups(&device_id, groups)? {
+        return Err(ApiError::not_found(format!("device {device_id}")));
+    }
+    info!("Administrator {} changed groups of {device_id}", admin.name);
+    Ok(Json(find_device(&admin.tenant, &device_id)?.into()))
+}
+
+async fn list_commands(
+    admin: Administrator,
+    Path(device_id): Path<String>,
+) -> ApiResult<Vec<CommandView>> {
+    let commands = admin.tenant.command_queue.list(&device_id)?;
+    Ok(Json(commands.into_iter().map(CommandView::from).collect()))
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum Operation {
+    Add,
+    Replace,
+    Delete,
+    Get,
+    Exec,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandRequest {
+    operation: Operation,
+    uri: String,
+    /// One of int, chr, bool, xml, b64 or null
+    format: Option<String>,
+    data: Option<String>,
+}
+
+impl CommandRequest {
+    fn command(self) -> Result<Command, ApiError> {
+        let mut item = Item::target(self.uri);
+        if let Some(format) = &self.format {
+            let format = Format::parse(format)
+                .ok_or_else(|| ApiError::bad_request(format!("unknown format {format}")))?;
+            item = item.with_meta(Meta::with_format(format.as_str()));
+        }
+        if let Some(data) = self.data {
+            item = item.with_data(data);
+        }
+        let command = ItemCommand::new(item);
+        Ok(match self.operation {
+            Operation::Add => Command::Add(command),
+            Operation::Replace => Command::Replace(command),
+            Operation::Delete => Command::Delete(command),
+            Operation::Get => Command::Get(command),
+            Operation::Exec => Command::Exec(command),
+        })
+    }
+}
+
+async fn queue_command(
+    admin: Administrator,
+    Path(device_id): Path<String>,
+    Json(request): Json<CommandRequest>,
+) -> Result<(StatusCode, Json<CommandView>), ApiError> {
+    let device = find_device(&admin.tenant, &device_id)?;
+    let command = request.command()?;
+    admin
+        .tenant
+        .management_sessions
+        .validate(&command)
+        .map_err(ApiError::bad_request)?;
+    let queued = admin
+        .tenant
+        .command_queue
+        .push(&device.device_id, command, Utc::now())?;
+    info!(
+        "Administrator {} queued {} command {} for {device_id}",
+        admin.name,
+        queued.command.name(),
+        queued.id
+    );
+    Ok((StatusCode::CREATED, Json(queued.into())))
+}
+
+async fn get_command(admin: Administrator, Path(id): Path<i64>) -> ApiResult<CommandView> {
+    let queued = admin
+        .tenant
+        .command_queue
+        .get(id)?
+        .ok_or_else(|| ApiError::not_found(format!("command {id}")))?;
+    Ok(Json(queued.into()))
+}
+
+async fn list_profiles(admin: Administrator) -> ApiResult<Vec<ProfileView>> {
+    let profiles = admin.tenant.profiles.profiles();
+    Ok(Json(profiles.iter().map(ProfileView::from).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_store::Inventory;
+    use crate::test_support::TenantBuilder;
+    use axum::body::Body;
+    use axum::http::{header, Request};
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+    use tower_service::Service;
+
+    async fn call(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<&str>,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                header::AUTHORIZATION,
+                format!("Basic {}", BASE64.encode("admin:secret")),
+            )
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.unwrap_or_default().to_string()))
+            .unwrap();
+        let response = app.clone().call(request).await.unwrap();
+        let status = response.status();
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&bytes).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn admin_api_test() {
+        let (_directory, tenants) = TenantBuilder::new("admin_api")
+            .admin("admin", "secret")
+            .build();
+
+        let tenant = tenants.iter().next().unwrap().clone();
+        tenant
+            .device_store
+            .record_enrollment("DEVICE", "AB01", Inventory::default(), Utc::now())
+            .unwrap();
+        let app = router().with_state(AppState {
+            tenants: Arc::new(tenants),
+        });
+
+        let request = Request::get("/devices").body(Body::empty()).unwrap();
+        let response = app.clone().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let (status, devices) = call(&app, "GET", "/devices", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(devices[0]["device_id"], "DEVICE");
+        assert_eq!(devices[0]["state"], "enrolled");
+
+        let (status, device) = call(
+            &app,
+            "PUT",
+            "/devices/DEVICE/groups",
+            Some(r#"["finance"]"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(device["groups"][0], "finance");
+
+        let query = r#"{"operation": "Get", "uri": "./DevDetail/SwV"}"#;
+        let (status, command) = call(&app, "POST", "/devices/DEVICE/commands", Some(query)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(command["state"], "pending");
+        let (status, _) = call(&app, "POST", "/devices/UNKNOWN/commands", Some(query)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let invalid = r#"{"operation": "Replace", "uri": "./DevDetail/SwV", "format": "text"}"#;
+        let (status, _) = call(&app, "POST", "/devices/DEVICE/commands", Some(invalid)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = call(&app, "POST", "/devices/DEVICE/revoke", None).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, commands) = call(&app, "GET", "/devices/DEVICE/commands", None).await;
+        assert_eq!(commands.as_array().unwrap().len(), 2);
+        assert_eq!(commands[1]["operation"], "Exec");
+        let (_, device) = call(&app, "GET", "/devices/DEVICE", None).await;
+        assert_eq!(device["state"], "unenrolled");
+
+        let path = format!("/commands/{}", command["id"]);
+        let (status, command) = call(&app, "GET", &path, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(command["items"][0]["uri"], "./DevDetail/SwV");
+    }
+}
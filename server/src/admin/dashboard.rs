@@ -0,0 +1,586 @@
+//! HTML pages of the admin dashboard
+//!
+//! | Method | Path | |
+//! |---|---|---|
+//! | GET | /admin | Devices with their last check-in, enrollment state, compliance and certificate expiry |
+//! | GET | /admin/devices/{device_id} | Device, its profiles, drift and command history |
+//! | GET, POST | /admin/login | Sign in form |
+//! | POST | /admin/logout | Ends the session |
+//!
+//! The pages only read the state of the fleet, changes go through the admin API.
+
+use super::{administered_tenant, sign_in, DASHBOARD_PATH};
+use crate::command_queue::QueuedCommand;
+use crate::device_store::Device;
+use crate::html::escape;
+use crate::microsoft_protocol::syncml::Item;
+use crate::tenant::Tenant;
+use crate::AppState;
+use axum::{
+    extract::{FromRequestParts, Path},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::RngCore;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::info;
+
+const SESSION_COOKIE: &str = "simple_mdm_admin";
+/// Lifetime of a dashboard session, administrators sign in again afterwards.
+const SESSION_HOURS: i64 = 8;
+/// Amount of random bytes within a session token.
+const TOKEN_LENGTH: usize = 32;
+/// Certificates expiring within this many days are highlighted.
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+pub(super) fn router() -> Router<AppState> {
+    Router::new()
+        .route(DASHBOARD_PATH, get(devices_page))
+        .route(
+            &format!("{DASHBOARD_PATH}/devices/{{device_id}}"),
+            get(device_page),
+        )
+        .route(
+            &format!("{DASHBOARD_PATH}/login"),
+            get(login_page).post(login),
+        )
+        .route(&format!("{DASHBOARD_PATH}/logout"), post(logout))
+}
+
+#[derive(Debug)]
+struct Session {
+    name: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Administrators signed in at the dashboard, by session token.
+#[derive(Debug, Default)]
+pub struct DashboardSessions {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl DashboardSessions {
+    /// Starts a session for the administrator, returns its token.
+    pub fn create(&self, name: &str, now: DateTime<Utc>) -> String {
+        let mut token = [0u8; TOKEN_LENGTH];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token: String = token.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        let session = Session {
+            name: name.into(),
+            expires_at: now + TimeDelta::hours(SESSION_HOURS),
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(token.clone(), session);
+        token
+    }
+
+    /// Name of the administrator of an unexpired session.
+    pub fn get(&self, token: &str, now: DateTime<Utc>) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(token)
+            .filter(|session| session.expires_at > now)
+            .map(|session| session.name.clone())
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+}
+
+/// An administrator signed in at the dashboard, other requests are redirected to the sign in form.
+struct SignedIn {
+    name: String,
+    tenant: Tenant,
+}
+
+impl FromRequestParts<AppState> for SignedIn {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let tenant = administered_tenant(parts, state).await?;
+        let name = session_token(&parts.headers)
+            .and_then(|token| tenant.admin_sessions.get(&token, Utc::now()));
+        match name {
+            Some(name) => Ok(Self { name, tenant }),
+            None => Err(Redirect::to(&format!("{DASHBOARD_PATH}/login")).into_response()),
+        }
+    }
+}
+
+/// Value of the session cookie.
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+fn session_cookie(token: &str, max_age: i64) -> HeaderValue {
+    // NOTE; The dashboard is only served over https
+    let cookie = format!(
+        "{SESSION_COOKIE}={token}; Path={DASHBOARD_PATH}; Max-Age={max_age}; HttpOnly; Secure; SameSite=Strict"
+    );
+    HeaderValue::from_str(&cookie).expect("session cookie is ascii")
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+async fn login_page(tenant: Tenant) -> Response {
+    if tenant.admin_users.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+    Html(login_form("", None)).into_response()
+}
+
+async fn login(tenant: Tenant, Form(form): Form<LoginForm>) -> Response {
+    let Some(name) = sign_in(&tenant, &form.username, &form.password) else {
+        let page = login_form(&form.username, Some("Sign in failed"));
+        return (StatusCode::UNAUTHORIZED, Html(page)).into_response();
+    };
+    info!(
+        "Administrator {name} signed in at the dashboard of tenant {}",
+        tenant.name
+    );
+    let token = tenant.admin_sessions.create(&name, Utc::now());
+    let mut response = Redirect::to(DASHBOARD_PATH).into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        session_cookie(&token, SESSION_HOURS * 60 * 60),
+    );
+    response
+}
+
+async fn logout(tenant: Tenant, headers: HeaderMap) -> Response {
+    if let Some(token) = session_token(&headers) {
+        tenant.admin_sessions.remove(&token);
+    }
+    let mut response = Redirect::to(&format!("{DASHBOARD_PATH}/login")).into_response();
+    response
+        .headers_mut()
+        .insert(header::SET_COOKIE, session_cookie("", 0));
+    response
+}
+
+async fn devices_page(admin: SignedIn) -> Response {
+    let devices = match admin.tenant.device_store.list() {
+        Ok(devices) => devices,
+        Err(err) => {
+            eprintln!("Error listing devices: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+    let now = Utc::now();
+    let rows: String = devices
+        .iter()
+        .map(|device| {
+            let (compliance, class) = compliance(&admin.tenant, device);
+            format!(
+                "<tr><td><a href=\"{DASHBOARD_PATH}/devices/{link}\">{device_id}</a></td><td>{hostname}</td>\
+                 <td>{state}</td><td>{last_check_in}</td><td>{groups}</td>\
+                 <td class=\"{class}\">{compliance}</td>{expiry}</tr>\n",
+                link = escape(&path_segment(&device.device_id)),
+                device_id = escape(&device.device_id),
+                hostname = escape(device.hostname.as_deref().unwrap_or_default()),
+                state = device.state.as_str(),
+                last_check_in = optional_timestamp(device.last_check_in.as_ref()),
+                groups = escape(&device.groups.iter().cloned().collect::<Vec<_>>().join(", ")),
+                expiry = expiry_cell(certificate_expiry(&admin.tenant, device), now),
+            )
+        })
+        .collect();
+    let body = format!(
+        r#"<h1>Devices</h1>
+<table>
+<tr><th>Device</th><th>Hostname</th><th>State</th><th>Last check-in</th><th>Groups</th><th>Compliance</th><th>Certificate expires</th></tr>
+{rows}</table>
+<p>{count} devices</p>"#,
+        count = devices.len(),
+    );
+    Html(page("Devices", Some(&admin.name), &body)).into_response()
+}
+
+async fn device_page(admin: SignedIn, Path(device_id): Path<String>) -> Response {
+    let tenant = &admin.tenant;
+    let device = match tenant.device_store.get(&device_id) {
+        Ok(Some(device)) => device,
+        Ok(None) => {
+            let body = format!("<h1>Device {} not found</h1>", escape(&device_id));
+            let page = page("Not found", Some(&admin.name), &body);
+            return (StatusCode::NOT_FOUND, Html(page)).into_response();
+        }
+        Err(err) => {
+            eprintln!("Error reading device {device_id}: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+    let commands = match tenant.command_queue.list(&device_id) {
+        Ok(commands) => commands,
+        Err(err) => {
+            eprintln!("Error listing commands of {device_id}: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+
+    let body = format!(
+        "<h1>{title}</h1>\n{summary}\n<h2>Profiles</h2>\n{profiles}\n<h2>Drift</h2>\n{drift}\n\
+         <h2>Commands</h2>\n{commands}",
+        title = escape(device.hostname.as_deref().unwrap_or(&device.device_id)),
+        summary = device_summary(tenant, &device, Utc::now()),
+        profiles = profile_table(tenant, &device),
+        drift = drift_table(&device),
+        commands = command_table(&commands),
+    );
+    Html(page(&device.device_id, Some(&admin.name), &body)).into_response()
+}
+
+fn device_summary(tenant: &Tenant, device: &Device, now: DateTime<Utc>) -> String {
+    let optional = |value: &Option<String>| escape(value.as_deref().unwrap_or_default());
+    format!(
+        r#"<table>
+<tr><th>Device</th><td>{device_id}</td></tr>
+<tr><th>Hostname</th><td>{hostname}</td></tr>
+<tr><th>OS version</th><td>{os_version}</td></tr>
+<tr><th>Hardware id</th><td>{hw_dev_id}</td></tr>
+<tr><th>State</th><td>{state}</td></tr>
+<tr><th>First enrolled</th><td>{first_enrolled_at}</td></tr>
+<tr><th>Last enrolled</th><td>{last_enrolled_at}</td></tr>
+<tr><th>Last check-in</th><td>{last_check_in}</td></tr>
+<tr><th>Groups</th><td>{groups}</td></tr>
+<tr><th>Certificate</th><td>{thumbprint}</td></tr>
+<tr><th>Certificate expires</th>{expiry}</tr>
+</table>"#,
+        device_id = escape(&device.device_id),
+        hostname = optional(&device.hostname),
+        os_version = optional(&device.os_version),
+        hw_dev_id = optional(&device.hw_dev_id),
+        state = device.state.as_str(),
+        first_enrolled_at = timestamp(&device.first_enrolled_at),
+        last_enrolled_at = timestamp(&device.last_enrolled_at),
+        last_check_in = optional_timestamp(device.last_check_in.as_ref()),
+        groups = escape(&device.groups.iter().cloned().collect::<Vec<_>>().join(", ")),
+        thumbprint = optional(&device.certificate_thumbprint),
+        expiry = expiry_cell(certificate_expiry(tenant, device), now),
+    )
+}
+
+/// Profiles that apply to the device or were applied to it, with their state on the device.
+fn profile_table(tenant: &Tenant, device: &Device) -> String {
+    let profiles = tenant.profiles.profiles();
+    let mut rows = String::new();
+    for profile in profiles.iter() {
+        let applied = device.profiles.get(&profile.name);
+        let (state, class) = match (profile.applies_to(device), applied) {
+            (true, Some(version)) if *version == profile.version => ("applied", "ok"),
+            (true, Some(_)) => ("outdated", "warning"),
+            (true, None) => ("pending", "warning"),
+            (false, Some(_)) => ("no longer assigned", ""),
+            (false, None) => continue,
+        };
+        let drift = device
+            .drift
+            .values()
+            .filter(|drift| drift.profile == profile.name)
+            .count();
+        rows.push_str(&format!(
+            "<tr><td>{name}</td><td>{description}</td><td class=\"{class}\">{state}</td><td>{drift}</td></tr>\n",
+            name = escape(&profile.name),
+            description = escape(profile.description.as_deref().unwrap_or_default()),
+        ));
+    }
+    for name in device.profiles.keys() {
+        if !profiles.iter().any(|profile| profile.name == *name) {
+            rows.push_str(&format!(
+                "<tr><td>{}</td><td></td><td>removed</td><td></td></tr>\n",
+                escape(name)
+            ));
+        }
+    }
+    if rows.is_empty() {
+        return "<p>No profiles apply to this device.</p>".into();
+    }
+    format!(
+        "<table>\n<tr><th>Profile</th><th>Description</th><th>State</th><th>Drifted settings</th></tr>\n{rows}</table>"
+    )
+}
+
+fn drift_table(device: &Device) -> String {
+    if device.drift.is_empty() {
+        return "<p>All checked settings hold the value of their profile.</p>".into();
+    }
+    let rows: String = device
+        .drift
+        .iter()
+        .map(|(uri, drift)| {
+            format!(
+                "<tr><td>{uri}</td><td>{profile}</td><td>{expected}</td><td>{actual}</td><td>{detected_at}</td></tr>\n",
+                uri = escape(uri),
+                profile = escape(&drift.profile),
+                expected = escape(&drift.expected),
+                actual = drift
+                    .actual
+                    .as_deref()
+                    .map(escape)
+                    .unwrap_or_else(|| "<em>unreadable</em>".into()),
+                detected_at = timestamp(&drift.detected_at),
+            )
+        })
+        .collect();
+    format!(
+        "<table>\n<tr><th>Setting</th><th>Profile</th><th>Expected</th><th>Reported</th><th>Since</th></tr>\n{rows}</table>"
+    )
+}
+
+/// Queued commands, most recent first.
+fn command_table(commands: &[QueuedCommand]) -> String {
+    if commands.is_empty() {
+        return "<p>No commands were queued for this device.</p>".into();
+    }
+    let rows: String = commands
+        .iter()
+        .rev()
+        .map(|queued| {
+            format!(
+                "<tr><td>{id}</td><td>{operation}</td><td>{items}</td><td>{state}</td><td>{status}</td>\
+                 <td>{attempts}</td><td>{created_at}</td><td>{completed_at}</td><td>{results}</td></tr>\n",
+                id = queued.id,
+                operation = queued.command.name(),
+                items = items(queued.command.items()),
+                state = queued.state.as_str(),
+                status = queued.status.map(|status| status.to_string()).unwrap_or_default(),
+                attempts = queued.attempts,
+                created_at = timestamp(&queued.created_at),
+                completed_at = optional_timestamp(queued.completed_at.as_ref()),
+                results = items(&queued.results),
+            )
+        })
+        .collect();
+    format!(
+        "<table>\n<tr><th>#</th><th>Operation</th><th>Nodes</th><th>State</th><th>Status</th><th>Attempts</th>\
+         <th>Queued</th><th>Completed</th><th>Results</th></tr>\n{rows}</table>"
+    )
+}
+
+/// Node and data of each item, one per line.
+fn items(items: &[Item]) -> String {
+    items
+        .iter()
+        .map(|item| {
+            let uri = escape(item.loc_uri().unwrap_or_default());
+            match &item.data {
+                Some(data) => format!("{uri} = {}", escape(data)),
+                None => uri,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("<br>")
+}
+
+/// Summary of the profile compliance and the class highlighting it.
+fn compliance(tenant: &Tenant, device: &Device) -> (String, &'static str) {
+    let pending = tenant.profiles.pending(device).len();
+    match (device.drift.len(), pending) {
+        (0, 0) => ("compliant".into(), "ok"),
+        (0, pending) => (format!("{pending} profiles pending"), "warning"),
+        (drift, _) => (format!("{drift} settings drifted"), "error"),
+    }
+}
+
+/// Expiry of the certificate the device was most recently issued.
+fn certificate_expiry(tenant: &Tenant, device: &Device) -> Option<DateTime<Utc>> {
+    let thumbprint = device.certificate_thumbprint.as_ref()?;
+    let certificate = tenant
+        .certificate_authority
+        .store()
+        .find_by_thumbprint(thumbprint)?;
+    DateTime::from_timestamp(certificate.not_after.unix_timestamp(), 0)
+}
+
+fn expiry_cell(expiry: Option<DateTime<Utc>>, now: DateTime<Utc>) -> String {
+    let Some(expiry) = expiry else {
+        return "<td>unknown</td>".into();
+    };
+    let class = if expiry <= now {
+        "error"
+    } else if expiry <= now + TimeDelta::days(EXPIRY_WARNING_DAYS) {
+        "warning"
+    } else {
+        "ok"
+    };
+    format!("<td class=\"{class}\">{}</td>", timestamp(&expiry))
+}
+
+fn timestamp(at: &DateTime<Utc>) -> String {
+    at.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn optional_timestamp(at: Option<&DateTime<Utc>>) -> String {
+    at.map(timestamp).unwrap_or_else(|| "never".into())
+}
+
+/// Percent-encodes the value for use as a path segment.
+fn path_segment(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn login_form(username: &str, error: Option<&str>) -> String {
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", escape(error)))
+        .unwrap_or_default();
+    let body = format!(
+        r#"<h1>Sign in</h1>
+{error}
+<form method="post" action="{DASHBOARD_PATH}/login">
+<p><label>Username <input type="text" name="username" value="{username}" autocomplete="username"></label></p>
+<p><label>Password <input type="password" name="password" autocomplete="current-password"></label></p>
+<p><input type="submit" value="Sign in"></p>
+</form>"#,
+        username = escape(username),
+    );
+    page("Sign in", None, &body)
+}
+
+/// Surrounds the body with the navigation of the signed in administrator.
+fn page(title: &str, administrator: Option<&str>, body: &str) -> String {
+    let navigation = administrator
+        .map(|name| {
+            format!(
+                r#"<nav><a href="{DASHBOARD_PATH}">Devices</a> · signed in as {name}
+<form method="post" action="{DASHBOARD_PATH}/logout"><input type="submit" value="Sign out"></form></nav>"#,
+                name = escape(name),
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{title} · simple_mdm</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+nav form {{ display: inline; }}
+table {{ border-collapse: collapse; margin-bottom: 1em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }}
+.ok {{ color: #176117; }}
+.warning {{ color: #8a5a00; }}
+.error {{ color: #a31515; }}
+</style>
+</head>
+<body>
+{navigation}
+{body}
+</body>
+</html>"#,
+        title = escape(title),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_store::Inventory;
+    use crate::test_support::TenantBuilder;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+    use tower_service::Service;
+
+    async fn call(app: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+        let response = app.clone().call(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let bytes = body.collect().await.unwrap().to_bytes();
+        let page = String::from_utf8_lossy(&bytes).into();
+        (parts.status, parts.headers, page)
+    }
+
+    #[tokio::test]
+    async fn dashboard_test() {
+        let (_directory, tenants) = TenantBuilder::new("dashboard")
+            .admin("admin", "secret")
+            .build();
+
+        let tenant = tenants.iter().next().unwrap().clone();
+        let inventory = Inventory {
+            hostname: Some("<DESKTOP>".into()),
+            ..Default::default()
+        };
+        tenant
+            .device_store
+            .record_enrollment("DEVICE 1", "AB01", inventory, Utc::now())
+            .unwrap();
+        let app = router().with_state(AppState {
+            tenants: Arc::new(tenants),
+        });
+
+        let request = Request::get("/admin").body(Body::empty()).unwrap();
+        let (status, headers, _) = call(&app, request).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(headers[header::LOCATION], "/admin/login");
+
+        let sign_in = |password: &str| {
+            Request::post("/admin/login")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("username=admin&password={password}")))
+                .unwrap()
+        };
+        let (status, _, _) = call(&app, sign_in("wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, headers, _) = call(&app, sign_in("secret")).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        let cookie = headers[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+
+        let signed_in = |uri: &str| {
+            Request::get(uri)
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, _, page) = call(&app, signed_in("/admin")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(page.contains(r#"<a href="/admin/devices/DEVICE%201">DEVICE 1</a>"#));
+        assert!(page.contains("&lt;DESKTOP&gt;"));
+        assert!(page.contains("<td class=\"ok\">compliant</td>"));
+
+        let (status, _, page) = call(&app, signed_in("/admin/devices/DEVICE%201")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(page.contains("No commands were queued"));
+        let (status, _, _) = call(&app, signed_in("/admin/devices/UNKNOWN")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::post("/admin/logout")
+            .header(header::COOKIE, &cookie)
+            .body(Body::empty())
+            .unwrap();
+        call(&app, request).await;
+        let (status, _, _) = call(&app, signed_in("/admin")).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+    }
+}
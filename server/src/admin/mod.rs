@@ -0,0 +1,110 @@
+//! Administration of the managed devices
+//!
+//! The admin API is served below [`API_PATH`], on the HTTPS listeners of the device endpoints or on its own
+//! listeners when `admin.listen` is configured. Requests are assigned to a tenant by their Host header, as the
+//! device endpoints, and authenticated with HTTP basic authentication against the `admin_password_file` of
+//! the tenant. Tenants without that file don't serve the admin API.
+//!
+//! Next to the API, [`DASHBOARD_PATH`] serves HTML pages showing the state of the fleet. The dashboard signs
+//! administrators in with a form and keeps them signed in with a session cookie.
+
+use crate::authentication::{EnrollmentAuthenticator, Error};
+use crate::microsoft_protocol::wsse::Credentials;
+use crate::tenant::Tenant;
+use crate::AppState;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use tracing::warn;
+
+mod api;
+mod dashboard;
+
+pub use dashboard::DashboardSessions;
+
+pub const API_PATH: &str = "/admin/api";
+pub const DASHBOARD_PATH: &str = "/admin";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .nest(API_PATH, api::router())
+        .merge(dashboard::router())
+}
+
+/// An authenticated administrator and the tenant they administer.
+pub struct Administrator {
+    pub name: String,
+    pub tenant: Tenant,
+}
+
+impl FromRequestParts<AppState> for Administrator {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let tenant = administered_tenant(parts, state).await?;
+        let Some((username, password)) = basic_credentials(parts) else {
+            return Err(unauthorized());
+        };
+        match sign_in(&tenant, &username, &password) {
+            Some(name) => Ok(Self { name, tenant }),
+            None => Err(unauthorized()),
+        }
+    }
+}
+
+/// Tenant of the request, tenants without administrators answer 404.
+async fn administered_tenant(parts: &mut Parts, state: &AppState) -> Result<Tenant, Response> {
+    let tenant = Tenant::from_request_parts(parts, state).await?;
+    match tenant.admin_users {
+        Some(_) => Ok(tenant),
+        None => Err((StatusCode::NOT_FOUND, "Not Found").into_response()),
+    }
+}
+
+/// Checks the credentials against the administrators of the tenant, returns the name of the administrator.
+fn sign_in(tenant: &Tenant, username: &str, password: &str) -> Option<String> {
+    let admin_users = tenant.admin_users.as_ref()?;
+    let credentials = Credentials::UsernameToken { username, password };
+    match admin_users.authenticate(&credentials) {
+        Ok(principal) => Some(principal.name),
+        Err(Error::InvalidCredentials) => {
+            warn!(
+                "Failed admin sign in of {username} at tenant {}",
+                tenant.name
+            );
+            None
+        }
+        Err(err) => {
+            eprintln!("Error authenticating administrator: {}", err);
+            None
+        }
+    }
+}
+
+/// Username and password of the `Authorization: Basic` header.
+fn basic_credentials(parts: &Parts) -> Option<(String, String)> {
+    let value = parts.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.into(), password.into()))
+}
+
+fn unauthorized() -> Response {
+    let mut response = (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"simple_mdm admin\", charset=\"UTF-8\""),
+    );
+    response
+}
@@ -0,0 +1,102 @@
+//! Certificate authentication for enrolled devices
+//!
+//! Devices that renew their enrollment present the identity certificate issued by the internal authority.
+
+use super::{EnrollmentAuthenticator, Error, Principal};
+use crate::certificate_authority::{thumbprint, CertificateAuthority};
+use crate::config::AuthPolicy;
+use crate::microsoft_protocol::wsse::{self, Credentials};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+pub struct CertificateAuthenticator {
+    certificate_authority: Arc<CertificateAuthority>,
+}
+
+impl CertificateAuthenticator {
+    pub fn new(certificate_authority: Arc<CertificateAuthority>) -> Self {
+        Self {
+            certificate_authority,
+        }
+    }
+}
+
+impl EnrollmentAuthenticator for CertificateAuthenticator {
+    fn auth_policy(&self) -> AuthPolicy {
+        AuthPolicy::Certificate
+    }
+
+    fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        let Credentials::SecurityToken { value_type, token } = credentials else {
+            return Err(Error::UnsupportedCredentials);
+        };
+        if *value_type != wsse::VALUE_TYPE_X509V3 {
+            return Err(Error::UnsupportedCredentials);
+        }
+        let der = BASE64
+            .decode(token)
+            .map_err(|_| Error::InvalidCredentials)?;
+
+        // NOTE; The certificate is only accepted when it's byte-identical to one the authority issued.
+        // WARN; This proves knowledge of the certificate only, callers must have the device prove possession of
+        // its key, e.g. with the TLS client certificate.
+        let issued = self
+            .certificate_authority
+            .store()
+            .find_by_thumbprint(&thumbprint(&der))
+            .ok_or(Error::InvalidCredentials)?;
+        if !issued.is_valid(OffsetDateTime::now_utc()) {
+            return Err(Error::InvalidCredentials);
+        }
+
+        Ok(Principal {
+            name: issued.device_id,
+            policy: AuthPolicy::Certificate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, KeyPair};
+
+    #[test]
+    fn issued_certificate_test() {
+        let certificate_authority = Arc::new(CertificateAuthority::ephemeral("Test CA").unwrap());
+        let key_pair = KeyPair::generate().unwrap();
+        let request = CertificateParams::default()
+            .serialize_request(&key_pair)
+            .unwrap();
+        let issued = certificate_authority
+            .sign_request(request.der(), "DEVICE")
+            .unwrap();
+        let authenticator = CertificateAuthenticator::new(certificate_authority);
+
+        let principal = authenticator
+            .authenticate(&Credentials::SecurityToken {
+                value_type: wsse::VALUE_TYPE_X509V3,
+                token: BASE64.encode(&issued.der),
+            })
+            .unwrap();
+        assert_eq!(principal.name, "DEVICE");
+        assert!(matches!(
+            authenticator.authenticate(&Credentials::SecurityToken {
+                value_type: wsse::VALUE_TYPE_X509V3,
+                token: BASE64.encode(b"unknown"),
+            }),
+            Err(Error::InvalidCredentials)
+        ));
+
+        let authority = &authenticator.certificate_authority;
+        authority.revoke_device("DEVICE").unwrap();
+        assert!(matches!(
+            authenticator.authenticate(&Credentials::SecurityToken {
+                value_type: wsse::VALUE_TYPE_X509V3,
+                token: BASE64.encode(&issued.der),
+            }),
+            Err(Error::InvalidCredentials)
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+//! Authentication of enrolling users
+//!
+//! The policy and enrollment requests carry credentials in their WS-Security header. Which credentials are
+//! accepted follows from the authentication policy, each policy has its own [`EnrollmentAuthenticator`].
+//!
+//! REF; https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-mde2/e7fc1d8c-ac21-4fc3-b9a0-e0bdff6fcfb7
+
+use crate::certificate_authority::CertificateAuthority;
+use crate::config::{AuthPolicy, ServerConfig};
+use crate::microsoft_protocol::wsse::Credentials;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+mod certificate;
+mod one_time_token;
+mod password_file;
+
+pub use certificate::CertificateAuthenticator;
+pub use one_time_token::OneTimeTokenAuthenticator;
+pub use password_file::PasswordFileAuthenticator;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The credentials are of a kind the authenticator doesn't handle
+    UnsupportedCredentials,
+    /// Unknown user, wrong password or unknown token
+    InvalidCredentials,
+    /// A credentials file could not be parsed
+    InvalidFile(PathBuf, String),
+    Io(PathBuf, std::io::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnsupportedCredentials => f.write_str("unsupported credentials"),
+            Error::InvalidCredentials => f.write_str("invalid credentials"),
+            Error::InvalidFile(path, reason) => write!(f, "invalid {}: {reason}", path.display()),
+            Error::Io(path, error) => write!(f, "cannot access {}: {error}", path.display()),
+        }
+    }
+}
+
+/// The authenticated party of an enrollment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    /// User name, or the device identifier when authenticated by certificate
+    pub name: String,
+    /// Policy of the authenticator that accepted the credentials
+    pub policy: AuthPolicy,
+}
+
+pub trait EnrollmentAuthenticator: Send + Sync {
+    /// Authentication policy advertised during discovery
+    fn auth_policy(&self) -> AuthPolicy;
+
+    /// Validates the credentials, called for both the policy and the enrollment request.
+    fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Principal, Error>;
+
+    /// Signs in a user at the federated login page, returns the security token for the device.
+    fn sign_in(&self, _username: &str, _password: &str) -> Result<String, Error> {
+        Err(Error::UnsupportedCredentials)
+    }
+
+    /// Validates the credentials of an enrollment request, one-time credentials are used up and refused
+    /// afterwards.
+    fn redeem(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        self.authenticate(credentials)
+    }
+}
+
+/// The authenticators of all allowed policies, in order of preference.
+pub struct Authenticators {
+    authenticators: Vec<Arc<dyn EnrollmentAuthenticator>>,
+}
+
+impl Authenticators {
+    /// Creates the authenticators for the configured authentication policies.
+    pub fn from_config(
+        config: &ServerConfig,
+        certificate_authority: Arc<CertificateAuthority>,
+    ) -> Result<Self, Error> {
+        let mut authenticators: Vec<Arc<dyn EnrollmentAuthenticator>> = Vec::new();
+        for policy in &config.enrollment.auth_policies {
+            authenticators.push(match policy {
+                AuthPolicy::OnPremise => Arc::new(PasswordFileAuthenticator::open(
+                    &config.enrollment.password_file,
+                )?),
+                AuthPolicy::Federated => Arc::new(OneTimeTokenAuthenticator::open(
+                    &config.enrollment.token_file,
+                    PasswordFileAuthenticator::open(&config.enrollment.password_file)?,
+                )?),
+                AuthPolicy::Certificate => {
+                    Arc::new(CertificateAuthenticator::new(certificate_authority.clone()))
+                }
+            });
+        }
+        Ok(Self { authenticators })
+    }
+
+    pub fn policies(&self) -> impl Iterator<Item = AuthPolicy> + '_ {
+        self.authenticators
+            .iter()
+            .map(|authenticator| authenticator.auth_policy())
+    }
+
+    /// Picks the most preferred policy that's also supported by the client.
+    pub fn negotiate(&self, supported: &[AuthPolicy]) -> Option<AuthPolicy> {
+        self.policies().find(|policy| supported.contains(policy))
+    }
+
+    /// Runs the operation on each authenticator until one handles the credentials.
+    fn dispatch<T>(
+        &self,
+        operation: impl Fn(&dyn EnrollmentAuthenticator) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        for authenticator in &self.authenticators {
+            match operation(authenticator.as_ref()) {
+                Err(Error::UnsupportedCredentials) => continue,
+                result => return result,
+            }
+        }
+        Err(Error::UnsupportedCredentials)
+    }
+}
+
+impl EnrollmentAuthenticator for Authenticators {
+    /// The most preferred policy.
+    fn auth_policy(&self) -> AuthPolicy {
+        self.policies().next().unwrap_or(AuthPolicy::OnPremise)
+    }
+
+    fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        self.dispatch(|authenticator| authenticator.authenticate(credentials))
+    }
+
+    fn sign_in(&self, username: &str, password: &str) -> Result<String, Error> {
+        self.dispatch(|authenticator| authenticator.sign_in(username, password))
+    }
+
+    fn redeem(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        self.dispatch(|authenticator| authenticator.redeem(credentials))
+    }
+}
+
+/// Parses a credentials file of `name:secret` lines, empty lines and lines starting with '#' are skipped.
+fn read_entries(path: &std::path::Path) -> Result<Vec<(String, String)>, Error> {
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::Io(path.into(), e))?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| match line.split_once(':') {
+            Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+                Ok((name.to_string(), secret.to_string()))
+            }
+            _ => Err(Error::InvalidFile(
+                path.into(),
+                format!("line {} is not formatted as name:secret", index + 1),
+            )),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_test() {
+        let certificate_authority = Arc::new(CertificateAuthority::ephemeral("Test CA").unwrap());
+        let authenticators = Authenticators {
+            authenticators: vec![
+                Arc::new(CertificateAuthenticator::new(certificate_authority)),
+                Arc::new(PasswordFileAuthenticator::default()),
+            ],
+        };
+
+        assert_eq!(
+            authenticators.negotiate(&[AuthPolicy::OnPremise, AuthPolicy::Certificate]),
+            Some(AuthPolicy::Certificate)
+        );
+        assert_eq!(
+            authenticators.negotiate(&[AuthPolicy::Federated, AuthPolicy::OnPremise]),
+            Some(AuthPolicy::OnPremise)
+        );
+        assert_eq!(authenticators.negotiate(&[AuthPolicy::Federated]), None);
+
+        // NOTE; The password file rejects the user, the certificate authenticator doesn't handle usernames
+        let credentials = Credentials::UsernameToken {
+            username: "user@mdmwindows.com",
+            password: "secret",
+        };
+        assert!(matches!(
+            authenticators.authenticate(&credentials),
+            Err(Error::InvalidCredentials)
+        ));
+    }
+}
@@ -0,0 +1,264 @@
+//! Federated authentication with one-time enrollment tokens
+//!
+//! Users sign in at the federated login page with a username and password from the password file, and
+//! receive a token that windows presents as security token. Each line of the token file holds
+//! `username:token:expires_at` with the expiry as unix timestamp, the enrollment request redeeming the token
+//! removes it from the file.
+
+use super::{read_entries, EnrollmentAuthenticator, Error, PasswordFileAuthenticator, Principal};
+use crate::certificate_authority::write_private;
+use crate::config::AuthPolicy;
+use crate::microsoft_protocol::wsse::{self, Credentials};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Amount of random bytes within a token.
+const TOKEN_LENGTH: usize = 32;
+/// Lifetime of a token, users sign in again when windows didn't enroll in time.
+const TOKEN_MINUTES: i64 = 30;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    username: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct OneTimeTokenAuthenticator {
+    path: PathBuf,
+    /// Unused tokens by their value
+    tokens: Mutex<BTreeMap<String, Token>>,
+    /// Users allowed to sign in at the login page
+    users: PasswordFileAuthenticator,
+}
+
+impl OneTimeTokenAuthenticator {
+    /// Loads the unused tokens, a missing token file holds no tokens.
+    pub fn open(path: &Path, users: PasswordFileAuthenticator) -> Result<Self, Error> {
+        let tokens = match path.exists() {
+            true => read_entries(path)?
+                .into_iter()
+                .map(|(username, secret)| {
+                    let (value, expires_at) = secret
+                        .split_once(':')
+                        .and_then(|(value, expires_at)| {
+                            let expires_at = expires_at.parse().ok()?;
+                            Some((value, DateTime::from_timestamp(expires_at, 0)?))
+                        })
+                        .ok_or_else(|| {
+                            Error::InvalidFile(
+                                path.into(),
+                                format!("token of {username} has no valid expiry"),
+                            )
+                        })?;
+                    let token = Token {
+                        username: username.clone(),
+                        expires_at,
+                    };
+                    Ok((value.to_string(), token))
+                })
+                .collect::<Result<_, Error>>()?,
+            false => BTreeMap::new(),
+        };
+        Ok(Self {
+            path: path.into(),
+            tokens: Mutex::new(tokens),
+            users,
+        })
+    }
+
+    /// Creates a new token for the user.
+    pub fn issue(&self, username: &str) -> Result<String, Error> {
+        let mut token = [0u8; TOKEN_LENGTH];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token: String = token.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        let now = Utc::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, token| token.expires_at > now);
+        tokens.insert(
+            token.clone(),
+            Token {
+                username: username.into(),
+                expires_at: now + TimeDelta::minutes(TOKEN_MINUTES),
+            },
+        );
+        self.store(&tokens)?;
+        Ok(token)
+    }
+
+    /// Returns the known, unexpired token presented by the device.
+    fn token(
+        tokens: &BTreeMap<String, Token>,
+        credentials: &Credentials<'_>,
+    ) -> Result<String, Error> {
+        let Credentials::SecurityToken { value_type, token } = credentials else {
+            return Err(Error::UnsupportedCredentials);
+        };
+        if *value_type != wsse::VALUE_TYPE_USER_TOKEN {
+            return Err(Error::UnsupportedCredentials);
+        }
+        let now = Utc::now();
+        let unexpired = |token: &String| {
+            tokens
+                .get(token)
+                .is_some_and(|token| token.expires_at > now)
+        };
+        if unexpired(token) {
+            return Ok(token.clone());
+        }
+        // NOTE; Windows can base64 encode the token it received from the authentication service
+        BASE64
+            .decode(token)
+            .ok()
+            .and_then(|token| String::from_utf8(token).ok())
+            .filter(unexpired)
+            .ok_or(Error::InvalidCredentials)
+    }
+
+    fn store(&self, tokens: &BTreeMap<String, Token>) -> Result<(), Error> {
+        let contents: String = tokens
+            .iter()
+            .map(|(value, token)| {
+                format!(
+                    "{}:{value}:{}\n",
+                    token.username,
+                    token.expires_at.timestamp()
+                )
+            })
+            .collect();
+        // NOTE; Tokens are credentials, readable by the owner only
+        write_private(&self.path, &contents).map_err(|e| Error::Io(self.path.clone(), e))
+    }
+}
+
+impl EnrollmentAuthenticator for OneTimeTokenAuthenticator {
+    fn auth_policy(&self) -> AuthPolicy {
+        AuthPolicy::Federated
+    }
+
+    fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        let tokens = self.tokens.lock().unwrap();
+        let token = Self::token(&tokens, credentials)?;
+        Ok(Principal {
+            name: tokens[&token].username.clone(),
+            policy: AuthPolicy::Federated,
+        })
+    }
+
+    fn sign_in(&self, username: &str, password: &str) -> Result<String, Error> {
+        let principal = self
+            .users
+            .authenticate(&Credentials::UsernameToken { username, password })?;
+        self.issue(&principal.name)
+    }
+
+    fn redeem(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        // NOTE; Looked up and removed under the same lock, only one request can redeem the token
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = Self::token(&tokens, credentials)?;
+        let token = tokens.remove(&token).expect("token was found");
+        self.store(&tokens)?;
+        Ok(Principal {
+            name: token.username,
+            policy: AuthPolicy::Federated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn token_consumed_test() {
+        let directory = TempDir::new("tokens");
+        let path = directory.join("enrollment_tokens");
+        std::fs::write(
+            &path,
+            "user@mdmwindows.com:2b7e1516:4102444800\n\
+             other@mdmwindows.com:28aed2a6:4102444800\n\
+             late@mdmwindows.com:3c4fcf09:946684800\n",
+        )
+        .unwrap();
+        let users = PasswordFileAuthenticator::default();
+        let authenticator = OneTimeTokenAuthenticator::open(&path, users).unwrap();
+
+        let credentials = Credentials::SecurityToken {
+            value_type: wsse::VALUE_TYPE_USER_TOKEN,
+            token: BASE64.encode("2b7e1516"),
+        };
+        let principal = authenticator.authenticate(&credentials).unwrap();
+        assert_eq!(principal.name, "user@mdmwindows.com");
+        let expired = Credentials::SecurityToken {
+            value_type: wsse::VALUE_TYPE_USER_TOKEN,
+            token: "3c4fcf09".into(),
+        };
+        assert!(matches!(
+            authenticator.authenticate(&expired),
+            Err(Error::InvalidCredentials)
+        ));
+
+        let principal = authenticator.redeem(&credentials).unwrap();
+        assert_eq!(principal.name, "user@mdmwindows.com");
+        assert!(matches!(
+            authenticator.authenticate(&credentials),
+            Err(Error::InvalidCredentials)
+        ));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            contents,
+            "other@mdmwindows.com:28aed2a6:4102444800\nlate@mdmwindows.com:3c4fcf09:946684800\n"
+        );
+
+        let token = authenticator.issue("new@mdmwindows.com").unwrap();
+        let credentials = Credentials::SecurityToken {
+            value_type: wsse::VALUE_TYPE_USER_TOKEN,
+            token,
+        };
+        let principal = authenticator.authenticate(&credentials).unwrap();
+        assert_eq!(principal.name, "new@mdmwindows.com");
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("late@mdmwindows.com"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn concurrent_redeem_test() {
+        let directory = TempDir::new("concurrent_tokens");
+        let path = directory.join("enrollment_tokens");
+        let users = PasswordFileAuthenticator::default();
+        let authenticator = OneTimeTokenAuthenticator::open(&path, users).unwrap();
+        let token = authenticator.issue("user@mdmwindows.com").unwrap();
+
+        let redeemed = std::thread::scope(|scope| {
+            let requests: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        authenticator.redeem(&Credentials::SecurityToken {
+                            value_type: wsse::VALUE_TYPE_USER_TOKEN,
+                            token: token.clone(),
+                        })
+                    })
+                })
+                .collect();
+            requests
+                .into_iter()
+                .map(|request| request.join().unwrap())
+                .filter(Result::is_ok)
+                .count()
+        });
+        assert_eq!(redeemed, 1);
+    }
+}
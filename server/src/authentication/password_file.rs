@@ -0,0 +1,97 @@
+//! OnPremise authentication against a static file of users
+//!
+//! Each line holds `username:hash`, where hash is an argon2 PHC string. Such a hash is generated with eg
+//! `echo -n "password" | argon2 "$(openssl rand -base64 16)" -id -e`.
+
+use super::{read_entries, EnrollmentAuthenticator, Error, Principal};
+use crate::config::AuthPolicy;
+use crate::microsoft_protocol::wsse::Credentials;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct PasswordFileAuthenticator {
+    /// Password hash per username, usernames are compared case-insensitive
+    users: HashMap<String, String>,
+}
+
+impl PasswordFileAuthenticator {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut users = HashMap::new();
+        for (username, hash) in read_entries(path)? {
+            PasswordHash::new(&hash).map_err(|e| {
+                Error::InvalidFile(path.into(), format!("invalid hash for {username}: {e}"))
+            })?;
+            users.insert(username.to_lowercase(), hash);
+        }
+        Ok(Self { users })
+    }
+}
+
+impl EnrollmentAuthenticator for PasswordFileAuthenticator {
+    fn auth_policy(&self) -> AuthPolicy {
+        AuthPolicy::OnPremise
+    }
+
+    fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Principal, Error> {
+        let Credentials::UsernameToken { username, password } = credentials else {
+            return Err(Error::UnsupportedCredentials);
+        };
+        let hash = self
+            .users
+            .get(&username.to_lowercase())
+            .ok_or(Error::InvalidCredentials)?;
+        // NOTE; Hashes were validated when loading the file
+        let hash = PasswordHash::new(hash).map_err(|_| Error::InvalidCredentials)?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .map_err(|_| Error::InvalidCredentials)?;
+
+        Ok(Principal {
+            name: username.to_string(),
+            policy: AuthPolicy::OnPremise,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{password_hash, TempDir};
+
+    #[test]
+    fn password_test() {
+        let hash = password_hash("secret");
+        let directory = TempDir::new("users");
+        let path = directory.join("enrollment_users");
+        std::fs::write(
+            &path,
+            format!("# Enrollment users\nUser@mdmwindows.com:{hash}\n"),
+        )
+        .unwrap();
+        let authenticator = PasswordFileAuthenticator::open(&path).unwrap();
+
+        let principal = authenticator
+            .authenticate(&Credentials::UsernameToken {
+                username: "user@mdmwindows.com",
+                password: "secret",
+            })
+            .unwrap();
+        assert_eq!(principal.name, "user@mdmwindows.com");
+        assert!(matches!(
+            authenticator.authenticate(&Credentials::UsernameToken {
+                username: "user@mdmwindows.com",
+                password: "wrong",
+            }),
+            Err(Error::InvalidCredentials)
+        ));
+        assert!(matches!(
+            authenticator.authenticate(&Credentials::SecurityToken {
+                value_type: "",
+                token: "secret".into(),
+            }),
+            Err(Error::UnsupportedCredentials)
+        ));
+    }
+}
@@ -0,0 +1,625 @@
+//! Certificate authority that signs device identity certificates
+//!
+//! Enrolling devices submit a PKCS#10 certificate signing request, the issued certificate is used by
+//! the device to authenticate itself against the management service. Before the certificate expires the
+//! device renews it with a new request, signed with the key of the certificate being renewed.
+//!
+//! Revoked certificates are published in a certificate revocation list at [`CRL_PATH`], the issued
+//! certificates name that list as their CRL distribution point.
+
+use rcgen::{
+    BasicConstraints, CertificateParams, CertificateRevocationListParams, CrlDistributionPoint,
+    DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, KeyIdMethod, KeyPair,
+    KeyUsagePurpose, RevokedCertParams, SerialNumber, SubjectPublicKeyInfo,
+};
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
+use x509_parser::{
+    certification_request::X509CertificationRequest,
+    pem::parse_x509_pem,
+    prelude::{FromDer, X509Certificate},
+};
+
+mod pkcs7;
+mod store;
+
+pub use store::CertificateStore;
+
+/// Validity of generated authority certificates.
+const AUTHORITY_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+/// Issued certificates are valid slightly before signing, to accommodate clock differences with devices.
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// Period after which clients fetch the revocation list again.
+const CRL_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Path of the certificate revocation list, relative to the external url of the tenant.
+pub const CRL_PATH: &str = "/CertificateAuthority/devices.crl";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The certificate signing request could not be decoded
+    InvalidRequest(String),
+    /// The signature of the certificate signing request doesn't match its public key
+    InvalidRequestSignature,
+    /// The renewal request isn't signed with an unexpired certificate issued by this authority
+    UnknownRenewalCertificate,
+    /// Failure while creating or signing a certificate
+    Signing(rcgen::Error),
+    /// The stored authority material is unusable
+    InvalidAuthority(String),
+    Io(std::io::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidRequest(reason) => {
+                write!(f, "invalid certificate signing request: {reason}")
+            }
+            Error::InvalidRequestSignature => {
+                f.write_str("certificate signing request signature is invalid")
+            }
+            Error::UnknownRenewalCertificate => {
+                f.write_str("renewal request is not signed with a valid issued certificate")
+            }
+            Error::Signing(error) => write!(f, "certificate signing failed: {error}"),
+            Error::InvalidAuthority(reason) => write!(f, "invalid certificate authority: {reason}"),
+            Error::Io(error) => write!(f, "certificate authority storage failed: {error}"),
+        }
+    }
+}
+
+impl From<rcgen::Error> for Error {
+    fn from(value: rcgen::Error) -> Self {
+        Error::Signing(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+/// A certificate signed by the authority.
+#[derive(Debug, Clone)]
+pub struct IssuedCertificate {
+    pub serial_number: u64,
+    /// Subject common name of the certificate
+    pub device_id: String,
+    pub not_after: OffsetDateTime,
+    pub der: Vec<u8>,
+    /// Hex encoded SHA-1 hash of the DER encoding, the way windows identifies certificates in its stores
+    pub thumbprint: String,
+    /// Moment the certificate was revoked, `None` for certificates in good standing
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+impl IssuedCertificate {
+    /// Whether the certificate is neither expired nor revoked.
+    pub fn is_valid(&self, now: OffsetDateTime) -> bool {
+        self.revoked_at.is_none() && self.not_after > now
+    }
+}
+
+/// Contents of the certificates issued to devices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IssuanceProfile {
+    pub validity: Duration,
+    pub key_usages: Vec<KeyUsagePurpose>,
+    pub extended_key_usages: Vec<ExtendedKeyUsagePurpose>,
+    /// Address of the revocation list, included as CRL distribution point
+    pub crl_url: Option<String>,
+}
+
+impl Default for IssuanceProfile {
+    fn default() -> Self {
+        Self {
+            validity: Duration::from_secs(365 * 24 * 60 * 60),
+            key_usages: vec![
+                KeyUsagePurpose::DigitalSignature,
+                KeyUsagePurpose::KeyEncipherment,
+            ],
+            extended_key_usages: vec![ExtendedKeyUsagePurpose::ClientAuth],
+            crl_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthorityOptions {
+    /// Directory holding the authority key material and issued certificates, `None` keeps everything in memory
+    pub directory: Option<PathBuf>,
+    /// Common name of the root certificate, the intermediate certificate appends "intermediate"
+    pub common_name: String,
+    /// Sign device certificates with an intermediate authority instead of the root
+    pub use_intermediate: bool,
+    pub profile: IssuanceProfile,
+}
+
+impl Default for AuthorityOptions {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            common_name: "simple_mdm device authority".into(),
+            use_intermediate: false,
+            profile: IssuanceProfile::default(),
+        }
+    }
+}
+
+/// Certificate and key of one level of the authority hierarchy.
+struct Authority {
+    /// Handle used by rcgen to sign, this is not necessarily the distributed certificate
+    certificate: rcgen::Certificate,
+    key_pair: KeyPair,
+    /// The distributed certificate
+    der: Vec<u8>,
+}
+
+impl Authority {
+    fn generate(common_name: &str, parent: Option<&Authority>) -> Result<Self, Error> {
+        let key_pair = KeyPair::generate()?;
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.is_ca = match parent {
+            Some(_) => IsCa::Ca(BasicConstraints::Constrained(0)),
+            None => IsCa::Ca(BasicConstraints::Unconstrained),
+        };
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        params.use_authority_key_identifier_extension = parent.is_some();
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - CLOCK_SKEW;
+        params.not_after = now + AUTHORITY_VALIDITY;
+
+        let certificate = match parent {
+            Some(parent) => params.signed_by(&key_pair, &parent.certificate, &parent.key_pair)?,
+            None => params.self_signed(&key_pair)?,
+        };
+        Ok(Self {
+            der: certificate.der().to_vec(),
+            certificate,
+            key_pair,
+        })
+    }
+
+    /// Loads the PEM encoded certificate and private key "{name}.crt" and "{name}.key" from the directory.
+    fn load(directory: &Path, name: &str) -> Result<Option<Self>, Error> {
+        let certificate_path = directory.join(format!("{name}.crt"));
+        let key_path = directory.join(format!("{name}.key"));
+        if !certificate_path.exists() && !key_path.exists() {
+            return Ok(None);
+        }
+
+        let certificate_pem = std::fs::read_to_string(&certificate_path)?;
+        let key_pair = KeyPair::from_pem(&std::fs::read_to_string(&key_path)?)?;
+        let (_, pem) = parse_x509_pem(certificate_pem.as_bytes()).map_err(|error| {
+            Error::InvalidAuthority(format!("{}: {error}", certificate_path.display()))
+        })?;
+        let (_, parsed) = X509Certificate::from_der(&pem.contents).map_err(|error| {
+            Error::InvalidAuthority(format!("{}: {error}", certificate_path.display()))
+        })?;
+        if parsed.public_key().subject_public_key.data.as_ref() != key_pair.public_key_raw() {
+            return Err(Error::InvalidAuthority(format!(
+                "{} doesn't belong to {}",
+                key_path.display(),
+                certificate_path.display()
+            )));
+        }
+
+        // NOTE; Rcgen needs a certificate object to sign with. Re-creating it from the stored parameters keeps
+        // the subject and key identifier of the original, the re-created signature is never distributed.
+        let certificate =
+            CertificateParams::from_ca_cert_pem(&certificate_pem)?.self_signed(&key_pair)?;
+        Ok(Some(Self {
+            certificate,
+            key_pair,
+            der: pem.contents,
+        }))
+    }
+
+    fn store(&self, directory: &Path, name: &str) -> Result<(), Error> {
+        std::fs::write(
+            directory.join(format!("{name}.crt")),
+            self.certificate.pem(),
+        )?;
+        write_private(
+            &directory.join(format!("{name}.key")),
+            &self.key_pair.serialize_pem(),
+        )?;
+        Ok(())
+    }
+}
+
+pub struct CertificateAuthority {
+    /// The authority signing device certificates
+    issuer: Authority,
+    /// DER encoded certificates from the issuer up to, and including, the root
+    chain: Vec<Vec<u8>>,
+    profile: IssuanceProfile,
+    store: CertificateStore,
+}
+
+impl CertificateAuthority {
+    /// Creates a new self-signed root authority that only lives as long as the process.
+    #[cfg(test)]
+    pub fn ephemeral(common_name: &str) -> Result<Self, Error> {
+        Self::load_or_generate(&AuthorityOptions {
+            common_name: common_name.into(),
+            ..Default::default()
+        })
+    }
+
+    /// Loads the authority from the configured directory, missing authority certificates are generated
+    /// and written into the directory.
+    pub fn load_or_generate(options: &AuthorityOptions) -> Result<Self, Error> {
+        let intermediate_name = format!("{} intermediate", options.common_name);
+        let Some(directory) = &options.directory else {
+            let root = Authority::generate(&options.common_name, None)?;
+            let store = CertificateStore::in_memory();
+            if !options.use_intermediate {
+                return Ok(Self::new(root, vec![], options, store));
+            }
+            let intermediate = Authority::generate(&intermediate_name, Some(&root))?;
+            return Ok(Self::new(intermediate, vec![root.der], options, store));
+        };
+
+        std::fs::create_dir_all(directory)?;
+        let root = match Authority::load(directory, "root")? {
+            Some(root) => root,
+            None => {
+                let root = Authority::generate(&options.common_name, None)?;
+                root.store(directory, "root")?;
+                root
+            }
+        };
+        let store = CertificateStore::open(directory.join("issued"))?;
+        if !options.use_intermediate {
+            return Ok(Self::new(root, vec![], options, store));
+        }
+
+        let intermediate = match Authority::load(directory, "intermediate")? {
+            Some(intermediate) => intermediate,
+            None => {
+                let intermediate = Authority::generate(&intermediate_name, Some(&root))?;
+                intermediate.store(directory, "intermediate")?;
+                intermediate
+            }
+        };
+        Ok(Self::new(intermediate, vec![root.der], options, store))
+    }
+
+    fn new(
+        issuer: Authority,
+        parents: Vec<Vec<u8>>,
+        options: &AuthorityOptions,
+        store: CertificateStore,
+    ) -> Self {
+        let mut chain = vec![issuer.der.clone()];
+        chain.extend(parents);
+        Self {
+            issuer,
+            chain,
+            profile: options.profile.clone(),
+            store,
+        }
+    }
+
+    /// Certificate of the authority signing device certificates.
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.issuer.der
+    }
+
+    /// Certificate of the trust anchor, this is the issuer certificate when no intermediate is used.
+    pub fn root_der(&self) -> &[u8] {
+        self.chain
+            .last()
+            .expect("chain contains at least the issuer")
+    }
+
+    /// Certificates between the root and the device certificates, ordered from the issuer upwards.
+    pub fn intermediates_der(&self) -> &[Vec<u8>] {
+        &self.chain[..self.chain.len() - 1]
+    }
+
+    pub fn store(&self) -> &CertificateStore {
+        &self.store
+    }
+
+    /// Signs the DER encoded PKCS#10 request, the issued certificate will carry the device identifier
+    /// as its subject common name.
+    ///
+    /// NOTE; Only the public key is taken from the request, the certificate contents are decided by the authority.
+    pub fn sign_request(
+        &self,
+        request_der: &[u8],
+        device_id: &str,
+    ) -> Result<IssuedCertificate, Error> {
+        let (_, request) = X509CertificationRequest::from_der(request_der)
+            .map_err(|error| Error::InvalidRequest(error.to_string()))?;
+        request
+            .verify_signature()
+            .map_err(|_| Error::InvalidRequestSignature)?;
+        let public_key =
+            SubjectPublicKeyInfo::from_der(request.certification_request_info.subject_pki.raw)?;
+
+        let serial_number = self.store.next_serial_number();
+        let mut params = CertificateParams::default();
+        params.serial_number = Some(serial_number.into());
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, device_id);
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_usages = self.profile.key_usages.clone();
+        params.extended_key_usages = self.profile.extended_key_usages.clone();
+        params.use_authority_key_identifier_extension = true;
+        if let Some(crl_url) = &self.profile.crl_url {
+            params.crl_distribution_points = vec![CrlDistributionPoint {
+                uris: vec![crl_url.clone()],
+            }];
+        }
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - CLOCK_SKEW;
+        params.not_after = now + self.profile.validity;
+        let not_after = params.not_after;
+
+        let certificate =
+            params.signed_by(&public_key, &self.issuer.certificate, &self.issuer.key_pair)?;
+        let der = certificate.der().to_vec();
+        let issued = IssuedCertificate {
+            serial_number,
+            device_id: device_id.into(),
+            not_after,
+            thumbprint: thumbprint(&der),
+            der,
+            revoked_at: None,
+        };
+        self.store.insert(issued.clone())?;
+        Ok(issued)
+    }
+
+    /// Verifies a renewal request, a PKCS#7 structure signed with a certificate of this authority that
+    /// didn't expire. Returns the certificate being renewed and the certificate signing request within.
+    pub fn verify_renewal(&self, signed_der: &[u8]) -> Result<(IssuedCertificate, Vec<u8>), Error> {
+        let signed = pkcs7::verify(signed_der)?;
+        let renewed = self
+            .store
+            .find_by_thumbprint(&thumbprint(&signed.signer_der))
+            .filter(|issued| issued.is_valid(OffsetDateTime::now_utc()))
+            .ok_or(Error::UnknownRenewalCertificate)?;
+        Ok((renewed, signed.content))
+    }
+
+    /// Revokes all certificates issued to the device, returns the certificates that weren't revoked before.
+    pub fn revoke_device(&self, device_id: &str) -> Result<Vec<IssuedCertificate>, Error> {
+        let now = OffsetDateTime::now_utc();
+        let mut revoked = Vec::new();
+        for certificate in self.store.find_by_device(device_id) {
+            if certificate.revoked_at.is_some() {
+                continue;
+            }
+            if let Some(certificate) = self.store.revoke(certificate.serial_number, now)? {
+                revoked.push(certificate);
+            }
+        }
+        Ok(revoked)
+    }
+
+    /// DER encoded revocation list of the revoked certificates that didn't expire yet, signed by the issuer.
+    pub fn revocation_list(&self) -> Result<Vec<u8>, Error> {
+        let now = OffsetDateTime::now_utc();
+        let revoked_certs = self
+            .store
+            .revoked()
+            .into_iter()
+            .filter(|certificate| certificate.not_after > now)
+            .filter_map(|certificate| {
+                Some(RevokedCertParams {
+                    serial_number: certificate.serial_number.into(),
+                    revocation_time: certificate.revoked_at?,
+                    reason_code: None,
+                    invalidity_date: None,
+                })
+            })
+            .collect();
+        let params = CertificateRevocationListParams {
+            this_update: now - CLOCK_SKEW,
+            next_update: now + CRL_VALIDITY,
+            // NOTE; The list is created on request, the time keeps the number increasing
+            crl_number: SerialNumber::from(now.unix_timestamp() as u64),
+            issuing_distribution_point: None,
+            revoked_certs,
+            key_identifier_method: KeyIdMethod::Sha256,
+        };
+        let crl = params.signed_by(&self.issuer.certificate, &self.issuer.key_pair)?;
+        Ok(crl.der().to_vec())
+    }
+}
+
+/// Writes key material, readable by the owner only.
+pub(crate) fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Windows style certificate thumbprint, uppercase hex encoded SHA-1 hash of the DER encoding.
+pub fn thumbprint(der: &[u8]) -> String {
+    Sha1::digest(der)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use x509_parser::prelude::{CertificateRevocationList, ParsedExtension};
+
+    fn signing_request(key_pair: &KeyPair) -> Vec<u8> {
+        CertificateParams::default()
+            .serialize_request(key_pair)
+            .unwrap()
+            .der()
+            .to_vec()
+    }
+
+    #[test]
+    fn sign_request_test() {
+        let authority = CertificateAuthority::load_or_generate(&AuthorityOptions {
+            use_intermediate: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let request = signing_request(&KeyPair::generate().unwrap());
+
+        let first = authority.sign_request(&request, "DEVICE-1").unwrap();
+        let second = authority.sign_request(&request, "DEVICE-2").unwrap();
+        assert_eq!(first.serial_number + 1, second.serial_number);
+        assert_eq!(first.device_id, "DEVICE-1");
+        assert_eq!(authority.intermediates_der().len(), 1);
+
+        let (_, certificate) = X509Certificate::from_der(&first.der).unwrap();
+        let (_, issuer) = X509Certificate::from_der(authority.certificate_der()).unwrap();
+        assert!(certificate
+            .verify_signature(Some(issuer.public_key()))
+            .is_ok());
+        assert_eq!(
+            authority
+                .store()
+                .find_by_thumbprint(&first.thumbprint)
+                .unwrap()
+                .serial_number,
+            first.serial_number
+        );
+    }
+
+    #[test]
+    fn verify_renewal_test() {
+        let authority = CertificateAuthority::ephemeral("Test CA").unwrap();
+        let key_pair = KeyPair::generate().unwrap();
+        let issued = authority
+            .sign_request(&signing_request(&key_pair), "DEVICE")
+            .unwrap();
+
+        let renewal_key = KeyPair::generate().unwrap();
+        let request = signing_request(&renewal_key);
+        let signed = pkcs7::tests::sign(&request, &issued.der, &key_pair);
+        let (renewed, renewal_request) = authority.verify_renewal(&signed).unwrap();
+        assert_eq!(renewed.serial_number, issued.serial_number);
+        assert_eq!(renewal_request, request);
+
+        // A certificate of another authority can't renew
+        let other = CertificateAuthority::ephemeral("Other CA").unwrap();
+        let foreign = other
+            .sign_request(&signing_request(&key_pair), "DEVICE")
+            .unwrap();
+        let signed = pkcs7::tests::sign(&request, &foreign.der, &key_pair);
+        assert!(matches!(
+            authority.verify_renewal(&signed),
+            Err(Error::UnknownRenewalCertificate)
+        ));
+    }
+
+    #[test]
+    fn revocation_test() {
+        let mut options = AuthorityOptions::default();
+        options.profile.crl_url = Some("https://mdm.example.com/devices.crl".into());
+        let authority = CertificateAuthority::load_or_generate(&options).unwrap();
+        let key_pair = KeyPair::generate().unwrap();
+        let first = authority
+            .sign_request(&signing_request(&key_pair), "DEVICE")
+            .unwrap();
+        let other = authority
+            .sign_request(&signing_request(&key_pair), "OTHER")
+            .unwrap();
+
+        let (_, certificate) = X509Certificate::from_der(&first.der).unwrap();
+        let distribution_points = certificate
+            .extensions()
+            .iter()
+            .find_map(|extension| match extension.parsed_extension() {
+                ParsedExtension::CRLDistributionPoints(points) => Some(format!("{points:?}")),
+                _ => None,
+            })
+            .unwrap();
+        assert!(distribution_points.contains("https://mdm.example.com/devices.crl"));
+
+        let revoked = authority.revoke_device("DEVICE").unwrap();
+        assert_eq!(revoked.len(), 1);
+        assert!(authority.revoke_device("DEVICE").unwrap().is_empty());
+        let stored = authority
+            .store()
+            .find_by_thumbprint(&first.thumbprint)
+            .unwrap();
+        assert!(!stored.is_valid(OffsetDateTime::now_utc()));
+
+        let crl = authority.revocation_list().unwrap();
+        let (_, crl) = CertificateRevocationList::from_der(&crl).unwrap();
+        let (_, issuer) = X509Certificate::from_der(authority.certificate_der()).unwrap();
+        assert!(crl.verify_signature(issuer.public_key()).is_ok());
+        let serials: Vec<_> = crl
+            .iter_revoked_certificates()
+            .map(|revoked| revoked.raw_serial().to_vec())
+            .collect();
+        assert_eq!(
+            serials,
+            vec![certificate.tbs_certificate.raw_serial().to_vec()]
+        );
+        assert!(authority
+            .store()
+            .find_by_thumbprint(&other.thumbprint)
+            .unwrap()
+            .revoked_at
+            .is_none());
+    }
+
+    #[test]
+    fn load_or_generate_persists_test() {
+        let directory = TempDir::new("authority");
+        let options = AuthorityOptions {
+            directory: Some(directory.join("certificate_authority")),
+            ..Default::default()
+        };
+
+        let authority = CertificateAuthority::load_or_generate(&options).unwrap();
+        let request = signing_request(&KeyPair::generate().unwrap());
+        let issued = authority.sign_request(&request, "DEVICE").unwrap();
+
+        let reloaded = CertificateAuthority::load_or_generate(&options).unwrap();
+        assert_eq!(reloaded.certificate_der(), authority.certificate_der());
+        assert_eq!(reloaded.store().find_by_device("DEVICE").len(), 1);
+        authority.revoke_device("DEVICE").unwrap();
+        let reloaded = CertificateAuthority::load_or_generate(&options).unwrap();
+        assert!(reloaded.store().find_by_device("DEVICE")[0]
+            .revoked_at
+            .is_some());
+        let next = reloaded.sign_request(&request, "DEVICE").unwrap();
+        assert_eq!(next.serial_number, issued.serial_number + 1);
+
+        // Certificates signed by the reloaded authority chain to the original certificate
+        let (_, certificate) = X509Certificate::from_der(&next.der).unwrap();
+        let (_, issuer) = X509Certificate::from_der(authority.certificate_der()).unwrap();
+        assert!(certificate
+            .verify_signature(Some(issuer.public_key()))
+            .is_ok());
+    }
+}
@@ -0,0 +1,371 @@
+//! Signed certificate requests
+//!
+//! Devices renewing their identity certificate wrap the PKCS#10 request into a PKCS#7 (CMS) SignedData
+//! structure, signed with the key of the certificate being renewed. Only the parts needed to verify the
+//! signature of the first signer are decoded.
+//!
+//! REF; https://datatracker.ietf.org/doc/html/rfc5652#section-5
+
+use super::Error;
+use ring::digest;
+use x509_parser::der_parser::asn1_rs::{Any, BitString, Class, FromDer, Oid, Tag};
+use x509_parser::oid_registry::{
+    OID_HASH_SHA1, OID_NIST_HASH_SHA256, OID_NIST_HASH_SHA384, OID_NIST_HASH_SHA512,
+    OID_PKCS1_RSAENCRYPTION, OID_PKCS1_SHA1WITHRSA, OID_PKCS1_SHA256WITHRSA,
+    OID_PKCS1_SHA384WITHRSA, OID_PKCS1_SHA512WITHRSA, OID_PKCS7_ID_SIGNED_DATA,
+    OID_PKCS9_ID_MESSAGE_DIGEST,
+};
+use x509_parser::prelude::{AlgorithmIdentifier, ParsedExtension, X509Certificate};
+use x509_parser::verify::verify_signature;
+
+/// Content of a SignedData structure whose signature was verified.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedContent {
+    pub content: Vec<u8>,
+    /// DER encoded certificate of the signer, taken from the structure itself
+    pub signer_der: Vec<u8>,
+}
+
+/// Identifies the certificate of a signer.
+enum SignerId<'a> {
+    /// Content bytes of the serial number INTEGER
+    SerialNumber(&'a [u8]),
+    SubjectKeyIdentifier(&'a [u8]),
+}
+
+impl SignerId<'_> {
+    fn matches(&self, certificate: &X509Certificate) -> bool {
+        match self {
+            // NOTE; Serial numbers are unique per issuer, a certificate of another issuer fails verification
+            SignerId::SerialNumber(serial) => certificate.tbs_certificate.raw_serial() == *serial,
+            SignerId::SubjectKeyIdentifier(identifier) => {
+                certificate.extensions().iter().any(|extension| {
+                    matches!(extension.parsed_extension(),
+                        ParsedExtension::SubjectKeyIdentifier(key) if key.0 == *identifier)
+                })
+            }
+        }
+    }
+}
+
+fn invalid(reason: impl std::fmt::Display) -> Error {
+    Error::InvalidRequest(reason.to_string())
+}
+
+/// Reads the next element, returns it and the remaining input.
+fn next<'a>(input: &'a [u8], what: &str) -> Result<(Any<'a>, &'a [u8]), Error> {
+    let (rest, element) = Any::from_der(input).map_err(|_| invalid(format!("malformed {what}")))?;
+    Ok((element, rest))
+}
+
+fn expect(element: &Any, class: Class, tag: Tag, what: &str) -> Result<(), Error> {
+    match element.header.class() == class && element.header.tag() == tag {
+        true => Ok(()),
+        false => Err(invalid(format!("unexpected {what}"))),
+    }
+}
+
+fn oid<'a>(element: &Any<'a>, what: &str) -> Result<Oid<'a>, Error> {
+    element
+        .clone()
+        .oid()
+        .map_err(|_| invalid(format!("{what} is not an object identifier")))
+}
+
+/// Object identifier of an AlgorithmIdentifier, the parameters are ignored.
+fn algorithm<'a>(element: &Any<'a>, what: &str) -> Result<Oid<'a>, Error> {
+    expect(element, Class::Universal, Tag::Sequence, what)?;
+    let (identifier, _) = next(element.data, what)?;
+    oid(&identifier, what)
+}
+
+fn digest_algorithm(oid: &Oid) -> Result<&'static digest::Algorithm, Error> {
+    if *oid == OID_HASH_SHA1 {
+        Ok(&digest::SHA1_FOR_LEGACY_USE_ONLY)
+    } else if *oid == OID_NIST_HASH_SHA256 {
+        Ok(&digest::SHA256)
+    } else if *oid == OID_NIST_HASH_SHA384 {
+        Ok(&digest::SHA384)
+    } else if *oid == OID_NIST_HASH_SHA512 {
+        Ok(&digest::SHA512)
+    } else {
+        Err(invalid(format!("unsupported digest algorithm {oid}")))
+    }
+}
+
+/// Signature algorithm as used in certificates.
+///
+/// NOTE; CMS commonly identifies RSA signatures by the key algorithm, combined with the digest algorithm
+fn signature_algorithm(signature: Oid<'static>, digest: &Oid) -> Oid<'static> {
+    if signature != OID_PKCS1_RSAENCRYPTION {
+        return signature;
+    }
+    if *digest == OID_HASH_SHA1 {
+        OID_PKCS1_SHA1WITHRSA
+    } else if *digest == OID_NIST_HASH_SHA256 {
+        OID_PKCS1_SHA256WITHRSA
+    } else if *digest == OID_NIST_HASH_SHA384 {
+        OID_PKCS1_SHA384WITHRSA
+    } else if *digest == OID_NIST_HASH_SHA512 {
+        OID_PKCS1_SHA512WITHRSA
+    } else {
+        signature
+    }
+}
+
+/// Decodes a ContentInfo holding SignedData and verifies the signature of its first signer against the
+/// certificate of the signer included in the structure.
+///
+/// WARN; The signer certificate itself is not validated, the caller decides whether it's trusted
+pub fn verify(der: &[u8]) -> Result<SignedContent, Error> {
+    let (content_info, _) = next(der, "content info")?;
+    expect(
+        &content_info,
+        Class::Universal,
+        Tag::Sequence,
+        "content info",
+    )?;
+    let (content_type, rest) = next(content_info.data, "content type")?;
+    if oid(&content_type, "content type")? != OID_PKCS7_ID_SIGNED_DATA {
+        return Err(invalid("content is not signed data"));
+    }
+    let (explicit, _) = next(rest, "signed data")?;
+    expect(&explicit, Class::ContextSpecific, Tag(0), "signed data")?;
+    let (signed_data, _) = next(explicit.data, "signed data")?;
+    expect(&signed_data, Class::Universal, Tag::Sequence, "signed data")?;
+
+    let (_version, rest) = next(signed_data.data, "version")?;
+    let (_digest_algorithms, rest) = next(rest, "digest algorithms")?;
+    let (encapsulated, mut rest) = next(rest, "encapsulated content")?;
+    expect(
+        &encapsulated,
+        Class::Universal,
+        Tag::Sequence,
+        "encapsulated content",
+    )?;
+    let (_content_type, content) = next(encapsulated.data, "encapsulated content type")?;
+    let (explicit, _) = next(content, "encapsulated content")?;
+    expect(&explicit, Class::ContextSpecific, Tag(0), "content")?;
+    let (content, _) = next(explicit.data, "content")?;
+    expect(&content, Class::Universal, Tag::OctetString, "content")?;
+    let content = content.data;
+
+    // NOTE; Certificates [0] and revocation lists [1] are optional, followed by the signer infos
+    let mut certificates = Vec::new();
+    let signer_infos = loop {
+        let (element, remaining) = next(rest, "signer infos")?;
+        rest = remaining;
+        if element.header.class() != Class::ContextSpecific {
+            break element;
+        }
+        if element.header.tag() != Tag(0) {
+            continue;
+        }
+        let mut input = element.data;
+        while !input.is_empty() {
+            let (_, remaining) = next(input, "certificate")?;
+            certificates.push(&input[..input.len() - remaining.len()]);
+            input = remaining;
+        }
+    };
+    expect(&signer_infos, Class::Universal, Tag::Set, "signer infos")?;
+    let (signer_info, _) = next(signer_infos.data, "signer info")?;
+    expect(&signer_info, Class::Universal, Tag::Sequence, "signer info")?;
+
+    let (_version, rest) = next(signer_info.data, "signer version")?;
+    let (sid, rest) = next(rest, "signer identifier")?;
+    let signer_id = match (sid.header.class(), sid.header.tag()) {
+        (Class::Universal, Tag::Sequence) => {
+            let (_issuer, serial) = next(sid.data, "issuer")?;
+            let (serial, _) = next(serial, "serial number")?;
+            SignerId::SerialNumber(serial.data)
+        }
+        (Class::ContextSpecific, Tag(0)) => SignerId::SubjectKeyIdentifier(sid.data),
+        _ => return Err(invalid("unexpected signer identifier")),
+    };
+    let (digest_oid, mut rest) = next(rest, "digest algorithm")?;
+    let digest_oid = algorithm(&digest_oid, "digest algorithm")?;
+    let (mut element, mut after) = next(rest, "signature algorithm")?;
+    let mut signed_attributes = None;
+    if element.header.class() == Class::ContextSpecific && element.header.tag() == Tag(0) {
+        signed_attributes = Some((&rest[..rest.len() - after.len()], element));
+        rest = after;
+        (element, after) = next(rest, "signature algorithm")?;
+    }
+    let signature_oid = algorithm(&element, "signature algorithm")?.to_owned();
+    let (signature, _) = next(after, "signature")?;
+    expect(&signature, Class::Universal, Tag::OctetString, "signature")?;
+
+    // NOTE; With signed attributes the signature covers their SET OF encoding, which includes the digest
+    let digest = digest::digest(digest_algorithm(&digest_oid)?, content);
+    let signed = match signed_attributes {
+        Some((raw, attributes)) => {
+            if message_digest(attributes.data)? != digest.as_ref() {
+                return Err(Error::InvalidRequestSignature);
+            }
+            let mut signed = raw.to_vec();
+            signed[0] = 0x31;
+            signed
+        }
+        None => content.to_vec(),
+    };
+
+    let signer_der = certificates
+        .into_iter()
+        .find(|der| {
+            X509Certificate::from_der(der)
+                .is_ok_and(|(_, certificate)| signer_id.matches(&certificate))
+        })
+        .ok_or_else(|| invalid("signer certificate is missing"))?;
+    let (_, signer) = X509Certificate::from_der(signer_der).map_err(invalid)?;
+    verify_signature(
+        signer.public_key(),
+        &AlgorithmIdentifier::new(signature_algorithm(signature_oid, &digest_oid), None),
+        &BitString::new(0, signature.data),
+        &signed,
+    )
+    .map_err(|_| Error::InvalidRequestSignature)?;
+
+    Ok(SignedContent {
+        content: content.to_vec(),
+        signer_der: signer_der.to_vec(),
+    })
+}
+
+/// Value of the message digest attribute.
+fn message_digest(mut attributes: &[u8]) -> Result<&[u8], Error> {
+    while !attributes.is_empty() {
+        let (attribute, rest) = next(attributes, "signed attribute")?;
+        attributes = rest;
+        let (attribute_type, values) = next(attribute.data, "attribute type")?;
+        if oid(&attribute_type, "attribute type")? != OID_PKCS9_ID_MESSAGE_DIGEST {
+            continue;
+        }
+        let (values, _) = next(values, "attribute values")?;
+        let (value, _) = next(values.data, "message digest")?;
+        return Ok(value.data);
+    }
+    Err(invalid("message digest attribute is missing"))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use rcgen::KeyPair;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    /// DER encoding of a single element.
+    fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        match contents.len() {
+            length @ 0..=0x7f => encoded.push(length as u8),
+            length @ 0x80..=0xff => encoded.extend([0x81, length as u8]),
+            length => encoded.extend([0x82, (length >> 8) as u8, length as u8]),
+        }
+        encoded.extend_from_slice(contents);
+        encoded
+    }
+
+    fn oid(value: &Oid) -> Vec<u8> {
+        tlv(0x06, value.as_bytes())
+    }
+
+    /// SignedData over `content`, signed by `key_pair` which belongs to `certificate_der`.
+    pub(in crate::certificate_authority) fn sign(
+        content: &[u8],
+        certificate_der: &[u8],
+        key_pair: &KeyPair,
+    ) -> Vec<u8> {
+        let (_, certificate) = X509Certificate::from_der(certificate_der).unwrap();
+        let sha256 = tlv(0x30, &oid(&OID_NIST_HASH_SHA256));
+        let digest = digest::digest(&digest::SHA256, content);
+        let attributes = tlv(
+            0x30,
+            &[
+                oid(&OID_PKCS9_ID_MESSAGE_DIGEST),
+                tlv(0x31, &tlv(0x04, digest.as_ref())),
+            ]
+            .concat(),
+        );
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &key_pair.serialize_der(),
+            &SystemRandom::new(),
+        )
+        .unwrap();
+        let signature = key
+            .sign(&SystemRandom::new(), &tlv(0x31, &attributes))
+            .unwrap();
+
+        let issuer_and_serial = tlv(
+            0x30,
+            &[
+                certificate.tbs_certificate.issuer.as_raw().to_vec(),
+                tlv(0x02, certificate.tbs_certificate.raw_serial()),
+            ]
+            .concat(),
+        );
+        let signer_info = tlv(
+            0x30,
+            &[
+                tlv(0x02, &[1]),
+                issuer_and_serial,
+                sha256.clone(),
+                tlv(0xa0, &attributes),
+                tlv(
+                    0x30,
+                    &oid(&x509_parser::oid_registry::OID_SIG_ECDSA_WITH_SHA256),
+                ),
+                tlv(0x04, signature.as_ref()),
+            ]
+            .concat(),
+        );
+        let signed_data = tlv(
+            0x30,
+            &[
+                tlv(0x02, &[1]),
+                tlv(0x31, &sha256),
+                tlv(
+                    0x30,
+                    &[
+                        oid(&x509_parser::oid_registry::OID_PKCS7_ID_DATA),
+                        tlv(0xa0, &tlv(0x04, content)),
+                    ]
+                    .concat(),
+                ),
+                tlv(0xa0, certificate_der),
+                tlv(0x31, &signer_info),
+            ]
+            .concat(),
+        );
+        tlv(
+            0x30,
+            &[oid(&OID_PKCS7_ID_SIGNED_DATA), tlv(0xa0, &signed_data)].concat(),
+        )
+    }
+
+    #[test]
+    fn verify_test() {
+        let key_pair = KeyPair::generate().unwrap();
+        let certificate = rcgen::CertificateParams::new(vec!["DEVICE".into()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let signed = sign(b"request", certificate.der(), &key_pair);
+
+        let verified = verify(&signed).unwrap();
+        assert_eq!(verified.content, b"request");
+        assert_eq!(verified.signer_der, certificate.der().to_vec());
+
+        let other = sign(b"request", certificate.der(), &KeyPair::generate().unwrap());
+        assert!(matches!(
+            verify(&other),
+            Err(Error::InvalidRequestSignature)
+        ));
+        assert!(matches!(
+            verify(&signed[..signed.len() - 1]),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+}
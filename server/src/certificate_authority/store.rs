@@ -0,0 +1,214 @@
+//! Record of all certificates issued by the authority
+//!
+//! Each issued certificate is written DER encoded into the store directory, named after its serial number.
+//! Revocation adds a file with the same name holding the revocation time as unix timestamp. The serial
+//! counter is recovered from the directory contents at startup.
+
+use super::{Error, IssuedCertificate};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const CERTIFICATE_EXTENSION: &str = "cer";
+const REVOCATION_EXTENSION: &str = "revoked";
+
+#[derive(Debug, Default)]
+struct StoreState {
+    last_serial_number: u64,
+    issued: BTreeMap<u64, IssuedCertificate>,
+    /// Serial numbers keyed by the uppercase thumbprint of the certificate
+    thumbprints: HashMap<String, u64>,
+}
+
+impl StoreState {
+    fn insert(&mut self, certificate: IssuedCertificate) {
+        self.thumbprints.insert(
+            certificate.thumbprint.to_ascii_uppercase(),
+            certificate.serial_number,
+        );
+        self.issued.insert(certificate.serial_number, certificate);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CertificateStore {
+    /// Directory holding the issued certificates, `None` keeps the certificates in memory only
+    directory: Option<PathBuf>,
+    state: Mutex<StoreState>,
+}
+
+impl CertificateStore {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Opens the store directory, creating it when missing, and loads all previously issued certificates.
+    pub fn open(directory: impl Into<PathBuf>) -> Result<Self, Error> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+
+        let mut state = StoreState::default();
+        let mut revocations = Vec::new();
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(CERTIFICATE_EXTENSION) => {
+                    let certificate = read_certificate(&path)?;
+                    state.last_serial_number =
+                        state.last_serial_number.max(certificate.serial_number);
+                    state.insert(certificate);
+                }
+                Some(REVOCATION_EXTENSION) => revocations.push(path),
+                _ => {}
+            }
+        }
+        for path in revocations {
+            let (serial_number, revoked_at) = read_revocation(&path)?;
+            if let Some(certificate) = state.issued.get_mut(&serial_number) {
+                certificate.revoked_at = Some(revoked_at);
+            }
+        }
+
+        Ok(Self {
+            directory: Some(directory),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Reserves the next serial number, serial numbers are never reused even when signing fails.
+    pub fn next_serial_number(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.last_serial_number += 1;
+        state.last_serial_number
+    }
+
+    pub fn insert(&self, certificate: IssuedCertificate) -> Result<(), Error> {
+        if let Some(directory) = &self.directory {
+            let path = directory.join(file_name(certificate.serial_number));
+            std::fs::write(path, &certificate.der)?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.insert(certificate);
+        Ok(())
+    }
+
+    /// Marks the certificate as revoked, returns `None` for unknown certificates. Revoking a certificate
+    /// again keeps the original revocation time.
+    pub fn revoke(
+        &self,
+        serial_number: u64,
+        at: OffsetDateTime,
+    ) -> Result<Option<IssuedCertificate>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let Some(certificate) = state.issued.get_mut(&serial_number) else {
+            return Ok(None);
+        };
+        if certificate.revoked_at.is_none() {
+            if let Some(directory) = &self.directory {
+                let path = directory.join(format!("{serial_number:016X}.{REVOCATION_EXTENSION}"));
+                std::fs::write(path, at.unix_timestamp().to_string())?;
+            }
+            certificate.revoked_at = Some(at);
+        }
+        Ok(Some(certificate.clone()))
+    }
+
+    pub fn find_by_thumbprint(&self, thumbprint: &str) -> Option<IssuedCertificate> {
+        let state = self.state.lock().unwrap();
+        state
+            .thumbprints
+            .get(&thumbprint.to_ascii_uppercase())
+            .and_then(|serial_number| state.issued.get(serial_number))
+            .cloned()
+    }
+
+    /// All certificates issued to the device, oldest first.
+    pub fn find_by_device(&self, device_id: &str) -> Vec<IssuedCertificate> {
+        let state = self.state.lock().unwrap();
+        state
+            .issued
+            .values()
+            .filter(|certificate| certificate.device_id == device_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn revoked(&self) -> Vec<IssuedCertificate> {
+        let state = self.state.lock().unwrap();
+        state
+            .issued
+            .values()
+            .filter(|certificate| certificate.revoked_at.is_some())
+            .cloned()
+            .collect()
+    }
+}
+
+fn file_name(serial_number: u64) -> String {
+    format!("{serial_number:016X}.{CERTIFICATE_EXTENSION}")
+}
+
+fn read_certificate(path: &Path) -> Result<IssuedCertificate, Error> {
+    let der = std::fs::read(path)?;
+    IssuedCertificate::from_der(der).map_err(|error| {
+        Error::InvalidAuthority(format!(
+            "unreadable certificate {}: {error}",
+            path.display()
+        ))
+    })
+}
+
+/// Serial number and revocation time of a revocation file.
+fn read_revocation(path: &Path) -> Result<(u64, OffsetDateTime), Error> {
+    let invalid = || Error::InvalidAuthority(format!("unreadable revocation {}", path.display()));
+    let serial_number = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| u64::from_str_radix(stem, 16).ok())
+        .ok_or_else(invalid)?;
+    let revoked_at = std::fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+        .ok_or_else(invalid)?;
+    Ok((serial_number, revoked_at))
+}
+
+impl IssuedCertificate {
+    pub(super) fn from_der(der: Vec<u8>) -> Result<Self, String> {
+        let (_, certificate) = X509Certificate::from_der(&der).map_err(|e| e.to_string())?;
+
+        let serial = certificate.tbs_certificate.raw_serial();
+        let serial = match serial.iter().position(|byte| *byte != 0) {
+            Some(start) => &serial[start..],
+            None => &[],
+        };
+        if serial.len() > 8 {
+            return Err("serial number exceeds 64 bits".into());
+        }
+        let mut serial_number = [0u8; 8];
+        serial_number[8 - serial.len()..].copy_from_slice(serial);
+
+        let device_id = certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .ok_or("missing subject common name")?
+            .to_string();
+        let not_after = certificate.validity().not_after.to_datetime();
+
+        Ok(Self {
+            serial_number: u64::from_be_bytes(serial_number),
+            device_id,
+            not_after,
+            thumbprint: super::thumbprint(&der),
+            der,
+            revoked_at: None,
+        })
+    }
+}
@@ -0,0 +1,64 @@
+//! Command queue that lives as long as the process
+
+use super::{CommandQueue, Error, QueuedCommand};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub struct MemoryCommandQueue {
+    commands: Mutex<BTreeMap<i64, QueuedCommand>>,
+}
+
+impl MemoryCommandQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CommandQueue for MemoryCommandQueue {
+    fn insert(&self, command: &QueuedCommand) -> Result<i64, Error> {
+        let mut commands = self.commands.lock().unwrap();
+        let id = commands.keys().next_back().map_or(1, |id| id + 1);
+        commands.insert(
+            id,
+            QueuedCommand {
+                id,
+                ..command.clone()
+            },
+        );
+        Ok(id)
+    }
+
+    fn get(&self, id: i64) -> Result<Option<QueuedCommand>, Error> {
+        Ok(self.commands.lock().unwrap().get(&id).cloned())
+    }
+
+    fn list(&self, device_id: &str) -> Result<Vec<QueuedCommand>, Error> {
+        Ok(self
+            .commands
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|command| command.device_id == device_id)
+            .cloned()
+            .collect())
+    }
+
+    fn update(&self, command: &QueuedCommand) -> Result<(), Error> {
+        self.commands
+            .lock()
+            .unwrap()
+            .insert(command.id, command.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_queue_test() {
+        super::super::tests::exercise_queue(&MemoryCommandQueue::new());
+    }
+}
@@ -0,0 +1,249 @@
+//! Durable queue of commands for individual devices
+//!
+//! Ad-hoc operations, eg a reboot or reading a node, are queued per device and survive restarts of the server.
+//! Each check-in hands the pending commands of the device to its management session, the status and results
+//! reported by the device are stored with the command. Commands that don't receive a status, because the
+//! session ended early, are retried at the next check-in up to [`MAX_ATTEMPTS`] times.
+
+use crate::microsoft_protocol::syncml::{Command, Item};
+use chrono::{DateTime, Utc};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryCommandQueue;
+pub use sqlite::SqliteCommandQueue;
+
+/// Deliveries of a command before it is given up.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum Error {
+    Database(rusqlite::Error),
+    /// A stored record could not be decoded
+    InvalidRecord(String),
+    /// Only commands addressing nodes can be queued, eg not Atomic
+    Unsupported(&'static str),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Database(error) => write!(f, "command queue failure: {error}"),
+            Error::InvalidRecord(reason) => write!(f, "invalid command record: {reason}"),
+            Error::Unsupported(name) => write!(f, "{name} commands cannot be queued"),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::Database(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandState {
+    /// Waiting for the next check-in of the device
+    Pending,
+    /// Handed to a management session, awaiting status
+    Sent,
+    /// The device reported a status, which may be an error
+    Completed,
+    /// The device didn't report a status within [`MAX_ATTEMPTS`] deliveries
+    Failed,
+}
+
+impl CommandState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandState::Pending => "pending",
+            CommandState::Sent => "sent",
+            CommandState::Completed => "completed",
+            CommandState::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for CommandState {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pending" => Ok(CommandState::Pending),
+            "sent" => Ok(CommandState::Sent),
+            "completed" => Ok(CommandState::Completed),
+            "failed" => Ok(CommandState::Failed),
+            _ => Err(Error::InvalidRecord(format!(
+                "unknown command state '{value}'"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedCommand {
+    /// Assigned by the queue, increases with every queued command
+    pub id: i64,
+    pub device_id: String,
+    pub command: Command,
+    pub state: CommandState,
+    /// Status code reported by the device
+    pub status: Option<u16>,
+    /// Items of the Results the device returned, eg the value of a Get
+    pub results: Vec<Item>,
+    pub created_at: DateTime<Utc>,
+    /// Most recent delivery
+    pub sent_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Amount of deliveries
+    pub attempts: u32,
+}
+
+pub trait CommandQueue: Send + Sync {
+    /// Stores a new record, returns the assigned identifier.
+    fn insert(&self, command: &QueuedCommand) -> Result<i64, Error>;
+
+    fn get(&self, id: i64) -> Result<Option<QueuedCommand>, Error>;
+
+    /// Commands of the device, ordered by identifier.
+    fn list(&self, device_id: &str) -> Result<Vec<QueuedCommand>, Error>;
+
+    /// Replaces the record with the same identifier.
+    fn update(&self, command: &QueuedCommand) -> Result<(), Error>;
+
+    fn push(
+        &self,
+        device_id: &str,
+        command: Command,
+        now: DateTime<Utc>,
+    ) -> Result<QueuedCommand, Error> {
+        if let Command::Alert(_) | Command::Atomic(_) | Command::Sequence(_) = command {
+            return Err(Error::Unsupported(command.name()));
+        }
+        let mut queued = QueuedCommand {
+            id: 0,
+            device_id: device_id.into(),
+            command,
+            state: CommandState::Pending,
+            status: None,
+            results: Vec::new(),
+            created_at: now,
+            sent_at: None,
+            completed_at: None,
+            attempts: 0,
+        };
+        queued.id = self.insert(&queued)?;
+        Ok(queued)
+    }
+
+    /// Pending commands of the device, marked as sent.
+    fn take_pending(
+        &self,
+        device_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<QueuedCommand>, Error> {
+        let mut taken = Vec::new();
+        for mut command in self.list(device_id)? {
+            if command.state != CommandState::Pending {
+                continue;
+            }
+            command.state = CommandState::Sent;
+            command.sent_at = Some(now);
+            command.attempts += 1;
+            self.update(&command)?;
+            taken.push(command);
+        }
+        Ok(taken)
+    }
+
+    /// Registers the end of a delivery, commands without status are retried until [`MAX_ATTEMPTS`].
+    fn record_outcome(
+        &self,
+        id: i64,
+        status: Option<u16>,
+        results: Vec<Item>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<QueuedCommand>, Error> {
+        let Some(mut command) = self.get(id)? else {
+            return Ok(None);
+        };
+        match status {
+            Some(_) => {
+                command.state = CommandState::Completed;
+                command.completed_at = Some(now);
+            }
+            None if command.attempts < MAX_ATTEMPTS => command.state = CommandState::Pending,
+            None => {
+                command.state = CommandState::Failed;
+                command.completed_at = Some(now);
+            }
+        }
+        command.status = status;
+        command.results = results;
+        self.update(&command)?;
+        Ok(Some(command))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microsoft_protocol::syncml::{ItemCommand, Meta};
+
+    /// Runs the shared bookkeeping against a backend.
+    pub(super) fn exercise_queue(queue: &dyn CommandQueue) {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let reboot = Command::Exec(ItemCommand::new(Item::target(
+            "./Device/Vendor/MSFT/Reboot/RebootNow",
+        )));
+        let query = Command::Get(ItemCommand::new(Item::target("./DevDetail/SwV")));
+        let rename = Command::Replace(ItemCommand::new(
+            Item::target("./DevDetail/Ext/Microsoft/DeviceName")
+                .with_meta(Meta::with_format("chr"))
+                .with_data("DESKTOP-2"),
+        ));
+
+        let reboot = queue.push("DEVICE", reboot, now).unwrap();
+        let query = queue.push("DEVICE", query, now).unwrap();
+        let rename = queue.push("OTHER", rename, now).unwrap();
+        assert!(reboot.id < query.id);
+        assert_eq!(queue.get(rename.id).unwrap().unwrap(), rename);
+        assert!(matches!(
+            queue.push("DEVICE", Command::Atomic(Default::default()), now),
+            Err(Error::Unsupported("Atomic"))
+        ));
+
+        let taken = queue.take_pending("DEVICE", now).unwrap();
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[0].command, reboot.command);
+        assert_eq!(taken[0].attempts, 1);
+        assert!(queue.take_pending("DEVICE", now).unwrap().is_empty());
+
+        let results = vec![Item::source("./DevDetail/SwV").with_data("10.0.22631.4460")];
+        let completed = queue
+            .record_outcome(query.id, Some(200), results.clone(), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(completed.state, CommandState::Completed);
+
+        // NOTE; The reboot never receives a status
+        for attempt in 1..MAX_ATTEMPTS {
+            let retried = queue.record_outcome(reboot.id, None, vec![], now).unwrap();
+            assert_eq!(retried.unwrap().state, CommandState::Pending);
+            let taken = queue.take_pending("DEVICE", now).unwrap();
+            assert_eq!(taken[0].attempts, attempt + 1);
+        }
+        queue.record_outcome(reboot.id, None, vec![], now).unwrap();
+
+        let commands = queue.list("DEVICE").unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].state, CommandState::Failed);
+        assert_eq!(commands[0].attempts, MAX_ATTEMPTS);
+        assert_eq!(commands[1].status, Some(200));
+        assert_eq!(commands[1].results, results);
+        assert_eq!(commands[1].completed_at, Some(now));
+    }
+}
@@ -0,0 +1,262 @@
+//! Command queue backed by an embedded SQLite database
+
+use super::{CommandQueue, CommandState, Error, QueuedCommand};
+use crate::microsoft_protocol::syncml::{Command, Item, ItemCommand, LocationRef, Meta};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Row, Transaction};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS queued_commands (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    device_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    state TEXT NOT NULL,
+    status INTEGER,
+    created_at INTEGER NOT NULL,
+    sent_at INTEGER,
+    completed_at INTEGER,
+    attempts INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS queued_commands_device ON queued_commands (device_id);
+CREATE TABLE IF NOT EXISTS queued_command_items (
+    command_id INTEGER NOT NULL REFERENCES queued_commands (id) ON DELETE CASCADE,
+    result INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    target TEXT,
+    source TEXT,
+    format TEXT,
+    type TEXT,
+    data TEXT,
+    PRIMARY KEY (command_id, result, position)
+);
+";
+
+const COLUMNS: &str =
+    "id, device_id, name, state, status, created_at, sent_at, completed_at, attempts";
+
+pub struct SqliteCommandQueue {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteCommandQueue {
+    /// Opens the database file, creating it and its tables when missing.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, Error> {
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+        // NOTE; Sessions don't survive a restart, commands awaiting status are delivered again
+        connection.execute(
+            "UPDATE queued_commands SET state = ?1 WHERE state = ?2",
+            params![CommandState::Pending.as_str(), CommandState::Sent.as_str()],
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn timestamp(value: i64) -> Result<DateTime<Utc>, Error> {
+    DateTime::from_timestamp(value, 0)
+        .ok_or_else(|| Error::InvalidRecord(format!("timestamp {value} out of range")))
+}
+
+/// Columns are read in the order of [`COLUMNS`], the items are read separately.
+fn read_command(row: &Row) -> Result<(QueuedCommand, String), Error> {
+    let state: String = row.get(3)?;
+    let sent_at: Option<i64> = row.get(6)?;
+    let completed_at: Option<i64> = row.get(7)?;
+    let command = QueuedCommand {
+        id: row.get(0)?,
+        device_id: row.get(1)?,
+        command: Command::Get(ItemCommand::default()),
+        state: state.parse()?,
+        status: row.get(4)?,
+        results: Vec::new(),
+        created_at: timestamp(row.get(5)?)?,
+        sent_at: sent_at.map(timestamp).transpose()?,
+        completed_at: completed_at.map(timestamp).transpose()?,
+        attempts: row.get(8)?,
+    };
+    Ok((command, row.get(2)?))
+}
+
+/// Loads the items of the command and its results.
+fn read_items(
+    connection: &Connection,
+    command: &mut QueuedCommand,
+    name: &str,
+) -> Result<(), Error> {
+    let mut statement = connection.prepare(
+        "SELECT result, target, source, format, type, data FROM queued_command_items \
+         WHERE command_id = ?1 ORDER BY result, position",
+    )?;
+    let mut rows = statement.query(params![command.id])?;
+    let mut items = Vec::new();
+    while let Some(row) = rows.next()? {
+        let result: bool = row.get(0)?;
+        let target: Option<String> = row.get(1)?;
+        let source: Option<String> = row.get(2)?;
+        let format: Option<String> = row.get(3)?;
+        let meta_type: Option<String> = row.get(4)?;
+        let item = Item {
+            target: target.map(LocationRef::new),
+            source: source.map(LocationRef::new),
+            meta: (format.is_some() || meta_type.is_some()).then_some(Meta {
+                format,
+                meta_type,
+                max_msg_size: None,
+            }),
+            data: row.get(5)?,
+        };
+        match result {
+            true => command.results.push(item),
+            false => items.push(item),
+        }
+    }
+
+    let item_command = ItemCommand {
+        item: items,
+        ..Default::default()
+    };
+    command.command = match name {
+        "Add" => Command::Add(item_command),
+        "Replace" => Command::Replace(item_command),
+        "Delete" => Command::Delete(item_command),
+        "Get" => Command::Get(item_command),
+        "Exec" => Command::Exec(item_command),
+        _ => return Err(Error::InvalidRecord(format!("unknown command '{name}'"))),
+    };
+    Ok(())
+}
+
+fn write_items(transaction: &Transaction, command: &QueuedCommand) -> Result<(), Error> {
+    transaction.execute(
+        "DELETE FROM queued_command_items WHERE command_id = ?1",
+        params![command.id],
+    )?;
+    let items = command.command.items().iter().map(|item| (false, item));
+    let results = command.results.iter().map(|item| (true, item));
+    for (position, (result, item)) in items.chain(results).enumerate() {
+        let meta = item.meta.as_ref();
+        transaction.execute(
+            "INSERT INTO queued_command_items \
+             (command_id, result, position, target, source, format, type, data) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                command.id,
+                result,
+                position,
+                item.target.as_ref().map(|target| &target.loc_uri),
+                item.source.as_ref().map(|source| &source.loc_uri),
+                meta.and_then(|meta| meta.format.as_ref()),
+                meta.and_then(|meta| meta.meta_type.as_ref()),
+                item.data,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+impl CommandQueue for SqliteCommandQueue {
+    fn insert(&self, command: &QueuedCommand) -> Result<i64, Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO queued_commands \
+             (device_id, name, state, status, created_at, sent_at, completed_at, attempts) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                command.device_id,
+                command.command.name(),
+                command.state.as_str(),
+                command.status,
+                command.created_at.timestamp(),
+                command.sent_at.map(|at| at.timestamp()),
+                command.completed_at.map(|at| at.timestamp()),
+                command.attempts,
+            ],
+        )?;
+        let id = transaction.last_insert_rowid();
+        write_items(
+            &transaction,
+            &QueuedCommand {
+                id,
+                ..command.clone()
+            },
+        )?;
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    fn get(&self, id: i64) -> Result<Option<QueuedCommand>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM queued_commands WHERE id = ?1"
+        ))?;
+        let mut rows = statement.query(params![id])?;
+        let Some((mut command, name)) = rows.next()?.map(read_command).transpose()? else {
+            return Ok(None);
+        };
+        read_items(&connection, &mut command, &name)?;
+        Ok(Some(command))
+    }
+
+    fn list(&self, device_id: &str) -> Result<Vec<QueuedCommand>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM queued_commands WHERE device_id = ?1 ORDER BY id"
+        ))?;
+        let mut rows = statement.query(params![device_id])?;
+        let mut commands = Vec::new();
+        while let Some(row) = rows.next()? {
+            commands.push(read_command(row)?);
+        }
+        commands
+            .into_iter()
+            .map(|(mut command, name)| {
+                read_items(&connection, &mut command, &name)?;
+                Ok(command)
+            })
+            .collect()
+    }
+
+    fn update(&self, command: &QueuedCommand) -> Result<(), Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "UPDATE queued_commands SET state = ?2, status = ?3, sent_at = ?4, completed_at = ?5, \
+             attempts = ?6 WHERE id = ?1",
+            params![
+                command.id,
+                command.state.as_str(),
+                command.status,
+                command.sent_at.map(|at| at.timestamp()),
+                command.completed_at.map(|at| at.timestamp()),
+                command.attempts,
+            ],
+        )?;
+        write_items(&transaction, command)?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_queue_test() {
+        super::super::tests::exercise_queue(&SqliteCommandQueue::open_in_memory().unwrap());
+    }
+}
@@ -0,0 +1,1029 @@
+//! Server configuration
+//!
+//! The configuration is read from a TOML file, every setting has a default so the file only needs to contain
+//! the deviations. Environment variables override the file, see [`ServerConfig::apply_overrides`].
+//!
+//! The file is located through the `SIMPLE_MDM_CONFIG` environment variable, defaulting to `simple_mdm.toml`
+//! within the working directory. Relative paths, including the default locations, are resolved against the
+//! directory of the file, or against the working directory when no file is read.
+//!
+//! Without tenants the settings describe a single environment. Each configured tenant receives its own copy of
+//! the settings, see [`ServerConfig::tenant_config`].
+
+use crate::certificate_authority::{AuthorityOptions, CRL_PATH};
+use crate::enrollment_policy::{CertificateTemplate, HashAlgorithm, KeySpec, PolicySet};
+use crate::microsoft_protocol::mde_v2::AuthPolicyType;
+use crate::xsd_primitives::Decimal;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const CONFIG_PATH_VARIABLE: &str = "SIMPLE_MDM_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "simple_mdm.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A setting holds an unusable value, the first field names the setting
+    InvalidValue(String, String),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "cannot read {}: {error}", path.display()),
+            ConfigError::Parse(path, error) => write!(f, "invalid {}: {error}", path.display()),
+            ConfigError::InvalidValue(setting, reason) => {
+                write!(f, "invalid value for {setting}: {reason}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Socket addresses the HTTPS server listens on
+    pub listen: Vec<SocketAddr>,
+    /// Scheme and authority devices use to reach this server, eg "https://mdm.example.com"
+    pub external_url: String,
+    /// Maximum size of a request body, in bytes
+    pub body_limit: usize,
+    /// SQLite database holding the device registry, `None` keeps the registry in memory
+    pub database: Option<PathBuf>,
+    pub tls: TlsConfig,
+    pub enrollment: EnrollmentConfig,
+    pub discovery: DiscoveryConfig,
+    pub certificate_authority: CertificateAuthorityConfig,
+    /// Administrators, lines of `username:argon2-hash`
+    pub admin_password_file: PathBuf,
+    /// Directory of DDF files describing the CSPs of the devices, `None` queues commands without validation
+    pub csp_definitions: Option<PathBuf>,
+    /// Directory of configuration profiles, `.toml` or `.yaml` files applied to device groups
+    pub profiles: PathBuf,
+    pub drift: DriftConfig,
+    pub admin: AdminConfig,
+    /// Environments served by this server, each with isolated data
+    pub tenants: Vec<TenantConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    /// Identifier of the tenant, names its data directory
+    pub name: String,
+    /// Host names the tenant is reached at, besides the host of its external url
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Email domains of the users enrolling into the tenant
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Directory holding the database, certificate authority and credential files of the tenant, defaults to
+    /// tenants/{name} next to the configuration file
+    pub directory: Option<PathBuf>,
+    /// Defaults to external_url
+    pub external_url: Option<String>,
+    /// Defaults to enrollment.auth_policies
+    pub auth_policies: Option<Vec<AuthPolicy>>,
+    /// Defaults to enrollment.allowed_os_editions
+    pub allowed_os_editions: Option<Vec<u32>>,
+    /// Defaults to enrollment.authentication_service_url
+    pub authentication_service_url: Option<String>,
+    /// Common name of the tenant certificate authority, defaults to certificate_authority.common_name
+    pub certificate_authority_name: Option<String>,
+    /// Server certificate for the hosts of the tenant, chosen by SNI, defaults to tls
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub certificate: PathBuf,
+    /// PEM encoded PKCS#8 private key
+    pub private_key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrollmentConfig {
+    /// Allowed authentication policies in order of preference, discovery picks the first one the client supports
+    pub auth_policies: Vec<AuthPolicy>,
+    /// Highest MS-MDE2 protocol version supported by the server, eg "4.0"
+    pub version: String,
+    /// OS edition (product type) numbers allowed to enroll, empty allows all editions
+    pub allowed_os_editions: Vec<u32>,
+    /// Users allowed to enroll under the OnPremise policy, lines of `username:argon2-hash`
+    pub password_file: PathBuf,
+    /// Unused enrollment tokens for the Federated policy, lines of `username:token:expires_at`
+    pub token_file: PathBuf,
+    /// Page where users sign in under the Federated policy, `None` uses the login page of this server
+    pub authentication_service_url: Option<String>,
+    /// Certificate templates offered by the enrollment policy, empty offers the default template
+    pub templates: Vec<TemplateConfig>,
+}
+
+/// Certificate template offered by the enrollment policy, validity and renewal period follow the
+/// certificate authority.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateConfig {
+    /// Name of the template, shown to the client
+    pub common_name: String,
+    /// Unique object identifier of the template, eg "1.3.6.1.4.1.311.21.8.1.1"
+    pub oid: String,
+    /// Minimal length of the key pair generated by the client, in bits
+    pub minimal_key_length: u32,
+    /// KeyExchange or Signature, `None` leaves the purpose of the key to the client
+    pub key_spec: Option<KeySpec>,
+    /// Sha256, Sha384 or Sha512
+    pub hash_algorithm: HashAlgorithm,
+    /// Bump whenever the template changes, clients re-evaluate on revision change
+    pub major_revision: u32,
+    pub minor_revision: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Services per email domain of the enrolling user. When empty every user is sent to this server,
+    /// otherwise users of unlisted domains are refused.
+    pub routes: Vec<DiscoveryRoute>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryRoute {
+    /// Email domain, compared case-insensitive
+    pub domain: String,
+    /// Scheme and authority of the enrollment services for this domain, defaults to external_url
+    pub external_url: Option<String>,
+    /// Authentication policies allowed for this domain, defaults to enrollment.auth_policies
+    pub auth_policies: Option<Vec<AuthPolicy>>,
+    /// Page where users of this domain sign in under the Federated policy, defaults to
+    /// enrollment.authentication_service_url
+    pub authentication_service_url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CertificateAuthorityConfig {
+    /// Directory holding the authority key material and issued certificates
+    pub directory: Option<PathBuf>,
+    pub common_name: String,
+    pub use_intermediate: bool,
+    /// Validity of issued device certificates, in days
+    pub validity_days: u64,
+    /// Days before expiry at which devices start renewing their certificate
+    pub renewal_period_days: u64,
+    /// Days between renewal attempts of a device after a failed renewal
+    pub renewal_retry_interval_days: u64,
+    /// Devices renew on their own with their current certificate (ROBO), otherwise the user is prompted to
+    /// renew
+    pub robo_support: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Socket addresses serving only the admin API, when empty the admin API is served by `listen`
+    pub listen: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriftConfig {
+    /// Hours between verifications of the applied profiles of a device, 0 disables verification
+    pub check_interval_hours: u64,
+    /// Sends settings that drifted again, otherwise drift is only recorded
+    pub remediate: bool,
+}
+
+impl Default for DriftConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_hours: 24,
+            remediate: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AuthPolicy {
+    OnPremise,
+    Federated,
+    Certificate,
+}
+
+impl FromStr for AuthPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "OnPremise" => Ok(AuthPolicy::OnPremise),
+            "Federated" => Ok(AuthPolicy::Federated),
+            "Certificate" => Ok(AuthPolicy::Certificate),
+            _ => Err(format!(
+                "unknown policy '{value}', expected OnPremise, Federated or Certificate"
+            )),
+        }
+    }
+}
+
+impl TryFrom<&AuthPolicyType> for AuthPolicy {
+    type Error = String;
+
+    fn try_from(value: &AuthPolicyType) -> Result<Self, Self::Error> {
+        match value {
+            AuthPolicyType::OnPremise => Ok(AuthPolicy::OnPremise),
+            AuthPolicyType::Federated => Ok(AuthPolicy::Federated),
+            AuthPolicyType::Certificate => Ok(AuthPolicy::Certificate),
+            AuthPolicyType::__Unknown__(value) => Err(format!("unknown policy '{value}'")),
+        }
+    }
+}
+
+impl From<AuthPolicy> for AuthPolicyType {
+    fn from(value: AuthPolicy) -> Self {
+        match value {
+            AuthPolicy::OnPremise => AuthPolicyType::OnPremise,
+            AuthPolicy::Federated => AuthPolicyType::Federated,
+            AuthPolicy::Certificate => AuthPolicyType::Certificate,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 1, 167], 3000))],
+            external_url: "https://mdmwindows.com".into(),
+            body_limit: 5 * 1024 * 1024,
+            database: Some(PathBuf::from("simple_mdm.sqlite")),
+            tls: TlsConfig::default(),
+            enrollment: EnrollmentConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            certificate_authority: CertificateAuthorityConfig::default(),
+            admin_password_file: PathBuf::from("admin_users"),
+            csp_definitions: None,
+            profiles: PathBuf::from("profiles"),
+            drift: DriftConfig::default(),
+            admin: AdminConfig::default(),
+            tenants: Vec::new(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        let directory = PathBuf::from("self_signed_certs");
+        Self {
+            certificate: directory.join("cert.pem"),
+            private_key: directory.join("key.pem"),
+        }
+    }
+}
+
+impl Default for EnrollmentConfig {
+    fn default() -> Self {
+        Self {
+            auth_policies: vec![AuthPolicy::OnPremise],
+            version: "4.0".into(),
+            allowed_os_editions: Vec::new(),
+            password_file: PathBuf::from("enrollment_users"),
+            token_file: PathBuf::from("enrollment_tokens"),
+            authentication_service_url: None,
+            templates: Vec::new(),
+        }
+    }
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        let defaults = CertificateTemplate::default();
+        Self {
+            common_name: defaults.common_name,
+            oid: defaults.oid,
+            minimal_key_length: defaults.minimal_key_length,
+            key_spec: defaults.key_spec,
+            hash_algorithm: defaults.hash_algorithm,
+            major_revision: defaults.major_revision,
+            minor_revision: defaults.minor_revision,
+        }
+    }
+}
+
+impl Default for CertificateAuthorityConfig {
+    fn default() -> Self {
+        let defaults = AuthorityOptions::default();
+        Self {
+            directory: Some(PathBuf::from("certificate_authority")),
+            common_name: defaults.common_name,
+            use_intermediate: defaults.use_intermediate,
+            validity_days: defaults.profile.validity.as_secs() / (24 * 60 * 60),
+            renewal_period_days: 42,
+            renewal_retry_interval_days: 7,
+            robo_support: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the configuration file and applies the environment overrides.
+    ///
+    /// NOTE; A missing file at the default location results in the default configuration, a missing file
+    /// that was explicitly requested is an error.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var(CONFIG_PATH_VARIABLE) {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Self::default(),
+        };
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.into(), e))?;
+        let mut config: Self =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.into(), e))?;
+        config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+        Ok(config)
+    }
+
+    /// Prefixes all relative paths with `base`.
+    ///
+    /// NOTE; Tenants without directory receive tenants/{name} within `base`
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        };
+        if let Some(database) = &mut self.database {
+            resolve(database);
+        }
+        resolve(&mut self.tls.certificate);
+        resolve(&mut self.tls.private_key);
+        resolve(&mut self.enrollment.password_file);
+        resolve(&mut self.enrollment.token_file);
+        if let Some(directory) = &mut self.certificate_authority.directory {
+            resolve(directory);
+        }
+        resolve(&mut self.admin_password_file);
+        if let Some(csp_definitions) = &mut self.csp_definitions {
+            resolve(csp_definitions);
+        }
+        resolve(&mut self.profiles);
+        for tenant in &mut self.tenants {
+            let directory = tenant
+                .directory
+                .get_or_insert_with(|| Path::new("tenants").join(&tenant.name));
+            resolve(directory);
+            if let Some(tls) = &mut tenant.tls {
+                resolve(&mut tls.certificate);
+                resolve(&mut tls.private_key);
+            }
+        }
+    }
+
+    /// Overrides settings with the matching `SIMPLE_MDM_*` variables, `lookup` resolves a variable name.
+    ///
+    /// | Variable | Setting |
+    /// |---|---|
+    /// | SIMPLE_MDM_LISTEN | listen, comma separated |
+    /// | SIMPLE_MDM_EXTERNAL_URL | external_url |
+    /// | SIMPLE_MDM_BODY_LIMIT | body_limit |
+    /// | SIMPLE_MDM_DATABASE | database |
+    /// | SIMPLE_MDM_TLS_CERTIFICATE | tls.certificate |
+    /// | SIMPLE_MDM_TLS_PRIVATE_KEY | tls.private_key |
+    /// | SIMPLE_MDM_AUTH_POLICIES | enrollment.auth_policies, comma separated |
+    /// | SIMPLE_MDM_ENROLLMENT_VERSION | enrollment.version |
+    /// | SIMPLE_MDM_PASSWORD_FILE | enrollment.password_file |
+    /// | SIMPLE_MDM_TOKEN_FILE | enrollment.token_file |
+    /// | SIMPLE_MDM_AUTHENTICATION_SERVICE_URL | enrollment.authentication_service_url |
+    /// | SIMPLE_MDM_CA_DIRECTORY | certificate_authority.directory |
+    /// | SIMPLE_MDM_CSP_DEFINITIONS | csp_definitions |
+    /// | SIMPLE_MDM_PROFILES | profiles |
+    /// | SIMPLE_MDM_DRIFT_CHECK_INTERVAL_HOURS | drift.check_interval_hours |
+    /// | SIMPLE_MDM_DRIFT_REMEDIATE | drift.remediate |
+    /// | SIMPLE_MDM_ADMIN_LISTEN | admin.listen, comma separated |
+    pub fn apply_overrides(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), ConfigError> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .trim()
+                .parse()
+                .map_err(|e: T::Err| ConfigError::InvalidValue(name.into(), e.to_string()))
+        }
+
+        if let Some(value) = lookup("SIMPLE_MDM_LISTEN") {
+            self.listen = value
+                .split(',')
+                .map(|address| parse("SIMPLE_MDM_LISTEN", address))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_EXTERNAL_URL") {
+            self.external_url = value;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_BODY_LIMIT") {
+            self.body_limit = parse("SIMPLE_MDM_BODY_LIMIT", &value)?;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_DATABASE") {
+            self.database = Some(value.into());
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_TLS_CERTIFICATE") {
+            self.tls.certificate = value.into();
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_TLS_PRIVATE_KEY") {
+            self.tls.private_key = value.into();
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_AUTH_POLICIES") {
+            self.enrollment.auth_policies = value
+                .split(',')
+                .map(|policy| parse("SIMPLE_MDM_AUTH_POLICIES", policy))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_ENROLLMENT_VERSION") {
+            self.enrollment.version = value;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_PASSWORD_FILE") {
+            self.enrollment.password_file = value.into();
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_TOKEN_FILE") {
+            self.enrollment.token_file = value.into();
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_AUTHENTICATION_SERVICE_URL") {
+            self.enrollment.authentication_service_url = Some(value);
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_CA_DIRECTORY") {
+            self.certificate_authority.directory = Some(value.into());
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_CSP_DEFINITIONS") {
+            self.csp_definitions = Some(value.into());
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_PROFILES") {
+            self.profiles = value.into();
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_DRIFT_CHECK_INTERVAL_HOURS") {
+            self.drift.check_interval_hours =
+                parse("SIMPLE_MDM_DRIFT_CHECK_INTERVAL_HOURS", &value)?;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_DRIFT_REMEDIATE") {
+            self.drift.remediate = parse("SIMPLE_MDM_DRIFT_REMEDIATE", &value)?;
+        }
+        if let Some(value) = lookup("SIMPLE_MDM_ADMIN_LISTEN") {
+            self.admin.listen = value
+                .split(',')
+                .map(|address| parse("SIMPLE_MDM_ADMIN_LISTEN", address))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::InvalidValue(
+                "listen".into(),
+                "at least one address is required".into(),
+            ));
+        }
+        if !self.external_url.starts_with("https://") {
+            return Err(ConfigError::InvalidValue(
+                "external_url".into(),
+                "devices only connect over https".into(),
+            ));
+        }
+        if let Some(address) = self.admin.listen.iter().find(|a| self.listen.contains(a)) {
+            return Err(ConfigError::InvalidValue(
+                "admin.listen".into(),
+                format!("{address} is also listed in listen"),
+            ));
+        }
+        let authority = &self.certificate_authority;
+        if authority.renewal_period_days == 0
+            || authority.renewal_period_days >= authority.validity_days
+        {
+            return Err(ConfigError::InvalidValue(
+                "certificate_authority.renewal_period_days".into(),
+                "must be larger than zero and smaller than validity_days".into(),
+            ));
+        }
+        if self.body_limit == 0 {
+            return Err(ConfigError::InvalidValue(
+                "body_limit".into(),
+                "must be larger than zero".into(),
+            ));
+        }
+        for (index, route) in self.discovery.routes.iter().enumerate() {
+            let setting = format!("discovery.routes[{index}]");
+            if route.domain.is_empty() || route.domain.contains('@') {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    format!("'{}' is not an email domain", route.domain),
+                ));
+            }
+            if self.discovery.routes[..index]
+                .iter()
+                .any(|other| other.domain.eq_ignore_ascii_case(&route.domain))
+            {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    format!("domain {} is listed more than once", route.domain),
+                ));
+            }
+            if matches!(&route.external_url, Some(url) if !url.starts_with("https://")) {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    "devices only connect over https".into(),
+                ));
+            }
+            let policies = route.auth_policies.as_deref().unwrap_or_default();
+            if let Some(policy) = policies
+                .iter()
+                .find(|policy| !self.enrollment.auth_policies.contains(policy))
+            {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    format!("{policy:?} is not listed in enrollment.auth_policies"),
+                ));
+            }
+        }
+        let shared = |ours: &[String], theirs: &[String]| {
+            ours.iter()
+                .find(|name| theirs.iter().any(|other| other.eq_ignore_ascii_case(name)))
+                .cloned()
+        };
+        for (index, tenant) in self.tenants.iter().enumerate() {
+            let setting = format!("tenants[{index}]");
+            let valid_name = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if tenant.name.is_empty() || !tenant.name.chars().all(valid_name) {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    "name must consist of letters, digits, '-' or '_'".into(),
+                ));
+            }
+            for other in &self.tenants[..index] {
+                let duplicate = (other.name == tenant.name)
+                    .then(|| tenant.name.clone())
+                    .or_else(|| shared(&tenant.domains, &other.domains))
+                    .or_else(|| shared(&tenant.hosts, &other.hosts));
+                if let Some(duplicate) = duplicate {
+                    return Err(ConfigError::InvalidValue(
+                        setting,
+                        format!("{duplicate} is also used by tenant {}", other.name),
+                    ));
+                }
+            }
+            self.tenant_config(tenant)
+                .validate()
+                .map_err(|err| ConfigError::InvalidValue(setting, err.to_string()))?;
+        }
+        for (index, template) in self.enrollment.templates.iter().enumerate() {
+            let setting = format!("enrollment.templates[{index}]");
+            if template.common_name.trim().is_empty() {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    "common_name is required".into(),
+                ));
+            }
+            if !is_object_identifier(&template.oid) {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    format!("'{}' is not an object identifier", template.oid),
+                ));
+            }
+            // NOTE; Windows generates RSA keys, shorter keys are refused by current versions
+            if !(2048..=16384).contains(&template.minimal_key_length)
+                || template.minimal_key_length % 8 != 0
+            {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    "minimal_key_length must be a multiple of 8 between 2048 and 16384".into(),
+                ));
+            }
+            let duplicate = self.enrollment.templates[..index].iter().find_map(|other| {
+                (other.oid == template.oid)
+                    .then_some(&template.oid)
+                    .or((other.common_name == template.common_name)
+                        .then_some(&template.common_name))
+            });
+            if let Some(duplicate) = duplicate {
+                return Err(ConfigError::InvalidValue(
+                    setting,
+                    format!("{duplicate} is also used by another template"),
+                ));
+            }
+        }
+        if self.enrollment.auth_policies.is_empty() {
+            return Err(ConfigError::InvalidValue(
+                "enrollment.auth_policies".into(),
+                "at least one policy is required".into(),
+            ));
+        }
+        self.enrollment_version()?;
+        Ok(())
+    }
+
+    /// Absolute url of the endpoint at `path` on this server.
+    pub fn url(&self, path: &str) -> String {
+        join_url(&self.external_url, path)
+    }
+
+    /// Route for users of the email domain, `None` for unlisted domains.
+    ///
+    /// NOTE; Users of unlisted domains are only allowed to enroll while no routes are configured.
+    pub fn discovery_route(&self, domain: &str) -> Option<&DiscoveryRoute> {
+        self.discovery
+            .routes
+            .iter()
+            .find(|route| route.domain.eq_ignore_ascii_case(domain))
+    }
+
+    /// The settings of the tenant, derived from the shared settings.
+    ///
+    /// NOTE; All data of the tenant is kept within its directory, those locations cannot be shared between tenants.
+    pub fn tenant_config(&self, tenant: &TenantConfig) -> ServerConfig {
+        let directory = tenant
+            .directory
+            .clone()
+            .unwrap_or_else(|| Path::new("tenants").join(&tenant.name));
+
+        let mut config = self.clone();
+        config.tenants = Vec::new();
+        // NOTE; Tenants are selected by email domain, which replaces the discovery routes
+        config.discovery = DiscoveryConfig::default();
+        if let Some(external_url) = &tenant.external_url {
+            config.external_url = external_url.clone();
+        }
+        if self.database.is_some() {
+            config.database = Some(directory.join("simple_mdm.sqlite"));
+        }
+        config.admin_password_file = directory.join("admin_users");
+        config.profiles = directory.join("profiles");
+        config.enrollment.password_file = directory.join("enrollment_users");
+        config.enrollment.token_file = directory.join("enrollment_tokens");
+        if let Some(auth_policies) = &tenant.auth_policies {
+            config.enrollment.auth_policies = auth_policies.clone();
+        }
+        if let Some(allowed_os_editions) = &tenant.allowed_os_editions {
+            config.enrollment.allowed_os_editions = allowed_os_editions.clone();
+        }
+        if tenant.authentication_service_url.is_some() {
+            config.enrollment.authentication_service_url =
+                tenant.authentication_service_url.clone();
+        }
+        config.certificate_authority.directory = Some(directory.join("certificate_authority"));
+        if let Some(common_name) = &tenant.certificate_authority_name {
+            config.certificate_authority.common_name = common_name.clone();
+        }
+        if let Some(tls) = &tenant.tls {
+            config.tls = tls.clone();
+        }
+        config
+    }
+
+    /// Host name within the external url, without port.
+    pub fn external_host(&self) -> Option<&str> {
+        let authority = self.external_url.split_once("://")?.1;
+        authority_host(authority.split(['/', '?', '#']).next()?)
+    }
+
+    pub fn enrollment_version(&self) -> Result<Decimal, ConfigError> {
+        Decimal::from_str(&self.enrollment.version).map_err(|_| {
+            ConfigError::InvalidValue(
+                "enrollment.version".into(),
+                format!("'{}' is not a decimal number", self.enrollment.version),
+            )
+        })
+    }
+
+    pub fn authority_options(&self) -> AuthorityOptions {
+        let mut options = AuthorityOptions {
+            directory: self.certificate_authority.directory.clone(),
+            common_name: self.certificate_authority.common_name.clone(),
+            use_intermediate: self.certificate_authority.use_intermediate,
+            ..Default::default()
+        };
+        options.profile.validity =
+            Duration::from_secs(self.certificate_authority.validity_days * 24 * 60 * 60);
+        options.profile.crl_url = Some(self.url(CRL_PATH));
+        options
+    }
+
+    /// Enrollment policy offering the configured templates, with the validity and renewal period of the
+    /// issued certificates.
+    pub fn enrollment_policy(&self) -> PolicySet {
+        let mut policy = PolicySet::default();
+        if !self.enrollment.templates.is_empty() {
+            policy.templates = self
+                .enrollment
+                .templates
+                .iter()
+                .map(|template| CertificateTemplate {
+                    common_name: template.common_name.clone(),
+                    oid: template.oid.clone(),
+                    minimal_key_length: template.minimal_key_length,
+                    key_spec: template.key_spec,
+                    hash_algorithm: template.hash_algorithm,
+                    major_revision: template.major_revision,
+                    minor_revision: template.minor_revision,
+                    ..Default::default()
+                })
+                .collect();
+        }
+        for template in &mut policy.templates {
+            template.validity =
+                Duration::from_secs(self.certificate_authority.validity_days * 24 * 60 * 60);
+            template.renewal_period =
+                Duration::from_secs(self.certificate_authority.renewal_period_days * 24 * 60 * 60);
+        }
+        policy
+    }
+}
+
+impl DiscoveryRoute {
+    /// Absolute url of the endpoint at `path` for this domain.
+    pub fn url(&self, config: &ServerConfig, path: &str) -> String {
+        match &self.external_url {
+            Some(external_url) => join_url(external_url, path),
+            None => config.url(path),
+        }
+    }
+}
+
+/// Host name of an authority (`host[:port]`), without port.
+pub fn authority_host(authority: &str) -> Option<&str> {
+    let host = match authority.strip_prefix('[') {
+        // NOTE; IPv6 literal
+        Some(literal) => literal.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    Some(host).filter(|host| !host.is_empty())
+}
+
+/// Checks for a dotted-decimal object identifier, eg 1.3.6.1.
+fn is_object_identifier(value: &str) -> bool {
+    let arcs: Vec<_> = value.split('.').collect();
+    arcs.len() >= 2
+        && matches!(arcs[0], "0" | "1" | "2")
+        && arcs
+            .iter()
+            .all(|arc| !arc.is_empty() && arc.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn join_url(base: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn partial_file_test() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            listen = ["0.0.0.0:443", "[::]:443"]
+            external_url = "https://mdm.example.com/"
+
+            [enrollment]
+            auth_policies = ["Federated", "OnPremise"]
+            authentication_service_url = "https://login.example.com/mdm"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(
+            config.enrollment.auth_policies,
+            [AuthPolicy::Federated, AuthPolicy::OnPremise]
+        );
+        assert_eq!(config.enrollment.version, "4.0");
+        assert_eq!(
+            config.url("/EnrollmentServer/Discovery.svc"),
+            "https://mdm.example.com/EnrollmentServer/Discovery.svc"
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn templates_test() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [[enrollment.templates]]
+            common_name = "Contoso device"
+            oid = "1.3.6.1.4.1.311.21.8.7.1"
+            minimal_key_length = 4096
+            key_spec = "KeyExchange"
+            hash_algorithm = "Sha384"
+
+            [[enrollment.templates]]
+            common_name = "Contoso kiosk"
+            oid = "1.3.6.1.4.1.311.21.8.7.2"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let policy = config.enrollment_policy();
+        assert_eq!(policy.templates.len(), 2);
+        let template = &policy.templates[0];
+        assert_eq!(template.common_name, "Contoso device");
+        assert_eq!(template.minimal_key_length, 4096);
+        assert_eq!(template.key_spec, Some(KeySpec::KeyExchange));
+        assert_eq!(template.hash_algorithm, HashAlgorithm::Sha384);
+        assert_eq!(template.validity, Duration::from_secs(365 * 24 * 60 * 60));
+        let template = &policy.templates[1];
+        assert_eq!(template.minimal_key_length, 2048);
+        assert_eq!(template.hash_algorithm, HashAlgorithm::Sha256);
+
+        let invalid = |change: fn(&mut TemplateConfig)| {
+            let mut config = config.clone();
+            change(&mut config.enrollment.templates[1]);
+            config.validate().is_err()
+        };
+        assert!(invalid(|template| template.common_name = " ".into()));
+        assert!(invalid(|template| template.oid = "1.3.6..1".into()));
+        assert!(invalid(|template| template.oid = "contoso".into()));
+        assert!(invalid(|template| template.minimal_key_length = 1024));
+        assert!(invalid(|template| template.minimal_key_length = 2050));
+        assert!(invalid(
+            |template| template.oid = "1.3.6.1.4.1.311.21.8.7.1".into()
+        ));
+        assert!(invalid(
+            |template| template.common_name = "Contoso device".into()
+        ));
+
+        // NOTE; Without templates the default template is offered
+        assert_eq!(
+            ServerConfig::default().enrollment_policy().templates[0].oid,
+            CertificateTemplate::default().oid
+        );
+    }
+
+    #[test]
+    fn discovery_routes_test() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [enrollment]
+            auth_policies = ["OnPremise", "Federated"]
+
+            [[discovery.routes]]
+            domain = "contoso.com"
+            external_url = "https://mdm.contoso.com"
+            auth_policies = ["Federated"]
+
+            [[discovery.routes]]
+            domain = "fabrikam.com"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let route = config.discovery_route("Contoso.com").unwrap();
+        assert_eq!(
+            route.url(&config, "/EnrollmentServer/Policy.svc"),
+            "https://mdm.contoso.com/EnrollmentServer/Policy.svc"
+        );
+        let route = config.discovery_route("fabrikam.com").unwrap();
+        assert_eq!(
+            route.url(&config, "/EnrollmentServer/Policy.svc"),
+            config.url("/EnrollmentServer/Policy.svc")
+        );
+        assert!(config.discovery_route("example.com").is_none());
+
+        let mut config = config;
+        config.discovery.routes[1].auth_policies = Some(vec![AuthPolicy::Certificate]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn tenant_config_test() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            external_url = "https://mdm.example.com:8443"
+
+            [[tenants]]
+            name = "contoso"
+            domains = ["contoso.com"]
+            external_url = "https://mdm.contoso.com"
+            auth_policies = ["Federated"]
+
+            [tenants.tls]
+            certificate = "/etc/simple_mdm/contoso.crt"
+            private_key = "/etc/simple_mdm/contoso.key"
+
+            [[tenants]]
+            name = "fabrikam"
+            hosts = ["mdm.fabrikam.com"]
+            directory = "/var/lib/simple_mdm/fabrikam"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.external_host(), Some("mdm.example.com"));
+
+        let contoso = config.tenant_config(&config.tenants[0]);
+        assert_eq!(contoso.external_host(), Some("mdm.contoso.com"));
+        assert_eq!(contoso.enrollment.auth_policies, [AuthPolicy::Federated]);
+        assert!(contoso.tenants.is_empty());
+        assert_eq!(
+            contoso.tls.certificate,
+            Path::new("/etc/simple_mdm/contoso.crt")
+        );
+
+        let fabrikam = config.tenant_config(&config.tenants[1]);
+        assert_eq!(fabrikam.external_url, config.external_url);
+        assert_eq!(
+            fabrikam.database.as_deref(),
+            Some(Path::new("/var/lib/simple_mdm/fabrikam/simple_mdm.sqlite"))
+        );
+        assert_eq!(
+            fabrikam.certificate_authority.directory.as_deref(),
+            Some(Path::new(
+                "/var/lib/simple_mdm/fabrikam/certificate_authority"
+            ))
+        );
+        assert_ne!(contoso.database, fabrikam.database);
+        assert_eq!(fabrikam.tls, config.tls);
+
+        let mut config = config;
+        config.tenants[1].hosts.push("MDM.contoso.com".into());
+        config.tenants[1].domains.push("Contoso.com".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn relative_paths_test() {
+        let mut config: ServerConfig = toml::from_str(
+            r#"
+            database = "data/simple_mdm.sqlite"
+            profiles = "/etc/simple_mdm/profiles"
+
+            [[tenants]]
+            name = "contoso"
+            "#,
+        )
+        .unwrap();
+        config.resolve_paths(Path::new("/etc/simple_mdm"));
+
+        assert_eq!(
+            config.database.as_deref(),
+            Some(Path::new("/etc/simple_mdm/data/simple_mdm.sqlite"))
+        );
+        assert_eq!(config.profiles, Path::new("/etc/simple_mdm/profiles"));
+        assert_eq!(
+            config.tls.certificate,
+            Path::new("/etc/simple_mdm/self_signed_certs/cert.pem")
+        );
+        assert_eq!(
+            config.tenant_config(&config.tenants[0]).admin_password_file,
+            Path::new("/etc/simple_mdm/tenants/contoso/admin_users")
+        );
+    }
+
+    #[test]
+    fn environment_overrides_test() {
+        let variables = HashMap::from([
+            ("SIMPLE_MDM_LISTEN", "127.0.0.1:8443, 127.0.0.2:8443"),
+            ("SIMPLE_MDM_BODY_LIMIT", "1024"),
+            ("SIMPLE_MDM_AUTH_POLICIES", "Certificate, OnPremise"),
+        ]);
+        let mut config = ServerConfig::default();
+        config
+            .apply_overrides(|name| variables.get(name).map(|value| value.to_string()))
+            .unwrap();
+
+        assert_eq!(config.listen[1], SocketAddr::from(([127, 0, 0, 2], 8443)));
+        assert_eq!(config.body_limit, 1024);
+        assert_eq!(
+            config.enrollment.auth_policies,
+            [AuthPolicy::Certificate, AuthPolicy::OnPremise]
+        );
+
+        let error = config
+            .apply_overrides(|name| (name == "SIMPLE_MDM_BODY_LIMIT").then(|| "5mb".into()))
+            .unwrap_err();
+        assert!(matches!(error, ConfigError::InvalidValue(..)));
+    }
+}
@@ -0,0 +1,103 @@
+//! BitLocker CSP, manages drive encryption
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/bitlocker-csp
+
+use super::{Access, Constraint, Format, Node, Value};
+
+const ROOT: &str = "./Device/Vendor/MSFT/BitLocker";
+
+fn setting(path: &str, format: Format) -> Node {
+    Node::new(format!("{ROOT}/{path}"), format, Access::ALL)
+}
+
+fn status(path: &str) -> Node {
+    Node::new(format!("{ROOT}/{path}"), Format::Int, Access::GET)
+}
+
+/// Cipher used to encrypt a drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionMethod {
+    AesCbc128 = 3,
+    AesCbc256 = 4,
+    XtsAes128 = 6,
+    XtsAes256 = 7,
+}
+
+/// 1 prompts the user to encrypt the device.
+pub fn require_device_encryption() -> Node {
+    setting("RequireDeviceEncryption", Format::Int).with_constraint(Constraint::Range(0, 1))
+}
+
+/// 0 encrypts silently, without warning about third party encryption.
+pub fn allow_warning_for_other_disk_encryption() -> Node {
+    setting("AllowWarningForOtherDiskEncryption", Format::Int)
+        .with_constraint(Constraint::Range(0, 1))
+}
+
+/// Takes the value built by [`encryption_methods`].
+pub fn encryption_method_by_drive_type() -> Node {
+    setting("EncryptionMethodByDriveType", Format::Chr)
+}
+
+/// 0 disables rotation, 1 rotates on Entra ID joined devices, 2 also on hybrid joined devices.
+pub fn configure_recovery_password_rotation() -> Node {
+    setting("ConfigureRecoveryPasswordRotation", Format::Int)
+        .with_constraint(Constraint::Range(0, 2))
+}
+
+pub fn rotate_recovery_passwords() -> Node {
+    Node::new(
+        format!("{ROOT}/RotateRecoveryPasswords"),
+        Format::Null,
+        Access::EXEC,
+    )
+}
+
+/// 0 when the device is encrypted as configured, otherwise a bitmask of the failed requirements
+pub fn device_encryption_status() -> Node {
+    status("Status/DeviceEncryptionStatus")
+}
+
+pub fn rotate_recovery_passwords_status() -> Node {
+    status("Status/RotateRecoveryPasswordsStatus")
+}
+
+/// Value of [`encryption_method_by_drive_type`], for the operating system, fixed and removable drives.
+///
+/// NOTE; The value is an ADMX policy fragment, sent as chr
+pub fn encryption_methods(
+    os_drive: EncryptionMethod,
+    fixed_drives: EncryptionMethod,
+    removable_drives: EncryptionMethod,
+) -> Value {
+    Value::Chr(format!(
+        "<enabled/>\
+         <data id=\"EncryptionMethodWithXtsOsDropDown_Name\" value=\"{}\"/>\
+         <data id=\"EncryptionMethodWithXtsFdvDropDown_Name\" value=\"{}\"/>\
+         <data id=\"EncryptionMethodWithXtsRdvDropDown_Name\" value=\"{}\"/>",
+        os_drive as u32, fixed_drives as u32, removable_drives as u32
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microsoft_protocol::syncml::Command;
+
+    #[test]
+    fn encryption_methods_test() {
+        let value = encryption_methods(
+            EncryptionMethod::XtsAes256,
+            EncryptionMethod::XtsAes256,
+            EncryptionMethod::AesCbc128,
+        );
+        let Command::Replace(replace) = encryption_method_by_drive_type().replace(value).unwrap()
+        else {
+            panic!("expected a Replace command");
+        };
+        let data = replace.item[0].data.as_deref().unwrap();
+        assert!(data.starts_with("<enabled/>"));
+        assert!(data.contains("EncryptionMethodWithXtsOsDropDown_Name\" value=\"7\""));
+        assert!(data.ends_with("EncryptionMethodWithXtsRdvDropDown_Name\" value=\"3\"/>"));
+    }
+}
@@ -0,0 +1,692 @@
+//! Device Description Framework (DDF) v2 definitions
+//!
+//! Microsoft publishes the node tree of each CSP as a DDF file, listing per node its access types, format,
+//! allowed values and default value. The files are loaded into one tree, against which commands are validated
+//! before they're queued for a device.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/configuration-service-provider-ddf
+//! REF; https://www.openmobilealliance.org/release/DM/V1_2-20070209-A/OMA-TS-DM_TND-V1_2-20070209-A.pdf
+
+use super::{check_xml, Access, Format};
+use crate::microsoft_protocol::syncml::{Command, CommandContainer, ItemCommand};
+use std::path::{Path, PathBuf};
+use xml::reader::{ParserConfig, XmlEvent};
+
+/// Maximum amount of suggestions for an unknown node.
+const MAX_SUGGESTIONS: usize = 3;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    /// A definition file is not a DDF document
+    Parse(PathBuf, String),
+    /// The OMA-URI is not defined, with the closest defined nodes
+    UnknownNode {
+        uri: String,
+        suggestions: Vec<String>,
+    },
+    /// The command doesn't fit the node definition
+    Invalid(super::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(path, error) => write!(f, "cannot read {}: {error}", path.display()),
+            Error::Parse(path, reason) => write!(f, "invalid {}: {reason}", path.display()),
+            Error::UnknownNode { uri, suggestions } if suggestions.is_empty() => {
+                write!(f, "unknown node {uri}")
+            }
+            Error::UnknownNode { uri, suggestions } => {
+                write!(
+                    f,
+                    "unknown node {uri}, did you mean {}?",
+                    suggestions.join(" or ")
+                )
+            }
+            Error::Invalid(error) => error.fmt(f),
+        }
+    }
+}
+
+impl From<super::Error> for Error {
+    fn from(value: super::Error) -> Self {
+        Error::Invalid(value)
+    }
+}
+
+/// Values a node accepts, as listed by the MSFT:AllowedValues element.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedValues {
+    Enum(Vec<String>),
+    /// Inclusive range of integers
+    Range(i64, i64),
+    /// Bits that may be combined
+    Flag(Vec<u64>),
+    /// Regular expression, not validated
+    RegEx(String),
+    /// Values described by an XSD or ADMX definition, not validated
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DdfNode {
+    /// Empty for dynamic nodes, which are named by the server or the device
+    pub name: String,
+    /// Describes dynamic nodes, eg the rule name of a firewall rule
+    pub title: Option<String>,
+    pub access: Access,
+    pub format: Format,
+    pub default_value: Option<String>,
+    pub description: Option<String>,
+    pub allowed_values: Option<AllowedValues>,
+    pub children: Vec<DdfNode>,
+}
+
+impl DdfNode {
+    /// Interior node created along the path of a definition.
+    fn interior(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            title: None,
+            access: Access::GET,
+            format: Format::Node,
+            default_value: None,
+            description: None,
+            allowed_values: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    fn child(&self, name: &str) -> Option<&DdfNode> {
+        self.children
+            .iter()
+            .find(|child| child.name == name)
+            .or_else(|| self.children.iter().find(|child| child.is_dynamic()))
+    }
+
+    /// Adds the node as child, a child of the same name takes over the properties of the node.
+    fn insert(&mut self, mut node: DdfNode) {
+        let Some(existing) = self
+            .children
+            .iter_mut()
+            .find(|child| child.name == node.name)
+        else {
+            self.children.push(node);
+            return;
+        };
+        let children = std::mem::take(&mut node.children);
+        node.children = std::mem::take(&mut existing.children);
+        *existing = node;
+        for child in children {
+            existing.insert(child);
+        }
+    }
+
+    /// Child with the given name, created as interior node when missing.
+    fn interior_child(&mut self, name: &str) -> &mut DdfNode {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(DdfNode::interior(name));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    /// Checks the format and data of an item sent with an Add, Replace or Exec.
+    fn validate_item(
+        &self,
+        uri: &str,
+        format: Option<&str>,
+        data: Option<&str>,
+    ) -> Result<(), super::Error> {
+        if let Some(received) = format.and_then(Format::parse) {
+            if received != self.format {
+                return Err(super::Error::InvalidFormat {
+                    uri: uri.into(),
+                    expected: self.format,
+                    received,
+                });
+            }
+        }
+        let Some(data) = data else {
+            return Ok(());
+        };
+
+        let invalid = |reason: String| super::Error::InvalidValue {
+            uri: uri.into(),
+            reason,
+        };
+        match self.format {
+            Format::Int if data.parse::<i64>().is_err() => {
+                return Err(invalid(format!("{data:?} is not an integer")))
+            }
+            Format::Bool if data != "true" && data != "false" => {
+                return Err(invalid(format!("{data:?} is not true or false")))
+            }
+            Format::Xml => check_xml(data).map_err(|e| invalid(format!("malformed xml: {e}")))?,
+            _ => {}
+        }
+        match &self.allowed_values {
+            Some(AllowedValues::Enum(values)) if !values.iter().any(|value| value == data) => {
+                Err(invalid(format!("{data:?} is not one of {values:?}")))
+            }
+            Some(AllowedValues::Range(min, max)) => match data.parse::<i64>() {
+                Ok(value) if (*min..=*max).contains(&value) => Ok(()),
+                _ => Err(invalid(format!("{data:?} is not within {min} and {max}"))),
+            },
+            Some(AllowedValues::Flag(flags)) => {
+                let mask = flags.iter().fold(0, |mask, flag| mask | flag);
+                match data.parse::<u64>() {
+                    Ok(value) if value & !mask == 0 => Ok(()),
+                    _ => Err(invalid(format!(
+                        "{data:?} is not a combination of {flags:?}"
+                    ))),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Node tree of all loaded definitions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definitions {
+    /// The "." node
+    root: DdfNode,
+}
+
+impl Default for Definitions {
+    fn default() -> Self {
+        Self {
+            root: DdfNode::interior("."),
+        }
+    }
+}
+
+impl Definitions {
+    /// Loads all `.xml` files within the directory.
+    pub fn load_directory(directory: &Path) -> Result<Self, Error> {
+        let entries = std::fs::read_dir(directory).map_err(|e| Error::Io(directory.into(), e))?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| Error::Io(directory.into(), e))?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("xml"))
+            {
+                paths.push(path);
+            }
+        }
+        // NOTE; Later files override nodes of earlier files, keep the outcome independent of the file system
+        paths.sort();
+
+        let mut definitions = Self::default();
+        for path in paths {
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| Error::Io(path.clone(), e))?;
+            definitions
+                .add(&contents)
+                .map_err(|reason| Error::Parse(path.clone(), reason))?;
+        }
+        Ok(definitions)
+    }
+
+    /// Adds the nodes of a DDF document.
+    pub fn add(&mut self, document: &str) -> Result<(), String> {
+        let tree = Element::parse(document)?;
+        if tree.name != "MgmtTree" {
+            return Err(format!("expected MgmtTree, found {}", tree.name));
+        }
+        for element in tree.children_named("Node") {
+            let node = parse_node(element)?;
+            let path = element.child_text("Path").unwrap_or(".");
+            let mut parent = &mut self.root;
+            for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+                if segment != "." {
+                    parent = parent.interior_child(segment);
+                }
+            }
+            parent.insert(node);
+        }
+        Ok(())
+    }
+
+    /// Definition of the node addressed by the OMA-URI.
+    ///
+    /// NOTE; Device scoped nodes are also reachable without the ./Device prefix
+    pub fn resolve(&self, uri: &str) -> Result<&DdfNode, Error> {
+        let uri = uri.split_once('?').map_or(uri, |(path, _query)| path);
+        let alias = match (
+            uri.strip_prefix("./Vendor/"),
+            uri.strip_prefix("./Device/Vendor/"),
+        ) {
+            (Some(path), _) => Some(format!("./Device/Vendor/{path}")),
+            (_, Some(path)) => Some(format!("./Vendor/{path}")),
+            _ => None,
+        };
+        match (self.find(uri), alias) {
+            (Ok(node), _) => Ok(node),
+            (Err(error), Some(alias)) => self.find(&alias).map_err(|_| error),
+            (Err(error), None) => Err(error),
+        }
+    }
+
+    fn find(&self, uri: &str) -> Result<&DdfNode, Error> {
+        let path = uri.strip_prefix("./").unwrap_or(uri);
+        let mut node = &self.root;
+        let mut resolved = String::from(".");
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            match node.child(segment) {
+                Some(child) => node = child,
+                None => {
+                    return Err(Error::UnknownNode {
+                        uri: uri.into(),
+                        suggestions: suggestions(node, &resolved, segment),
+                    })
+                }
+            }
+            resolved.push('/');
+            resolved.push_str(segment);
+        }
+        Ok(node)
+    }
+
+    /// Checks every item of the command, including nested commands, against the definitions.
+    pub fn validate(&self, command: &Command) -> Result<(), Error> {
+        match command {
+            Command::Alert(_) => Ok(()),
+            Command::Add(command) => self.validate_items("Add", command, |access| access.add),
+            Command::Replace(command) => {
+                self.validate_items("Replace", command, |access| access.replace)
+            }
+            Command::Delete(command) => {
+                self.validate_items("Delete", command, |access| access.delete)
+            }
+            Command::Get(command) => self.validate_items("Get", command, |access| access.get),
+            Command::Exec(command) => self.validate_items("Exec", command, |access| access.exec),
+            Command::Atomic(container) | Command::Sequence(container) => {
+                self.validate_container(container)
+            }
+        }
+    }
+
+    fn validate_container(&self, container: &CommandContainer) -> Result<(), Error> {
+        for command in &container.commands {
+            self.validate(command)?;
+        }
+        Ok(())
+    }
+
+    fn validate_items(
+        &self,
+        operation: &'static str,
+        command: &ItemCommand,
+        supported: impl Fn(Access) -> bool,
+    ) -> Result<(), Error> {
+        for item in &command.item {
+            let Some(uri) = item.loc_uri() else {
+                continue;
+            };
+            let node = self.resolve(uri)?;
+            if !supported(node.access) {
+                return Err(super::Error::UnsupportedOperation {
+                    uri: uri.into(),
+                    operation,
+                }
+                .into());
+            }
+            if operation != "Get" && operation != "Delete" {
+                // NOTE; The Meta of the item takes precedence over the Meta of the command
+                let format = (item.meta.as_ref().or(command.meta.as_ref()))
+                    .and_then(|meta| meta.format.as_deref());
+                node.validate_item(uri, format, item.data.as_deref())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Paths of the children closest to the unknown segment.
+fn suggestions(parent: &DdfNode, path: &str, segment: &str) -> Vec<String> {
+    let threshold = (segment.chars().count() / 3).max(2);
+    let mut candidates: Vec<_> = parent
+        .children
+        .iter()
+        .filter(|child| !child.is_dynamic())
+        .map(|child| {
+            let distance = edit_distance(&child.name.to_lowercase(), &segment.to_lowercase());
+            (distance, &child.name)
+        })
+        .filter(|(distance, _)| *distance <= threshold)
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, name)| format!("{path}/{name}"))
+        .collect()
+}
+
+/// Levenshtein distance, the amount of single character edits turning one string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn parse_node(element: &Element) -> Result<DdfNode, String> {
+    let name = element
+        .child_text("NodeName")
+        .unwrap_or_default()
+        .to_string();
+    let properties = element
+        .child("DFProperties")
+        .ok_or_else(|| format!("node {name:?} has no DFProperties"))?;
+
+    let mut access = Access::new(false, false, false, false, false);
+    for operation in properties
+        .child("AccessType")
+        .map_or(&[][..], |a| &a.children)
+    {
+        match operation.name.as_str() {
+            "Add" => access.add = true,
+            "Delete" => access.delete = true,
+            "Get" => access.get = true,
+            "Replace" => access.replace = true,
+            "Exec" => access.exec = true,
+            _ => {}
+        }
+    }
+    // NOTE; Formats without counterpart, eg float and date, are sent as strings
+    let format = properties
+        .child("DFFormat")
+        .and_then(|format| format.children.first())
+        .map_or(Format::Chr, |format| {
+            Format::parse(&format.name).unwrap_or(Format::Chr)
+        });
+    let allowed_values = properties.child("AllowedValues").map(parse_allowed_values);
+
+    let mut children = Vec::new();
+    for child in element.children_named("Node") {
+        children.push(parse_node(child)?);
+    }
+    Ok(DdfNode {
+        name,
+        title: properties.child_text("DFTitle").map(Into::into),
+        access,
+        format,
+        default_value: properties.child_text("DefaultValue").map(Into::into),
+        description: properties.child_text("Description").map(Into::into),
+        allowed_values,
+        children,
+    })
+}
+
+fn parse_allowed_values(element: &Element) -> AllowedValues {
+    let value_type = element.attribute("ValueType").unwrap_or_default();
+    let values = || {
+        element
+            .children_named("Enum")
+            .filter_map(|e| e.child_text("Value"))
+    };
+    match value_type.to_lowercase().as_str() {
+        "enum" => AllowedValues::Enum(values().map(Into::into).collect()),
+        "flag" => AllowedValues::Flag(values().filter_map(|value| value.parse().ok()).collect()),
+        "range" => {
+            // NOTE; Formatted as [min-max]
+            let range = element.child_text("Value").unwrap_or_default();
+            let bounds = range
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .split_once('-')
+                .and_then(|(min, max)| Some((min.trim().parse().ok()?, max.trim().parse().ok()?)));
+            match bounds {
+                Some((min, max)) => AllowedValues::Range(min, max),
+                None => AllowedValues::Other(range.into()),
+            }
+        }
+        "regex" => AllowedValues::RegEx(element.child_text("Value").unwrap_or_default().into()),
+        _ => AllowedValues::Other(value_type.into()),
+    }
+}
+
+/// Element of a parsed XML document, namespace prefixes are dropped.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn parse(document: &str) -> Result<Self, String> {
+        let reader = ParserConfig::new()
+            .trim_whitespace(true)
+            .create_reader(document.as_bytes());
+        let mut stack: Vec<Element> = vec![Element::default()];
+        for event in reader {
+            match event.map_err(|e| e.to_string())? {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes
+                        .into_iter()
+                        .map(|attribute| (attribute.name.local_name, attribute.value))
+                        .collect(),
+                    ..Default::default()
+                }),
+                XmlEvent::EndElement { .. } => {
+                    let element = stack.pop().expect("balanced by the parser");
+                    stack
+                        .last_mut()
+                        .expect("document element has a parent")
+                        .children
+                        .push(element);
+                }
+                XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(&text);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let document = stack.pop().unwrap_or_default();
+        document
+            .children
+            .into_iter()
+            .next()
+            .ok_or_else(|| "empty document".to_string())
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    /// Trimmed text of the child, `None` when missing or empty.
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name)
+            .map(|child| child.text.trim())
+            .filter(|text| !text.is_empty())
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp;
+    use crate::microsoft_protocol::syncml::{Item, Meta};
+
+    const FIREWALL_DDF: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE MgmtTree PUBLIC " -//OMA//DTD-DM-DDF 1.2//EN" "http://www.openmobilealliance.org/tech/DTD/DM_DDF-V1_2.dtd"[<?oma-dm-ddf-ver supported-versions="2.0"?>]>
+<MgmtTree xmlns:MSFT="http://schemas.microsoft.com/MobileDevice/DM">
+  <VerDTD>1.2</VerDTD>
+  <Node>
+    <NodeName>Firewall</NodeName>
+    <Path>./Vendor/MSFT</Path>
+    <DFProperties>
+      <AccessType><Get /></AccessType>
+      <DFFormat><node /></DFFormat>
+    </DFProperties>
+    <Node>
+      <NodeName>MdmStore</NodeName>
+      <DFProperties>
+        <AccessType><Get /></AccessType>
+        <DFFormat><node /></DFFormat>
+      </DFProperties>
+      <Node>
+        <NodeName>DomainProfile</NodeName>
+        <DFProperties>
+          <AccessType><Get /></AccessType>
+          <DFFormat><node /></DFFormat>
+        </DFProperties>
+        <Node>
+          <NodeName>DefaultInboundAction</NodeName>
+          <DFProperties>
+            <AccessType><Add /><Delete /><Get /><Replace /></AccessType>
+            <DefaultValue>1</DefaultValue>
+            <Description><![CDATA[Default action for inbound traffic.]]></Description>
+            <DFFormat><int /></DFFormat>
+            <MSFT:AllowedValues ValueType="ENUM">
+              <MSFT:Enum><MSFT:Value>0</MSFT:Value><MSFT:ValueDescription>Allow</MSFT:ValueDescription></MSFT:Enum>
+              <MSFT:Enum><MSFT:Value>1</MSFT:Value><MSFT:ValueDescription>Block</MSFT:ValueDescription></MSFT:Enum>
+            </MSFT:AllowedValues>
+          </DFProperties>
+        </Node>
+      </Node>
+      <Node>
+        <NodeName>FirewallRules</NodeName>
+        <DFProperties>
+          <AccessType><Get /></AccessType>
+          <DFFormat><node /></DFFormat>
+        </DFProperties>
+        <Node>
+          <NodeName />
+          <DFProperties>
+            <AccessType><Add /><Delete /><Get /></AccessType>
+            <DFFormat><node /></DFFormat>
+            <DFTitle>FirewallRuleName</DFTitle>
+          </DFProperties>
+          <Node>
+            <NodeName>Protocol</NodeName>
+            <DFProperties>
+              <AccessType><Add /><Delete /><Get /><Replace /></AccessType>
+              <DFFormat><int /></DFFormat>
+              <MSFT:AllowedValues ValueType="Range"><MSFT:Value>[0-255]</MSFT:Value></MSFT:AllowedValues>
+            </DFProperties>
+          </Node>
+        </Node>
+      </Node>
+    </Node>
+  </Node>
+</MgmtTree>"#;
+
+    fn replace(uri: &str, format: &str, data: &str) -> Command {
+        Command::Replace(ItemCommand::new(
+            Item::target(uri)
+                .with_meta(Meta::with_format(format))
+                .with_data(data),
+        ))
+    }
+
+    #[test]
+    fn resolve_test() {
+        let mut definitions = Definitions::default();
+        definitions.add(FIREWALL_DDF).unwrap();
+
+        let node = definitions
+            .resolve("./Vendor/MSFT/Firewall/MdmStore/DomainProfile/DefaultInboundAction")
+            .unwrap();
+        assert_eq!(node.format, Format::Int);
+        assert_eq!(node.default_value.as_deref(), Some("1"));
+        assert_eq!(
+            node.description.as_deref(),
+            Some("Default action for inbound traffic.")
+        );
+        assert_eq!(
+            node.allowed_values,
+            Some(AllowedValues::Enum(vec!["0".into(), "1".into()]))
+        );
+
+        // Dynamic node, and the device scoped alias
+        let node = definitions
+            .resolve("./Device/Vendor/MSFT/Firewall/MdmStore/FirewallRules/Web/Protocol")
+            .unwrap();
+        assert_eq!(node.allowed_values, Some(AllowedValues::Range(0, 255)));
+
+        let Err(Error::UnknownNode { suggestions, .. }) =
+            definitions.resolve("./Vendor/MSFT/Firewall/MdmStore/DomainProfle/EnableFirewall")
+        else {
+            panic!("expected an unknown node");
+        };
+        assert_eq!(
+            suggestions,
+            vec!["./Vendor/MSFT/Firewall/MdmStore/DomainProfile"]
+        );
+    }
+
+    #[test]
+    fn validate_test() {
+        let mut definitions = Definitions::default();
+        definitions.add(FIREWALL_DDF).unwrap();
+        let inbound = "./Vendor/MSFT/Firewall/MdmStore/DomainProfile/DefaultInboundAction";
+        let protocol = "./Vendor/MSFT/Firewall/MdmStore/FirewallRules/Web/Protocol";
+
+        assert!(definitions.validate(&replace(inbound, "int", "0")).is_ok());
+        assert!(definitions.validate(&replace(protocol, "int", "6")).is_ok());
+        assert!(matches!(
+            definitions.validate(&replace(inbound, "int", "2")),
+            Err(Error::Invalid(csp::Error::InvalidValue { .. }))
+        ));
+        assert!(matches!(
+            definitions.validate(&replace(protocol, "chr", "6")),
+            Err(Error::Invalid(csp::Error::InvalidFormat { .. }))
+        ));
+        assert!(matches!(
+            definitions.validate(&replace(protocol, "int", "256")),
+            Err(Error::Invalid(csp::Error::InvalidValue { .. }))
+        ));
+
+        let delete = Command::Delete(ItemCommand::new(Item::target(
+            "./Vendor/MSFT/Firewall/MdmStore/FirewallRules",
+        )));
+        let atomic = Command::Atomic(CommandContainer::new([delete]));
+        assert!(matches!(
+            definitions.validate(&atomic),
+            Err(Error::Invalid(csp::Error::UnsupportedOperation {
+                operation: "Delete",
+                ..
+            }))
+        ));
+    }
+}
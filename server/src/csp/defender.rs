@@ -0,0 +1,97 @@
+//! Defender CSP, runs scans and reports the health of Microsoft Defender Antivirus
+//!
+//! NOTE; Defender settings are configured through the Defender area of the Policy CSP
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/defender-csp
+
+use super::{Access, Constraint, Format, Node, Value};
+
+const ROOT: &str = "./Device/Vendor/MSFT/Defender";
+
+fn health(path: &str, format: Format) -> Node {
+    Node::new(format!("{ROOT}/Health/{path}"), format, Access::GET)
+}
+
+fn action(path: &str, format: Format) -> Node {
+    Node::new(format!("{ROOT}/{path}"), format, Access::EXEC)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanType {
+    Quick = 1,
+    Full = 2,
+}
+
+impl From<ScanType> for Value {
+    fn from(value: ScanType) -> Self {
+        Value::Int(value as u32)
+    }
+}
+
+/// Starts a scan, takes a [`ScanType`].
+pub fn scan() -> Node {
+    action("Scan", Format::Int).with_constraint(Constraint::Range(1, 2))
+}
+
+pub fn update_signature() -> Node {
+    action("UpdateSignature", Format::Null)
+}
+
+/// Restarts the device into a scan without network access.
+pub fn offline_scan() -> Node {
+    action("OfflineScan", Format::Null)
+}
+
+/// Bitmask of problems, 0 when healthy
+pub fn product_status() -> Node {
+    health("ProductStatus", Format::Int)
+}
+
+/// Bitmask of pending actions, eg 4 for a pending reboot
+pub fn computer_state() -> Node {
+    health("ComputerState", Format::Int)
+}
+
+pub fn defender_enabled() -> Node {
+    health("DefenderEnabled", Format::Bool)
+}
+
+pub fn rtp_enabled() -> Node {
+    health("RtpEnabled", Format::Bool)
+}
+
+pub fn signature_out_of_date() -> Node {
+    health("SignatureOutOfDate", Format::Bool)
+}
+
+pub fn quick_scan_overdue() -> Node {
+    health("QuickScanOverdue", Format::Bool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::Error;
+    use crate::microsoft_protocol::syncml::Command;
+
+    #[test]
+    fn scan_test() {
+        let Command::Exec(exec) = scan().exec(ScanType::Full).unwrap() else {
+            panic!("expected an Exec command");
+        };
+        let item = &exec.item[0];
+        assert_eq!(item.loc_uri(), Some("./Device/Vendor/MSFT/Defender/Scan"));
+        assert_eq!(item.data.as_deref(), Some("2"));
+        assert!(matches!(scan().exec(3), Err(Error::InvalidValue { .. })));
+        assert!(update_signature().exec(Value::Null).is_ok());
+
+        let node = rtp_enabled();
+        assert_eq!(
+            node.uri(),
+            "./Device/Vendor/MSFT/Defender/Health/RtpEnabled"
+        );
+        assert_eq!(node.format(), Format::Bool);
+        assert!(node.get().is_ok());
+        assert!(node.replace(true).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! DevDetail CSP, reports details of the device hardware and software
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/devdetail-csp
+
+use super::{Access, Constraint, Format, Node};
+
+const ROOT: &str = "./DevDetail";
+
+fn detail(path: &str, format: Format) -> Node {
+    Node::new(format!("{ROOT}/{path}"), format, Access::GET)
+}
+
+/// Windows build, eg 10.0.22631.4460
+pub fn software_version() -> Node {
+    detail("SwV", Format::Chr)
+}
+
+pub fn firmware_version() -> Node {
+    detail("FwV", Format::Chr)
+}
+
+pub fn hardware_version() -> Node {
+    detail("HwV", Format::Chr)
+}
+
+pub fn oem() -> Node {
+    detail("OEM", Format::Chr)
+}
+
+pub fn device_type() -> Node {
+    detail("DevTyp", Format::Chr)
+}
+
+/// Windows edition, eg Windows 10 Enterprise
+pub fn os_platform() -> Node {
+    detail("Ext/Microsoft/OSPlatform", Format::Chr)
+}
+
+/// Computer name, renaming takes effect after a reboot.
+pub fn device_name() -> Node {
+    Node::new(
+        format!("{ROOT}/Ext/Microsoft/DeviceName"),
+        Format::Chr,
+        Access::GET_REPLACE,
+    )
+    .with_constraint(Constraint::MaxLength(15))
+}
+
+/// Physical memory in MB
+pub fn total_ram() -> Node {
+    detail("Ext/Microsoft/TotalRAM", Format::Int)
+}
+
+/// Free storage in MB
+pub fn total_storage() -> Node {
+    detail("Ext/Microsoft/TotalStorage", Format::Int)
+}
+
+pub fn smbios_serial_number() -> Node {
+    detail("Ext/Microsoft/SMBIOSSerialNumber", Format::Chr)
+}
+
+pub fn local_time() -> Node {
+    detail("Ext/Microsoft/LocalTime", Format::Chr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::Error;
+
+    #[test]
+    fn detail_test() {
+        let node = total_ram();
+        assert_eq!(node.uri(), "./DevDetail/Ext/Microsoft/TotalRAM");
+        assert_eq!(node.format(), Format::Int);
+        assert!(node.replace(1024).is_err());
+
+        let node = device_name();
+        assert!(node.replace("DESKTOP-01").is_ok());
+        assert!(matches!(
+            node.replace("DESKTOP-0123456789"),
+            Err(Error::InvalidValue { .. })
+        ));
+    }
+}
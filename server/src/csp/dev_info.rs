@@ -0,0 +1,50 @@
+//! DevInfo CSP, identifies the device
+//!
+//! NOTE; The device sends these nodes unprompted at the start of each management session
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/devinfo-csp
+
+use super::{Access, Format, Node};
+
+const ROOT: &str = "./DevInfo";
+
+fn info(path: &str) -> Node {
+    Node::new(format!("{ROOT}/{path}"), Format::Chr, Access::GET)
+}
+
+/// Device identifier, equal to the LocURI of the Source within the SyncML header
+pub fn device_id() -> Node {
+    info("DevId")
+}
+
+pub fn manufacturer() -> Node {
+    info("Man")
+}
+
+pub fn model() -> Node {
+    info("Mod")
+}
+
+/// Supported OMA-DM version
+pub fn dm_version() -> Node {
+    info("DmV")
+}
+
+pub fn language() -> Node {
+    info("Lang")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_test() {
+        let node = device_id();
+        assert_eq!(node.uri(), "./DevInfo/DevId");
+        assert_eq!(node.format(), Format::Chr);
+        assert!(node.get().is_ok());
+        assert!(node.replace("DEVICE").is_err());
+        assert_eq!(dm_version().uri(), "./DevInfo/DmV");
+    }
+}
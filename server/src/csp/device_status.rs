@@ -0,0 +1,75 @@
+//! DeviceStatus CSP, reports the compliance and health of the device
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/devicestatus-csp
+
+use super::{Access, Format, Node};
+
+const ROOT: &str = "./Vendor/MSFT/DeviceStatus";
+
+fn status(path: &str, format: Format) -> Node {
+    Node::new(format!("{ROOT}/{path}"), format, Access::GET)
+}
+
+/// 1 when all drives are encrypted
+pub fn encryption_compliance() -> Node {
+    status("Compliance/EncryptionCompliance", Format::Int)
+}
+
+pub fn tpm_specification_version() -> Node {
+    status("TPM/SpecificationVersion", Format::Chr)
+}
+
+/// 0 not supported, 1 enabled, 2 disabled
+pub fn secure_boot_state() -> Node {
+    status("SecureBootState", Format::Int)
+}
+
+/// Edition number, as reported in the OSEdition of discovery
+pub fn os_edition() -> Node {
+    status("OS/Edition", Format::Int)
+}
+
+/// 0 on, 1 off, 2 snoozed, 3 expired, 4 not installed
+pub fn antivirus_status() -> Node {
+    status("Antivirus/Status", Format::Int)
+}
+
+/// 0 up to date, 1 outdated
+pub fn antivirus_signature_status() -> Node {
+    status("Antivirus/SignatureStatus", Format::Int)
+}
+
+/// 0 on, 1 off, 2 not installed
+pub fn firewall_status() -> Node {
+    status("Firewall/Status", Format::Int)
+}
+
+pub fn battery_status() -> Node {
+    status("Battery/Status", Format::Int)
+}
+
+/// Percentage, -1 when unknown
+pub fn battery_charge_remaining() -> Node {
+    status("Battery/EstimatedChargeRemaining", Format::Int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_test() {
+        let node = encryption_compliance();
+        assert_eq!(
+            node.uri(),
+            "./Vendor/MSFT/DeviceStatus/Compliance/EncryptionCompliance"
+        );
+        assert_eq!(node.format(), Format::Int);
+        assert!(node.get().is_ok());
+        assert!(node.replace(1).is_err());
+        assert_eq!(
+            tpm_specification_version().uri(),
+            "./Vendor/MSFT/DeviceStatus/TPM/SpecificationVersion"
+        );
+    }
+}
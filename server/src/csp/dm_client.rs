@@ -0,0 +1,90 @@
+//! DMClient CSP, configures the management client of the device for an MDM provider
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/dmclient-csp
+
+use super::{join, Access, Constraint, Error, Format, Node};
+
+const ROOT: &str = "./Vendor/MSFT/DMClient";
+
+fn provider_setting(provider: &str, path: &[&str], format: Format) -> Result<Node, Error> {
+    let uri = join(&format!("{ROOT}/Provider"), &[&[provider], path].concat())?;
+    Ok(Node::new(uri, format, Access::ALL))
+}
+
+/// Identifier of the device as known by the server.
+pub fn entdmid(provider: &str) -> Result<Node, Error> {
+    provider_setting(provider, &["EntDMID"], Format::Chr)
+}
+
+pub fn exchange_id(provider: &str) -> Result<Node, Error> {
+    provider_setting(provider, &["ExchangeID"], Format::Chr)
+}
+
+/// Management endpoints, as `<Address>` list in xml.
+pub fn management_service_address(provider: &str) -> Result<Node, Error> {
+    provider_setting(provider, &["ManagementServiceAddress"], Format::Chr)
+}
+
+/// Minutes between check-ins, during the first set of retries after enrollment.
+pub fn interval_for_first_set_of_retries(provider: &str) -> Result<Node, Error> {
+    poll_setting(provider, "IntervalForFirstSetOfRetries")
+}
+
+pub fn number_of_first_retries(provider: &str) -> Result<Node, Error> {
+    poll_setting(provider, "NumberOfFirstRetries")
+}
+
+pub fn interval_for_second_set_of_retries(provider: &str) -> Result<Node, Error> {
+    poll_setting(provider, "IntervalForSecondSetOfRetries")
+}
+
+pub fn number_of_second_retries(provider: &str) -> Result<Node, Error> {
+    poll_setting(provider, "NumberOfSecondRetries")
+}
+
+/// Minutes between the scheduled check-ins after the first and second set of retries.
+pub fn interval_for_remaining_scheduled_retries(provider: &str) -> Result<Node, Error> {
+    poll_setting(provider, "IntervalForRemainingScheduledRetries")
+}
+
+/// 0 keeps checking in indefinitely.
+pub fn number_of_remaining_scheduled_retries(provider: &str) -> Result<Node, Error> {
+    poll_setting(provider, "NumberOfRemainingScheduledRetries")
+}
+
+/// Checks in whenever a user logs on.
+pub fn poll_on_login(provider: &str) -> Result<Node, Error> {
+    provider_setting(provider, &["Poll", "PollOnLogin"], Format::Bool)
+}
+
+fn poll_setting(provider: &str, setting: &str) -> Result<Node, Error> {
+    Ok(provider_setting(provider, &["Poll", setting], Format::Int)?
+        .with_constraint(Constraint::Range(0, 1440)))
+}
+
+/// Unenrolls the device from the provider passed as value.
+pub fn unenroll() -> Node {
+    Node::new(format!("{ROOT}/Unenroll"), Format::Chr, Access::EXEC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_test() {
+        let node = interval_for_first_set_of_retries("MDMServer").unwrap();
+        assert_eq!(
+            node.uri(),
+            "./Vendor/MSFT/DMClient/Provider/MDMServer/Poll/IntervalForFirstSetOfRetries"
+        );
+        assert!(node.replace(15).is_ok());
+        assert!(matches!(
+            node.replace(1441),
+            Err(Error::InvalidValue { .. })
+        ));
+        assert_eq!(poll_on_login("MDMServer").unwrap().format(), Format::Bool);
+        assert!(entdmid("..").is_err());
+        assert!(unenroll().exec("MDMServer").is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+//! Firewall CSP, configures the profiles and rules of Windows Defender Firewall
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/firewall-csp
+
+use super::{join, Access, Constraint, Error, Format, Node};
+
+const ROOT: &str = "./Vendor/MSFT/Firewall/MdmStore";
+
+/// Network profile a setting applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Domain,
+    Private,
+    Public,
+}
+
+impl Profile {
+    fn segment(self) -> &'static str {
+        match self {
+            Profile::Domain => "DomainProfile",
+            Profile::Private => "PrivateProfile",
+            Profile::Public => "PublicProfile",
+        }
+    }
+}
+
+fn profile_setting(profile: Profile, setting: &str, format: Format) -> Node {
+    let uri = format!("{ROOT}/{}/{setting}", profile.segment());
+    Node::new(uri, format, Access::ALL)
+}
+
+fn rule_setting(rule: &str, path: &[&str], format: Format) -> Result<Node, Error> {
+    let uri = join(&format!("{ROOT}/FirewallRules"), &[&[rule], path].concat())?;
+    Ok(Node::new(uri, format, Access::ALL))
+}
+
+pub fn enable_firewall(profile: Profile) -> Node {
+    profile_setting(profile, "EnableFirewall", Format::Bool)
+}
+
+/// Blocks all incoming connections, ignoring the rules allowing them.
+pub fn shields_up(profile: Profile) -> Node {
+    profile_setting(profile, "Shielded", Format::Bool)
+}
+
+/// 0 allows, 1 blocks connections without matching rule.
+pub fn default_inbound_action(profile: Profile) -> Node {
+    profile_setting(profile, "DefaultInboundAction", Format::Int)
+        .with_constraint(Constraint::Range(0, 1))
+}
+
+/// 0 allows, 1 blocks connections without matching rule.
+pub fn default_outbound_action(profile: Profile) -> Node {
+    profile_setting(profile, "DefaultOutboundAction", Format::Int)
+        .with_constraint(Constraint::Range(0, 1))
+}
+
+/// The rule itself, created with an Add of null before its settings.
+pub fn rule(rule: &str) -> Result<Node, Error> {
+    let uri = join(&format!("{ROOT}/FirewallRules"), &[rule])?;
+    Ok(Node::new(uri, Format::Node, Access::INSTANCE))
+}
+
+/// Name shown in the firewall configuration of the device.
+pub fn rule_name(rule: &str) -> Result<Node, Error> {
+    rule_setting(rule, &["Name"], Format::Chr)
+}
+
+pub fn rule_enabled(rule: &str) -> Result<Node, Error> {
+    rule_setting(rule, &["Enabled"], Format::Bool)
+}
+
+/// 0 blocks, 1 allows matching connections.
+pub fn rule_action(rule: &str) -> Result<Node, Error> {
+    Ok(rule_setting(rule, &["Action", "Type"], Format::Int)?
+        .with_constraint(Constraint::Range(0, 1)))
+}
+
+pub fn rule_direction(rule: &str) -> Result<Node, Error> {
+    Ok(rule_setting(rule, &["Direction"], Format::Chr)?
+        .with_constraint(Constraint::OneOf(&["IN", "OUT"])))
+}
+
+/// IANA protocol number, eg 6 for TCP and 17 for UDP
+pub fn rule_protocol(rule: &str) -> Result<Node, Error> {
+    Ok(rule_setting(rule, &["Protocol"], Format::Int)?.with_constraint(Constraint::Range(0, 255)))
+}
+
+/// Comma separated ports and port ranges, eg 80,443,8000-8080
+pub fn rule_local_port_ranges(rule: &str) -> Result<Node, Error> {
+    rule_setting(rule, &["LocalPortRanges"], Format::Chr)
+}
+
+/// Comma separated ports and port ranges, eg 80,443,8000-8080
+pub fn rule_remote_port_ranges(rule: &str) -> Result<Node, Error> {
+    rule_setting(rule, &["RemotePortRanges"], Format::Chr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::Value;
+
+    #[test]
+    fn rule_test() {
+        let node = rule_direction("Web").unwrap();
+        assert_eq!(
+            node.uri(),
+            "./Vendor/MSFT/Firewall/MdmStore/FirewallRules/Web/Direction"
+        );
+        assert!(node.add("IN").is_ok());
+        assert!(matches!(node.add("in"), Err(Error::InvalidValue { .. })));
+        assert!(rule_protocol("Web").unwrap().add(256).is_err());
+        assert!(rule("Web").unwrap().add(Value::Null).is_ok());
+        assert!(rule("Web/Name").is_err());
+        assert_eq!(
+            enable_firewall(Profile::Public).uri(),
+            "./Vendor/MSFT/Firewall/MdmStore/PublicProfile/EnableFirewall"
+        );
+    }
+}
@@ -0,0 +1,448 @@
+//! Configuration service providers (CSPs)
+//!
+//! Windows exposes its settings as a tree of nodes, grouped per CSP and addressed by OMA-URI. The builders in
+//! this module know the location, format and supported operations of commonly used nodes, and validate values
+//! before they're turned into SyncML commands.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/configuration-service-provider-reference
+
+use crate::microsoft_protocol::syncml::{Command, Item, ItemCommand, Meta};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+pub mod bitlocker;
+pub mod ddf;
+pub mod defender;
+pub mod dev_detail;
+pub mod dev_info;
+pub mod device_status;
+pub mod dm_client;
+pub mod firewall;
+pub mod node_cache;
+pub mod policy;
+pub mod reboot;
+pub mod remote_wipe;
+pub mod update;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The path segment cannot be part of an OMA-URI
+    InvalidSegment(String),
+    /// The node doesn't support the operation
+    UnsupportedOperation {
+        uri: String,
+        operation: &'static str,
+    },
+    /// The value is of another format than the node
+    InvalidFormat {
+        uri: String,
+        expected: Format,
+        received: Format,
+    },
+    /// The value is not allowed for the node
+    InvalidValue { uri: String, reason: String },
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidSegment(segment) => write!(f, "invalid OMA-URI segment {segment:?}"),
+            Error::UnsupportedOperation { uri, operation } => {
+                write!(f, "{uri} doesn't support {operation}")
+            }
+            Error::InvalidFormat {
+                uri,
+                expected,
+                received,
+            } => write!(f, "{uri} expects a {expected} value, received {received}"),
+            Error::InvalidValue { uri, reason } => write!(f, "invalid value for {uri}: {reason}"),
+        }
+    }
+}
+
+/// Data format of a node, sent as the Format element within the Meta of an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Int,
+    Chr,
+    Bool,
+    Xml,
+    B64,
+    Null,
+    /// Interior node, holding other nodes
+    Node,
+}
+
+impl Format {
+    /// Format named by a Format element, eg "int".
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "int" => Some(Format::Int),
+            "chr" => Some(Format::Chr),
+            "bool" => Some(Format::Bool),
+            "xml" => Some(Format::Xml),
+            "b64" => Some(Format::B64),
+            "null" => Some(Format::Null),
+            "node" => Some(Format::Node),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Int => "int",
+            Format::Chr => "chr",
+            Format::Bool => "bool",
+            Format::Xml => "xml",
+            Format::B64 => "b64",
+            Format::Null => "null",
+            Format::Node => "node",
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(u32),
+    Chr(String),
+    Bool(bool),
+    /// XML document, sent escaped within the Data element
+    Xml(String),
+    /// Binary data, sent base64 encoded
+    B64(Vec<u8>),
+    Null,
+}
+
+impl Value {
+    pub fn format(&self) -> Format {
+        match self {
+            Value::Int(_) => Format::Int,
+            Value::Chr(_) => Format::Chr,
+            Value::Bool(_) => Format::Bool,
+            Value::Xml(_) => Format::Xml,
+            Value::B64(_) => Format::B64,
+            Value::Null => Format::Null,
+        }
+    }
+
+    /// Contents of the Data element.
+    pub fn data(&self) -> Option<String> {
+        match self {
+            Value::Int(value) => Some(value.to_string()),
+            Value::Chr(value) | Value::Xml(value) => Some(value.clone()),
+            Value::Bool(value) => Some(value.to_string()),
+            Value::B64(value) => Some(BASE64.encode(value)),
+            Value::Null => None,
+        }
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Chr(value.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Chr(value)
+    }
+}
+
+/// Operations supported by a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub add: bool,
+    pub delete: bool,
+    pub get: bool,
+    pub replace: bool,
+    pub exec: bool,
+}
+
+impl Access {
+    /// Read-only node, eg reported device information
+    pub const GET: Self = Self::new(false, false, true, false, false);
+    pub const GET_REPLACE: Self = Self::new(false, false, true, true, false);
+    /// Setting created, changed and removed by the server
+    pub const ALL: Self = Self::new(true, true, true, true, false);
+    /// Interior node of a named instance, created and removed by the server
+    pub const INSTANCE: Self = Self::new(true, true, true, false, false);
+    /// Node triggering an action on the device
+    pub const EXEC: Self = Self::new(false, false, false, false, true);
+    pub const GET_EXEC: Self = Self::new(false, false, true, false, true);
+
+    pub(crate) const fn new(add: bool, delete: bool, get: bool, replace: bool, exec: bool) -> Self {
+        Self {
+            add,
+            delete,
+            get,
+            replace,
+            exec,
+        }
+    }
+}
+
+/// Values allowed for a node, on top of its format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    None,
+    /// Inclusive range of integers
+    Range(u32, u32),
+    /// One of the listed strings, compared case-sensitive
+    OneOf(&'static [&'static str]),
+    /// Maximum amount of characters
+    MaxLength(usize),
+    /// Date and time in RFC 3339 format, eg 2024-10-01T22:00:00Z
+    DateTime,
+}
+
+/// A node of a CSP, addressed by its OMA-URI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    uri: String,
+    format: Format,
+    access: Access,
+    constraint: Constraint,
+}
+
+impl Node {
+    pub fn new(uri: impl Into<String>, format: Format, access: Access) -> Self {
+        Self {
+            uri: uri.into(),
+            format,
+            access,
+            constraint: Constraint::None,
+        }
+    }
+
+    pub fn with_constraint(mut self, constraint: Constraint) -> Self {
+        self.constraint = constraint;
+        self
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn access(&self) -> Access {
+        self.access
+    }
+
+    /// Checks the value against the format and constraint of the node.
+    pub fn validate(&self, value: &Value) -> Result<(), Error> {
+        // NOTE; Interior nodes are created without data
+        let expected = match self.format {
+            Format::Node => Format::Null,
+            format => format,
+        };
+        if value.format() != expected {
+            return Err(Error::InvalidFormat {
+                uri: self.uri.clone(),
+                expected,
+                received: value.format(),
+            });
+        }
+
+        let invalid = |reason: String| Error::InvalidValue {
+            uri: self.uri.clone(),
+            reason,
+        };
+        match (value, &self.constraint) {
+            (Value::Chr(value), _) if value.contains('\0') => {
+                Err(invalid("contains a NUL character".into()))
+            }
+            (Value::Xml(value), _) => {
+                check_xml(value).map_err(|e| invalid(format!("malformed xml: {e}")))
+            }
+            (Value::Int(value), Constraint::Range(min, max)) if !(min..=max).contains(&value) => {
+                Err(invalid(format!("{value} is not within {min} and {max}")))
+            }
+            (Value::Chr(value), Constraint::OneOf(allowed))
+                if !allowed.contains(&value.as_str()) =>
+            {
+                Err(invalid(format!("{value:?} is not one of {allowed:?}")))
+            }
+            (Value::Chr(value), Constraint::MaxLength(max)) if value.chars().count() > *max => {
+                Err(invalid(format!("longer than {max} characters")))
+            }
+            (Value::Chr(value), Constraint::DateTime) => {
+                chrono::DateTime::parse_from_rfc3339(value)
+                    .map(|_| ())
+                    .map_err(|e| invalid(format!("{value:?} is not an RFC 3339 date: {e}")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get(&self) -> Result<Command, Error> {
+        self.supports(self.access.get, "Get")?;
+        Ok(Command::Get(ItemCommand::new(Item::target(&self.uri))))
+    }
+
+    /// Creates the node, interior nodes are created with [`Value::Null`].
+    pub fn add(&self, value: impl Into<Value>) -> Result<Command, Error> {
+        self.supports(self.access.add, "Add")?;
+        Ok(Command::Add(ItemCommand::new(self.item(value.into())?)))
+    }
+
+    pub fn replace(&self, value: impl Into<Value>) -> Result<Command, Error> {
+        self.supports(self.access.replace, "Replace")?;
+        Ok(Command::Replace(ItemCommand::new(self.item(value.into())?)))
+    }
+
+    pub fn delete(&self) -> Result<Command, Error> {
+        self.supports(self.access.delete, "Delete")?;
+        Ok(Command::Delete(ItemCommand::new(Item::target(&self.uri))))
+    }
+
+    /// Triggers the action of the node, most actions take [`Value::Null`].
+    pub fn exec(&self, value: impl Into<Value>) -> Result<Command, Error> {
+        self.supports(self.access.exec, "Exec")?;
+        Ok(Command::Exec(ItemCommand::new(self.item(value.into())?)))
+    }
+
+    fn supports(&self, supported: bool, operation: &'static str) -> Result<(), Error> {
+        match supported {
+            true => Ok(()),
+            false => Err(Error::UnsupportedOperation {
+                uri: self.uri.clone(),
+                operation,
+            }),
+        }
+    }
+
+    fn item(&self, value: Value) -> Result<Item, Error> {
+        self.validate(&value)?;
+        let item = Item::target(&self.uri);
+        let item = match self.format {
+            Format::Node => item.with_meta(Meta::with_format(Format::Node.as_str())),
+            Format::Null => item,
+            format => item.with_meta(Meta::with_format(format.as_str())),
+        };
+        Ok(match value.data() {
+            Some(data) => item.with_data(data),
+            None => item,
+        })
+    }
+}
+
+/// Root of the nodes that apply to the device or to the logged-on user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Device,
+    User,
+}
+
+impl Scope {
+    pub fn root(self) -> &'static str {
+        match self {
+            Scope::Device => "./Device/Vendor/MSFT",
+            Scope::User => "./User/Vendor/MSFT",
+        }
+    }
+}
+
+/// Appends the segments to the base uri, rejecting segments that would address another node.
+pub fn join(base: &str, segments: &[&str]) -> Result<String, Error> {
+    let mut uri = base.to_string();
+    for segment in segments {
+        let invalid = segment.is_empty()
+            || *segment == "."
+            || *segment == ".."
+            || segment
+                .chars()
+                .any(|c| matches!(c, '/' | '?' | '#') || c.is_control());
+        if invalid {
+            return Err(Error::InvalidSegment(segment.to_string()));
+        }
+        uri.push('/');
+        uri.push_str(segment);
+    }
+    Ok(uri)
+}
+
+/// Checks the document is well-formed.
+fn check_xml(value: &str) -> Result<(), xml::reader::Error> {
+    for event in xml::reader::EventReader::from_str(value) {
+        event?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_test() {
+        let node = Node::new("./Vendor/MSFT/Test", Format::Int, Access::GET_REPLACE)
+            .with_constraint(Constraint::Range(0, 2));
+
+        let Command::Replace(replace) = node.replace(1).unwrap() else {
+            panic!("expected a Replace command");
+        };
+        let item = &replace.item[0];
+        assert_eq!(item.loc_uri(), Some("./Vendor/MSFT/Test"));
+        assert_eq!(item.meta.as_ref().unwrap().format.as_deref(), Some("int"));
+        assert_eq!(item.data.as_deref(), Some("1"));
+
+        assert!(matches!(node.replace(3), Err(Error::InvalidValue { .. })));
+        assert!(matches!(
+            node.replace("1"),
+            Err(Error::InvalidFormat {
+                expected: Format::Int,
+                received: Format::Chr,
+                ..
+            })
+        ));
+        assert!(matches!(
+            node.delete(),
+            Err(Error::UnsupportedOperation {
+                operation: "Delete",
+                ..
+            })
+        ));
+
+        let xml = Node::new("./Vendor/MSFT/Xml", Format::Xml, Access::ALL);
+        assert!(xml.add(Value::Xml("<a><b/></a>".into())).is_ok());
+        assert!(xml.add(Value::Xml("<a><b></a>".into())).is_err());
+    }
+
+    #[test]
+    fn join_test() {
+        assert_eq!(
+            join(
+                "./Vendor/MSFT/Firewall",
+                &["MdmStore", "FirewallRules", "Web"]
+            )
+            .unwrap(),
+            "./Vendor/MSFT/Firewall/MdmStore/FirewallRules/Web"
+        );
+        assert!(join("./Vendor/MSFT", &["a/b"]).is_err());
+        assert!(join("./Vendor/MSFT", &[".."]).is_err());
+        assert!(join("./Vendor/MSFT", &[""]).is_err());
+    }
+}
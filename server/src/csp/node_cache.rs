@@ -0,0 +1,70 @@
+//! NodeCache CSP, lets the device report changes of watched nodes
+//!
+//! The server registers nodes with their expected value, the device lists the nodes whose value changed at
+//! `ChangedNodes`. This saves the server from querying each node during every session.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/nodecache-csp
+
+use super::{join, Access, Error, Format, Node};
+
+const ROOT: &str = "./Vendor/MSFT/NodeCache";
+
+fn cache_setting(provider: &str, path: &[&str], format: Format) -> Result<Node, Error> {
+    let uri = join(ROOT, &[&[provider], path].concat())?;
+    Ok(Node::new(uri, format, Access::ALL))
+}
+
+/// The cache of the provider, created with an Add of null before registering nodes.
+pub fn provider(provider: &str) -> Result<Node, Error> {
+    Ok(Node::new(
+        join(ROOT, &[provider])?,
+        Format::Node,
+        Access::INSTANCE,
+    ))
+}
+
+/// Version of the cached nodes, chosen by the server.
+pub fn cache_version(provider: &str) -> Result<Node, Error> {
+    cache_setting(provider, &["CacheVersion"], Format::Chr)
+}
+
+/// Slash separated identifiers of the nodes whose value differs from the expected value
+pub fn changed_nodes(provider: &str) -> Result<Node, Error> {
+    let uri = join(ROOT, &[provider, "ChangedNodes"])?;
+    Ok(Node::new(uri, Format::Chr, Access::GET))
+}
+
+/// A watched node, created with an Add of null.
+pub fn node(provider: &str, node: &str) -> Result<Node, Error> {
+    let uri = join(ROOT, &[provider, "Nodes", node])?;
+    Ok(Node::new(uri, Format::Node, Access::INSTANCE))
+}
+
+/// OMA-URI of the watched node.
+pub fn node_uri(provider: &str, node: &str) -> Result<Node, Error> {
+    cache_setting(provider, &["Nodes", node, "NodeURI"], Format::Chr)
+}
+
+pub fn expected_value(provider: &str, node: &str) -> Result<Node, Error> {
+    cache_setting(provider, &["Nodes", node, "ExpectedValue"], Format::Chr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::Value;
+
+    #[test]
+    fn node_cache_test() {
+        let expected = expected_value("MDMServer", "1").unwrap();
+        assert_eq!(
+            expected.uri(),
+            "./Vendor/MSFT/NodeCache/MDMServer/Nodes/1/ExpectedValue"
+        );
+        assert!(expected.add("Enabled").is_ok());
+        assert!(expected.add(1).is_err());
+        assert!(node("MDMServer", "1").unwrap().add(Value::Null).is_ok());
+        assert!(changed_nodes("MDMServer").unwrap().replace("1").is_err());
+        assert!(provider("MDM/Server").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! Policy CSP, configures the policies of the device or of the logged-on user
+//!
+//! Each policy is addressed by its area and name, eg `Camera/AllowCamera`. The value configured by this server
+//! is at `Policy/Config`, the value in effect after merging all configuration sources at `Policy/Result`.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/policy-configuration-service-provider
+
+use super::{join, Access, Constraint, Error, Format, Node, Scope};
+
+/// Configured value of a policy.
+pub fn config(scope: Scope, area: &str, policy: &str, format: Format) -> Result<Node, Error> {
+    let uri = join(scope.root(), &["Policy", "Config", area, policy])?;
+    Ok(Node::new(uri, format, Access::ALL))
+}
+
+/// Value of a policy in effect on the device.
+pub fn result(scope: Scope, area: &str, policy: &str, format: Format) -> Result<Node, Error> {
+    let uri = join(scope.root(), &["Policy", "Result", area, policy])?;
+    Ok(Node::new(uri, format, Access::GET))
+}
+
+/// Integer device policy, for policies with a known valid range.
+fn device_policy(area: &str, policy: &str, min: u32, max: u32) -> Node {
+    config(Scope::Device, area, policy, Format::Int)
+        .expect("static policy path")
+        .with_constraint(Constraint::Range(min, max))
+}
+
+/// 0 disables the camera, 1 allows it.
+pub fn allow_camera() -> Node {
+    device_policy("Camera", "AllowCamera", 0, 1)
+}
+
+/// 0 requires a password, 1 doesn't.
+///
+/// NOTE; The inverted value is as documented
+pub fn device_password_enabled() -> Node {
+    device_policy("DeviceLock", "DevicePasswordEnabled", 0, 1)
+}
+
+pub fn min_device_password_length() -> Node {
+    device_policy("DeviceLock", "MinDevicePasswordLength", 4, 16)
+}
+
+/// Minutes of inactivity before the device locks, 0 never locks.
+pub fn max_inactivity_time_device_lock() -> Node {
+    device_policy("DeviceLock", "MaxInactivityTimeDeviceLock", 0, 999)
+}
+
+/// 0 notifies before downloading, up to 5 which turns off automatic updates.
+pub fn allow_auto_update() -> Node {
+    device_policy("Update", "AllowAutoUpdate", 0, 5)
+}
+
+pub fn allow_realtime_monitoring() -> Node {
+    device_policy("Defender", "AllowRealtimeMonitoring", 0, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_uri_test() {
+        assert_eq!(
+            allow_camera().uri(),
+            "./Device/Vendor/MSFT/Policy/Config/Camera/AllowCamera"
+        );
+        let node = result(Scope::User, "Browser", "AllowCookies", Format::Int).unwrap();
+        assert_eq!(
+            node.uri(),
+            "./User/Vendor/MSFT/Policy/Result/Browser/AllowCookies"
+        );
+        assert!(node.replace(1).is_err());
+        assert!(config(Scope::Device, "Camera/AllowCamera", "x", Format::Int).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! Reboot CSP, restarts the device now or on a schedule
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/reboot-csp
+
+use super::{Access, Constraint, Format, Node};
+
+const ROOT: &str = "./Device/Vendor/MSFT/Reboot";
+
+/// Restarts the device as soon as possible.
+pub fn reboot_now() -> Node {
+    Node::new(format!("{ROOT}/RebootNow"), Format::Null, Access::GET_EXEC)
+}
+
+/// Restarts the device once at the given time, eg 2024-10-01T22:00:00Z
+pub fn schedule_single() -> Node {
+    Node::new(format!("{ROOT}/Schedule/Single"), Format::Chr, Access::ALL)
+        .with_constraint(Constraint::DateTime)
+}
+
+/// Restarts the device daily at the time of day of the given moment, starting at that moment.
+pub fn schedule_daily_recurrent() -> Node {
+    Node::new(
+        format!("{ROOT}/Schedule/DailyRecurrent"),
+        Format::Chr,
+        Access::ALL,
+    )
+    .with_constraint(Constraint::DateTime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::{Error, Value};
+
+    #[test]
+    fn schedule_test() {
+        let node = schedule_single();
+        assert_eq!(node.uri(), "./Device/Vendor/MSFT/Reboot/Schedule/Single");
+        assert!(node.replace("2024-10-01T22:00:00Z").is_ok());
+        assert!(matches!(
+            node.replace("tomorrow"),
+            Err(Error::InvalidValue { .. })
+        ));
+        assert!(reboot_now().exec(Value::Null).is_ok());
+        assert!(reboot_now().replace(Value::Null).is_err());
+    }
+}
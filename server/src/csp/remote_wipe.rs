@@ -0,0 +1,78 @@
+//! RemoteWipe CSP, resets the device to factory settings
+//!
+//! WARN; Wiping removes the enrollment, the device won't check in again
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/remotewipe-csp
+
+use super::{Access, Format, Node};
+
+const ROOT: &str = "./Device/Vendor/MSFT/RemoteWipe";
+
+fn wipe(path: &str) -> Node {
+    Node::new(format!("{ROOT}/{path}"), Format::Null, Access::EXEC)
+}
+
+/// Removes all user data and settings.
+pub fn do_wipe() -> Node {
+    wipe("doWipe")
+}
+
+/// Like [`do_wipe`], restoring the provisioning packages of the device afterwards.
+pub fn do_wipe_persist_provisioned_data() -> Node {
+    wipe("doWipePersistProvisionedData")
+}
+
+/// Like [`do_wipe`], but cannot be cancelled by powering off the device.
+pub fn do_wipe_protected() -> Node {
+    wipe("doWipeProtected")
+}
+
+/// Removes the applications and settings, keeping user accounts and their data.
+pub fn do_wipe_persist_user_data() -> Node {
+    wipe("doWipePersistUserData")
+}
+
+/// Autopilot reset, returns the device to a business-ready state keeping its enrollment.
+pub fn do_automatic_redeployment() -> Node {
+    wipe("AutomaticRedeployment/doAutomaticRedeployment")
+}
+
+/// HRESULT of the last automatic redeployment
+pub fn automatic_redeployment_last_error() -> Node {
+    Node::new(
+        format!("{ROOT}/AutomaticRedeployment/LastError"),
+        Format::Int,
+        Access::GET,
+    )
+}
+
+pub fn automatic_redeployment_status() -> Node {
+    Node::new(
+        format!("{ROOT}/AutomaticRedeployment/Status"),
+        Format::Int,
+        Access::GET,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::Value;
+    use crate::microsoft_protocol::syncml::Command;
+
+    #[test]
+    fn wipe_test() {
+        let Command::Exec(exec) = do_wipe_protected().exec(Value::Null).unwrap() else {
+            panic!("expected an Exec command");
+        };
+        let item = &exec.item[0];
+        assert_eq!(
+            item.loc_uri(),
+            Some("./Device/Vendor/MSFT/RemoteWipe/doWipeProtected")
+        );
+        assert_eq!(item.data, None);
+        assert!(do_wipe().exec("now").is_err());
+        assert!(do_wipe().get().is_err());
+        assert!(automatic_redeployment_status().get().is_ok());
+    }
+}
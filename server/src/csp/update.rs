@@ -0,0 +1,83 @@
+//! Update CSP, approves and reports windows updates
+//!
+//! Updates are identified by their GUID, which is sent URL-encoded between braces.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/update-csp
+
+use super::{Access, Error, Format, Node};
+
+const ROOT: &str = "./Vendor/MSFT/Update";
+
+fn report(path: &str, format: Format) -> Node {
+    Node::new(format!("{ROOT}/{path}"), format, Access::GET)
+}
+
+/// Segment addressing the update, eg `%7ba317dafe-baf4-453f-b232-a7075efae36e%7d`.
+fn update_segment(guid: &str) -> Result<String, Error> {
+    let guid = guid.trim_start_matches('{').trim_end_matches('}');
+    let groups: Vec<_> = guid.split('-').collect();
+    let valid = groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.chars().all(|c| c.is_ascii_hexdigit()));
+    match valid {
+        true => Ok(format!("%7b{}%7d", guid.to_lowercase())),
+        false => Err(Error::InvalidSegment(guid.into())),
+    }
+}
+
+/// Approves the update for installation, created with an Add of null.
+pub fn approved_update(guid: &str) -> Result<Node, Error> {
+    let uri = format!("{ROOT}/ApprovedUpdates/{}", update_segment(guid)?);
+    Ok(Node::new(uri, Format::Node, Access::INSTANCE))
+}
+
+/// Time the approval was received by the device.
+pub fn approved_time(guid: &str) -> Result<Node, Error> {
+    let uri = format!(
+        "{ROOT}/ApprovedUpdates/{}/ApprovedTime",
+        update_segment(guid)?
+    );
+    Ok(Node::new(uri, Format::Chr, Access::GET))
+}
+
+pub fn approved_updates() -> Node {
+    report("ApprovedUpdates", Format::Node)
+}
+
+pub fn installed_updates() -> Node {
+    report("InstalledUpdates", Format::Node)
+}
+
+pub fn failed_updates() -> Node {
+    report("FailedUpdates", Format::Node)
+}
+
+pub fn pending_reboot_updates() -> Node {
+    report("PendingRebootUpdates", Format::Node)
+}
+
+pub fn last_successful_scan_time() -> Node {
+    report("LastSuccessfulScanTime", Format::Chr)
+}
+
+pub fn defer_upgrade() -> Node {
+    report("DeferUpgrade", Format::Bool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::Value;
+
+    #[test]
+    fn approved_update_test() {
+        let node = approved_update("{A317DAFE-BAF4-453F-B232-A7075EFAE36E}").unwrap();
+        assert_eq!(
+            node.uri(),
+            "./Vendor/MSFT/Update/ApprovedUpdates/%7ba317dafe-baf4-453f-b232-a7075efae36e%7d"
+        );
+        assert!(node.add(Value::Null).is_ok());
+        assert!(approved_update("a317dafe-baf4").is_err());
+    }
+}
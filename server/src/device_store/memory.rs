@@ -0,0 +1,53 @@
+//! Device store that lives as long as the process
+
+use super::{Device, DeviceStore, Error};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub struct MemoryDeviceStore {
+    devices: Mutex<BTreeMap<String, Device>>,
+}
+
+impl MemoryDeviceStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DeviceStore for MemoryDeviceStore {
+    fn get(&self, device_id: &str) -> Result<Option<Device>, Error> {
+        Ok(self.devices.lock().unwrap().get(device_id).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<Device>, Error> {
+        Ok(self.devices.lock().unwrap().values().cloned().collect())
+    }
+
+    fn update(
+        &self,
+        device_id: &str,
+        update: &mut dyn FnMut(Option<Device>) -> Option<Device>,
+    ) -> Result<Option<Device>, Error> {
+        let mut devices = self.devices.lock().unwrap();
+        let updated = update(devices.get(device_id).cloned());
+        if let Some(device) = &updated {
+            devices.insert(device.device_id.clone(), device.clone());
+        }
+        Ok(updated)
+    }
+
+    fn remove(&self, device_id: &str) -> Result<bool, Error> {
+        Ok(self.devices.lock().unwrap().remove(device_id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_test() {
+        super::super::tests::exercise_store(&MemoryDeviceStore::new());
+    }
+}
@@ -0,0 +1,413 @@
+//! Registry of enrolled devices
+//!
+//! The registry is fed by the enrollment and management endpoints. Backends only implement storage of
+//! [`Device`] records, the bookkeeping on enrollment and check-in is shared through the provided methods of
+//! [`DeviceStore`].
+
+use crate::microsoft_protocol::syncml::Item;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryDeviceStore;
+pub use sqlite::SqliteDeviceStore;
+
+/// Node paths reported by windows devices that hold inventory information.
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/devdetail-csp
+pub mod node {
+    pub const OS_VERSION: &str = "./DevDetail/SwV";
+    pub const HOSTNAME: &str = "./DevDetail/Ext/Microsoft/DNSComputerName";
+    pub const HW_DEV_ID: &str = "./Vendor/MSFT/DMClient/HWDevID";
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Database(rusqlite::Error),
+    /// A stored record could not be decoded
+    InvalidRecord(String),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Database(error) => write!(f, "device store failure: {error}"),
+            Error::InvalidRecord(reason) => write!(f, "invalid device record: {reason}"),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::Database(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnrollmentState {
+    /// A certificate was issued, the device didn't check-in yet
+    Enrolled,
+    /// The device checked in at the management endpoint
+    Managed,
+    /// The device reported it left management
+    Unenrolled,
+}
+
+impl EnrollmentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EnrollmentState::Enrolled => "enrolled",
+            EnrollmentState::Managed => "managed",
+            EnrollmentState::Unenrolled => "unenrolled",
+        }
+    }
+}
+
+impl std::str::FromStr for EnrollmentState {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "enrolled" => Ok(EnrollmentState::Enrolled),
+            "managed" => Ok(EnrollmentState::Managed),
+            "unenrolled" => Ok(EnrollmentState::Unenrolled),
+            _ => Err(Error::InvalidRecord(format!(
+                "unknown enrollment state '{value}'"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    /// Identifier the device reports in its enrollment request and as management session source
+    pub device_id: String,
+    pub hw_dev_id: Option<String>,
+    pub hostname: Option<String>,
+    pub os_version: Option<String>,
+    /// Thumbprint of the most recently issued identity certificate
+    pub certificate_thumbprint: Option<String>,
+    pub first_enrolled_at: DateTime<Utc>,
+    pub last_enrolled_at: DateTime<Utc>,
+    pub last_check_in: Option<DateTime<Utc>>,
+    pub state: EnrollmentState,
+    /// Groups the device is assigned to, selecting the configuration profiles that apply to it
+    pub groups: BTreeSet<String>,
+    /// Version of each configuration profile applied to the device, by profile name
+    pub profiles: BTreeMap<String, String>,
+    /// Settings of applied profiles the device reported a different value for, by node URI
+    pub drift: BTreeMap<String, Drift>,
+}
+
+/// A setting that no longer holds the value of its profile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub profile: String,
+    pub expected: String,
+    /// Value reported by the device, `None` when the node couldn't be read
+    pub actual: Option<String>,
+    /// First check that found the difference
+    pub detected_at: DateTime<Utc>,
+}
+
+/// Information about the device, unknown values are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inventory {
+    pub hw_dev_id: Option<String>,
+    pub hostname: Option<String>,
+    pub os_version: Option<String>,
+}
+
+impl Inventory {
+    /// Collects the inventory nodes from items the device sent, eg through Replace or Results.
+    pub fn from_items<'a>(items: impl IntoIterator<Item = &'a Item>) -> Self {
+        let mut inventory = Self::default();
+        for item in items {
+            let (Some(path), Some(value)) = (item.loc_uri(), item.data.clone()) else {
+                continue;
+            };
+            match path {
+                node::OS_VERSION => inventory.os_version = Some(value),
+                node::HOSTNAME => inventory.hostname = Some(value),
+                node::HW_DEV_ID => inventory.hw_dev_id = Some(value),
+                _ => {}
+            }
+        }
+        inventory
+    }
+
+    /// Overwrites the device information with the known values.
+    fn apply(&self, device: &mut Device) {
+        if self.hw_dev_id.is_some() {
+            device.hw_dev_id.clone_from(&self.hw_dev_id);
+        }
+        if self.hostname.is_some() {
+            device.hostname.clone_from(&self.hostname);
+        }
+        if self.os_version.is_some() {
+            device.os_version.clone_from(&self.os_version);
+        }
+    }
+}
+
+pub trait DeviceStore: Send + Sync {
+    fn get(&self, device_id: &str) -> Result<Option<Device>, Error>;
+
+    /// All devices, ordered by device identifier.
+    fn list(&self) -> Result<Vec<Device>, Error>;
+
+    /// Reads the device, passes it to `update` and stores the returned record in one transaction, so concurrent
+    /// updates of the same device don't overwrite each other. `update` receives `None` for unknown devices and
+    /// returns `None` to leave the store untouched.
+    ///
+    /// Returns the record returned by `update`.
+    fn update(
+        &self,
+        device_id: &str,
+        update: &mut dyn FnMut(Option<Device>) -> Option<Device>,
+    ) -> Result<Option<Device>, Error>;
+
+    fn remove(&self, device_id: &str) -> Result<bool, Error>;
+
+    /// Registers an issued identity certificate, re-enrolling devices keep their first enrollment timestamp.
+    fn record_enrollment(
+        &self,
+        device_id: &str,
+        certificate_thumbprint: &str,
+        inventory: Inventory,
+        now: DateTime<Utc>,
+    ) -> Result<Device, Error> {
+        let device = self.update(device_id, &mut |device| {
+            let mut device = device.unwrap_or_else(|| Device {
+                device_id: device_id.into(),
+                hw_dev_id: None,
+                hostname: None,
+                os_version: None,
+                certificate_thumbprint: None,
+                first_enrolled_at: now,
+                last_enrolled_at: now,
+                last_check_in: None,
+                state: EnrollmentState::Enrolled,
+                groups: BTreeSet::new(),
+                profiles: BTreeMap::new(),
+                drift: BTreeMap::new(),
+            });
+            inventory.apply(&mut device);
+            device.certificate_thumbprint = Some(certificate_thumbprint.into());
+            device.last_enrolled_at = now;
+            device.state = EnrollmentState::Enrolled;
+            Some(device)
+        })?;
+        Ok(device.expect("enrollment always stores the device"))
+    }
+
+    /// Replaces the certificate of an enrolled device after renewal, returns `None` for unknown devices.
+    fn record_renewal(
+        &self,
+        device_id: &str,
+        certificate_thumbprint: &str,
+    ) -> Result<Option<Device>, Error> {
+        self.update(device_id, &mut |device| {
+            let mut device = device?;
+            device.certificate_thumbprint = Some(certificate_thumbprint.into());
+            Some(device)
+        })
+    }
+
+    /// Registers a management session message of the device, returns `None` for unknown devices.
+    fn record_check_in(
+        &self,
+        device_id: &str,
+        inventory: Inventory,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Device>, Error> {
+        self.update(device_id, &mut |device| {
+            let mut device = device?;
+            inventory.apply(&mut device);
+            device.last_check_in = Some(now);
+            if device.state == EnrollmentState::Enrolled {
+                device.state = EnrollmentState::Managed;
+            }
+            Some(device)
+        })
+    }
+
+    fn set_state(&self, device_id: &str, state: EnrollmentState) -> Result<bool, Error> {
+        let updated = self.update(device_id, &mut |device| {
+            let mut device = device?;
+            device.state = state;
+            Some(device)
+        })?;
+        Ok(updated.is_some())
+    }
+
+    fn set_groups(&self, device_id: &str, groups: BTreeSet<String>) -> Result<bool, Error> {
+        let updated = self.update(device_id, &mut |device| {
+            let mut device = device?;
+            device.groups = groups.clone();
+            Some(device)
+        })?;
+        Ok(updated.is_some())
+    }
+
+    /// Registers the version of the profile that was sent to the device.
+    fn record_profile(&self, device_id: &str, profile: &str, version: &str) -> Result<bool, Error> {
+        let updated = self.update(device_id, &mut |device| {
+            let mut device = device?;
+            device.profiles.insert(profile.into(), version.into());
+            Some(device)
+        })?;
+        Ok(updated.is_some())
+    }
+
+    /// Registers the outcome of checking a setting, `None` when the setting holds its expected value.
+    ///
+    /// NOTE; A setting that keeps drifting keeps the timestamp it was first detected at
+    fn record_drift(
+        &self,
+        device_id: &str,
+        uri: &str,
+        drift: Option<Drift>,
+    ) -> Result<bool, Error> {
+        let updated = self.update(device_id, &mut |device| {
+            let mut device = device?;
+            match &drift {
+                Some(drift) => {
+                    let detected_at = device
+                        .drift
+                        .get(uri)
+                        .map_or(drift.detected_at, |previous| previous.detected_at);
+                    device.drift.insert(
+                        uri.into(),
+                        Drift {
+                            detected_at,
+                            ..drift.clone()
+                        },
+                    );
+                }
+                None => {
+                    device.drift.remove(uri);
+                }
+            }
+            Some(device)
+        })?;
+        Ok(updated.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the shared bookkeeping against a backend.
+    pub(super) fn exercise_store(store: &dyn DeviceStore) {
+        let enrolled_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let inventory = Inventory {
+            hostname: Some("DESKTOP-1".into()),
+            ..Default::default()
+        };
+        store
+            .record_enrollment("DEVICE", "AB01", inventory, enrolled_at)
+            .unwrap();
+        assert!(store
+            .record_check_in("UNKNOWN", Inventory::default(), enrolled_at)
+            .unwrap()
+            .is_none());
+
+        let checked_in_at = DateTime::from_timestamp(1_700_000_600, 0).unwrap();
+        let inventory = Inventory {
+            os_version: Some("10.0.22631.4460".into()),
+            ..Default::default()
+        };
+        let device = store
+            .record_check_in("DEVICE", inventory, checked_in_at)
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.state, EnrollmentState::Managed);
+
+        assert!(store
+            .set_groups("DEVICE", BTreeSet::from(["finance".to_string()]))
+            .unwrap());
+        assert!(store.record_profile("DEVICE", "baseline", "v1").unwrap());
+        assert!(!store.record_profile("UNKNOWN", "baseline", "v1").unwrap());
+        // Concurrent updates of the same device are all kept
+        std::thread::scope(|scope| {
+            for index in 0..8 {
+                scope.spawn(move || {
+                    store
+                        .record_profile("DEVICE", &format!("concurrent-{index}"), "v1")
+                        .unwrap()
+                });
+            }
+        });
+        assert_eq!(store.get("DEVICE").unwrap().unwrap().profiles.len(), 9);
+        let drift = Drift {
+            profile: "baseline".into(),
+            expected: "12".into(),
+            actual: Some("8".into()),
+            detected_at: checked_in_at,
+        };
+        store
+            .record_drift("DEVICE", "./Device/A", Some(drift.clone()))
+            .unwrap();
+        store
+            .record_drift("DEVICE", "./Device/B", Some(drift.clone()))
+            .unwrap();
+        let drift = Drift {
+            actual: None,
+            detected_at: enrolled_at,
+            ..drift
+        };
+        store
+            .record_drift("DEVICE", "./Device/A", Some(drift))
+            .unwrap();
+        store.record_drift("DEVICE", "./Device/B", None).unwrap();
+
+        let reenrolled_at = DateTime::from_timestamp(1_700_001_000, 0).unwrap();
+        store
+            .record_enrollment("DEVICE", "CD02", Inventory::default(), reenrolled_at)
+            .unwrap();
+
+        let device = store.get("DEVICE").unwrap().unwrap();
+        assert_eq!(device.hostname.as_deref(), Some("DESKTOP-1"));
+        assert_eq!(device.os_version.as_deref(), Some("10.0.22631.4460"));
+        assert_eq!(device.certificate_thumbprint.as_deref(), Some("CD02"));
+        assert_eq!(device.first_enrolled_at, enrolled_at);
+        assert_eq!(device.last_enrolled_at, reenrolled_at);
+        assert_eq!(device.last_check_in, Some(checked_in_at));
+        assert_eq!(device.state, EnrollmentState::Enrolled);
+        assert!(device.groups.contains("finance"));
+        assert_eq!(device.profiles["baseline"], "v1");
+        assert_eq!(device.profiles.len(), 9);
+        assert_eq!(device.drift.len(), 1);
+        assert_eq!(device.drift["./Device/A"].actual, None);
+        assert_eq!(device.drift["./Device/A"].detected_at, checked_in_at);
+
+        let renewed = store.record_renewal("DEVICE", "EF03").unwrap().unwrap();
+        assert_eq!(renewed.certificate_thumbprint.as_deref(), Some("EF03"));
+        assert_eq!(renewed.last_enrolled_at, reenrolled_at);
+        assert!(store.record_renewal("UNKNOWN", "EF03").unwrap().is_none());
+
+        assert!(store
+            .set_state("DEVICE", EnrollmentState::Unenrolled)
+            .unwrap());
+        assert_eq!(store.list().unwrap().len(), 1);
+        assert!(store.remove("DEVICE").unwrap());
+        assert!(store.get("DEVICE").unwrap().is_none());
+    }
+
+    #[test]
+    fn inventory_from_items_test() {
+        let items = [
+            Item::source(node::OS_VERSION).with_data("10.0.22631.4460"),
+            Item::source("./DevInfo/Man").with_data("Microsoft Corporation"),
+        ];
+        let inventory = Inventory::from_items(&items);
+        assert_eq!(inventory.os_version.as_deref(), Some("10.0.22631.4460"));
+        assert!(inventory.hostname.is_none());
+    }
+}
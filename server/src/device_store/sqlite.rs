@@ -0,0 +1,261 @@
+//! Device store backed by an embedded SQLite database
+
+use super::{Device, DeviceStore, Drift, EnrollmentState, Error};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Row};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS devices (
+    device_id TEXT PRIMARY KEY NOT NULL,
+    hw_dev_id TEXT,
+    hostname TEXT,
+    os_version TEXT,
+    certificate_thumbprint TEXT,
+    first_enrolled_at INTEGER NOT NULL,
+    last_enrolled_at INTEGER NOT NULL,
+    last_check_in INTEGER,
+    state TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS device_groups (
+    device_id TEXT NOT NULL REFERENCES devices (device_id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    PRIMARY KEY (device_id, name)
+);
+CREATE TABLE IF NOT EXISTS device_profiles (
+    device_id TEXT NOT NULL REFERENCES devices (device_id) ON DELETE CASCADE,
+    profile TEXT NOT NULL,
+    version TEXT NOT NULL,
+    PRIMARY KEY (device_id, profile)
+);
+CREATE TABLE IF NOT EXISTS device_drift (
+    device_id TEXT NOT NULL REFERENCES devices (device_id) ON DELETE CASCADE,
+    uri TEXT NOT NULL,
+    profile TEXT NOT NULL,
+    expected TEXT NOT NULL,
+    actual TEXT,
+    detected_at INTEGER NOT NULL,
+    PRIMARY KEY (device_id, uri)
+);
+";
+
+const COLUMNS: &str = "device_id, hw_dev_id, hostname, os_version, certificate_thumbprint, \
+    first_enrolled_at, last_enrolled_at, last_check_in, state";
+
+pub struct SqliteDeviceStore {
+    // NOTE; Queries are small and infrequent, a single connection suffices
+    connection: Mutex<Connection>,
+}
+
+impl SqliteDeviceStore {
+    /// Opens the database file, creating it and its tables when missing.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, Error> {
+        // NOTE; Removing a device cascades to its groups, profiles and drift
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn timestamp(value: i64) -> Result<DateTime<Utc>, Error> {
+    DateTime::from_timestamp(value, 0)
+        .ok_or_else(|| Error::InvalidRecord(format!("timestamp {value} out of range")))
+}
+
+/// Columns are read in the order of [`COLUMNS`].
+fn read_device(row: &Row) -> Result<Device, Error> {
+    let last_check_in: Option<i64> = row.get(7)?;
+    let state: String = row.get(8)?;
+    Ok(Device {
+        device_id: row.get(0)?,
+        hw_dev_id: row.get(1)?,
+        hostname: row.get(2)?,
+        os_version: row.get(3)?,
+        certificate_thumbprint: row.get(4)?,
+        first_enrolled_at: timestamp(row.get(5)?)?,
+        last_enrolled_at: timestamp(row.get(6)?)?,
+        last_check_in: last_check_in.map(timestamp).transpose()?,
+        state: state.parse::<EnrollmentState>()?,
+        groups: BTreeSet::new(),
+        profiles: BTreeMap::new(),
+        drift: BTreeMap::new(),
+    })
+}
+
+/// Loads the groups, profiles and drift of the device.
+fn read_relations(connection: &Connection, device: &mut Device) -> Result<(), Error> {
+    let mut statement =
+        connection.prepare("SELECT name FROM device_groups WHERE device_id = ?1")?;
+    device.groups = statement
+        .query_map(params![device.device_id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut statement =
+        connection.prepare("SELECT profile, version FROM device_profiles WHERE device_id = ?1")?;
+    device.profiles = statement
+        .query_map(params![device.device_id], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    let mut statement = connection.prepare(
+        "SELECT uri, profile, expected, actual, detected_at FROM device_drift WHERE device_id = ?1",
+    )?;
+    let mut rows = statement.query(params![device.device_id])?;
+    while let Some(row) = rows.next()? {
+        let drift = Drift {
+            profile: row.get(1)?,
+            expected: row.get(2)?,
+            actual: row.get(3)?,
+            detected_at: timestamp(row.get(4)?)?,
+        };
+        device.drift.insert(row.get(0)?, drift);
+    }
+    Ok(())
+}
+
+/// Reads the device together with its groups, profiles and drift.
+fn read(connection: &Connection, device_id: &str) -> Result<Option<Device>, Error> {
+    let mut statement = connection.prepare(&format!(
+        "SELECT {COLUMNS} FROM devices WHERE device_id = ?1"
+    ))?;
+    let mut rows = statement.query(params![device_id])?;
+    let Some(mut device) = rows.next()?.map(read_device).transpose()? else {
+        return Ok(None);
+    };
+    read_relations(connection, &mut device)?;
+    Ok(Some(device))
+}
+
+/// Inserts the device, or replaces the record with the same device identifier.
+fn write(connection: &Connection, device: &Device) -> Result<(), Error> {
+    // NOTE; An upsert instead of REPLACE, which would delete the related rows through the cascade
+    connection.execute(
+        &format!(
+            "INSERT INTO devices ({COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
+             ON CONFLICT (device_id) DO UPDATE SET hw_dev_id = excluded.hw_dev_id, \
+             hostname = excluded.hostname, os_version = excluded.os_version, \
+             certificate_thumbprint = excluded.certificate_thumbprint, \
+             first_enrolled_at = excluded.first_enrolled_at, \
+             last_enrolled_at = excluded.last_enrolled_at, \
+             last_check_in = excluded.last_check_in, state = excluded.state"
+        ),
+        params![
+            device.device_id,
+            device.hw_dev_id,
+            device.hostname,
+            device.os_version,
+            device.certificate_thumbprint,
+            device.first_enrolled_at.timestamp(),
+            device.last_enrolled_at.timestamp(),
+            device.last_check_in.map(|at| at.timestamp()),
+            device.state.as_str(),
+        ],
+    )?;
+    connection.execute(
+        "DELETE FROM device_groups WHERE device_id = ?1",
+        params![device.device_id],
+    )?;
+    for group in &device.groups {
+        connection.execute(
+            "INSERT INTO device_groups (device_id, name) VALUES (?1, ?2)",
+            params![device.device_id, group],
+        )?;
+    }
+    connection.execute(
+        "DELETE FROM device_profiles WHERE device_id = ?1",
+        params![device.device_id],
+    )?;
+    for (profile, version) in &device.profiles {
+        connection.execute(
+            "INSERT INTO device_profiles (device_id, profile, version) VALUES (?1, ?2, ?3)",
+            params![device.device_id, profile, version],
+        )?;
+    }
+    connection.execute(
+        "DELETE FROM device_drift WHERE device_id = ?1",
+        params![device.device_id],
+    )?;
+    for (uri, drift) in &device.drift {
+        connection.execute(
+            "INSERT INTO device_drift (device_id, uri, profile, expected, actual, detected_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                device.device_id,
+                uri,
+                drift.profile,
+                drift.expected,
+                drift.actual,
+                drift.detected_at.timestamp(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+impl DeviceStore for SqliteDeviceStore {
+    fn get(&self, device_id: &str) -> Result<Option<Device>, Error> {
+        let connection = self.connection.lock().unwrap();
+        read(&connection, device_id)
+    }
+
+    fn list(&self) -> Result<Vec<Device>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare(&format!("SELECT {COLUMNS} FROM devices ORDER BY device_id"))?;
+        let mut rows = statement.query([])?;
+        let mut devices = Vec::new();
+        while let Some(row) = rows.next()? {
+            devices.push(read_device(row)?);
+        }
+        for device in &mut devices {
+            read_relations(&connection, device)?;
+        }
+        Ok(devices)
+    }
+
+    fn update(
+        &self,
+        device_id: &str,
+        update: &mut dyn FnMut(Option<Device>) -> Option<Device>,
+    ) -> Result<Option<Device>, Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let updated = update(read(&transaction, device_id)?);
+        if let Some(device) = &updated {
+            write(&transaction, device)?;
+        }
+        transaction.commit()?;
+        Ok(updated)
+    }
+
+    fn remove(&self, device_id: &str) -> Result<bool, Error> {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection.execute(
+            "DELETE FROM devices WHERE device_id = ?1",
+            params![device_id],
+        )?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_store_test() {
+        super::super::tests::exercise_store(&SqliteDeviceStore::open_in_memory().unwrap());
+    }
+}
@@ -0,0 +1,352 @@
+//! Certificate enrollment policies, as served through the XCEP policy endpoint
+//!
+//! A policy tells the enrolling client which kind of key pair to generate and what certificate it can expect
+//! in return. Each policy is described by a [`CertificateTemplate`].
+
+use crate::microsoft_protocol::xcep;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// OID group identifiers, as defined by CryptoAPI (wincrypt.h).
+mod oid_group {
+    pub const HASH_ALGORITHM: u32 = 1;
+    pub const TEMPLATE: u32 = 9;
+}
+
+/// Reference identifier of the issuing authority, all templates are issued by the same authority.
+const CA_REFERENCE: i32 = 0;
+/// Client authentication type of the enrollment endpoint, as defined by MS-XCEP.
+///
+/// NOTE; The enrollment request is authenticated through the policy advertised during discovery
+const CLIENT_AUTHENTICATION_ANONYMOUS: u32 = 1;
+
+/// Algorithm the client hashes its certificate request with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn oid(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "2.16.840.1.101.3.4.2.1",
+            HashAlgorithm::Sha384 => "2.16.840.1.101.3.4.2.2",
+            HashAlgorithm::Sha512 => "2.16.840.1.101.3.4.2.3",
+        }
+    }
+
+    /// Name of the OID as known by CryptoAPI
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "szOID_NIST_sha256",
+            HashAlgorithm::Sha384 => "szOID_NIST_sha384",
+            HashAlgorithm::Sha512 => "szOID_NIST_sha512",
+        }
+    }
+}
+
+/// Purpose of the private key, as defined by CryptoAPI (AT_KEYEXCHANGE and AT_SIGNATURE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum KeySpec {
+    /// Used for both encryption and signing
+    KeyExchange = 1,
+    Signature = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateTemplate {
+    /// Name of the template, shown to the client
+    pub common_name: String,
+    /// Unique object identifier of the template
+    pub oid: String,
+    pub validity: Duration,
+    /// Period before expiry during which the client starts renewing its certificate
+    pub renewal_period: Duration,
+    pub minimal_key_length: u32,
+    /// `None` leaves the purpose of the key to the client
+    pub key_spec: Option<KeySpec>,
+    pub hash_algorithm: HashAlgorithm,
+    /// Bump whenever the template changes, clients re-evaluate on revision change
+    pub major_revision: u32,
+    pub minor_revision: u32,
+}
+
+impl Default for CertificateTemplate {
+    fn default() -> Self {
+        Self {
+            common_name: "simple_mdm device".into(),
+            // NOTE; Arbitrary identifier below the private enterprise arc of Microsoft templates
+            oid: "1.3.6.1.4.1.311.21.8.1.1".into(),
+            validity: Duration::from_secs(365 * 24 * 60 * 60),
+            renewal_period: Duration::from_secs(42 * 24 * 60 * 60),
+            minimal_key_length: 2048,
+            key_spec: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            major_revision: 1,
+            minor_revision: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicySet {
+    /// Identifier of the policy server, clients use it to detect a change of enrollment server
+    pub policy_id: String,
+    pub friendly_name: Option<String>,
+    /// Hours the client should wait before querying policies again
+    pub next_update_hours: Option<u32>,
+    pub templates: Vec<CertificateTemplate>,
+}
+
+impl Default for PolicySet {
+    fn default() -> Self {
+        Self {
+            policy_id: "simple_mdm".into(),
+            friendly_name: Some("Simple MDM enrollment policy".into()),
+            next_update_hours: None,
+            templates: vec![CertificateTemplate::default()],
+        }
+    }
+}
+
+impl PolicySet {
+    /// Builds the policy response, `ca_certificate_der` is the certificate of the authority signing
+    /// enrollment requests received at `enrollment_url`.
+    pub fn to_response(
+        &self,
+        ca_certificate_der: &[u8],
+        enrollment_url: &str,
+    ) -> xcep::GetPoliciesResponse {
+        let mut oids = Vec::new();
+        let mut policies = Vec::with_capacity(self.templates.len());
+        for template in &self.templates {
+            let algorithm = template.hash_algorithm;
+            let hash_reference = oid_reference(
+                &mut oids,
+                algorithm.oid(),
+                oid_group::HASH_ALGORITHM,
+                algorithm.name(),
+            );
+            let template_reference = oid_reference(
+                &mut oids,
+                &template.oid,
+                oid_group::TEMPLATE,
+                &template.common_name,
+            );
+            policies.push(template.to_policy(template_reference, hash_reference));
+        }
+
+        xcep::GetPoliciesResponse {
+            response: Some(xcep::Response {
+                policy_id: self.policy_id.clone(),
+                policy_friendly_name: self.friendly_name.clone(),
+                next_update_hours: self.next_update_hours,
+                // NOTE; Change tracking is not implemented, policies are always sent
+                policies_not_changed: None,
+                policies: Some(xcep::PolicyCollection { policy: policies }),
+            }),
+            c_as: Some(xcep::Cacollection {
+                c_a: vec![xcep::Ca {
+                    uris: xcep::Cauricollection {
+                        c_auri: vec![xcep::Cauri {
+                            client_authentication: CLIENT_AUTHENTICATION_ANONYMOUS,
+                            uri: enrollment_url.into(),
+                            priority: Some(1),
+                            renewal_only: false,
+                        }],
+                    },
+                    certificate: BASE64.encode(ca_certificate_der),
+                    enroll_permission: true,
+                    c_a_reference_id: CA_REFERENCE,
+                }],
+            }),
+            o_i_ds: Some(xcep::Oidcollection { o_id: oids }),
+        }
+    }
+}
+
+impl CertificateTemplate {
+    fn to_policy(
+        &self,
+        policy_oid_reference: i32,
+        hash_algorithm_oid_reference: i32,
+    ) -> xcep::CertificateEnrollmentPolicy {
+        xcep::CertificateEnrollmentPolicy {
+            policy_oid_reference,
+            c_as: Some(xcep::CareferenceCollection {
+                c_a_reference: vec![CA_REFERENCE],
+            }),
+            attributes: xcep::Attributes {
+                common_name: self.common_name.clone(),
+                // NOTE; Version 3 templates support CNG keys
+                policy_schema: 3,
+                certificate_validity: xcep::CertificateValidity {
+                    validity_period_seconds: self.validity.as_secs(),
+                    renewal_period_seconds: self.renewal_period.as_secs(),
+                },
+                permission: xcep::EnrollmentPermission {
+                    enroll: true,
+                    auto_enroll: false,
+                },
+                private_key_attributes: vec![xcep::PrivateKeyAttributes {
+                    minimal_key_length: self.minimal_key_length,
+                    key_spec: self.key_spec.map(|key_spec| key_spec as u32),
+                    key_usage_property: None,
+                    permissions: None,
+                    algorithm_oid_reference: None,
+                    crypto_providers: None,
+                }],
+                revision: xcep::Revision {
+                    major_revision: self.major_revision,
+                    minor_revision: self.minor_revision,
+                },
+                superseded_policies: None,
+                private_key_flags: None,
+                subject_name_flags: None,
+                enrollment_flags: None,
+                general_flags: None,
+                hash_algorithm_oid_reference: Some(hash_algorithm_oid_reference),
+                r_a_requirements: None,
+                key_archival_attributes: vec![],
+                extensions: None,
+            },
+        }
+    }
+}
+
+/// Reference identifier of the OID, added to the collection when it's not listed yet.
+fn oid_reference(oids: &mut Vec<xcep::Oid>, value: &str, group: u32, name: &str) -> i32 {
+    if let Some(oid) = oids
+        .iter()
+        .find(|oid| oid.value == value && oid.group == group)
+    {
+        return oid.o_id_reference_id;
+    }
+    let reference = oids.len() as i32;
+    oids.push(xcep::Oid {
+        value: value.into(),
+        group,
+        o_id_reference_id: reference,
+        default_name: Some(name.into()),
+    });
+    reference
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microsoft_protocol::soap::SoapEnvelope;
+    use crate::microsoft_protocol::wsa;
+
+    #[test]
+    fn policy_response_test() {
+        let policy = PolicySet {
+            templates: vec![
+                CertificateTemplate::default(),
+                CertificateTemplate {
+                    common_name: "Kiosk".into(),
+                    oid: "1.3.6.1.4.1.311.21.8.1.2".into(),
+                    minimal_key_length: 4096,
+                    key_spec: Some(KeySpec::Signature),
+                    hash_algorithm: HashAlgorithm::Sha384,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let envelope = SoapEnvelope {
+            header: wsa::ResponseHeader::new(
+                xcep::ACTION_GET_POLICIES_RESPONSE,
+                Some("urn:uuid:72048B64-0F19-448F-8C2E-B4C661860AA0".into()),
+            ),
+            body: xcep::GetPoliciesResponseBody {
+                get_policies_response: policy.to_response(
+                    b"certificate",
+                    "https://mdmwindows.com/EnrollmentServer/Enrollment.svc",
+                ),
+            },
+            encoding_style: None,
+            tnsattr: None,
+            urnattr: None,
+            xsiattr: None,
+        };
+        let xml = yaserde::ser::to_string(&envelope).unwrap();
+
+        // NOTE; Elements by their path of local names, with their text
+        let mut path = Vec::new();
+        let mut elements = Vec::new();
+        for event in xml::reader::EventReader::from_str(&xml) {
+            match event.unwrap() {
+                xml::reader::XmlEvent::StartElement { name, .. } => {
+                    path.push(name.local_name);
+                    elements.push((path.join("/"), String::new()));
+                }
+                xml::reader::XmlEvent::Characters(text) => elements.last_mut().unwrap().1 = text,
+                xml::reader::XmlEvent::EndElement { .. } => {
+                    path.pop();
+                }
+                _ => {}
+            }
+        }
+        let values = |suffix: &str| -> Vec<&str> {
+            elements
+                .iter()
+                .filter(|(path, _)| path.ends_with(suffix))
+                .map(|(_, text)| text.as_str())
+                .collect()
+        };
+
+        assert_eq!(
+            values("Envelope/Header/RelatesTo"),
+            ["urn:uuid:72048B64-0F19-448F-8C2E-B4C661860AA0"]
+        );
+        assert_eq!(
+            values("Header/Action"),
+            [xcep::ACTION_GET_POLICIES_RESPONSE]
+        );
+        let policy = "Body/GetPoliciesResponse/response/policies/policy";
+        assert_eq!(values(&format!("{policy}/policyOIDReference")), ["1", "3"]);
+        assert_eq!(
+            values(&format!("{policy}/attributes/commonName")),
+            ["simple_mdm device", "Kiosk"]
+        );
+        let key = format!("{policy}/attributes/privateKeyAttributes");
+        assert_eq!(values(&format!("{key}/minimalKeyLength")), ["2048", "4096"]);
+        assert_eq!(values(&format!("{key}/keySpec")), ["2"]);
+        assert_eq!(
+            values(&format!("{policy}/attributes/hashAlgorithmOIDReference")),
+            ["0", "2"]
+        );
+        assert_eq!(
+            values(&format!(
+                "{policy}/attributes/certificateValidity/validityPeriodSeconds"
+            )),
+            ["31536000", "31536000"]
+        );
+
+        // NOTE; Every reference resolves to an OID of the expected group
+        let oids = "Body/GetPoliciesResponse/oIDs/oID";
+        assert_eq!(
+            values(&format!("{oids}/value")),
+            [
+                "2.16.840.1.101.3.4.2.1",
+                "1.3.6.1.4.1.311.21.8.1.1",
+                "2.16.840.1.101.3.4.2.2",
+                "1.3.6.1.4.1.311.21.8.1.2"
+            ]
+        );
+        assert_eq!(values(&format!("{oids}/group")), ["1", "9", "1", "9"]);
+        assert_eq!(
+            values(&format!("{oids}/oIDReferenceID")),
+            ["0", "1", "2", "3"]
+        );
+        assert_eq!(
+            values("GetPoliciesResponse/cAs/cA/uris/cAURI/uri"),
+            ["https://mdmwindows.com/EnrollmentServer/Enrollment.svc"]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Federated authentication page
+//!
+//! With the Federated policy windows opens the authentication service url in a web view, passing the
+//! address to return to (appru) and the email address of the user (login_hint). After signing in, the page
+//! posts the security token back to windows as `wresult`. Windows presents that token in the policy and
+//! enrollment requests.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/federated-authentication-device-enrollment
+
+use crate::authentication::EnrollmentAuthenticator;
+use crate::html::escape;
+use crate::tenant::Tenant;
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Form,
+};
+use serde::Deserialize;
+
+/// Windows only accepts the token when posted back to the application that opened the page.
+const RETURN_URL_SCHEME: &str = "ms-app://";
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    appru: String,
+    login_hint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    appru: String,
+    username: String,
+    password: String,
+}
+
+pub async fn login_page(Query(query): Query<LoginQuery>) -> Response {
+    if !query.appru.starts_with(RETURN_URL_SCHEME) {
+        return (StatusCode::BAD_REQUEST, "Unsupported return address").into_response();
+    }
+
+    let username = query.login_hint.as_deref().unwrap_or_default();
+    Html(login_form(&query.appru, username, None)).into_response()
+}
+
+pub async fn login(tenant: Tenant, Form(form): Form<LoginForm>) -> Response {
+    // WARN; The token grants enrollment, never hand it to anything but the windows enrollment application
+    if !form.appru.starts_with(RETURN_URL_SCHEME) {
+        return (StatusCode::BAD_REQUEST, "Unsupported return address").into_response();
+    }
+
+    match tenant.authenticator.sign_in(&form.username, &form.password) {
+        Ok(token) => Html(token_form(&form.appru, &token)).into_response(),
+        Err(err) => {
+            eprintln!("Error signing in {}: {}", form.username, err);
+            let page = login_form(&form.appru, &form.username, Some("Sign in failed"));
+            (StatusCode::UNAUTHORIZED, Html(page)).into_response()
+        }
+    }
+}
+
+fn login_form(appru: &str, username: &str, error: Option<&str>) -> String {
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", escape(error)))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Sign in</title></head>
+<body>
+<h1>Sign in to enroll this device</h1>
+{error}
+<form method="post">
+<input type="hidden" name="appru" value="{appru}">
+<p><label>Username <input type="text" name="username" value="{username}" autocomplete="username"></label></p>
+<p><label>Password <input type="password" name="password" autocomplete="current-password"></label></p>
+<p><input type="submit" value="Sign in"></p>
+</form>
+</body>
+</html>"#,
+        appru = escape(appru),
+        username = escape(username),
+    )
+}
+
+/// Page posting the token back to windows, submitted as soon as it's loaded.
+fn token_form(appru: &str, token: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Signed in</title></head>
+<body>
+<form method="post" id="loginForm" action="{appru}">
+<input type="hidden" name="wresult" value="{token}">
+<input type="submit" value="Continue">
+</form>
+<script>document.getElementById('loginForm').submit();</script>
+</body>
+</html>"#,
+        appru = escape(appru),
+        token = escape(token),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_form_test() {
+        let page = token_form("ms-app://windows.immersivecontrolpanel", "0a1b\"2c");
+        assert!(page.contains(r#"action="ms-app://windows.immersivecontrolpanel""#));
+        assert!(page.contains(r#"name="wresult" value="0a1b&quot;2c""#));
+
+        let page = login_form("ms-app://x", "<user>", Some("Sign in failed"));
+        assert!(page.contains(r#"value="&lt;user&gt;""#));
+    }
+}
@@ -4,37 +4,73 @@
 //! cargo run -p example-low-level-native-tls
 //! ```
 
+use authentication::{EnrollmentAuthenticator, Principal};
 use axum::{
     body::{Body, Bytes},
-    extract::{DefaultBodyLimit, MatchedPath, Request},
-    http::{request::Parts, HeaderMap},
+    extract::{DefaultBodyLimit, MatchedPath, Request, State},
+    http::{header, request::Parts, HeaderMap, Uri},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
-use futures_util::pin_mut;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use certificate_authority::{CertificateAuthority, IssuedCertificate, CRL_PATH};
+use chrono::Utc;
+use config::{AuthPolicy, ServerConfig};
+use device_store::{Device, EnrollmentState, Inventory};
 use http_body_util::BodyExt;
-use hyper::{body::Incoming, Method, StatusCode};
+use hyper::{body::Incoming, StatusCode};
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use microsoft_protocol::{mde_v2::{discover_header::ReplyToType, DiscoverHeader}, xcep::ClientLastUpdate};
-use std::{path::PathBuf, str::FromStr, time::Duration};
-use tokio::net::TcpListener;
-use tokio_native_tls::{
-    native_tls::{Identity, Protocol, TlsAcceptor as NativeTlsAcceptor},
-    TlsAcceptor,
+use microsoft_protocol::{
+    soap::{EnrollmentError, FaultResponse, SoapFault, SoapRequest},
+    xcep::ClientLastUpdate,
 };
+use std::{sync::Arc, time::Duration};
+use tenant::{Tenant, Tenants};
+use tls::ClientIdentity;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 use tower_service::Service;
-use tracing::{debug_span, enabled, error, info, info_span, warn, Level, Span};
+use tracing::{debug, debug_span, enabled, error, info, info_span, warn, Level, Span};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use xsd_primitives::{DateTime, Decimal};
+use xsd_primitives::DateTime;
 use yaserde::ser::Config;
 
+mod admin;
+mod authentication;
+mod certificate_authority;
+mod command_queue;
+mod config;
+pub mod csp;
+mod device_store;
+mod enrollment_policy;
+mod federated_login;
+mod html;
+mod management;
 mod microsoft_protocol;
+mod tenant;
+#[cfg(test)]
+mod test_support;
+mod tls;
 mod xsd_primitives;
 
+/// Name under which this server registers itself with the windows management client.
+const MDM_PROVIDER_ID: &str = "simple_mdm";
+
+const DISCOVERY_PATH: &str = "/EnrollmentServer/Discovery.svc";
+const POLICY_PATH: &str = "/EnrollmentServer/Policy.svc";
+const ENROLLMENT_PATH: &str = "/EnrollmentServer/Enrollment.svc";
+const AUTHENTICATION_PATH: &str = "/EnrollmentServer/Authenticate";
+const MANAGEMENT_PATH: &str = "/ManagementServer/MDM.svc";
+
+#[derive(Clone)]
+struct AppState {
+    tenants: Arc<Tenants>,
+}
+
 #[tokio::main]
 pub async fn main() {
     tracing_subscriber::registry()
@@ -45,36 +81,110 @@ pub async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let tls_acceptor = native_tls_acceptor(
-        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("self_signed_certs")
-            .join("key.pem"),
-        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("self_signed_certs")
-            .join("cert.pem"),
-    );
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error loading configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let tls_acceptor = TlsAcceptor::from(tls_acceptor);
-    let bind = "127.0.1.167:3000";
-    let tcp_listener = TcpListener::bind(bind).await.unwrap();
-    info!("HTTPS server listening on {bind}. To contact curl -k https://127.0.1.167:3000");
-    let app = Router::new()
+    let tenants = match Tenants::open(&config) {
+        Ok(tenants) => tenants,
+        Err(err) => {
+            eprintln!("Error loading tenants: {}", err);
+            std::process::exit(1);
+        }
+    };
+    for tenant in tenants.iter() {
+        info!(
+            "Serving tenant {} at {}",
+            tenant.name, tenant.config.external_url
+        );
+    }
+    let (tls_acceptor, admin_tls_acceptor) = match tls_acceptors(&config, &tenants) {
+        Ok(acceptors) => acceptors,
+        Err(err) => {
+            eprintln!("Error loading TLS configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let state = AppState {
+        tenants: Arc::new(tenants),
+    };
+    let mut app = Router::new()
         .route(
-            "/EnrollmentServer/Discovery.svc",
+            DISCOVERY_PATH,
             get(get_discovery_handler).post(post_discovery_handler),
         )
-        .route("/EnrollmentServer/Policy.svc", post(policy_handler))
-        .route("/EnrollmentServer/Enrollment.svc", post(enroll_handler))
-        .route("/ManagementServer/MDM.svc", post(manage_handler))
-        .route("/", get(handler))
+        .route(POLICY_PATH, post(policy_handler))
+        .route(ENROLLMENT_PATH, post(enroll_handler))
+        .route(
+            AUTHENTICATION_PATH,
+            get(federated_login::login_page).post(federated_login::login),
+        )
+        .route(MANAGEMENT_PATH, post(manage_handler))
+        .route(CRL_PATH, get(crl_handler))
+        .route("/", get(handler));
+    // NOTE; Without listeners of its own, the admin API is served next to the device endpoints
+    if config.admin.listen.is_empty() {
+        app = app.merge(admin::router());
+    }
+    let app = with_layers(app, state.clone(), config.body_limit);
+
+    let mut listeners = tokio::task::JoinSet::new();
+    for bind in &config.listen {
+        let tcp_listener = TcpListener::bind(bind).await.unwrap();
+        info!("HTTPS server listening on {bind}. To contact curl -k https://{bind}");
+        listeners.spawn(serve(tcp_listener, tls_acceptor.clone(), app.clone()));
+    }
+    let admin_app = with_layers(admin::router(), state, config.body_limit);
+    for bind in &config.admin.listen {
+        let tcp_listener = TcpListener::bind(bind).await.unwrap();
+        info!("Admin API and dashboard listening on {bind}");
+        listeners.spawn(serve(
+            tcp_listener,
+            admin_tls_acceptor.clone(),
+            admin_app.clone(),
+        ));
+    }
+    while listeners.join_next().await.is_some() {}
+}
+
+/// Acceptors of the device and admin listeners, sharing the server certificates of all tenants.
+fn tls_acceptors(
+    config: &ServerConfig,
+    tenants: &Tenants,
+) -> Result<(TlsAcceptor, TlsAcceptor), tls::Error> {
+    let host_certificates = tenants
+        .hosts()
+        .map(|(host, tenant)| (host.to_string(), tenant.config.tls.clone()));
+    let resolver = Arc::new(tls::CertificateResolver::load(
+        &config.tls,
+        host_certificates,
+    )?);
+    tokio::spawn(resolver.clone().watch());
+
+    // NOTE; Device certificates of every tenant are accepted, handlers match them against the tenant
+    let authorities = tenants
+        .iter()
+        .map(|tenant| tenant.certificate_authority.clone());
+    Ok((
+        tls::device_acceptor(resolver.clone(), authorities)?,
+        tls::admin_acceptor(resolver)?,
+    ))
+}
+
+fn with_layers(router: Router<AppState>, state: AppState, body_limit: usize) -> Router {
+    router
+        .with_state(state)
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(
-            1024 * 1024 * 5_000, /* 5mb */
-        ))
+        .layer(RequestBodyLimitLayer::new(body_limit))
         .layer(middleware::from_fn(trace_request_response))
-        .layer(TraceLayer::new_for_http());
+        .layer(TraceLayer::new_for_http())
+}
 
-    pin_mut!(tcp_listener);
+async fn serve(tcp_listener: TcpListener, tls_acceptor: TlsAcceptor, app: Router) {
     loop {
         let tower_service = app.clone();
         let tls_acceptor = tls_acceptor.clone();
@@ -84,9 +194,15 @@ pub async fn main() {
 
         tokio::spawn(async move {
             // Wait for tls handshake to happen
-            let Ok(stream) = tls_acceptor.accept(cnx).await else {
-                error!("error during tls handshake connection from {}", addr);
-                return;
+            let (stream, identity) = match tls::accept(&tls_acceptor, cnx).await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(
+                        "error during tls handshake connection from {}: {}",
+                        addr, err
+                    );
+                    return;
+                }
             };
 
             // Hyper has its own `AsyncRead` and `AsyncWrite` traits and doesn't use tokio.
@@ -96,13 +212,18 @@ pub async fn main() {
             // Hyper also has its own `Service` trait and doesn't use tower. We can use
             // `hyper::service::service_fn` to create a hyper `Service` that calls our app through
             // `tower::Service::call`.
-            let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
-                // We have to clone `tower_service` because hyper's `Service` uses `&self` whereas
-                // tower's `Service` requires `&mut self`.
-                //
-                // We don't need to call `poll_ready` since `Router` is always ready.
-                tower_service.clone().call(request)
-            });
+            let hyper_service =
+                hyper::service::service_fn(move |mut request: Request<Incoming>| {
+                    // NOTE; Every request of the connection carries the identity of the client certificate
+                    if let Some(identity) = &identity {
+                        request.extensions_mut().insert(identity.clone());
+                    }
+                    // We have to clone `tower_service` because hyper's `Service` uses `&self` whereas
+                    // tower's `Service` requires `&mut self`.
+                    //
+                    // We don't need to call `poll_ready` since `Router` is always ready.
+                    tower_service.clone().call(request)
+                });
 
             let ret = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
                 .serve_connection_with_upgrades(stream, hyper_service)
@@ -115,6 +236,9 @@ pub async fn main() {
     }
 }
 
+/// Logs requests and responses, headers at debug level and bodies at trace level.
+///
+/// WARN; Bodies can still hold secrets besides the redacted Security header, eg the password of a login form
 async fn trace_request_response(
     mut req: Request,
     next: Next,
@@ -122,13 +246,17 @@ async fn trace_request_response(
     if enabled!(Level::DEBUG) {
         let (parts, body) = req.into_parts();
         headers_print("request", &parts);
-        let bytes = buffer_and_print("request", body).await?;
-        req = Request::from_parts(parts, Body::from(bytes));
+        if enabled!(Level::TRACE) {
+            let bytes = buffer_and_print("request", body).await?;
+            req = Request::from_parts(parts, Body::from(bytes));
+        } else {
+            req = Request::from_parts(parts, body);
+        }
     }
 
     let mut res = next.run(req).await;
 
-    if enabled!(Level::DEBUG) {
+    if enabled!(Level::TRACE) {
         let (parts, body) = res.into_parts();
         let bytes = buffer_and_print("response", body).await?;
         res = Response::from_parts(parts, Body::from(bytes));
@@ -140,7 +268,15 @@ async fn trace_request_response(
 fn headers_print(direction: &str, parts: &Parts) {
     let ref method = parts.method;
     let ref uri = parts.uri;
-    let ref headers = parts.headers;
+    // NOTE; Credentials of the admin API and dashboard
+    let headers: Vec<_> = parts
+        .headers
+        .iter()
+        .map(|(name, value)| match *name {
+            header::AUTHORIZATION | header::COOKIE => (name, "[REDACTED]"),
+            _ => (name, value.to_str().unwrap_or("[BINARY]")),
+        })
+        .collect();
     let http_string = format!("{method} {uri}");
     tracing::debug!("============================= {direction} HEADERS =============================\n{http_string}\n{headers:?}\n");
 }
@@ -161,7 +297,8 @@ where
     };
 
     if let Ok(body) = std::str::from_utf8(&bytes) {
-        tracing::debug!("============================= {direction} body =============================\n{body}\n");
+        let body = microsoft_protocol::wsse::redact(body);
+        tracing::trace!("============================= {direction} body =============================\n{body}\n");
     }
 
     Ok(bytes)
@@ -171,188 +308,1064 @@ async fn handler() -> &'static str {
     "Hello, World!"
 }
 
-fn native_tls_acceptor(key_file: PathBuf, cert_file: PathBuf) -> NativeTlsAcceptor {
-    let key_pem = std::fs::read_to_string(&key_file).unwrap();
-    let cert_pem = std::fs::read_to_string(&cert_file).unwrap();
-
-    let id = Identity::from_pkcs8(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
-    NativeTlsAcceptor::builder(id)
-        // let's be modern
-        .min_protocol_version(Some(Protocol::Tlsv12))
-        .build()
-        .unwrap()
-}
-
 async fn get_discovery_handler() -> StatusCode {
     StatusCode::NO_CONTENT
 }
 
-// WARN; Request the payload as a string, this will consume all bytes (the body) for us
 async fn post_discovery_handler(
-    method: Method,
+    State(state): State<AppState>,
+    uri: Uri,
     headers: HeaderMap,
-    payload: String,
-) -> impl IntoResponse {
+    request: SoapRequest<microsoft_protocol::mde_v2::DiscoverRequestBody>,
+) -> Response {
     use microsoft_protocol::mde_v2::{discover_response::*, *};
-    use microsoft_protocol::soap::*;
 
-    let parsed: Result<SoapEnvelope<DiscoverRequestBody, DiscoverHeader>, _> =
-        yaserde::de::from_str(&payload);
+    debug!(
+        "Received SOAP request with ID: {:?}",
+        request.header.message_id
+    );
+    // NOTE; The discovery request doesn't identify the device, devices are registered at enrollment
+    let discover_request = &request.body.discover.request;
+    info!(
+        "Discovery for {:?} from {:?} OS edition {}, client version {} requesting protocol {}",
+        discover_request.email_address(),
+        discover_request.device_type,
+        discover_request.os_edition,
+        discover_request.application_version,
+        discover_request.request_version
+    );
 
-    match parsed {
-        Ok(request) => {
-            println!(
-                "Received SOAP request with ID: {:?}",
-                request.header.message_id
-            );
+    // NOTE; The email domain selects the tenant, discovery through the tenant host is the fallback
+    let tenant = discover_request
+        .email_domain()
+        .and_then(|domain| state.tenants.by_email_domain(domain))
+        .or_else(|| state.tenants.by_request(&headers, &uri));
+    let Some(tenant) = tenant else {
+        eprintln!(
+            "No tenant for {:?} at host {:?}",
+            discover_request.email_address(),
+            tenant::request_host(&headers, &uri)
+        );
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::Authorization,
+                "The domain of the email address is not served by this server",
+            ))
+            .into_response();
+    };
+
+    if discover_request.device_type != DeviceType::CIMClientWindows {
+        eprintln!(
+            "Unsupported device type: {:?}",
+            discover_request.device_type
+        );
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::DeviceNotSupported,
+                "Only windows devices are supported",
+            ))
+            .into_response();
+    }
+    let allowed_os_editions = &tenant.config.enrollment.allowed_os_editions;
+    if !allowed_os_editions.is_empty()
+        && !allowed_os_editions.contains(&discover_request.os_edition)
+    {
+        eprintln!("Unsupported OS edition: {}", discover_request.os_edition);
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::DeviceNotSupported,
+                "This edition of windows is not allowed to enroll",
+            ))
+            .into_response();
+    }
+
+    let route = discover_request
+        .email_domain()
+        .and_then(|domain| tenant.config.discovery_route(domain));
+    if route.is_none() && !tenant.config.discovery.routes.is_empty() {
+        eprintln!(
+            "No discovery route for {:?}",
+            discover_request.email_address()
+        );
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::Authorization,
+                "The domain of the email address is not served by this server",
+            ))
+            .into_response();
+    }
+    let url = |path: &str| match route {
+        Some(route) => route.url(&tenant.config, path),
+        None => tenant.config.url(path),
+    };
+
+    let route_policies = route.and_then(|route| route.auth_policies.as_deref());
+    let supported_policies: Vec<AuthPolicy> = discover_request
+        .auth_policies
+        .auth_policy
+        .iter()
+        .filter_map(|policy| AuthPolicy::try_from(policy).ok())
+        .filter(|policy| route_policies.is_none_or(|allowed| allowed.contains(policy)))
+        .collect();
+    let Some(auth_policy) = tenant.authenticator.negotiate(&supported_policies) else {
+        eprintln!(
+            "No common authentication policy, client supports {:?}",
+            supported_policies
+        );
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::Authentication,
+                "None of the supported authentication policies is allowed",
+            ))
+            .into_response();
+    };
 
-            let response = SoapEnvelope {
-                header: DiscoverResponseHeader {
-                    action: "http://schemas.microsoft.com/windows/management/2012/01/enrollment/IDiscoveryService/DiscoverResponse".into(),
-                    activity_id: None,
-                    relates_to: request.header.message_id
+    // NOTE; Validated when loading the configuration
+    let server_version = tenant.config.enrollment_version().ok();
+    // NOTE; Answer with the highest version both sides support
+    let enrollment_version = match server_version {
+        Some(version) if discover_request.request_version < version => {
+            Some(discover_request.request_version.clone())
+        }
+        version => version,
+    };
+
+    request
+        .reply(DiscoverResponseBody {
+            discover: DiscoverResponse {
+                response: DiscoverResult {
+                    auth_policy: auth_policy.into(),
+                    enrollment_version,
+                    enrollment_policy_service_url: Some(url(POLICY_PATH)),
+                    enrollment_service_url: url(ENROLLMENT_PATH),
+                    // NOTE; Only applicable for auth_policy == AuthPolicyType::Federated
+                    authentication_service_url: match auth_policy {
+                        AuthPolicy::Federated => Some(
+                            route
+                                .and_then(|route| route.authentication_service_url.clone())
+                                .or_else(|| {
+                                    tenant.config.enrollment.authentication_service_url.clone()
+                                })
+                                .unwrap_or_else(|| url(AUTHENTICATION_PATH)),
+                        ),
+                        _ => None,
+                    },
                 },
-                body: DiscoverResponseBody {
-                    discover: DiscoverResponse {
-                        response: DiscoverResult {
-                            auth_policy: AuthPolicyType::OnPremise,
-                            enrollment_version: Some(Decimal::from_str("4.0").unwrap()),
-                            // WARN; Hardcoded
-                            enrollment_policy_service_url: Some("https://mdmwindows.com/EnrollmentServer/Policy.svc".into()),
-                            // WARN; Hardcoded
-                            enrollment_service_url: "https://mdmwindows.com/EnrollmentServer/Enrollment.svc".into(),
-                            // NOTE; Only applicable for auth_policy == AuthPolicyType::Federated
-                            authentication_service_url: None,
-                        }
-                    }
+            },
+        })
+        .into_response()
+}
+
+async fn policy_handler(
+    tenant: Tenant,
+    identity: Option<Extension<ClientIdentity>>,
+    request: SoapRequest<microsoft_protocol::xcep::GetPoliciesRequestBody>,
+) -> Response {
+    use microsoft_protocol::xcep;
+
+    debug!(
+        "Received SOAP request with ID: {:?}",
+        request.header.message_id
+    );
+    let identity = identity.as_ref().map(|Extension(identity)| identity);
+    if let Err(fault) = request.authenticate(|credentials| {
+        let accepted = tenant.authenticator.authenticate(&credentials);
+        authenticate(&tenant, identity, accepted)
+    }) {
+        return fault.into_response();
+    }
+
+    request
+        .reply(xcep::GetPoliciesResponseBody {
+            get_policies_response: tenant.enrollment_policy.to_response(
+                tenant.certificate_authority.certificate_der(),
+                &tenant.config.url(ENROLLMENT_PATH),
+            ),
+        })
+        .into_response()
+}
+
+async fn enroll_handler(
+    tenant: Tenant,
+    identity: Option<Extension<ClientIdentity>>,
+    request: SoapRequest<microsoft_protocol::wstep::RequestSecurityTokenBody>,
+) -> Response {
+    use microsoft_protocol::wstep::*;
+
+    debug!(
+        "Received SOAP request with ID: {:?}",
+        request.header.message_id
+    );
+
+    match request.body.request_security_token.request_type.as_str() {
+        REQUEST_TYPE_ISSUE => {}
+        REQUEST_TYPE_RENEW => return renew_certificate(&tenant, &request),
+        request_type => {
+            eprintln!("Unsupported request type: {request_type}");
+            return request
+                .fault(SoapFault::message_format("Unsupported request type"))
+                .into_response();
+        }
+    }
+
+    let identity = identity.as_ref().map(|Extension(identity)| identity);
+    let principal =
+        // NOTE; One-time credentials are used up here, before the certificate is issued, so concurrent
+        // requests cannot enroll with the same credentials
+        match request.authenticate(|credentials| {
+            let accepted = tenant.authenticator.redeem(&credentials);
+            authenticate(&tenant, identity, accepted)
+        }) {
+            Ok(principal) => principal,
+            Err(fault) => return fault.into_response(),
+        };
+
+    let token = &request.body.request_security_token;
+    let context = &token.additional_context;
+    let Some(device_id) = context.device_id() else {
+        eprintln!("Enrollment request is missing the DeviceID context item");
+        return request
+            .fault(SoapFault::message_format("Missing DeviceID"))
+            .into_response();
+    };
+    // NOTE; The DeviceID is claimed by the client, a device authenticated with its certificate can only enroll itself
+    if principal.policy == AuthPolicy::Certificate && principal.name != device_id {
+        eprintln!(
+            "Enrollment request of {device_id} is authenticated with the certificate of {}",
+            principal.name
+        );
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::Authentication,
+                "The certificate belongs to another device",
+            ))
+            .into_response();
+    }
+    let request_der = match binary_security_token(&request, VALUE_TYPE_PKCS10) {
+        Ok(request_der) => request_der,
+        Err(fault) => return fault.into_response(),
+    };
+
+    let certificate_authority = &tenant.certificate_authority;
+    let issued = match certificate_authority.sign_request(&request_der, device_id) {
+        Ok(issued) => issued,
+        Err(err) => return certificate_fault(&request, err),
+    };
+    info!(
+        "Issued certificate {} for device {device_id} of {} (hardware {:?}, os {:?})",
+        issued.thumbprint,
+        principal.name,
+        context.hw_dev_id(),
+        context.os_version()
+    );
+    let inventory = Inventory {
+        hw_dev_id: context.hw_dev_id().map(Into::into),
+        hostname: context.device_name().map(Into::into),
+        os_version: context.os_version().map(Into::into),
+    };
+    if let Err(err) =
+        tenant
+            .device_store
+            .record_enrollment(device_id, &issued.thumbprint, inventory, Utc::now())
+    {
+        eprintln!("Error registering device {device_id}: {}", err);
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::EnrollmentServer,
+                "Failed to register the device",
+            ))
+            .into_response();
+    }
+
+    let document = provisioning_document(
+        &tenant.config,
+        certificate_authority,
+        &issued,
+        certificate_store_name(context),
+        context.device_name().unwrap_or(device_id),
+    );
+    provisioning_response(&request, &document)
+}
+
+/// Issues a replacement for the certificate of an enrolled device.
+///
+/// NOTE; Renewals aren't bound to the enrollment policy, the request is authenticated by its PKCS#7
+/// signature made with the key of the certificate being renewed
+fn renew_certificate(
+    tenant: &Tenant,
+    request: &SoapRequest<microsoft_protocol::wstep::RequestSecurityTokenBody>,
+) -> Response {
+    use microsoft_protocol::wstep::{provisioning::WapProvisioningDoc, VALUE_TYPE_PKCS7};
+
+    let signed_der = match binary_security_token(request, VALUE_TYPE_PKCS7) {
+        Ok(signed_der) => signed_der,
+        Err(fault) => return fault.into_response(),
+    };
+    let certificate_authority = &tenant.certificate_authority;
+    let (renewed, request_der) = match certificate_authority.verify_renewal(&signed_der) {
+        Ok(renewal) => renewal,
+        Err(err) => return certificate_fault(request, err),
+    };
+    let device_id = renewed.device_id.as_str();
+    let context = &request.body.request_security_token.additional_context;
+    if let Some(context_device_id) = context.device_id().filter(|id| *id != device_id) {
+        eprintln!(
+            "Renewal request of {context_device_id} is signed with the certificate of {device_id}"
+        );
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::Authentication,
+                "The certificate belongs to another device",
+            ))
+            .into_response();
+    }
+    // NOTE; Removed and unenrolled devices have to enroll again
+    match tenant.device_store.get(device_id) {
+        Ok(Some(device)) if device.state != EnrollmentState::Unenrolled => {}
+        Ok(_) => {
+            eprintln!("Refused renewal of {device_id}, the device is not enrolled");
+            return request
+                .fault(SoapFault::new(
+                    EnrollmentError::Authorization,
+                    "The device is not enrolled",
+                ))
+                .into_response();
+        }
+        Err(err) => {
+            eprintln!("Error reading device {device_id}: {}", err);
+            return request
+                .fault(SoapFault::new(
+                    EnrollmentError::EnrollmentServer,
+                    "Failed to read the device",
+                ))
+                .into_response();
+        }
+    }
+
+    let issued = match certificate_authority.sign_request(&request_der, device_id) {
+        Ok(issued) => issued,
+        Err(err) => return certificate_fault(request, err),
+    };
+    info!(
+        "Renewed certificate {} of device {device_id} with {}",
+        renewed.thumbprint, issued.thumbprint
+    );
+    if let Err(err) = tenant
+        .device_store
+        .record_renewal(device_id, &issued.thumbprint)
+    {
+        eprintln!("Error registering renewal of {device_id}: {}", err);
+        return request
+            .fault(SoapFault::new(
+                EnrollmentError::EnrollmentServer,
+                "Failed to register the device",
+            ))
+            .into_response();
+    }
+
+    // NOTE; The management client keeps its configuration, only the certificate is replaced
+    let document = WapProvisioningDoc {
+        version: "1.1".into(),
+        characteristic: vec![certificate_stores(
+            &tenant.config,
+            certificate_authority,
+            &issued,
+            certificate_store_name(context),
+        )],
+    };
+    provisioning_response(request, &document)
+}
+
+/// Decoded binary security token of the request, which must be of the expected value type.
+fn binary_security_token(
+    request: &SoapRequest<microsoft_protocol::wstep::RequestSecurityTokenBody>,
+    value_type: &str,
+) -> Result<Vec<u8>, FaultResponse> {
+    let token = &request.body.request_security_token.binary_security_token;
+    if token.value_type != value_type {
+        eprintln!(
+            "Unsupported binary security token type: {}",
+            token.value_type
+        );
+        return Err(request.fault(SoapFault::new(
+            EnrollmentError::CertificateRequest,
+            "Unsupported binary security token type",
+        )));
+    }
+    BASE64.decode(token.compact_value()).map_err(|_| {
+        eprintln!("Binary security token is not valid base64");
+        request.fault(SoapFault::new(
+            EnrollmentError::CertificateRequest,
+            "Certificate request is not valid base64",
+        ))
+    })
+}
+
+/// Fault for a certificate request the authority refused or failed to sign.
+fn certificate_fault<T>(request: &SoapRequest<T>, err: certificate_authority::Error) -> Response {
+    eprintln!("Error issuing device certificate: {}", err);
+    let error = match err {
+        certificate_authority::Error::InvalidRequest(_)
+        | certificate_authority::Error::InvalidRequestSignature => {
+            EnrollmentError::CertificateRequest
+        }
+        certificate_authority::Error::UnknownRenewalCertificate => EnrollmentError::Authentication,
+        _ => EnrollmentError::EnrollmentServer,
+    };
+    request
+        .fault(SoapFault::new(error, err.to_string()))
+        .into_response()
+}
+
+/// Certificate store receiving the identity certificate.
+///
+/// NOTE; User enrollments install the identity certificate into the store of the enrolling user
+fn certificate_store_name(context: &microsoft_protocol::wstep::AdditionalContext) -> &'static str {
+    match context.enrollment_type() {
+        Some(microsoft_protocol::wstep::EnrollmentType::User) => "User",
+        _ => "System",
+    }
+}
+
+/// Answers the request with the provisioning document.
+fn provisioning_response(
+    request: &SoapRequest<microsoft_protocol::wstep::RequestSecurityTokenBody>,
+    document: &microsoft_protocol::wstep::provisioning::WapProvisioningDoc,
+) -> Response {
+    use microsoft_protocol::wstep::*;
+
+    let document = match yaserde::ser::to_string_with_config(
+        document,
+        &Config {
+            perform_indent: false,
+            write_document_declaration: false,
+            indent_string: None,
+        },
+    ) {
+        Ok(xml) => xml,
+        Err(err) => {
+            eprintln!("Error serializing provisioning document: {}", err);
+            return request
+                .fault(SoapFault::internal(
+                    "Failed to create the provisioning document",
+                ))
+                .into_response();
+        }
+    };
+
+    request
+        .reply(RequestSecurityTokenResponseCollectionBody {
+            collection: RequestSecurityTokenResponseCollection {
+                response: RequestSecurityTokenResponse {
+                    token_type: TOKEN_TYPE_DEVICE_ENROLLMENT.into(),
+                    disposition_message: None,
+                    requested_security_token: RequestedSecurityToken {
+                        binary_security_token: BinarySecurityToken {
+                            value_type: VALUE_TYPE_PROVISIONING_DOCUMENT.into(),
+                            encoding_type: ENCODING_TYPE_BASE64.into(),
+                            value: BASE64.encode(document),
+                        },
+                    },
+                    request_id: 0,
                 },
-                encoding_style: None,
-                tnsattr: None,
-                urnattr: None,
-                xsiattr: None,
-            };
+            },
+        })
+        .into_response()
+}
 
-            match yaserde::ser::to_string(&response) {
-                Ok(xml) => Response::builder()
-                    .header("Content-Type", "application/soap+xml; charset=utf-8")
-                    .body(xml)
-                    .unwrap(),
-                Err(err) => {
-                    eprintln!("Error serializing response: {}", err);
-                    Response::builder()
-                        .status(500)
-                        .body("Internal Server Error".to_string())
-                        .unwrap()
+/// Completes the authentication of a policy or enrollment request, `accepted` is the outcome of the configured
+/// authenticator.
+///
+/// NOTE; Issued certificates are public, presenting one proves nothing. Certificate authenticated requests must
+/// arrive over a TLS connection authenticated with the certificate of the same device, the handshake proves
+/// possession of its key.
+fn authenticate(
+    tenant: &Tenant,
+    identity: Option<&ClientIdentity>,
+    accepted: Result<Principal, authentication::Error>,
+) -> Result<Principal, FaultResponse> {
+    let principal = accepted.map_err(|err| {
+        eprintln!("Error authenticating enrollment request: {}", err);
+        let fault = match err {
+            authentication::Error::UnsupportedCredentials
+            | authentication::Error::InvalidCredentials => {
+                SoapFault::new(EnrollmentError::Authentication, err.to_string())
+            }
+            _ => SoapFault::new(
+                EnrollmentError::InternalServiceFault,
+                "Authentication failed",
+            ),
+        };
+        FaultResponse::from(fault)
+    })?;
+    // NOTE; Gate on the policy that accepted the credentials, other configured policies don't protect this request
+    if principal.policy == AuthPolicy::Certificate {
+        if let Err(reason) = verify_device_certificate(tenant, identity, &principal.name) {
+            eprintln!(
+                "Refused certificate of {} without proof of possession: {reason}",
+                principal.name
+            );
+            return Err(SoapFault::new(
+                EnrollmentError::Authentication,
+                "The client certificate doesn't match the security token",
+            )
+            .into());
+        }
+    }
+    Ok(principal)
+}
+
+/// Builds the client provisioning document that installs the certificates and configures the
+/// management client of the enrolled device.
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/w7-application-csp
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/dmclient-csp
+fn provisioning_document(
+    config: &ServerConfig,
+    certificate_authority: &CertificateAuthority,
+    issued: &IssuedCertificate,
+    certificate_store: &str,
+    device_name: &str,
+) -> microsoft_protocol::wstep::provisioning::WapProvisioningDoc {
+    use microsoft_protocol::wstep::provisioning::*;
+
+    let management_url = &config.url(MANAGEMENT_PATH);
+    let certificates = certificate_stores(config, certificate_authority, issued, certificate_store);
+
+    let application = Characteristic::new("APPLICATION")
+        .with_parm(Parm::new("APPID", "w7"))
+        .with_parm(Parm::new("PROVIDER-ID", MDM_PROVIDER_ID))
+        .with_parm(Parm::new("NAME", "Simple MDM"))
+        .with_parm(Parm::new("ADDR", management_url))
+        .with_parm(Parm::new("ServerList", management_url))
+        .with_parm(Parm::new("ROLE", "4294967295"))
+        .with_parm(Parm::flag("BACKCOMPATRETRYDISABLED"))
+        .with_parm(Parm::new(
+            "DEFAULTENCODING",
+            "application/vnd.syncml.dm+xml",
+        ))
+        // NOTE; The device authenticates with its client certificate, the digest secrets are unused
+        .with_characteristic(
+            Characteristic::new("APPAUTH")
+                .with_parm(Parm::new("AAUTHLEVEL", "CLIENT"))
+                .with_parm(Parm::new("AAUTHTYPE", "DIGEST"))
+                .with_parm(Parm::new("AAUTHSECRET", "dummy"))
+                .with_parm(Parm::new("AAUTHDATA", "nonce")),
+        )
+        .with_characteristic(
+            Characteristic::new("APPAUTH")
+                .with_parm(Parm::new("AAUTHLEVEL", "APPSRV"))
+                .with_parm(Parm::new("AAUTHTYPE", "DIGEST"))
+                .with_parm(Parm::new("AAUTHNAME", "dummy"))
+                .with_parm(Parm::new("AAUTHSECRET", "dummy"))
+                .with_parm(Parm::new("AAUTHDATA", "nonce")),
+        );
+
+    let dm_client = Characteristic::new("DMClient").with_characteristic(
+        Characteristic::new("Provider").with_characteristic(
+            Characteristic::new(MDM_PROVIDER_ID)
+                .with_parm(Parm::typed("EntDeviceName", device_name, "string"))
+                .with_characteristic(
+                    Characteristic::new("Poll")
+                        .with_parm(Parm::typed("NumberOfFirstRetries", "8", "integer"))
+                        .with_parm(Parm::typed("IntervalForFirstSetOfRetries", "15", "integer"))
+                        .with_parm(Parm::typed("NumberOfSecondRetries", "5", "integer"))
+                        .with_parm(Parm::typed("IntervalForSecondSetOfRetries", "3", "integer"))
+                        .with_parm(Parm::typed(
+                            "NumberOfRemainingScheduledRetries",
+                            "0",
+                            "integer",
+                        ))
+                        .with_parm(Parm::typed(
+                            "IntervalForRemainingScheduledRetries",
+                            "1560",
+                            "integer",
+                        ))
+                        .with_parm(Parm::typed("PollOnLogin", "true", "boolean")),
+                ),
+        ),
+    );
+
+    WapProvisioningDoc {
+        version: "1.1".into(),
+        characteristic: vec![certificates, application, dm_client],
+    }
+}
+
+/// Certificate stores holding the authority certificates and the identity certificate of the device, with the
+/// renewal settings of the identity certificate.
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/mdm/certificatestore-csp
+fn certificate_stores(
+    config: &ServerConfig,
+    certificate_authority: &CertificateAuthority,
+    issued: &IssuedCertificate,
+    certificate_store: &str,
+) -> microsoft_protocol::wstep::provisioning::Characteristic {
+    use microsoft_protocol::wstep::provisioning::*;
+
+    // NOTE; Without ServerURL the device renews with the enrollment service it enrolled with
+    let authority = &config.certificate_authority;
+    let renewal = Characteristic::new("WSTEP").with_characteristic(
+        Characteristic::new("Renew")
+            .with_parm(Parm::typed(
+                "ROBOSupport",
+                authority.robo_support.to_string(),
+                "boolean",
+            ))
+            .with_parm(Parm::typed(
+                "RenewPeriod",
+                authority.renewal_period_days.to_string(),
+                "integer",
+            ))
+            .with_parm(Parm::typed(
+                "RetryInterval",
+                authority.renewal_retry_interval_days.to_string(),
+                "integer",
+            )),
+    );
+
+    let encoded_certificate = |der: &[u8]| {
+        Characteristic::new(certificate_authority::thumbprint(der))
+            .with_parm(Parm::new("EncodedCertificate", BASE64.encode(der)))
+    };
+    let mut intermediate_store = Characteristic::new("System");
+    for der in certificate_authority.intermediates_der() {
+        intermediate_store = intermediate_store.with_characteristic(encoded_certificate(der));
+    }
+
+    let mut certificates = Characteristic::new("CertificateStore").with_characteristic(
+        Characteristic::new("Root").with_characteristic(
+            Characteristic::new("System")
+                .with_characteristic(encoded_certificate(certificate_authority.root_der())),
+        ),
+    );
+    if !intermediate_store.characteristic.is_empty() {
+        certificates = certificates
+            .with_characteristic(Characteristic::new("CA").with_characteristic(intermediate_store));
+    }
+    certificates.with_characteristic(
+        Characteristic::new("My")
+            .with_characteristic(
+                Characteristic::new(certificate_store)
+                    .with_characteristic(
+                        Characteristic::new(issued.thumbprint.clone())
+                            .with_parm(Parm::new("EncodedCertificate", BASE64.encode(&issued.der))),
+                    )
+                    .with_characteristic(Characteristic::new("PrivateKeyContainer")),
+            )
+            .with_characteristic(renewal),
+    )
+}
+
+/// Queues the settings of the configuration profiles the device did not receive yet, and the verification of
+/// the applied profiles when due.
+fn apply_profiles(tenant: &Tenant, device: &Device) {
+    let checks = tenant
+        .drift_monitor
+        .checks(&tenant.profiles.profiles(), device, Utc::now());
+    if !checks.is_empty() {
+        debug!(
+            "Verifying {} setting(s) of {}",
+            checks.len(),
+            device.device_id
+        );
+        if let Err(err) = tenant
+            .management_sessions
+            .enqueue_all(&device.device_id, checks)
+        {
+            eprintln!("Error verifying profiles of {}: {}", device.device_id, err);
+        }
+    }
+    for profile in tenant.profiles.pending(device) {
+        info!(
+            "Applying profile {} version {} to {}",
+            profile.name, profile.version, device.device_id
+        );
+        if let Err(err) = tenant
+            .management_sessions
+            .enqueue_all(&device.device_id, profile.commands())
+        {
+            eprintln!("Error applying profile {}: {}", profile.name, err);
+            continue;
+        }
+        if let Err(err) =
+            tenant
+                .device_store
+                .record_profile(&device.device_id, &profile.name, &profile.version)
+        {
+            eprintln!("Error registering profile {}: {}", profile.name, err);
+        }
+    }
+}
+
+/// Hands the pending commands of the durable queue to the management session of the device.
+fn deliver_queued(tenant: &Tenant, device_id: &str) {
+    let pending = match tenant.command_queue.take_pending(device_id, Utc::now()) {
+        Ok(pending) => pending,
+        Err(err) => {
+            eprintln!("Error reading command queue of {device_id}: {}", err);
+            return;
+        }
+    };
+    for queued in pending {
+        debug!(
+            "Delivering queued command {} to {device_id}, attempt {}",
+            queued.id, queued.attempts
+        );
+        tenant
+            .management_sessions
+            .enqueue_durable(device_id, queued.command, queued.id);
+    }
+}
+
+/// Registers the verified settings of the device, drifted settings are queued again when remediating.
+fn record_drift(tenant: &Tenant, device: &Device, completed: &[management::CommandOutcome]) {
+    let profiles = tenant.profiles.profiles();
+    let findings = tenant
+        .drift_monitor
+        .compare(&profiles, device, completed, Utc::now());
+    for finding in findings {
+        if let Some(drift) = &finding.drift {
+            warn!(
+                "Setting {} of profile {} drifted on {}, expected {:?} but found {:?}",
+                finding.uri, drift.profile, device.device_id, drift.expected, drift.actual
+            );
+            if tenant.drift_monitor.remediates() {
+                if let Err(err) = tenant
+                    .management_sessions
+                    .enqueue(&device.device_id, finding.remediation.clone())
+                {
+                    eprintln!("Error remediating {}: {}", finding.uri, err);
                 }
             }
         }
+        if let Err(err) =
+            tenant
+                .device_store
+                .record_drift(&device.device_id, &finding.uri, finding.drift)
+        {
+            eprintln!("Error registering drift of {}: {}", finding.uri, err);
+        }
+    }
+}
+
+/// Revocation list of the device certificates, referenced by the issued certificates.
+async fn crl_handler(tenant: Tenant) -> Response {
+    match tenant.certificate_authority.revocation_list() {
+        Ok(crl) => Response::builder()
+            .header("Content-Type", "application/pkix-crl")
+            .body(Body::from(crl))
+            .unwrap(),
         Err(err) => {
-            eprintln!("Error parsing SOAP request: {}", err);
-            Response::builder()
+            eprintln!("Error creating revocation list: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Checks the client certificate is one the tenant issued to the device and didn't revoke.
+fn verify_device_certificate(
+    tenant: &Tenant,
+    identity: Option<&ClientIdentity>,
+    device_id: &str,
+) -> Result<(), String> {
+    let Some(identity) = identity else {
+        return Err("no client certificate".into());
+    };
+    // NOTE; The TLS layer verified the chain and validity against the authorities of all tenants
+    let issued = tenant
+        .certificate_authority
+        .store()
+        .find_by_thumbprint(&identity.thumbprint)
+        .ok_or_else(|| format!("certificate {} is unknown", identity.thumbprint))?;
+    if issued.revoked_at.is_some() {
+        return Err(format!("certificate {} is revoked", issued.thumbprint));
+    }
+    if issued.device_id != device_id {
+        return Err(format!(
+            "certificate {} belongs to {}",
+            issued.thumbprint, issued.device_id
+        ));
+    }
+    Ok(())
+}
+
+async fn manage_handler(
+    tenant: Tenant,
+    identity: Option<Extension<ClientIdentity>>,
+    payload: String,
+) -> impl IntoResponse {
+    use microsoft_protocol::syncml::SyncMl;
+
+    let parsed: Result<SyncMl, _> = yaserde::de::from_str(&payload);
+    let message = match parsed {
+        Ok(message) => message,
+        Err(err) => {
+            eprintln!("Error parsing SyncML message: {}", err);
+            return Response::builder()
                 .status(400)
                 .body("Bad Request".to_string())
-                .unwrap()
+                .unwrap();
         }
+    };
+
+    let device_id = message.sync_hdr.source.loc_uri.clone();
+    let identity = identity.as_ref().map(|Extension(identity)| identity);
+    if let Err(reason) = verify_device_certificate(&tenant, identity, &device_id) {
+        warn!("Refused management session from {device_id}, {reason}");
+        return Response::builder()
+            .status(403)
+            .body("Forbidden".to_string())
+            .unwrap();
+    }
+    let reported = &message.sync_body.commands;
+    let inventory = Inventory::from_items(
+        reported
+            .iter()
+            .flat_map(|command| command.items())
+            .chain(message.sync_body.results.iter().flat_map(|r| &r.item)),
+    );
+    let device = match tenant
+        .device_store
+        .record_check_in(&device_id, inventory, Utc::now())
+    {
+        Ok(Some(device)) => Some(device),
+        // NOTE; Sessions of unknown devices are still served, the device could originate from a lost registry
+        Ok(None) => {
+            warn!("Management session from unregistered device {device_id}");
+            None
+        }
+        Err(err) => {
+            eprintln!("Error registering check-in of {device_id}: {}", err);
+            None
+        }
+    };
+    let unenrolled = reported.iter().any(is_unenrollment);
+    if unenrolled {
+        info!("Device {device_id} unenrolled");
+        if let Err(err) = tenant
+            .device_store
+            .set_state(&device_id, EnrollmentState::Unenrolled)
+        {
+            eprintln!("Error registering unenrollment of {device_id}: {}", err);
+        }
+        if let Err(err) = tenant.certificate_authority.revoke_device(&device_id) {
+            eprintln!("Error revoking certificates of {device_id}: {}", err);
+        }
+    }
+    let device = device.filter(|d| !unenrolled && d.state != EnrollmentState::Unenrolled);
+    if let Some(device) = &device {
+        apply_profiles(&tenant, device);
     }
-}
 
-async fn policy_handler(method: Method, headers: HeaderMap, payload: String) -> impl IntoResponse {
-    use microsoft_protocol::soap::*;
-    use microsoft_protocol::xcep;
+    deliver_queued(&tenant, &device_id);
 
-    tracing::debug!("Payload: {payload}");
-    let parsed: Result<SoapEnvelope<xcep::GetPoliciesRequestBody>, _> =
-        yaserde::de::from_str(&payload);
-
-    // let test_request = SoapEnvelope {
-    //     header: DefaultHeader {},
-    //     body: xcep::GetPoliciesRequestBody {
-    //         get_policies: xcep::GetPolicies {
-    //             client: xcep::Client {
-    //                 client_last_update: Some(ClientLastUpdate {
-    //                     is_null: None,
-    //                     value: Some(DateTime::from_str("2024-11-29 22:00:00.000").unwrap())
-    //                 }),
-    //                 //preferred_language: None,
-    //             },
-    //             //request_filter: None,
-    //         },
-    //     },
-    //     encoding_style: None,
-    //     tnsattr: None,
-    //     urnattr: None,
-    //     xsiattr: None,
-    // };
-    // match yaserde::ser::to_string_with_config(
-    //     &test_request,
-    //     &Config {
-    //         perform_indent: true,
-    //         write_document_declaration: false,
-    //         indent_string: None,
-    //     },
-    // ) {
-    //     Ok(xml) => tracing::debug!(xml),
-    //     Err(error) => tracing::error!(error),
-    // }
-
-    match parsed {
-        Ok(request) => {
-            println!("SOAP request: {:?}", request.body.get_policies);
-            println!(
-                "Received SOAP request with ID: {:?}",
-                // request.header.message_id
-                0,
-            );
+    let handled = match tenant.management_sessions.handle(message) {
+        Ok(handled) => handled,
+        Err(err) => {
+            eprintln!("Error handling SyncML message from {device_id}: {}", err);
+            return Response::builder()
+                .status(400)
+                .body("Bad Request".to_string())
+                .unwrap();
+        }
+    };
 
-            // let response = SoapEnvelope {
-            //     header: DiscoverResponseHeader {
-            //         action: "http://schemas.microsoft.com/windows/management/2012/01/enrollment/IDiscoveryService/DiscoverResponse".into(),
-            //         activity_id: None,
-            //         relates_to: request.header.message_id
-            //     },
-            //     body: DiscoverResponseBody {
-            //         discover: DiscoverResponse {
-            //             response: DiscoverResult {
-            //                 auth_policy: AuthPolicyType::OnPremise,
-            //                 enrollment_version: Some(Decimal::from_str("4.0").unwrap()),
-            //                 // WARN; Hardcoded
-            //                 enrollment_policy_service_url: Some("https://mdmwindows.com/EnrollmentServer/Policy.svc".into()),
-            //                 // WARN; Hardcoded
-            //                 enrollment_service_url: "https://mdmwindows.com/EnrollmentServer/Enrollment.svc".into(),
-            //                 // NOTE; Only applicable for auth_policy == AuthPolicyType::Federated
-            //                 authentication_service_url: None,
-            //             }
-            //         }
-            //     },
-            //     encoding_style: None,
-            //     tnsattr: None,
-            //     urnattr: None,
-            //     xsiattr: None,
-            // };
-
-            // match yaserde::ser::to_string(&response) {
-            match Err("Not implemented") {
-                Ok(xml) => Response::builder()
-                    .header("Content-Type", "application/soap+xml; charset=utf-8")
-                    .body(xml)
-                    .unwrap(),
-                Err(err) => {
-                    eprintln!("Error serializing response: {}", err);
-                    Response::builder()
-                        .status(500)
-                        .body("Internal Server Error".to_string())
-                        .unwrap()
-                }
+    for outcome in &handled.completed {
+        info!(
+            "Device {device_id} completed {} command {} with status {:?}",
+            outcome.name, outcome.cmd_id, outcome.status
+        );
+        if let Some(queue_id) = outcome.queue_id {
+            if let Err(err) = tenant.command_queue.record_outcome(
+                queue_id,
+                outcome.status,
+                outcome.results.clone(),
+                Utc::now(),
+            ) {
+                eprintln!("Error registering outcome of command {queue_id}: {}", err);
             }
         }
+    }
+    if let Some(device) = &device {
+        record_drift(&tenant, device, &handled.completed);
+    }
+    if handled.session_completed {
+        info!("Device {device_id} completed management session");
+    }
+
+    match yaserde::ser::to_string(&handled.response) {
+        Ok(xml) => Response::builder()
+            .header("Content-Type", "application/vnd.syncml.dm+xml")
+            .body(xml)
+            .unwrap(),
         Err(err) => {
-            eprintln!("Error parsing SOAP request: {}", err);
+            eprintln!("Error serializing response: {}", err);
             Response::builder()
-                .status(400)
-                .body("Bad Request".to_string())
+                .status(500)
+                .body("Internal Server Error".to_string())
                 .unwrap()
         }
     }
 }
-async fn enroll_handler() {}
-async fn manage_handler() {}
+
+/// Generic alert the device sends when the user removes the management account.
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/mdm-enrollment-of-windows-devices
+fn is_unenrollment(command: &microsoft_protocol::syncml::Command) -> bool {
+    use microsoft_protocol::syncml::{alert, Command};
+
+    const UNENROLLMENT_TYPE: &str = "com.microsoft:mdm.unenrollment.userrequest";
+    let Command::Alert(command) = command else {
+        return false;
+    };
+    command.data == Some(alert::GENERIC)
+        && command.item.iter().any(|item| {
+            item.meta
+                .as_ref()
+                .and_then(|meta| meta.meta_type.as_deref())
+                == Some(UNENROLLMENT_TYPE)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TenantBuilder;
+    use axum::http::Request;
+
+    fn discover_request(device_type: &str, request_version: &str) -> String {
+        format!(
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:a="http://www.w3.org/2005/08/addressing">
+    <s:Header>
+        <a:Action s:mustUnderstand="1">{ACTION}</a:Action>
+        <a:MessageID>urn:uuid:748132ec-a575-4329-b01b-6171a9cf8478</a:MessageID>
+        <a:ReplyTo>
+            <a:Address>http://www.w3.org/2005/08/addressing/anonymous</a:Address>
+        </a:ReplyTo>
+        <a:To s:mustUnderstand="1">https://mdmwindows.com/EnrollmentServer/Discovery.svc</a:To>
+    </s:Header>
+    <s:Body>
+        <Discover xmlns="http://schemas.microsoft.com/windows/management/2012/01/enrollment">
+            <request xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                <EmailAddress>user@mdmwindows.com</EmailAddress>
+                <RequestVersion>{request_version}</RequestVersion>
+                <DeviceType>{device_type}</DeviceType>
+                <ApplicationVersion>10.0.22631.4460</ApplicationVersion>
+                <OSEdition>48</OSEdition>
+                <AuthPolicies>
+                    <AuthPolicy>Federated</AuthPolicy>
+                    <AuthPolicy>Certificate</AuthPolicy>
+                </AuthPolicies>
+            </request>
+        </Discover>
+    </s:Body>
+</s:Envelope>"#,
+            ACTION = microsoft_protocol::mde_v2::ACTION_DISCOVER,
+        )
+    }
+
+    /// Posts the discovery request, returns the status and body of the response.
+    async fn discover(app: &Router, request: String) -> (StatusCode, String) {
+        let request = Request::post(DISCOVERY_PATH)
+            .header("Host", "mdmwindows.com")
+            .header("Content-Type", "application/soap+xml; charset=utf-8")
+            .body(Body::from(request))
+            .unwrap();
+        let response = app.clone().call(request).await.unwrap();
+        let status = response.status();
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn discovery_test() {
+        let (_directory, tenants) = TenantBuilder::new("discovery").build();
+        let app = Router::new()
+            .route(DISCOVERY_PATH, post(post_discovery_handler))
+            .with_state(AppState {
+                tenants: Arc::new(tenants),
+            });
+
+        let (status, response) = discover(&app, discover_request("WindowsPhone", "4.0")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.contains("DeviceNotSupported"));
+
+        // NOTE; The server answers with the highest version both sides support
+        let (status, response) = discover(&app, discover_request("CIMClient_Windows", "5.0")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.contains("<AuthPolicy>Certificate</AuthPolicy>"));
+        assert!(response.contains("<EnrollmentVersion>4.0</EnrollmentVersion>"));
+        let (status, response) = discover(&app, discover_request("CIMClient_Windows", "3.0")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.contains("<EnrollmentVersion>3.0</EnrollmentVersion>"));
+    }
+
+    fn policy_request(token: &str) -> String {
+        format!(
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:a="http://www.w3.org/2005/08/addressing">
+    <s:Header>
+        <a:Action s:mustUnderstand="1">{ACTION}</a:Action>
+        <a:MessageID>urn:uuid:72048B64-0F19-448F-8C2E-B4C661860AA0</a:MessageID>
+        <a:ReplyTo>
+            <a:Address>http://www.w3.org/2005/08/addressing/anonymous</a:Address>
+        </a:ReplyTo>
+        <a:To s:mustUnderstand="1">https://mdmwindows.com/EnrollmentServer/Policy.svc</a:To>
+        <wsse:Security xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd" s:mustUnderstand="1">
+            <wsse:BinarySecurityToken ValueType="{VALUE_TYPE}" EncodingType="{ENCODING_TYPE}">{token}</wsse:BinarySecurityToken>
+        </wsse:Security>
+    </s:Header>
+    <s:Body xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xmlns:xsd="http://www.w3.org/2001/XMLSchema">
+        <GetPolicies xmlns="http://schemas.microsoft.com/windows/pki/2009/01/enrollmentpolicy">
+            <client>
+                <lastUpdate xsi:nil="true"/>
+                <preferredLanguage xsi:nil="true"/>
+            </client>
+            <requestFilter xsi:nil="true"/>
+        </GetPolicies>
+    </s:Body>
+</s:Envelope>"#,
+            ACTION = microsoft_protocol::xcep::ACTION_GET_POLICIES,
+            VALUE_TYPE = microsoft_protocol::wsse::VALUE_TYPE_X509V3,
+            ENCODING_TYPE = microsoft_protocol::wsse::ENCODING_TYPE_BASE64,
+        )
+    }
+
+    #[tokio::test]
+    async fn certificate_token_test() {
+        // NOTE; The certificate authenticator isn't the most preferred one, its checks must apply regardless
+        let (_directory, tenants) = TenantBuilder::new("certificate_token")
+            .auth_policies(&[AuthPolicy::OnPremise, AuthPolicy::Certificate])
+            .enrollment_user("user@mdmwindows.com", "secret")
+            .build();
+
+        let tenant = tenants.iter().next().unwrap().clone();
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let request = rcgen::CertificateParams::default()
+            .serialize_request(&key_pair)
+            .unwrap();
+        let issued = tenant
+            .certificate_authority
+            .sign_request(request.der(), "DEVICE")
+            .unwrap();
+        let app = Router::new()
+            .route(POLICY_PATH, post(policy_handler))
+            .with_state(AppState {
+                tenants: Arc::new(tenants),
+            });
+
+        // NOTE; Without a TLS client certificate the token alone proves nothing
+        let request = Request::post(POLICY_PATH)
+            .header("Host", "mdmwindows.com")
+            .header("Content-Type", "application/soap+xml; charset=utf-8")
+            .body(Body::from(policy_request(&BASE64.encode(&issued.der))))
+            .unwrap();
+        let response = app.clone().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let response = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(response.contains("Authentication"));
+        assert!(!response.contains("GetPoliciesResponse"));
+    }
+}
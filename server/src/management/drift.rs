@@ -0,0 +1,239 @@
+//! Verification that devices keep the settings of their profiles
+//!
+//! When a check is due, the check-in queues a Get for every readable setting of the profiles applied to the
+//! device. The results are compared against the values of the profiles, a setting reporting another value or
+//! failing to be read has drifted. Any Get of a profile setting counts as a check, including Gets queued by
+//! an administrator.
+
+use super::profile::{Profile, Setting};
+use super::CommandOutcome;
+use crate::config::DriftConfig;
+use crate::device_store::{Device, Drift};
+use crate::microsoft_protocol::syncml::{status, Command};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Outcome of verifying one setting of an applied profile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub uri: String,
+    /// `None` when the device holds the value of the profile
+    pub drift: Option<Drift>,
+    /// Command restoring the setting
+    pub remediation: Command,
+}
+
+#[derive(Debug)]
+pub struct DriftMonitor {
+    /// `None` disables checks
+    interval: Option<Duration>,
+    remediate: bool,
+    /// Time of the last check per device
+    // NOTE; Kept in memory, every device is verified at its first check-in after a restart
+    checked: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl DriftMonitor {
+    pub fn new(config: &DriftConfig) -> Self {
+        let interval = match config.check_interval_hours {
+            0 => None,
+            hours => Some(
+                i64::try_from(hours)
+                    .ok()
+                    .and_then(Duration::try_hours)
+                    .unwrap_or(Duration::MAX),
+            ),
+        };
+        Self {
+            interval,
+            remediate: config.remediate,
+            checked: Mutex::default(),
+        }
+    }
+
+    /// Whether drifted settings are sent to the device again.
+    pub fn remediates(&self) -> bool {
+        self.remediate
+    }
+
+    /// Commands reading back the settings of the applied profiles, empty unless a check of the device is due.
+    pub fn checks(
+        &self,
+        profiles: &[Profile],
+        device: &Device,
+        now: DateTime<Utc>,
+    ) -> Vec<Command> {
+        let Some(interval) = self.interval else {
+            return Vec::new();
+        };
+        let mut checked = self.checked.lock().unwrap();
+        if checked
+            .get(&device.device_id)
+            .is_some_and(|at| now - *at < interval)
+        {
+            return Vec::new();
+        }
+        let checks: Vec<Command> = applied(profiles, device)
+            .filter_map(|(_, setting)| setting.check.clone())
+            .collect();
+        if !checks.is_empty() {
+            checked.insert(device.device_id.clone(), now);
+        }
+        checks
+    }
+
+    /// Compares the results of completed Get commands against the applied profiles.
+    pub fn compare(
+        &self,
+        profiles: &[Profile],
+        device: &Device,
+        completed: &[CommandOutcome],
+        now: DateTime<Utc>,
+    ) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for outcome in completed {
+            let (Some(Command::Get(get)), Some(code)) = (&outcome.command, outcome.status) else {
+                continue;
+            };
+            for uri in get.item.iter().filter_map(|item| item.loc_uri()) {
+                let Some((profile, setting)) =
+                    applied(profiles, device).find(|(_, setting)| setting.uri == uri)
+                else {
+                    continue;
+                };
+                let reported = outcome
+                    .results
+                    .iter()
+                    .find(|item| item.loc_uri() == Some(uri))
+                    .or(outcome.results.first())
+                    .and_then(|item| item.data.clone())
+                    .filter(|_| status::is_success(code));
+                let drift = match &reported {
+                    Some(reported) if setting.matches(reported) => None,
+                    _ => Some(Drift {
+                        profile: profile.name.clone(),
+                        expected: setting.value.data().unwrap_or_default(),
+                        actual: reported,
+                        detected_at: now,
+                    }),
+                };
+                findings.push(Finding {
+                    uri: uri.into(),
+                    drift,
+                    remediation: setting.command.clone(),
+                });
+            }
+        }
+        findings
+    }
+}
+
+/// Settings of the profiles of which the device received the current version.
+fn applied<'a>(
+    profiles: &'a [Profile],
+    device: &'a Device,
+) -> impl Iterator<Item = (&'a Profile, &'a Setting)> {
+    profiles
+        .iter()
+        .filter(|profile| {
+            profile.applies_to(device)
+                && device.profiles.get(&profile.name) == Some(&profile.version)
+        })
+        .flat_map(|profile| {
+            profile
+                .settings
+                .iter()
+                .map(move |setting| (profile, setting))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_store::EnrollmentState;
+    use crate::microsoft_protocol::syncml::Item;
+    use std::path::Path;
+
+    const BASELINE: &str = r#"
+[[settings]]
+uri = "./Device/Vendor/MSFT/Policy/Config/DeviceLock/MinDevicePasswordLength"
+value = 12
+
+[[settings]]
+uri = "./Device/Vendor/MSFT/Policy/Config/Camera/AllowCamera"
+value = false
+"#;
+
+    fn outcome(command: &Command, status: u16, data: Option<&str>) -> CommandOutcome {
+        let uri = command.items()[0].loc_uri().unwrap();
+        CommandOutcome {
+            msg_id: 2,
+            cmd_id: command.cmd_id(),
+            name: command.name(),
+            command: Some(command.clone()),
+            status: Some(status),
+            results: data
+                .map(|data| Item::source(uri).with_data(data))
+                .into_iter()
+                .collect(),
+            queue_id: None,
+        }
+    }
+
+    #[test]
+    fn compare_test() {
+        let profile = Profile::parse(Path::new("baseline.toml"), BASELINE).unwrap();
+        let now = Utc::now();
+        let mut device = Device {
+            device_id: "DEVICE".into(),
+            hw_dev_id: None,
+            hostname: None,
+            os_version: None,
+            certificate_thumbprint: None,
+            first_enrolled_at: now,
+            last_enrolled_at: now,
+            last_check_in: None,
+            state: EnrollmentState::Managed,
+            groups: Default::default(),
+            profiles: Default::default(),
+            drift: Default::default(),
+        };
+        let profiles = [profile];
+        let monitor = DriftMonitor::new(&DriftConfig::default());
+
+        // NOTE; Profiles are verified once applied
+        assert!(monitor.checks(&profiles, &device, now).is_empty());
+        device
+            .profiles
+            .insert("baseline".into(), profiles[0].version.clone());
+        let checks = monitor.checks(&profiles, &device, now);
+        assert_eq!(checks.len(), 2);
+        assert!(monitor
+            .checks(&profiles, &device, now + Duration::hours(1))
+            .is_empty());
+        assert_eq!(
+            monitor
+                .checks(&profiles, &device, now + Duration::hours(25))
+                .len(),
+            2
+        );
+
+        let completed = [
+            outcome(&checks[0], status::OK, Some("8")),
+            outcome(&checks[1], status::OK, Some("False")),
+        ];
+        let findings = monitor.compare(&profiles, &device, &completed, now);
+        assert_eq!(findings.len(), 2);
+        let drift = findings[0].drift.as_ref().unwrap();
+        assert_eq!(drift.expected, "12");
+        assert_eq!(drift.actual.as_deref(), Some("8"));
+        assert_eq!(findings[0].remediation, profiles[0].settings[0].command);
+        assert!(findings[1].drift.is_none());
+
+        // NOTE; The node doesn't exist on the device
+        let completed = [outcome(&checks[0], 404, None)];
+        let findings = monitor.compare(&profiles, &device, &completed, now);
+        assert_eq!(findings[0].drift.as_ref().unwrap().actual, None);
+    }
+}
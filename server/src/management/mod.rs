@@ -0,0 +1,13 @@
+//! Device management, driving OMA-DM sessions with enrolled devices
+//!
+//! Devices check-in at the management endpoint, each check-in starts a session during which the server
+//! sends its queued commands and collects their status and results. Configuration profiles describe settings
+//! the server keeps applied to groups of devices, and verifies periodically.
+
+pub mod drift;
+pub mod profile;
+mod session;
+
+pub use drift::DriftMonitor;
+pub use profile::{Profile, ProfileStore};
+pub use session::{CommandOutcome, SessionManager};
@@ -0,0 +1,522 @@
+//! Configuration profiles, the desired state of managed devices
+//!
+//! A profile lists settings, each a node of a CSP and the value it must hold, and the device groups the profile
+//! applies to. Profiles are read from `.toml`, `.yaml` or `.yml` files within the profile directory, eg
+//!
+//! ```toml
+//! description = "Encrypted devices with a strong password"
+//! groups = ["finance"]
+//!
+//! [[settings]]
+//! node = "bitlocker.require_device_encryption"
+//! value = 1
+//!
+//! [[settings]]
+//! uri = "./Device/Vendor/MSFT/Policy/Config/DeviceLock/MinDevicePasswordLength"
+//! value = 12
+//! ```
+//!
+//! The settings are compiled into SyncML commands, queued when a device of the groups checks in. Each profile
+//! has a version derived from its commands, a profile is sent again when its version differs from the version
+//! last sent to the device.
+
+use crate::csp::{bitlocker, dev_detail, firewall, policy, reboot, Access, Format, Node, Value};
+use crate::device_store::Device;
+use crate::microsoft_protocol::syncml::Command;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Group every device is a member of.
+pub const ALL_DEVICES: &str = "all";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    /// A profile file could not be parsed
+    Parse(PathBuf, String),
+    /// A setting cannot be sent to devices, the second field is the index of the setting
+    InvalidSetting(PathBuf, usize, String),
+    /// Multiple files describe a profile with the same name
+    DuplicateName(String),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(path, error) => write!(f, "cannot read {}: {error}", path.display()),
+            Error::Parse(path, reason) => write!(f, "invalid {}: {reason}", path.display()),
+            Error::InvalidSetting(path, index, reason) => {
+                write!(
+                    f,
+                    "invalid setting {} of {}: {reason}",
+                    index + 1,
+                    path.display()
+                )
+            }
+            Error::DuplicateName(name) => write!(f, "multiple profiles are named {name}"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    /// Defaults to the file name without extension
+    name: Option<String>,
+    description: Option<String>,
+    #[serde(default = "all_devices")]
+    groups: Vec<String>,
+    #[serde(default)]
+    settings: Vec<SettingFile>,
+}
+
+fn all_devices() -> Vec<String> {
+    vec![ALL_DEVICES.into()]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingFile {
+    /// OMA-URI of the node
+    uri: Option<String>,
+    /// Name of a node known by the CSP catalogue, eg "policy.min_device_password_length"
+    node: Option<String>,
+    value: Option<SettingValue>,
+    /// Format of the value for `uri` nodes, inferred from the value when missing
+    format: Option<String>,
+    #[serde(default)]
+    operation: Operation,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum SettingValue {
+    Bool(bool),
+    Integer(i64),
+    Text(String),
+}
+
+/// Command that puts the value on the device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// Creates the node, for nodes that don't exist until configured
+    Add,
+    #[default]
+    Replace,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub uri: String,
+    pub value: Value,
+    pub command: Command,
+    /// Reads the setting back from the device, `None` for nodes that can't be read
+    pub check: Option<Command>,
+}
+
+impl Setting {
+    /// Whether the value reported by the device equals the value of the setting.
+    pub fn matches(&self, reported: &str) -> bool {
+        let expected = self.value.data().unwrap_or_default();
+        match self.value {
+            // NOTE; Devices report booleans in either case
+            Value::Bool(_) => reported.trim().eq_ignore_ascii_case(&expected),
+            _ => reported.trim() == expected.trim(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub description: Option<String>,
+    pub groups: Vec<String>,
+    pub settings: Vec<Setting>,
+    /// Hash of the commands, changes whenever a setting changes
+    pub version: String,
+}
+
+impl Profile {
+    /// Parses the profile file, the extension selects the file format.
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, Error> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let file: ProfileFile = match extension {
+            Some("toml") => {
+                toml::from_str(contents).map_err(|e| Error::Parse(path.into(), e.to_string()))?
+            }
+            Some("yaml" | "yml") => serde_yaml::from_str(contents)
+                .map_err(|e| Error::Parse(path.into(), e.to_string()))?,
+            _ => return Err(Error::Parse(path.into(), "unknown file format".into())),
+        };
+
+        let name = match file.name {
+            Some(name) => name,
+            None => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let mut settings = Vec::new();
+        for (index, setting) in file.settings.into_iter().enumerate() {
+            let setting = compile(setting)
+                .map_err(|reason| Error::InvalidSetting(path.into(), index, reason))?;
+            settings.push(setting);
+        }
+
+        let mut hasher = Sha1::new();
+        for setting in &settings {
+            let Setting {
+                uri,
+                value,
+                command,
+                ..
+            } = setting;
+            let data = value.data().unwrap_or_default();
+            hasher.update(format!(
+                "{} {uri} {} {data}\n",
+                command.name(),
+                value.format()
+            ));
+        }
+        let version = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Ok(Self {
+            name,
+            description: file.description,
+            groups: file.groups,
+            settings,
+            version,
+        })
+    }
+
+    pub fn applies_to(&self, device: &Device) -> bool {
+        self.groups.iter().any(|group| {
+            group.eq_ignore_ascii_case(ALL_DEVICES)
+                || device
+                    .groups
+                    .iter()
+                    .any(|member| member.eq_ignore_ascii_case(group))
+        })
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = Command> + '_ {
+        self.settings.iter().map(|setting| setting.command.clone())
+    }
+}
+
+fn compile(setting: SettingFile) -> Result<Setting, String> {
+    let SettingFile {
+        uri,
+        node,
+        value,
+        format,
+        operation,
+    } = setting;
+    let node = match (uri, node) {
+        (Some(uri), None) => {
+            let format = match (&format, &value) {
+                (Some(format), _) => {
+                    Format::parse(format).ok_or_else(|| format!("unknown format {format}"))?
+                }
+                (None, Some(SettingValue::Bool(_))) => Format::Bool,
+                (None, Some(SettingValue::Integer(_))) => Format::Int,
+                (None, Some(SettingValue::Text(_))) => Format::Chr,
+                (None, None) => Format::Null,
+            };
+            Node::new(uri, format, Access::ALL)
+        }
+        (None, Some(name)) => known_node(&name).ok_or_else(|| format!("unknown node {name}"))?,
+        _ => return Err("expected either uri or node".into()),
+    };
+
+    let value = convert(value, node.format())?;
+    let command = match operation {
+        Operation::Add => node.add(value.clone()),
+        Operation::Replace => node.replace(value.clone()),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(Setting {
+        uri: node.uri().into(),
+        value,
+        command,
+        check: node.get().ok(),
+    })
+}
+
+/// Converts the value from the file into the format of the node.
+fn convert(value: Option<SettingValue>, format: Format) -> Result<Value, String> {
+    let mismatch = |value: &SettingValue| format!("{value:?} is not a {format} value");
+    Ok(match (value, format) {
+        (None, Format::Null | Format::Node) => Value::Null,
+        (None, _) => return Err(format!("missing {format} value")),
+        (Some(SettingValue::Bool(value)), Format::Bool) => Value::Bool(value),
+        (Some(SettingValue::Integer(value)), Format::Int) => {
+            Value::Int(u32::try_from(value).map_err(|_| format!("{value} is out of range"))?)
+        }
+        (Some(SettingValue::Integer(value)), Format::Chr) => Value::Chr(value.to_string()),
+        (Some(SettingValue::Text(value)), Format::Chr) => Value::Chr(value),
+        (Some(SettingValue::Text(value)), Format::Xml) => Value::Xml(value),
+        (Some(SettingValue::Text(value)), Format::B64) => Value::B64(
+            BASE64
+                .decode(value.trim())
+                .map_err(|e| format!("invalid base64: {e}"))?,
+        ),
+        (Some(value), _) => return Err(mismatch(&value)),
+    })
+}
+
+/// Nodes of the CSP catalogue that can be named in a profile.
+fn known_node(name: &str) -> Option<Node> {
+    if let Some(setting) = name.strip_prefix("firewall.") {
+        let (profile, setting) = setting.split_once('.')?;
+        let profile = match profile {
+            "domain" => firewall::Profile::Domain,
+            "private" => firewall::Profile::Private,
+            "public" => firewall::Profile::Public,
+            _ => return None,
+        };
+        return match setting {
+            "enable_firewall" => Some(firewall::enable_firewall(profile)),
+            "shields_up" => Some(firewall::shields_up(profile)),
+            "default_inbound_action" => Some(firewall::default_inbound_action(profile)),
+            "default_outbound_action" => Some(firewall::default_outbound_action(profile)),
+            _ => None,
+        };
+    }
+
+    let node = match name {
+        "bitlocker.require_device_encryption" => bitlocker::require_device_encryption(),
+        "bitlocker.allow_warning_for_other_disk_encryption" => {
+            bitlocker::allow_warning_for_other_disk_encryption()
+        }
+        "bitlocker.encryption_method_by_drive_type" => bitlocker::encryption_method_by_drive_type(),
+        "bitlocker.configure_recovery_password_rotation" => {
+            bitlocker::configure_recovery_password_rotation()
+        }
+        "dev_detail.device_name" => dev_detail::device_name(),
+        "policy.allow_camera" => policy::allow_camera(),
+        "policy.device_password_enabled" => policy::device_password_enabled(),
+        "policy.min_device_password_length" => policy::min_device_password_length(),
+        "policy.max_inactivity_time_device_lock" => policy::max_inactivity_time_device_lock(),
+        "policy.allow_auto_update" => policy::allow_auto_update(),
+        "policy.allow_realtime_monitoring" => policy::allow_realtime_monitoring(),
+        "reboot.schedule_single" => reboot::schedule_single(),
+        "reboot.schedule_daily_recurrent" => reboot::schedule_daily_recurrent(),
+        _ => return None,
+    };
+    Some(node)
+}
+
+/// Modification time and size of each profile file, to detect changes.
+type Snapshot = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+#[derive(Debug, Default)]
+struct Loaded {
+    snapshot: Snapshot,
+    profiles: Arc<Vec<Profile>>,
+}
+
+/// The profiles within a directory, reloaded when the files change.
+#[derive(Debug)]
+pub struct ProfileStore {
+    directory: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl ProfileStore {
+    /// Loads the profiles, a missing directory holds no profiles.
+    pub fn open(directory: &Path) -> Result<Self, Error> {
+        let snapshot = snapshot(directory)?;
+        let profiles = load(&snapshot)?;
+        Ok(Self {
+            directory: directory.into(),
+            loaded: Mutex::new(Loaded {
+                snapshot,
+                profiles: Arc::new(profiles),
+            }),
+        })
+    }
+
+    /// The current profiles.
+    ///
+    /// NOTE; When changed files are invalid the previous profiles stay in effect
+    pub fn profiles(&self) -> Arc<Vec<Profile>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let reloaded = snapshot(&self.directory).and_then(|snapshot| {
+            if snapshot == loaded.snapshot {
+                return Ok(None);
+            }
+            let profiles = load(&snapshot)?;
+            Ok(Some((snapshot, profiles)))
+        });
+        match reloaded {
+            Ok(Some((snapshot, profiles))) => {
+                tracing::info!("Reloaded {} profile(s)", profiles.len());
+                *loaded = Loaded {
+                    snapshot,
+                    profiles: Arc::new(profiles),
+                };
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("Error reloading profiles: {}", err),
+        }
+        loaded.profiles.clone()
+    }
+
+    /// Profiles that apply to the device, of which the device didn't receive the current version.
+    pub fn pending(&self, device: &Device) -> Vec<Profile> {
+        self.profiles()
+            .iter()
+            .filter(|profile| profile.applies_to(device))
+            .filter(|profile| device.profiles.get(&profile.name) != Some(&profile.version))
+            .cloned()
+            .collect()
+    }
+}
+
+fn snapshot(directory: &Path) -> Result<Snapshot, Error> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(Error::Io(directory.into(), error)),
+    };
+    let mut snapshot = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| Error::Io(directory.into(), e))?.path();
+        let is_profile = path.extension().is_some_and(|extension| {
+            ["toml", "yaml", "yml"].contains(&&*extension.to_string_lossy())
+        });
+        if !is_profile {
+            continue;
+        }
+        let metadata = std::fs::metadata(&path).map_err(|e| Error::Io(path.clone(), e))?;
+        snapshot.push((path, metadata.modified().ok(), metadata.len()));
+    }
+    snapshot.sort();
+    Ok(snapshot)
+}
+
+fn load(snapshot: &Snapshot) -> Result<Vec<Profile>, Error> {
+    let mut profiles: Vec<Profile> = Vec::new();
+    for (path, _, _) in snapshot {
+        let contents = std::fs::read_to_string(path).map_err(|e| Error::Io(path.clone(), e))?;
+        let profile = Profile::parse(path, &contents)?;
+        if profiles.iter().any(|other| other.name == profile.name) {
+            return Err(Error::DuplicateName(profile.name));
+        }
+        profiles.push(profile);
+    }
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_store::EnrollmentState;
+    use crate::test_support::TempDir;
+
+    const BASELINE: &str = r#"
+description = "Encrypted devices with a strong password"
+groups = ["finance"]
+
+[[settings]]
+node = "bitlocker.require_device_encryption"
+value = 1
+
+[[settings]]
+uri = "./Device/Vendor/MSFT/Policy/Config/DeviceLock/MinDevicePasswordLength"
+value = 12
+"#;
+
+    const UPDATE_RING: &str = r#"
+name: update-ring
+settings:
+  - uri: ./Device/Vendor/MSFT/Policy/Config/Update/DeferFeatureUpdatesPeriodInDays
+    value: 30
+  - node: policy.allow_auto_update
+    value: 9
+"#;
+
+    fn device(groups: &[&str]) -> Device {
+        let now = chrono::Utc::now();
+        Device {
+            device_id: "DEVICE".into(),
+            hw_dev_id: None,
+            hostname: None,
+            os_version: None,
+            certificate_thumbprint: None,
+            first_enrolled_at: now,
+            last_enrolled_at: now,
+            last_check_in: None,
+            state: EnrollmentState::Managed,
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            profiles: Default::default(),
+            drift: Default::default(),
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        let profile = Profile::parse(Path::new("baseline.toml"), BASELINE).unwrap();
+        assert_eq!(profile.name, "baseline");
+        assert_eq!(profile.settings.len(), 2);
+        assert_eq!(profile.settings[1].value, Value::Int(12));
+        let Command::Replace(replace) = &profile.settings[0].command else {
+            panic!("expected a Replace command");
+        };
+        assert_eq!(
+            replace.item[0].loc_uri(),
+            Some("./Device/Vendor/MSFT/BitLocker/RequireDeviceEncryption")
+        );
+        assert!(profile.applies_to(&device(&["Finance"])));
+        assert!(!profile.applies_to(&device(&["sales"])));
+
+        let changed = BASELINE.replace("value = 12", "value = 14");
+        let changed = Profile::parse(Path::new("baseline.toml"), &changed).unwrap();
+        assert_ne!(profile.version, changed.version);
+
+        // NOTE; AllowAutoUpdate is limited to 0..=5
+        let Err(Error::InvalidSetting(_, index, _)) =
+            Profile::parse(Path::new("ring.yaml"), UPDATE_RING)
+        else {
+            panic!("expected an invalid setting");
+        };
+        assert_eq!(index, 1);
+        let profile =
+            Profile::parse(Path::new("ring.yaml"), &UPDATE_RING.replace("9", "3")).unwrap();
+        assert_eq!(profile.name, "update-ring");
+        assert!(profile.applies_to(&device(&[])));
+    }
+
+    #[test]
+    fn store_reload_test() {
+        let directory = TempDir::new("profiles");
+        let path = directory.join("baseline.toml");
+        std::fs::write(&path, BASELINE).unwrap();
+        let store = ProfileStore::open(directory.path()).unwrap();
+
+        let mut device = device(&["finance"]);
+        let pending = store.pending(&device);
+        assert_eq!(pending.len(), 1);
+        device
+            .profiles
+            .insert(pending[0].name.clone(), pending[0].version.clone());
+        assert!(store.pending(&device).is_empty());
+
+        std::fs::write(&path, BASELINE.replace("value = 12", "value = 128")).unwrap();
+        let pending = store.pending(&device);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].settings[1].value, Value::Int(128));
+    }
+}
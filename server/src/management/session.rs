@@ -0,0 +1,624 @@
+//! OMA-DM session state machine
+//!
+//! A session is a sequence of packages exchanged between the device and the server. Each side sends one
+//! package per turn, a package consists of one or more messages where the last message carries the Final
+//! element. The device opens the session with package 1 (its alerts and device information), the server
+//! answers with package 2 (status and commands), the device answers with package 3 (status and results) and
+//! so on until the server sends a package without commands.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/oma-dm-protocol-support
+
+use crate::csp::ddf::{self, Definitions};
+use crate::microsoft_protocol::syncml::{
+    self, alert, status, Alert, Command, Item, LocationRef, Status, SyncBody, SyncHdr, SyncMl,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+/// Maximum amount of top-level commands sent in one message, the remaining commands are sent in follow-up
+/// messages of the same package.
+const MAX_COMMANDS_PER_MESSAGE: usize = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    /// The message identifier didn't increase, the message was already processed
+    OutOfOrderMessage { last: u32, received: u32 },
+}
+
+impl std::error::Error for SessionError {}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::OutOfOrderMessage { last, received } => write!(
+                f,
+                "received message {received} after message {last}, messages must be sent in order"
+            ),
+        }
+    }
+}
+
+/// A command sent to the device together with everything the device reported about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandOutcome {
+    /// Identifier of the server message that carried the command
+    pub msg_id: u32,
+    pub cmd_id: u32,
+    /// Name of the command element, eg "Get"
+    pub name: &'static str,
+    /// The command as sent, only present for top-level commands
+    pub command: Option<Command>,
+    /// The status code reported by the device, `None` if the session ended before the device answered
+    pub status: Option<u16>,
+    /// Items of the Results elements returned for this command
+    pub results: Vec<Item>,
+    /// Identifier of the command in the durable command queue
+    pub queue_id: Option<i64>,
+}
+
+/// Result of processing one message from the device.
+#[derive(Debug, Clone)]
+pub struct HandledMessage {
+    /// Reply to send to the device
+    pub response: SyncMl,
+    /// Commands the device finished processing since the previous message
+    pub completed: Vec<CommandOutcome>,
+    /// True when the reply closes the session
+    pub session_completed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+    /// The device is sending a package spread over multiple messages
+    ReceivingPackage,
+    /// The server is sending a package spread over multiple messages
+    SendingPackage,
+    /// Each side finished its last package
+    Idle,
+}
+
+#[derive(Debug)]
+struct Session {
+    session_id: String,
+    device_id: String,
+    /// The address the device uses to reach the server
+    server_uri: String,
+    state: SessionState,
+    last_device_msg_id: u32,
+    server_msg_id: u32,
+    next_cmd_id: u32,
+    /// Commands of the package currently being sent
+    outgoing: VecDeque<Queued>,
+    /// Sent commands awaiting status, keyed by (message ID, command ID)
+    outstanding: HashMap<(u32, u32), CommandOutcome>,
+}
+
+impl Session {
+    fn new(session_id: String, device_id: String, server_uri: String) -> Self {
+        Self {
+            session_id,
+            device_id,
+            server_uri,
+            state: SessionState::Idle,
+            last_device_msg_id: 0,
+            server_msg_id: 0,
+            next_cmd_id: 1,
+            outgoing: VecDeque::new(),
+            outstanding: HashMap::new(),
+        }
+    }
+
+    fn allocate_cmd_id(&mut self) -> u32 {
+        let cmd_id = self.next_cmd_id;
+        self.next_cmd_id += 1;
+        cmd_id
+    }
+
+    /// Outstanding commands that will never receive a status, because the session is replaced.
+    fn abandon(self) -> Vec<CommandOutcome> {
+        let mut abandoned: Vec<_> = self.outstanding.into_values().collect();
+        abandoned.sort_by_key(|outcome| (outcome.msg_id, outcome.cmd_id));
+        abandoned
+    }
+}
+
+/// A command waiting to be sent.
+#[derive(Debug)]
+struct Queued {
+    command: Command,
+    queue_id: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Active session per device
+    sessions: HashMap<String, Session>,
+    /// Commands waiting for the next session per device
+    queued: HashMap<String, VecDeque<Queued>>,
+}
+
+/// Tracks the OMA-DM sessions of all devices.
+#[derive(Debug, Default)]
+pub struct SessionManager {
+    inner: Mutex<Inner>,
+    /// CSP definitions validating queued commands
+    definitions: Option<Arc<Definitions>>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Session manager refusing commands that don't match the definitions.
+    pub fn with_definitions(definitions: Arc<Definitions>) -> Self {
+        Self {
+            inner: Mutex::default(),
+            definitions: Some(definitions),
+        }
+    }
+
+    /// Queues a command for delivery at the next check-in of the device.
+    ///
+    /// NOTE; Command identifiers are assigned when the command is sent.
+    pub fn enqueue(&self, device_id: &str, command: Command) -> Result<(), ddf::Error> {
+        self.validate(&command)?;
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .queued
+            .entry(device_id.to_string())
+            .or_default()
+            .push_back(Queued {
+                command,
+                queue_id: None,
+            });
+        Ok(())
+    }
+
+    /// Queues a command of the durable command queue, its outcome refers to the identifier.
+    ///
+    /// NOTE; The command was validated when it entered the durable queue
+    pub fn enqueue_durable(&self, device_id: &str, command: Command, queue_id: i64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .queued
+            .entry(device_id.to_string())
+            .or_default()
+            .push_back(Queued {
+                command,
+                queue_id: Some(queue_id),
+            });
+    }
+
+    /// Checks the command against the CSP definitions, if any.
+    pub fn validate(&self, command: &Command) -> Result<(), ddf::Error> {
+        match &self.definitions {
+            Some(definitions) => definitions.validate(command),
+            None => Ok(()),
+        }
+    }
+
+    /// Queues the commands, none are queued when one of them is invalid.
+    pub fn enqueue_all(
+        &self,
+        device_id: &str,
+        commands: impl IntoIterator<Item = Command>,
+    ) -> Result<(), ddf::Error> {
+        let commands: Vec<Command> = commands.into_iter().collect();
+        for command in &commands {
+            self.validate(command)?;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .queued
+            .entry(device_id.to_string())
+            .or_default()
+            .extend(commands.into_iter().map(|command| Queued {
+                command,
+                queue_id: None,
+            }));
+        Ok(())
+    }
+
+    /// Amount of commands waiting for the next session of the device.
+    #[cfg(test)]
+    pub fn queued(&self, device_id: &str) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.queued.get(device_id).map_or(0, VecDeque::len)
+    }
+
+    /// Processes one message from a device and builds the reply.
+    pub fn handle(&self, message: SyncMl) -> Result<HandledMessage, SessionError> {
+        let SyncMl {
+            sync_hdr: header,
+            sync_body: body,
+        } = message;
+        let device_id = header.source.loc_uri.clone();
+
+        let mut inner = self.inner.lock().unwrap();
+        let mut completed = Vec::new();
+
+        let is_new_session = inner
+            .sessions
+            .get(&device_id)
+            .is_none_or(|session| session.session_id != header.session_id);
+        if is_new_session {
+            let session = Session::new(
+                header.session_id.clone(),
+                device_id.clone(),
+                header.target.loc_uri.clone(),
+            );
+            if let Some(previous) = inner.sessions.insert(device_id.clone(), session) {
+                debug!(
+                    "Device {device_id} started session {} replacing session {}",
+                    header.session_id, previous.session_id
+                );
+                let previous_session_id = previous.session_id.clone();
+                let abandoned = previous.abandon();
+                if !abandoned.is_empty() {
+                    warn!(
+                        "Device {device_id} didn't report status for {} command(s) of session {}",
+                        abandoned.len(),
+                        previous_session_id
+                    );
+                }
+                completed.extend(abandoned);
+            }
+        }
+
+        let Inner { sessions, queued } = &mut *inner;
+        let session = sessions
+            .get_mut(&device_id)
+            .expect("session was created above");
+
+        if header.msg_id <= session.last_device_msg_id {
+            return Err(SessionError::OutOfOrderMessage {
+                last: session.last_device_msg_id,
+                received: header.msg_id,
+            });
+        }
+        session.last_device_msg_id = header.msg_id;
+        session.server_msg_id += 1;
+
+        let mut reply = SyncBody::default();
+        reply.status.push(Status {
+            cmd_id: session.allocate_cmd_id(),
+            msg_ref: header.msg_id,
+            cmd_ref: 0,
+            cmd: syncml::CMD_SYNC_HDR.into(),
+            target_ref: Some(header.target.loc_uri.clone()),
+            source_ref: Some(header.source.loc_uri.clone()),
+            data: status::OK,
+            item: vec![],
+        });
+
+        // Status reported by the device on commands of previous messages
+        for reported in &body.status {
+            if reported.cmd_ref == 0 {
+                // NOTE; Status on our SyncHdr
+                continue;
+            }
+            match session
+                .outstanding
+                .get_mut(&(reported.msg_ref, reported.cmd_ref))
+            {
+                Some(outcome) => outcome.status = Some(reported.data),
+                None => warn!(
+                    "Device {device_id} reported status {} on unknown command {} of message {}",
+                    reported.data, reported.cmd_ref, reported.msg_ref
+                ),
+            }
+        }
+
+        for results in &body.results {
+            // NOTE; MsgRef is optional, omitted when referring to the previous server message
+            let msg_ref = results.msg_ref.unwrap_or(session.server_msg_id - 1);
+            match session.outstanding.get_mut(&(msg_ref, results.cmd_ref)) {
+                Some(outcome) => outcome.results.extend(results.item.iter().cloned()),
+                None => warn!(
+                    "Device {device_id} returned results for unknown command {} of message {msg_ref}",
+                    results.cmd_ref
+                ),
+            }
+            let cmd_id = session.allocate_cmd_id();
+            reply.status.push(Status {
+                cmd_id,
+                msg_ref: header.msg_id,
+                cmd_ref: results.cmd_id,
+                cmd: "Results".into(),
+                target_ref: None,
+                source_ref: None,
+                data: status::OK,
+                item: vec![],
+            });
+        }
+
+        // Acknowledge operations sent by the device
+        let mut next_message_requested = false;
+        for command in &body.commands {
+            if let Command::Alert(Alert {
+                data: Some(alert::NEXT_MESSAGE),
+                ..
+            }) = command
+            {
+                next_message_requested = true;
+            }
+            let no_resp = match command {
+                Command::Alert(alert) => alert.no_resp.is_some(),
+                Command::Add(command)
+                | Command::Replace(command)
+                | Command::Delete(command)
+                | Command::Get(command)
+                | Command::Exec(command) => command.no_resp.is_some(),
+                Command::Atomic(container) | Command::Sequence(container) => {
+                    container.no_resp.is_some()
+                }
+            };
+            if no_resp {
+                continue;
+            }
+            let cmd_id = session.allocate_cmd_id();
+            reply.status.push(Status {
+                cmd_id,
+                msg_ref: header.msg_id,
+                cmd_ref: command.cmd_id(),
+                cmd: command.name().into(),
+                target_ref: None,
+                source_ref: None,
+                data: status::OK,
+                item: vec![],
+            });
+        }
+
+        // Collect the commands that received their status
+        let mut finished: Vec<_> = session
+            .outstanding
+            .iter()
+            .filter(|(_, outcome)| outcome.status.is_some())
+            .map(|(key, _)| *key)
+            .collect();
+        finished.sort();
+        completed.extend(
+            finished
+                .into_iter()
+                .filter_map(|key| session.outstanding.remove(&key)),
+        );
+
+        let device_package_complete = body.is_final();
+        if !device_package_complete && !next_message_requested {
+            // The device has more messages in its package, ask for the next one before sending commands
+            session.state = SessionState::ReceivingPackage;
+            let cmd_id = session.allocate_cmd_id();
+            reply.commands.push(Command::Alert(Alert {
+                cmd_id,
+                no_resp: None,
+                data: Some(alert::NEXT_MESSAGE),
+                item: vec![],
+            }));
+            return Ok(HandledMessage {
+                response: session.reply(reply),
+                completed,
+                session_completed: false,
+            });
+        }
+
+        if session.outgoing.is_empty() && session.state != SessionState::SendingPackage {
+            if let Some(commands) = queued.get_mut(&device_id) {
+                session.outgoing.extend(commands.drain(..));
+            }
+        }
+
+        let mut sent_commands = false;
+        for _ in 0..MAX_COMMANDS_PER_MESSAGE {
+            let Some(Queued {
+                mut command,
+                queue_id,
+            }) = session.outgoing.pop_front()
+            else {
+                break;
+            };
+            let msg_id = session.server_msg_id;
+            let nested = command.assign_cmd_ids(|| session.allocate_cmd_id());
+            for (cmd_id, name) in nested {
+                session.outstanding.insert(
+                    (msg_id, cmd_id),
+                    CommandOutcome {
+                        msg_id,
+                        cmd_id,
+                        name,
+                        command: None,
+                        status: None,
+                        results: vec![],
+                        queue_id: None,
+                    },
+                );
+            }
+            session.outstanding.insert(
+                (msg_id, command.cmd_id()),
+                CommandOutcome {
+                    msg_id,
+                    cmd_id: command.cmd_id(),
+                    name: command.name(),
+                    command: Some(command.clone()),
+                    status: None,
+                    results: vec![],
+                    queue_id,
+                },
+            );
+            reply.commands.push(command);
+            sent_commands = true;
+        }
+
+        if !session.outgoing.is_empty() {
+            // NOTE; No Final, the device answers with Alert 1222 to request the next message
+            session.state = SessionState::SendingPackage;
+        } else {
+            session.state = SessionState::Idle;
+            reply.final_message = Some(syncml::Final {});
+        }
+
+        // A package without commands ends the session, the device doesn't answer a status-only package
+        let session_completed = !sent_commands && session.state == SessionState::Idle;
+        let response = session.reply(reply);
+        if session_completed {
+            if let Some(session) = sessions.remove(&device_id) {
+                completed.extend(session.abandon());
+            }
+        }
+
+        Ok(HandledMessage {
+            response,
+            completed,
+            session_completed,
+        })
+    }
+}
+
+impl Session {
+    fn reply(&self, body: SyncBody) -> SyncMl {
+        SyncMl {
+            sync_hdr: SyncHdr {
+                ver_dtd: syncml::VER_DTD.into(),
+                ver_proto: syncml::VER_PROTO.into(),
+                session_id: self.session_id.clone(),
+                msg_id: self.server_msg_id,
+                target: LocationRef::new(self.device_id.clone()),
+                source: LocationRef::new(self.server_uri.clone()),
+                cred: None,
+                meta: None,
+            },
+            sync_body: body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microsoft_protocol::syncml::{ItemCommand, Results};
+
+    fn device_message(msg_id: u32, body: SyncBody) -> SyncMl {
+        SyncMl {
+            sync_hdr: SyncHdr {
+                ver_dtd: syncml::VER_DTD.into(),
+                ver_proto: syncml::VER_PROTO.into(),
+                session_id: "1A".into(),
+                msg_id,
+                target: LocationRef::new("https://mdmwindows.com/ManagementServer/MDM.svc"),
+                source: LocationRef::new("DEVICE"),
+                cred: None,
+                meta: None,
+            },
+            sync_body: body,
+        }
+    }
+
+    fn session_start() -> SyncBody {
+        SyncBody {
+            commands: vec![Command::Alert(Alert {
+                cmd_id: 1,
+                no_resp: None,
+                // NOTE; Session initiated by the client
+                data: Some(1201),
+                item: vec![],
+            })],
+            final_message: Some(syncml::Final {}),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn session_without_commands_test() {
+        let manager = SessionManager::new();
+        let handled = manager.handle(device_message(1, session_start())).unwrap();
+
+        assert!(handled.session_completed);
+        let body = handled.response.sync_body;
+        assert!(body.is_final());
+        // Status for SyncHdr and the alert
+        assert_eq!(body.status.len(), 2);
+        assert_eq!(body.status[0].cmd, syncml::CMD_SYNC_HDR);
+        assert_eq!(body.status[1].cmd_ref, 1);
+        assert_eq!(handled.response.sync_hdr.target.loc_uri, "DEVICE");
+    }
+
+    #[test]
+    fn session_command_results_test() {
+        let manager = SessionManager::new();
+        manager
+            .enqueue(
+                "DEVICE",
+                Command::Get(ItemCommand::new(Item::target("./DevDetail/SwV"))),
+            )
+            .unwrap();
+
+        let handled = manager.handle(device_message(1, session_start())).unwrap();
+        assert!(!handled.session_completed);
+        assert_eq!(manager.queued("DEVICE"), 0);
+        let Command::Get(get) = &handled.response.sync_body.commands[0] else {
+            panic!("Expected the queued Get command");
+        };
+        let server_msg_id = handled.response.sync_hdr.msg_id;
+
+        let reply = SyncBody {
+            status: vec![Status {
+                cmd_id: 1,
+                msg_ref: server_msg_id,
+                cmd_ref: get.cmd_id,
+                cmd: "Get".into(),
+                data: status::OK,
+                ..Default::default()
+            }],
+            results: vec![Results {
+                cmd_id: 2,
+                msg_ref: Some(server_msg_id),
+                cmd_ref: get.cmd_id,
+                item: vec![Item {
+                    source: Some(LocationRef::new("./DevDetail/SwV")),
+                    data: Some("10.0.22631.4460".into()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            final_message: Some(syncml::Final {}),
+            ..Default::default()
+        };
+        let handled = manager.handle(device_message(2, reply)).unwrap();
+
+        assert!(handled.session_completed);
+        assert_eq!(handled.completed.len(), 1);
+        let outcome = &handled.completed[0];
+        assert_eq!(outcome.status, Some(status::OK));
+        assert_eq!(outcome.results[0].data.as_deref(), Some("10.0.22631.4460"));
+    }
+
+    #[test]
+    fn multi_message_device_package_test() {
+        let manager = SessionManager::new();
+        let mut first = session_start();
+        first.final_message = None;
+
+        let handled = manager.handle(device_message(1, first)).unwrap();
+        let body = handled.response.sync_body;
+        assert!(!body.is_final());
+        assert!(matches!(
+            &body.commands[0],
+            Command::Alert(Alert {
+                data: Some(alert::NEXT_MESSAGE),
+                ..
+            })
+        ));
+
+        let error = manager
+            .handle(device_message(1, session_start()))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            SessionError::OutOfOrderMessage {
+                last: 1,
+                received: 1
+            }
+        );
+    }
+}
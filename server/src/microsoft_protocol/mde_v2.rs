@@ -3,49 +3,14 @@
 //! MSDE is the discovery service, used by MDM clients to bootstrap with the MDM server.
 //! The server replies with other service endpoints.
 
+use super::soap::SoapAction;
 use yaserde::{YaDeserialize, YaSerialize}; // Traits
 use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
 
-#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
-#[yaserde(
-    prefix = "a",
-    namespaces = {
-        "a" = "http://www.w3.org/2005/08/addressing",
-    },
-)]
-pub struct DiscoverHeader {
-    #[yaserde(prefix = "a", rename = "Action")]
-    pub action: String,
-
-    #[yaserde(prefix = "a", rename = "MessageID")]
-    pub message_id: String,
-
-    #[yaserde(prefix = "a", rename = "ReplyTo")]
-    pub reply_to: discover_header::ReplyToType,
-
-    #[yaserde(prefix = "a", rename = "To")]
-    pub to: String,
-}
-
-// impl Validate for DiscoverHeader {}
-
-pub mod discover_header {
-    use super::*;
-
-    #[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
-    #[yaserde(
-        prefix = "a",
-        namespaces = {
-            "a" = "http://www.w3.org/2005/08/addressing",
-        },
-    )]
-    pub struct ReplyToType {
-        #[yaserde(prefix = "a", rename = "Address")]
-        pub address: String,
-    }
-
-    // impl Validate for ReplyToType {}
-}
+pub const ACTION_DISCOVER: &str =
+    "http://schemas.microsoft.com/windows/management/2012/01/enrollment/IDiscoveryService/Discover";
+pub const ACTION_DISCOVER_RESPONSE: &str =
+    "http://schemas.microsoft.com/windows/management/2012/01/enrollment/IDiscoveryService/DiscoverResponse";
 
 #[derive(PartialEq, Debug, Clone, YaSerialize, YaDeserialize)]
 #[yaserde(
@@ -104,6 +69,10 @@ pub struct DiscoverRequestBody {
     pub discover: discover::Discover,
 }
 
+impl SoapAction for DiscoverRequestBody {
+    const ACTION: &'static str = ACTION_DISCOVER;
+}
+
 pub mod discover {
     use super::*;
     use crate::xsd_primitives::Decimal;
@@ -133,7 +102,6 @@ pub mod discover {
     )]
     pub struct RequestType {
         #[yaserde(prefix = "enroll", rename = "EmailAddress")]
-        // NOTE; Not processing the contents, so not parsing string
         // ERROR; No content element could also represent empty string, depends on encoder!
         pub email_address: Option<String>,
 
@@ -144,7 +112,7 @@ pub mod discover {
         pub device_type: DeviceType,
 
         #[yaserde(prefix = "enroll", rename = "ApplicationVersion")]
-        // NOTE; Not processing the contents, so not parsing string
+        // NOTE; Version of the enrollment client, eg "10.0.22631.4460"
         pub application_version: String,
 
         #[yaserde(prefix = "enroll", rename = "OSEdition")]
@@ -156,6 +124,24 @@ pub mod discover {
 
     // impl Validate for RequestType {}
 
+    impl RequestType {
+        /// Email address entered by the user, `None` when missing or empty.
+        pub fn email_address(&self) -> Option<&str> {
+            self.email_address
+                .as_deref()
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+        }
+
+        /// Domain part of the email address.
+        pub fn email_domain(&self) -> Option<&str> {
+            self.email_address()
+                .and_then(|address| address.rsplit_once('@'))
+                .map(|(_, domain)| domain)
+                .filter(|domain| !domain.is_empty())
+        }
+    }
+
     pub mod request_type {
         use super::*;
 
@@ -176,23 +162,6 @@ pub mod discover {
     }
 }
 
-#[derive(Debug, PartialEq, YaSerialize, YaDeserialize, Clone)]
-#[yaserde(
-    prefix = "a",
-    namespaces = {
-        "a" = "http://www.w3.org/2005/08/addressing",
-    },
-)]
-pub struct DiscoverResponseHeader {
-    #[yaserde(rename = "Action", prefix = "a")]
-    pub action: String,
-    #[yaserde(rename = "ActivityId", prefix = "a")]
-    // NOTE; Made optional since it's part of Microsoft diagnostics
-    pub activity_id: Option<String>,
-    #[yaserde(rename = "RelatesTo", prefix = "a")]
-    pub relates_to: String,
-}
-
 #[derive(Clone, Debug, YaSerialize, YaDeserialize)]
 #[yaserde(
     namespaces = {
@@ -204,6 +173,10 @@ pub struct DiscoverResponseBody {
     pub discover: discover_response::DiscoverResponse,
 }
 
+impl SoapAction for DiscoverResponseBody {
+    const ACTION: &'static str = ACTION_DISCOVER_RESPONSE;
+}
+
 pub mod discover_response {
     use super::*;
     use crate::xsd_primitives::Decimal;
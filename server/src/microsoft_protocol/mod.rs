@@ -1,3 +1,7 @@
 pub mod mde_v2;
 pub mod soap;
+pub mod syncml;
+pub mod wsa;
+pub mod wsse;
+pub mod wstep;
 pub mod xcep;
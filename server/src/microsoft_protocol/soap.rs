@@ -1,3 +1,9 @@
+use super::{wsa, wsse};
+use axum::{
+    extract::{FromRequest, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
 use yaserde::{YaDeserialize, YaSerialize}; // Traits
 use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
 
@@ -32,7 +38,82 @@ where
     pub body: TBODY,
 }
 
-#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone)]
+/// Action of a reply carrying a fault.
+pub const ACTION_FAULT: &str = "http://www.w3.org/2005/08/addressing/soap/fault";
+
+/// Top-level fault code values.
+///
+/// REF; https://www.w3.org/TR/soap12-part1/#faultcodes
+pub mod fault_code {
+    /// The request was malformed, sending it again will fail again
+    pub const SENDER: &str = "s:Sender";
+    /// The server failed processing the request
+    pub const RECEIVER: &str = "s:Receiver";
+}
+
+/// Enrollment errors, carried as fault subcode and as error type of the fault details.
+///
+/// REF; MS-MDE2 section 3.2.4.1.4 and 3.3.4.1.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnrollmentError {
+    /// The request is malformed
+    MessageFormat,
+    /// The user credentials are wrong
+    Authentication,
+    /// The user isn't allowed to enroll
+    Authorization,
+    /// The certificate request could not be processed
+    CertificateRequest,
+    /// Generic failure of the enrollment server
+    EnrollmentServer,
+    /// Unhandled failure of the server
+    InternalServiceFault,
+    /// The security header is missing or can't be processed
+    InvalidSecurity,
+    /// The device type or operating system edition isn't managed by this server
+    DeviceNotSupported,
+}
+
+impl EnrollmentError {
+    /// Subcode value, qualified with the prefix Windows expects.
+    ///
+    /// NOTE; The `a:` prefixed values originate from WCF, Windows compares the values textually
+    pub fn subcode(&self) -> &'static str {
+        match self {
+            EnrollmentError::MessageFormat => "s:MessageFormat",
+            EnrollmentError::Authentication => "s:Authentication",
+            EnrollmentError::Authorization => "s:Authorization",
+            EnrollmentError::CertificateRequest => "s:CertificateRequest",
+            EnrollmentError::EnrollmentServer => "s:EnrollmentServer",
+            EnrollmentError::InternalServiceFault => "a:InternalServiceFault",
+            EnrollmentError::InvalidSecurity => "a:InvalidSecurity",
+            EnrollmentError::DeviceNotSupported => "s:DeviceNotSupported",
+        }
+    }
+
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            EnrollmentError::MessageFormat => "MessageFormat",
+            EnrollmentError::Authentication => "Authentication",
+            EnrollmentError::Authorization => "Authorization",
+            EnrollmentError::CertificateRequest => "CertificateRequest",
+            EnrollmentError::EnrollmentServer => "EnrollmentServer",
+            EnrollmentError::InternalServiceFault => "InternalServiceFault",
+            EnrollmentError::InvalidSecurity => "InvalidSecurity",
+            EnrollmentError::DeviceNotSupported => "DeviceNotSupported",
+        }
+    }
+
+    /// Errors caused by the content of the request are attributed to the sender.
+    pub fn fault_code(&self) -> &'static str {
+        match self {
+            EnrollmentError::MessageFormat | EnrollmentError::InvalidSecurity => fault_code::SENDER,
+            _ => fault_code::RECEIVER,
+        }
+    }
+}
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
 #[yaserde(
     rename = "Fault",
     namespaces = {
@@ -41,26 +122,381 @@ where
     prefix = "s"
 )]
 pub struct SoapFault {
-    #[yaserde(rename = "faultcode")]
-    pub fault_code: Option<String>,
-    #[yaserde(rename = "faultstring")]
-    pub fault_string: Option<String>,
+    #[yaserde(rename = "Code", prefix = "s")]
+    pub code: FaultCode,
+    #[yaserde(rename = "Reason", prefix = "s")]
+    pub reason: FaultReason,
+    #[yaserde(rename = "Detail", prefix = "s")]
+    pub detail: Option<FaultDetail>,
 }
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
+#[yaserde(
+    namespaces = {
+        "s" = "http://www.w3.org/2003/05/soap-envelope",
+    },
+    prefix = "s"
+)]
+pub struct FaultCode {
+    #[yaserde(rename = "Value", prefix = "s")]
+    pub value: String,
+    #[yaserde(rename = "Subcode", prefix = "s")]
+    pub subcode: Option<FaultSubcode>,
+}
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
+#[yaserde(
+    namespaces = {
+        "s" = "http://www.w3.org/2003/05/soap-envelope",
+    },
+    prefix = "s"
+)]
+pub struct FaultSubcode {
+    #[yaserde(rename = "Value", prefix = "s")]
+    pub value: String,
+}
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
+#[yaserde(
+    namespaces = {
+        "s" = "http://www.w3.org/2003/05/soap-envelope",
+    },
+    prefix = "s"
+)]
+pub struct FaultReason {
+    #[yaserde(rename = "Text", prefix = "s")]
+    pub text: FaultText,
+}
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
+pub struct FaultText {
+    #[yaserde(rename = "lang", prefix = "xml", attribute = true)]
+    pub lang: String,
+    #[yaserde(text = true)]
+    pub text: String,
+}
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
+#[yaserde(
+    namespaces = {
+        "s" = "http://www.w3.org/2003/05/soap-envelope",
+    },
+    prefix = "s"
+)]
+pub struct FaultDetail {
+    #[yaserde(rename = "DeviceEnrollmentServiceError")]
+    pub device_enrollment_service_error: Option<DeviceEnrollmentServiceError>,
+}
+
+#[derive(Debug, Default, YaSerialize, YaDeserialize, Clone, PartialEq)]
+#[yaserde(
+    rename = "DeviceEnrollmentServiceError",
+    default_namespace = "enrollment",
+    namespaces = {
+        "enrollment" = "http://schemas.microsoft.com/windows/pki/2009/01/enrollment",
+    },
+)]
+pub struct DeviceEnrollmentServiceError {
+    #[yaserde(rename = "ErrorType")]
+    pub error_type: String,
+    #[yaserde(rename = "Message")]
+    pub message: String,
+    #[yaserde(rename = "TraceId")]
+    pub trace_id: Option<String>,
+}
+
+impl SoapFault {
+    pub fn new(error: EnrollmentError, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            code: FaultCode {
+                value: error.fault_code().into(),
+                subcode: Some(FaultSubcode {
+                    value: error.subcode().into(),
+                }),
+            },
+            reason: FaultReason {
+                text: FaultText {
+                    lang: "en-US".into(),
+                    text: message.clone(),
+                },
+            },
+            detail: Some(FaultDetail {
+                device_enrollment_service_error: Some(DeviceEnrollmentServiceError {
+                    error_type: error.error_type().into(),
+                    message,
+                    trace_id: None,
+                }),
+            }),
+        }
+    }
+
+    pub fn message_format(message: impl Into<String>) -> Self {
+        Self::new(EnrollmentError::MessageFormat, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(EnrollmentError::InternalServiceFault, message)
+    }
+
+    /// HTTP status of the reply, as defined by the SOAP 1.2 HTTP binding.
+    pub fn status_code(&self) -> StatusCode {
+        match self.code.value.as_str() {
+            fault_code::SENDER => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl std::error::Error for SoapFault {}
 
 impl std::fmt::Display for SoapFault {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match (&self.fault_code, &self.fault_string) {
-            (None, None) => Ok(()),
-            (None, Some(fault_string)) => f.write_str(fault_string),
-            (Some(fault_code), None) => f.write_str(fault_code),
-            (Some(fault_code), Some(fault_string)) => {
-                f.write_str(fault_code)?;
-                f.write_str(": ")?;
-                f.write_str(fault_string)
-            }
+        let code = match &self.code.subcode {
+            Some(subcode) => &subcode.value,
+            None => &self.code.value,
+        };
+        write!(f, "{code}: {}", self.reason.text.text)
+    }
+}
+
+#[derive(YaSerialize, YaDeserialize, Debug, Default, Clone, PartialEq)]
+#[yaserde(
+    namespaces = {
+        "s" = "http://www.w3.org/2003/05/soap-envelope",
+    },
+)]
+pub struct FaultBody {
+    #[yaserde(rename = "Fault", prefix = "s")]
+    pub fault: SoapFault,
+}
+
+/// A fault, optionally addressed as reply to a request.
+///
+/// NOTE; The fault is boxed, it's passed around as error of most request handling steps
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultResponse {
+    pub fault: Box<SoapFault>,
+    pub relates_to: Option<String>,
+}
+
+impl From<SoapFault> for FaultResponse {
+    fn from(fault: SoapFault) -> Self {
+        Self {
+            fault: Box::new(fault),
+            relates_to: None,
+        }
+    }
+}
+
+impl IntoResponse for SoapFault {
+    fn into_response(self) -> Response {
+        FaultResponse::from(self).into_response()
+    }
+}
+
+impl IntoResponse for FaultResponse {
+    fn into_response(self) -> Response {
+        let status = self.fault.status_code();
+        let header = wsa::ResponseHeader::new(ACTION_FAULT, self.relates_to);
+        envelope_response(status, header, FaultBody { fault: *self.fault })
+    }
+}
+
+/// Body of a SOAP message, identified by the WS-Addressing action of the operation.
+pub trait SoapAction {
+    const ACTION: &'static str;
+}
+
+impl SoapAction for FaultBody {
+    const ACTION: &'static str = ACTION_FAULT;
+}
+
+/// Extractor for a SOAP request, the action of the request must match the expected body.
+///
+/// Malformed requests are rejected with a fault.
+#[derive(Debug, Clone)]
+pub struct SoapRequest<TBODY> {
+    pub header: wsa::RequestHeader,
+    pub body: TBODY,
+}
+
+impl<S, TBODY> FromRequest<S> for SoapRequest<TBODY>
+where
+    S: Send + Sync,
+    TBODY: YaSerialize + YaDeserialize + SoapAction + Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // WARN; Request the payload as a string, this will consume all bytes (the body) for us
+        let payload = String::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let envelope: SoapEnvelope<TBODY, wsa::RequestHeader> = yaserde::de::from_str(&payload)
+            .map_err(|err| {
+                eprintln!("Error parsing SOAP request: {}", err);
+                SoapFault::message_format("Malformed request").into_response()
+            })?;
+        let SoapEnvelope { header, body, .. } = envelope;
+
+        if header.action != TBODY::ACTION {
+            eprintln!("Unexpected SOAP action: {}", header.action);
+            let fault = FaultResponse {
+                fault: Box::new(SoapFault::message_format(format!(
+                    "Unsupported action {}",
+                    header.action
+                ))),
+                relates_to: header.message_id,
+            };
+            return Err(fault.into_response());
+        }
+
+        Ok(Self { header, body })
+    }
+}
+
+impl<TBODY> SoapRequest<TBODY> {
+    /// Reply to this request, Action and RelatesTo are derived from the reply body and this request.
+    pub fn reply<TREPLY: SoapAction>(&self, body: TREPLY) -> SoapResponse<TREPLY> {
+        SoapResponse {
+            header: wsa::ResponseHeader::new(TREPLY::ACTION, self.header.message_id.clone()),
+            body,
+        }
+    }
+
+    /// Credentials from the Security header, `None` when the client didn't present any.
+    pub fn credentials(&self) -> Option<wsse::Credentials<'_>> {
+        self.header
+            .security
+            .as_ref()
+            .and_then(wsse::Security::credentials)
+    }
+
+    /// Authenticates the request with the provided verifier.
+    ///
+    /// Requests without credentials, or credentials refused by the verifier, are answered with a fault.
+    pub fn authenticate<T>(
+        &self,
+        verify: impl FnOnce(wsse::Credentials<'_>) -> Result<T, FaultResponse>,
+    ) -> Result<T, FaultResponse> {
+        let Some(credentials) = self.credentials() else {
+            return Err(self.fault(SoapFault::new(
+                EnrollmentError::InvalidSecurity,
+                "Missing credentials",
+            )));
+        };
+        verify(credentials).map_err(|FaultResponse { fault, .. }| self.fault(*fault))
+    }
+
+    /// Fault in reply to this request.
+    pub fn fault(&self, fault: SoapFault) -> FaultResponse {
+        FaultResponse {
+            fault: Box::new(fault),
+            relates_to: self.header.message_id.clone(),
+        }
+    }
+}
+
+/// Successful reply to a SOAP request.
+#[derive(Debug, Clone)]
+pub struct SoapResponse<TBODY> {
+    pub header: wsa::ResponseHeader,
+    pub body: TBODY,
+}
+
+impl<TBODY> IntoResponse for SoapResponse<TBODY>
+where
+    TBODY: YaSerialize + YaDeserialize,
+{
+    fn into_response(self) -> Response {
+        envelope_response(StatusCode::OK, self.header, self.body)
+    }
+}
+
+fn envelope_response<TBODY>(
+    status: StatusCode,
+    header: wsa::ResponseHeader,
+    body: TBODY,
+) -> Response
+where
+    TBODY: YaSerialize + YaDeserialize,
+{
+    let envelope = SoapEnvelope {
+        header,
+        body,
+        encoding_style: None,
+        tnsattr: None,
+        urnattr: None,
+        xsiattr: None,
+    };
+
+    match yaserde::ser::to_string(&envelope) {
+        Ok(xml) => (
+            status,
+            [(CONTENT_TYPE, "application/soap+xml; charset=utf-8")],
+            xml,
+        )
+            .into_response(),
+        Err(err) => {
+            eprintln!("Error serializing response: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
-// pub type SoapResponse = Result<(reqwest::StatusCode, String), reqwest::Error>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fault_serialize_test() {
+        let envelope = SoapEnvelope {
+            header: wsa::ResponseHeader::new(
+                ACTION_FAULT,
+                Some("urn:uuid:748132ec-a575-4329-b01b-6171a9cf8478".into()),
+            ),
+            body: FaultBody {
+                fault: SoapFault::new(EnrollmentError::DeviceNotSupported, "Unsupported device"),
+            },
+            encoding_style: None,
+            tnsattr: None,
+            urnattr: None,
+            xsiattr: None,
+        };
+        let xml = yaserde::ser::to_string(&envelope).unwrap();
+
+        assert!(xml.contains("<s:Value>s:Receiver</s:Value>"));
+        assert!(xml.contains("<s:Subcode><s:Value>s:DeviceNotSupported</s:Value></s:Subcode>"));
+        assert!(xml.contains("<s:Text xml:lang=\"en-US\">Unsupported device</s:Text>"));
+        assert!(xml.contains("<ErrorType>DeviceNotSupported</ErrorType>"));
+        assert_eq!(
+            envelope.body.fault.status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            SoapFault::message_format("Malformed").status_code(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn missing_credentials_test() {
+        let request = SoapRequest {
+            header: wsa::RequestHeader {
+                message_id: Some("urn:uuid:0d5a1441-5891-453b-becf-a2e5f6ea3749".into()),
+                ..Default::default()
+            },
+            body: (),
+        };
+        let response = request.authenticate(|_| Ok(())).unwrap_err();
+
+        assert_eq!(response.relates_to, request.header.message_id);
+        assert_eq!(
+            response.fault.code.subcode.as_ref().unwrap().value,
+            "a:InvalidSecurity"
+        );
+        assert_eq!(response.fault.status_code(), StatusCode::BAD_REQUEST);
+    }
+}
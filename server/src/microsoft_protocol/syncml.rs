@@ -0,0 +1,858 @@
+//! OMA-DM (SyncML 1.2) protocol types and implementations
+//!
+//! SyncML is the device management protocol, used by enrolled MDM clients to check-in with the MDM server.
+//! The server answers with commands operating on configuration nodes (OMA-URIs) of the device, the device
+//! replies with the status and results of those commands in its next message.
+//!
+//! REF; https://learn.microsoft.com/en-us/windows/client-management/oma-dm-protocol-support
+//! REF; https://www.openmobilealliance.org/release/DM/V1_2-20070209-A/OMA-TS-DM_RepPro-V1_2-20070209-A.pdf
+//!
+//! NOTE; Derived yaserde implementations cannot represent mixed sequences of elements, [`SyncBody`] and
+//! [`CommandContainer`] implement (de)serialization by hand to keep commands in the order they were added.
+
+use std::io::{Read, Write};
+use xml::{
+    attribute::OwnedAttribute, namespace::Namespace, reader::XmlEvent as ReadEvent,
+    writer::XmlEvent as WriteEvent,
+};
+use yaserde::{de::Deserializer, ser::Serializer, YaDeserialize, YaSerialize}; // Traits
+use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
+
+pub const VER_DTD: &str = "1.2";
+pub const VER_PROTO: &str = "DM/1.2";
+
+/// Alert codes
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/oma-dm-protocol-support#alert-codes
+pub mod alert {
+    /// The sender requests the next message of the package from the recipient
+    pub const NEXT_MESSAGE: u32 = 1222;
+    /// Generic alert, carrying an asynchronous result
+    pub const GENERIC: u32 = 1226;
+}
+
+/// Status codes, modelled after HTTP status codes
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/oma-dm-protocol-support#syncml-response-status-codes
+pub mod status {
+    pub const OK: u16 = 200;
+
+    /// Status codes 2xx report a successfully processed command
+    pub fn is_success(code: u16) -> bool {
+        (200..300).contains(&code)
+    }
+}
+
+/// Value of the Cmd element inside Status, when referring to the SyncHdr of a message
+pub const CMD_SYNC_HDR: &str = "SyncHdr";
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    rename = "SyncML",
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct SyncMl {
+    #[yaserde(rename = "SyncHdr", prefix = "syncml")]
+    pub sync_hdr: SyncHdr,
+
+    #[yaserde(rename = "SyncBody", prefix = "syncml")]
+    pub sync_body: SyncBody,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct SyncHdr {
+    #[yaserde(rename = "VerDTD", prefix = "syncml")]
+    pub ver_dtd: String,
+
+    #[yaserde(rename = "VerProto", prefix = "syncml")]
+    pub ver_proto: String,
+
+    #[yaserde(rename = "SessionID", prefix = "syncml")]
+    pub session_id: String,
+
+    #[yaserde(rename = "MsgID", prefix = "syncml")]
+    pub msg_id: u32,
+
+    #[yaserde(rename = "Target", prefix = "syncml")]
+    pub target: LocationRef,
+
+    #[yaserde(rename = "Source", prefix = "syncml")]
+    pub source: LocationRef,
+
+    #[yaserde(rename = "Cred", prefix = "syncml")]
+    pub cred: Option<Cred>,
+
+    #[yaserde(rename = "Meta", prefix = "syncml")]
+    pub meta: Option<Meta>,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct LocationRef {
+    #[yaserde(rename = "LocURI", prefix = "syncml")]
+    pub loc_uri: String,
+
+    #[yaserde(rename = "LocName", prefix = "syncml")]
+    pub loc_name: Option<String>,
+}
+
+impl LocationRef {
+    pub fn new(loc_uri: impl Into<String>) -> Self {
+        Self {
+            loc_uri: loc_uri.into(),
+            loc_name: None,
+        }
+    }
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct Cred {
+    #[yaserde(rename = "Meta", prefix = "syncml")]
+    pub meta: Option<Meta>,
+
+    #[yaserde(rename = "Data", prefix = "syncml")]
+    pub data: String,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    namespaces = {
+        "metinf" = "syncml:metinf",
+    },
+)]
+pub struct Meta {
+    #[yaserde(prefix = "metinf", rename = "Format")]
+    pub format: Option<String>,
+
+    #[yaserde(prefix = "metinf", rename = "Type")]
+    pub meta_type: Option<String>,
+
+    #[yaserde(prefix = "metinf", rename = "MaxMsgSize")]
+    pub max_msg_size: Option<u32>,
+}
+
+impl Meta {
+    pub fn with_format(format: impl Into<String>) -> Self {
+        Self {
+            format: Some(format.into()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct Item {
+    #[yaserde(rename = "Target", prefix = "syncml")]
+    pub target: Option<LocationRef>,
+
+    #[yaserde(rename = "Source", prefix = "syncml")]
+    pub source: Option<LocationRef>,
+
+    #[yaserde(rename = "Meta", prefix = "syncml")]
+    pub meta: Option<Meta>,
+
+    #[yaserde(rename = "Data", prefix = "syncml")]
+    pub data: Option<String>,
+}
+
+impl Item {
+    /// Item addressing a node on the device.
+    pub fn target(loc_uri: impl Into<String>) -> Self {
+        Self {
+            target: Some(LocationRef::new(loc_uri)),
+            ..Default::default()
+        }
+    }
+
+    /// Item describing a node of the device, as sent by the device.
+    #[cfg(test)]
+    pub fn source(loc_uri: impl Into<String>) -> Self {
+        Self {
+            source: Some(LocationRef::new(loc_uri)),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_meta(mut self, meta: Meta) -> Self {
+        self.meta = Some(meta);
+        self
+    }
+
+    pub fn with_data(mut self, data: impl Into<String>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// The node this item refers to, the source for items sent by the device and target otherwise.
+    pub fn loc_uri(&self) -> Option<&str> {
+        self.source
+            .as_ref()
+            .or(self.target.as_ref())
+            .map(|location| location.loc_uri.as_str())
+    }
+}
+
+/// Empty marker element, indicating the last message of a package.
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct Final {}
+
+/// Empty marker element, indicating the sender doesn't want a status for the command.
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct NoResp {}
+
+/// Body of a SyncML message.
+///
+/// NOTE; Status and Results are serialized first, followed by the commands in insertion order.
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct SyncBody {
+    pub status: Vec<Status>,
+    pub results: Vec<Results>,
+    pub commands: Vec<Command>,
+    pub final_message: Option<Final>,
+}
+
+impl SyncBody {
+    pub fn is_final(&self) -> bool {
+        self.final_message.is_some()
+    }
+}
+
+impl YaSerialize for SyncBody {
+    fn serialize<W: Write>(&self, writer: &mut Serializer<W>) -> Result<(), String> {
+        write_start(writer, "SyncBody")?;
+        for status in &self.status {
+            write_struct(writer, "Status", status)?;
+        }
+        for results in &self.results {
+            write_struct(writer, "Results", results)?;
+        }
+        for command in &self.commands {
+            command.serialize(writer)?;
+        }
+        if let Some(final_message) = &self.final_message {
+            write_struct(writer, "Final", final_message)?;
+        }
+        write_end(writer)
+    }
+
+    fn serialize_attributes(
+        &self,
+        attributes: Vec<OwnedAttribute>,
+        namespace: Namespace,
+    ) -> Result<(Vec<OwnedAttribute>, Namespace), String> {
+        Ok((attributes, namespace))
+    }
+}
+
+impl YaDeserialize for SyncBody {
+    fn deserialize<R: Read>(reader: &mut Deserializer<R>) -> Result<Self, String> {
+        let mut body = SyncBody::default();
+        read_children(reader, |name, reader| {
+            match name {
+                "Status" => body.status.push(read_struct(reader)?),
+                "Results" => body.results.push(read_struct(reader)?),
+                "Final" => body.final_message = Some(read_struct(reader)?),
+                name if Command::NAMES.contains(&name) => body.commands.push(read_struct(reader)?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(body)
+    }
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct Status {
+    #[yaserde(rename = "CmdID", prefix = "syncml")]
+    pub cmd_id: u32,
+
+    #[yaserde(rename = "MsgRef", prefix = "syncml")]
+    pub msg_ref: u32,
+
+    #[yaserde(rename = "CmdRef", prefix = "syncml")]
+    pub cmd_ref: u32,
+
+    #[yaserde(rename = "Cmd", prefix = "syncml")]
+    pub cmd: String,
+
+    #[yaserde(rename = "TargetRef", prefix = "syncml")]
+    pub target_ref: Option<String>,
+
+    #[yaserde(rename = "SourceRef", prefix = "syncml")]
+    pub source_ref: Option<String>,
+
+    #[yaserde(rename = "Data", prefix = "syncml")]
+    pub data: u16,
+
+    #[yaserde(rename = "Item", prefix = "syncml")]
+    pub item: Vec<Item>,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct Results {
+    #[yaserde(rename = "CmdID", prefix = "syncml")]
+    pub cmd_id: u32,
+
+    #[yaserde(rename = "MsgRef", prefix = "syncml")]
+    pub msg_ref: Option<u32>,
+
+    #[yaserde(rename = "CmdRef", prefix = "syncml")]
+    pub cmd_ref: u32,
+
+    #[yaserde(rename = "Meta", prefix = "syncml")]
+    pub meta: Option<Meta>,
+
+    #[yaserde(rename = "TargetRef", prefix = "syncml")]
+    pub target_ref: Option<String>,
+
+    #[yaserde(rename = "SourceRef", prefix = "syncml")]
+    pub source_ref: Option<String>,
+
+    #[yaserde(rename = "Item", prefix = "syncml")]
+    pub item: Vec<Item>,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct Alert {
+    #[yaserde(rename = "CmdID", prefix = "syncml")]
+    pub cmd_id: u32,
+
+    #[yaserde(rename = "NoResp", prefix = "syncml")]
+    pub no_resp: Option<NoResp>,
+
+    #[yaserde(rename = "Data", prefix = "syncml")]
+    pub data: Option<u32>,
+
+    #[yaserde(rename = "Item", prefix = "syncml")]
+    pub item: Vec<Item>,
+}
+
+/// Shape shared by the Add, Replace, Delete, Get and Exec commands.
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "syncml",
+    default_namespace = "syncml",
+    namespaces = {
+        "syncml" = "SYNCML:SYNCML1.2",
+    },
+)]
+pub struct ItemCommand {
+    #[yaserde(rename = "CmdID", prefix = "syncml")]
+    pub cmd_id: u32,
+
+    #[yaserde(rename = "NoResp", prefix = "syncml")]
+    pub no_resp: Option<NoResp>,
+
+    #[yaserde(rename = "Meta", prefix = "syncml")]
+    pub meta: Option<Meta>,
+
+    #[yaserde(rename = "Item", prefix = "syncml")]
+    pub item: Vec<Item>,
+}
+
+impl ItemCommand {
+    pub fn new(item: Item) -> Self {
+        Self {
+            item: vec![item],
+            ..Default::default()
+        }
+    }
+}
+
+/// Shape shared by the Atomic and Sequence commands.
+///
+/// NOTE; Atomic executes all nested commands or none, Sequence executes the nested commands in order.
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct CommandContainer {
+    pub cmd_id: u32,
+    pub no_resp: Option<NoResp>,
+    pub meta: Option<Meta>,
+    pub commands: Vec<Command>,
+}
+
+impl CommandContainer {
+    #[cfg(test)]
+    pub fn new(commands: impl IntoIterator<Item = Command>) -> Self {
+        Self {
+            commands: commands.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl YaSerialize for CommandContainer {
+    fn serialize<W: Write>(&self, writer: &mut Serializer<W>) -> Result<(), String> {
+        // NOTE; Written as Atomic or Sequence, the element name is provided by the enclosing Command
+        write_start(writer, "Atomic")?;
+        write_text(writer, "CmdID", &self.cmd_id.to_string())?;
+        if let Some(no_resp) = &self.no_resp {
+            write_struct(writer, "NoResp", no_resp)?;
+        }
+        if let Some(meta) = &self.meta {
+            write_struct(writer, "Meta", meta)?;
+        }
+        for command in &self.commands {
+            command.serialize(writer)?;
+        }
+        write_end(writer)
+    }
+
+    fn serialize_attributes(
+        &self,
+        attributes: Vec<OwnedAttribute>,
+        namespace: Namespace,
+    ) -> Result<(Vec<OwnedAttribute>, Namespace), String> {
+        Ok((attributes, namespace))
+    }
+}
+
+impl YaDeserialize for CommandContainer {
+    fn deserialize<R: Read>(reader: &mut Deserializer<R>) -> Result<Self, String> {
+        let mut container = CommandContainer::default();
+        read_children(reader, |name, reader| {
+            match name {
+                "CmdID" => {
+                    container.cmd_id = read_text(reader)?
+                        .parse()
+                        .map_err(|err| format!("Invalid CmdID: {}", err))?
+                }
+                "NoResp" => container.no_resp = Some(read_struct(reader)?),
+                "Meta" => container.meta = Some(read_struct(reader)?),
+                name if Command::NAMES.contains(&name) => {
+                    container.commands.push(read_struct(reader)?)
+                }
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(container)
+    }
+}
+
+/// A single SyncML operation, as sent by either side of the session.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Command {
+    Alert(Alert),
+    Add(ItemCommand),
+    Replace(ItemCommand),
+    Delete(ItemCommand),
+    Get(ItemCommand),
+    Exec(ItemCommand),
+    Atomic(CommandContainer),
+    Sequence(CommandContainer),
+}
+
+impl Command {
+    /// Element names of all commands.
+    pub const NAMES: [&'static str; 8] = [
+        "Alert", "Add", "Replace", "Delete", "Get", "Exec", "Atomic", "Sequence",
+    ];
+
+    /// Name of the command element, as referred to by the Cmd element of Status.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Alert(_) => "Alert",
+            Command::Add(_) => "Add",
+            Command::Replace(_) => "Replace",
+            Command::Delete(_) => "Delete",
+            Command::Get(_) => "Get",
+            Command::Exec(_) => "Exec",
+            Command::Atomic(_) => "Atomic",
+            Command::Sequence(_) => "Sequence",
+        }
+    }
+
+    pub fn cmd_id(&self) -> u32 {
+        match self {
+            Command::Alert(alert) => alert.cmd_id,
+            Command::Add(command)
+            | Command::Replace(command)
+            | Command::Delete(command)
+            | Command::Get(command)
+            | Command::Exec(command) => command.cmd_id,
+            Command::Atomic(container) | Command::Sequence(container) => container.cmd_id,
+        }
+    }
+
+    /// Items of the command, nested commands of Atomic and Sequence are not included.
+    pub fn items(&self) -> &[Item] {
+        match self {
+            Command::Alert(alert) => &alert.item,
+            Command::Add(command)
+            | Command::Replace(command)
+            | Command::Delete(command)
+            | Command::Get(command)
+            | Command::Exec(command) => &command.item,
+            Command::Atomic(_) | Command::Sequence(_) => &[],
+        }
+    }
+
+    /// Assigns command identifiers to this command and all nested commands, taken from the provided allocator.
+    ///
+    /// Returns the identifiers of all nested commands, which the device will report status on individually.
+    pub fn assign_cmd_ids(&mut self, mut next_id: impl FnMut() -> u32) -> Vec<(u32, &'static str)> {
+        let mut nested = Vec::new();
+        match self {
+            Command::Alert(alert) => alert.cmd_id = next_id(),
+            Command::Add(command)
+            | Command::Replace(command)
+            | Command::Delete(command)
+            | Command::Get(command)
+            | Command::Exec(command) => command.cmd_id = next_id(),
+            Command::Atomic(container) | Command::Sequence(container) => {
+                container.cmd_id = next_id();
+                assign_nested(container, &mut next_id, &mut nested);
+            }
+        }
+        nested
+    }
+}
+
+fn assign_nested(
+    container: &mut CommandContainer,
+    next_id: &mut impl FnMut() -> u32,
+    nested: &mut Vec<(u32, &'static str)>,
+) {
+    // NOTE; Same order as serialization, so identifiers increase throughout the document
+    for command in container.commands.iter_mut() {
+        let name = command.name();
+        match command {
+            Command::Alert(alert) => alert.cmd_id = next_id(),
+            Command::Add(command)
+            | Command::Replace(command)
+            | Command::Delete(command)
+            | Command::Get(command)
+            | Command::Exec(command) => command.cmd_id = next_id(),
+            Command::Atomic(inner) | Command::Sequence(inner) => {
+                inner.cmd_id = next_id();
+                nested.push((inner.cmd_id, name));
+                assign_nested(inner, next_id, nested);
+                continue;
+            }
+        }
+        nested.push((command.cmd_id(), name));
+    }
+}
+
+impl YaSerialize for Command {
+    fn serialize<W: Write>(&self, writer: &mut Serializer<W>) -> Result<(), String> {
+        match self {
+            Command::Alert(alert) => write_struct(writer, self.name(), alert),
+            Command::Add(command)
+            | Command::Replace(command)
+            | Command::Delete(command)
+            | Command::Get(command)
+            | Command::Exec(command) => write_struct(writer, self.name(), command),
+            Command::Atomic(container) | Command::Sequence(container) => {
+                write_struct(writer, self.name(), container)
+            }
+        }
+    }
+
+    fn serialize_attributes(
+        &self,
+        attributes: Vec<OwnedAttribute>,
+        namespace: Namespace,
+    ) -> Result<(Vec<OwnedAttribute>, Namespace), String> {
+        Ok((attributes, namespace))
+    }
+}
+
+impl YaDeserialize for Command {
+    fn deserialize<R: Read>(reader: &mut Deserializer<R>) -> Result<Self, String> {
+        let name = match reader.peek()? {
+            ReadEvent::StartElement { name, .. } => name.local_name.clone(),
+            event => return Err(format!("Expected a command, found {:?}", event)),
+        };
+        match name.as_str() {
+            "Alert" => Alert::deserialize(reader).map(Command::Alert),
+            "Add" => ItemCommand::deserialize(reader).map(Command::Add),
+            "Replace" => ItemCommand::deserialize(reader).map(Command::Replace),
+            "Delete" => ItemCommand::deserialize(reader).map(Command::Delete),
+            "Get" => ItemCommand::deserialize(reader).map(Command::Get),
+            "Exec" => ItemCommand::deserialize(reader).map(Command::Exec),
+            "Atomic" => CommandContainer::deserialize(reader).map(Command::Atomic),
+            "Sequence" => CommandContainer::deserialize(reader).map(Command::Sequence),
+            name => Err(format!("Unknown command {}", name)),
+        }
+    }
+}
+
+fn write_start<W: Write>(writer: &mut Serializer<W>, default_name: &str) -> Result<(), String> {
+    if writer.skip_start_end() {
+        return Ok(());
+    }
+    let name = writer
+        .get_start_event_name()
+        .unwrap_or_else(|| default_name.to_string());
+    writer
+        .write(WriteEvent::start_element(name.as_str()))
+        .map_err(|err| err.to_string())
+}
+
+fn write_end<W: Write>(writer: &mut Serializer<W>) -> Result<(), String> {
+    if writer.skip_start_end() {
+        return Ok(());
+    }
+    writer
+        .write(WriteEvent::end_element())
+        .map_err(|err| err.to_string())
+}
+
+fn write_struct<W: Write, T: YaSerialize>(
+    writer: &mut Serializer<W>,
+    name: &str,
+    value: &T,
+) -> Result<(), String> {
+    writer.set_start_event_name(Some(name.to_string()));
+    writer.set_skip_start_end(false);
+    value.serialize(writer)
+}
+
+fn write_text<W: Write>(writer: &mut Serializer<W>, name: &str, text: &str) -> Result<(), String> {
+    writer
+        .write(WriteEvent::start_element(name))
+        .and_then(|_| writer.write(WriteEvent::characters(text)))
+        .and_then(|_| writer.write(WriteEvent::end_element()))
+        .map_err(|err| err.to_string())
+}
+
+/// Visits the child elements of the element at the reader position, stopping before its end element.
+///
+/// The visitor consumes the child it recognizes and returns true, other children are skipped.
+fn read_children<R: Read>(
+    reader: &mut Deserializer<R>,
+    mut visit: impl FnMut(&str, &mut Deserializer<R>) -> Result<bool, String>,
+) -> Result<(), String> {
+    match reader.next_event()? {
+        ReadEvent::StartElement { .. } => {}
+        event => return Err(format!("Expected a start element, found {:?}", event)),
+    }
+    loop {
+        match reader.peek()?.to_owned() {
+            ReadEvent::StartElement { name, .. } => {
+                if !visit(&name.local_name, reader)? {
+                    reader.next_event()?;
+                    reader.skip_element(|_| {})?;
+                }
+            }
+            // NOTE; Left for the caller, like derived deserializers do
+            ReadEvent::EndElement { .. } => return Ok(()),
+            ReadEvent::EndDocument => return Err("Unexpected end of document".to_string()),
+            _ => {
+                reader.next_event()?;
+            }
+        }
+    }
+}
+
+fn read_struct<R: Read, T: YaDeserialize>(reader: &mut Deserializer<R>) -> Result<T, String> {
+    let value = T::deserialize(reader)?;
+    // Consume the end element of the child
+    reader.next_event()?;
+    Ok(value)
+}
+
+fn read_text<R: Read>(reader: &mut Deserializer<R>) -> Result<String, String> {
+    reader.read_inner_value(|reader| match reader.next_event()? {
+        ReadEvent::Characters(text) => Ok(text),
+        event => Err(format!("Expected text, found {:?}", event)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_INITIATED_SESSION: &str = r#"<SyncML xmlns="SYNCML:SYNCML1.2">
+    <SyncHdr>
+        <VerDTD>1.2</VerDTD>
+        <VerProto>DM/1.2</VerProto>
+        <SessionID>1</SessionID>
+        <MsgID>1</MsgID>
+        <Target>
+            <LocURI>https://mdmwindows.com/ManagementServer/MDM.svc</LocURI>
+        </Target>
+        <Source>
+            <LocURI>AB157C3A18B74A6B9C5DF4E6F5C7A1B2</LocURI>
+        </Source>
+    </SyncHdr>
+    <SyncBody>
+        <Alert>
+            <CmdID>2</CmdID>
+            <Data>1201</Data>
+        </Alert>
+        <Alert>
+            <CmdID>3</CmdID>
+            <Data>1224</Data>
+            <Item>
+                <Meta>
+                    <Type xmlns="syncml:metinf">com.microsoft/MDM/LoginStatus</Type>
+                </Meta>
+                <Data>user</Data>
+            </Item>
+        </Alert>
+        <Replace>
+            <CmdID>4</CmdID>
+            <Item>
+                <Source>
+                    <LocURI>./DevInfo/DevId</LocURI>
+                </Source>
+                <Data>AB157C3A18B74A6B9C5DF4E6F5C7A1B2</Data>
+            </Item>
+            <Item>
+                <Source>
+                    <LocURI>./DevInfo/Man</LocURI>
+                </Source>
+                <Data>Microsoft Corporation</Data>
+            </Item>
+        </Replace>
+        <Final/>
+    </SyncBody>
+</SyncML>"#;
+
+    #[test]
+    fn syncml_deserialize_test() {
+        let message: SyncMl = yaserde::de::from_str(CLIENT_INITIATED_SESSION).unwrap();
+
+        assert_eq!(message.sync_hdr.session_id, "1");
+        assert_eq!(message.sync_hdr.msg_id, 1);
+        assert_eq!(
+            message.sync_hdr.source.loc_uri,
+            "AB157C3A18B74A6B9C5DF4E6F5C7A1B2"
+        );
+        assert!(message.sync_body.is_final());
+
+        let commands = &message.sync_body.commands;
+        assert_eq!(
+            commands.iter().map(Command::cmd_id).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(commands[2].name(), "Replace");
+        assert_eq!(commands[2].items()[1].loc_uri(), Some("./DevInfo/Man"));
+        assert_eq!(
+            commands[1].items()[0].meta.as_ref().unwrap().meta_type,
+            Some("com.microsoft/MDM/LoginStatus".to_string())
+        );
+    }
+
+    #[test]
+    fn syncml_serialize_test() {
+        let message: SyncMl = yaserde::de::from_str(CLIENT_INITIATED_SESSION).unwrap();
+        let serialized = yaserde::ser::to_string(&message).unwrap();
+
+        assert!(serialized.contains(r#"<SyncML xmlns="SYNCML:SYNCML1.2">"#));
+        assert_eq!(
+            yaserde::de::from_str::<SyncMl>(&serialized).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn command_order_test() {
+        let mut message: SyncMl = yaserde::de::from_str(CLIENT_INITIATED_SESSION).unwrap();
+        message.sync_body.commands = vec![
+            Command::Replace(ItemCommand::new(Item::target("./Vendor/MSFT/A"))),
+            Command::Atomic(CommandContainer::new([
+                Command::Get(ItemCommand::new(Item::target("./Vendor/MSFT/B"))),
+                Command::Add(ItemCommand::new(Item::target("./Vendor/MSFT/C"))),
+            ])),
+            Command::Alert(Alert::default()),
+        ];
+
+        let serialized = yaserde::ser::to_string(&message).unwrap();
+        let parsed: SyncMl = yaserde::de::from_str(&serialized).unwrap();
+
+        assert_eq!(parsed, message);
+        let names: Vec<_> = parsed
+            .sync_body
+            .commands
+            .iter()
+            .map(Command::name)
+            .collect();
+        assert_eq!(names, vec!["Replace", "Atomic", "Alert"]);
+    }
+
+    #[test]
+    fn assign_nested_cmd_ids_test() {
+        let mut atomic = Command::Atomic(CommandContainer::new([
+            Command::Replace(ItemCommand::new(Item::target("./Vendor/MSFT/A"))),
+            Command::Add(ItemCommand::new(Item::target("./Vendor/MSFT/B"))),
+        ]));
+
+        let mut next = 10;
+        let nested = atomic.assign_cmd_ids(|| {
+            next += 1;
+            next
+        });
+
+        assert_eq!(atomic.cmd_id(), 11);
+        assert_eq!(nested, vec![(12, "Replace"), (13, "Add")]);
+    }
+}
@@ -0,0 +1,74 @@
+//! WS-Addressing protocol types
+//!
+//! Every SOAP message exchanged with the enrollment services is addressed through these headers. The
+//! request carries the operation (Action) and an identifier (MessageID), the reply echoes that identifier
+//! in RelatesTo. The WS-Security header travels along in the same SOAP header.
+//!
+//! REF; https://www.w3.org/TR/ws-addr-core/
+
+use super::wsse;
+use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "a",
+    namespaces = {
+        "a" = "http://www.w3.org/2005/08/addressing",
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+    },
+)]
+pub struct RequestHeader {
+    #[yaserde(prefix = "a", rename = "Action")]
+    pub action: String,
+
+    #[yaserde(prefix = "a", rename = "MessageID")]
+    pub message_id: Option<String>,
+
+    #[yaserde(prefix = "a", rename = "ReplyTo")]
+    pub reply_to: Option<EndpointReference>,
+
+    #[yaserde(prefix = "a", rename = "To")]
+    pub to: Option<String>,
+
+    #[yaserde(prefix = "wsse", rename = "Security")]
+    pub security: Option<wsse::Security>,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "a",
+    namespaces = {
+        "a" = "http://www.w3.org/2005/08/addressing",
+    },
+)]
+pub struct EndpointReference {
+    #[yaserde(prefix = "a", rename = "Address")]
+    pub address: String,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "a",
+    namespaces = {
+        "a" = "http://www.w3.org/2005/08/addressing",
+    },
+)]
+pub struct ResponseHeader {
+    #[yaserde(rename = "Action", prefix = "a")]
+    pub action: String,
+    #[yaserde(rename = "ActivityId", prefix = "a")]
+    // NOTE; Made optional since it's part of Microsoft diagnostics
+    pub activity_id: Option<String>,
+    #[yaserde(rename = "RelatesTo", prefix = "a")]
+    pub relates_to: Option<String>,
+}
+
+impl ResponseHeader {
+    pub fn new(action: impl Into<String>, relates_to: Option<String>) -> Self {
+        Self {
+            action: action.into(),
+            activity_id: None,
+            relates_to,
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! WS-Security protocol types
+//!
+//! The policy and enrollment requests carry the credentials of the user inside the Security header. Depending
+//! on the authentication policy this is a UsernameToken (OnPremise) or a BinarySecurityToken (Federated).
+//!
+//! REF; https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-mde2/5b02c625-ced2-4a01-a8e1-da0ae84f5bb7
+//! REF; http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0.pdf
+
+use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
+
+pub const PASSWORD_TEXT: &str =
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordText";
+/// Security token issued by the federated authentication service.
+pub const VALUE_TYPE_USER_TOKEN: &str =
+    "http://schemas.microsoft.com/5.0.0.0/ConfigurationManager/Enrollment/DeviceEnrollmentUserToken";
+/// Certificate previously issued to the device.
+pub const VALUE_TYPE_X509V3: &str =
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-x509-token-profile-1.0#X509v3";
+pub const ENCODING_TYPE_BASE64: &str =
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd#base64binary";
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wsse",
+    namespaces = {
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+    },
+)]
+pub struct Security {
+    #[yaserde(prefix = "wsse", rename = "UsernameToken")]
+    pub username_token: Option<UsernameToken>,
+
+    #[yaserde(prefix = "wsse", rename = "BinarySecurityToken")]
+    pub binary_security_token: Option<BinarySecurityToken>,
+}
+
+impl Security {
+    /// Returns the credentials presented by the client, the username token takes precedence.
+    pub fn credentials(&self) -> Option<Credentials<'_>> {
+        if let Some(token) = &self.username_token {
+            // NOTE; Digests can't be verified against the password file, they're treated as a missing password
+            let password = token.password.as_ref().filter(|password| {
+                password
+                    .password_type
+                    .as_deref()
+                    .is_none_or(|password_type| password_type == PASSWORD_TEXT)
+            });
+            return Some(Credentials::UsernameToken {
+                username: &token.username,
+                password: password.map_or("", |p| p.value.as_str()),
+            });
+        }
+
+        self.binary_security_token
+            .as_ref()
+            .map(|token| Credentials::SecurityToken {
+                value_type: &token.value_type,
+                token: token.compact_value(),
+            })
+    }
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wsse",
+    namespaces = {
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+    },
+)]
+pub struct UsernameToken {
+    #[yaserde(prefix = "wsse", rename = "Username")]
+    pub username: String,
+
+    #[yaserde(prefix = "wsse", rename = "Password")]
+    pub password: Option<Password>,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wsse",
+    namespaces = {
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+    },
+)]
+pub struct Password {
+    #[yaserde(rename = "Type", attribute = true)]
+    // NOTE; Only PasswordText is sent by windows, digests are not supported
+    pub password_type: Option<String>,
+
+    #[yaserde(text = true)]
+    pub value: String,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wsse",
+    namespaces = {
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+    },
+)]
+pub struct BinarySecurityToken {
+    #[yaserde(rename = "ValueType", attribute = true)]
+    pub value_type: String,
+
+    #[yaserde(rename = "EncodingType", attribute = true)]
+    pub encoding_type: String,
+
+    #[yaserde(text = true)]
+    // NOTE; Base64 encoded, see encoding_type
+    pub value: String,
+}
+
+impl BinarySecurityToken {
+    /// Returns the token contents with all (line-wrapping) whitespace removed, ready for base64 decoding.
+    pub fn compact_value(&self) -> String {
+        self.value.split_whitespace().collect()
+    }
+}
+
+/// Credentials taken from the Security header of a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials<'a> {
+    UsernameToken {
+        username: &'a str,
+        password: &'a str,
+    },
+    SecurityToken {
+        value_type: &'a str,
+        /// Base64 encoded token contents
+        token: String,
+    },
+}
+
+/// Replaces the contents of all Security elements in the XML document, for logging requests without the
+/// credentials they carry.
+///
+/// NOTE; Textual replacement, the document doesn't need to be well-formed
+pub fn redact(document: &str) -> String {
+    let mut redacted = String::with_capacity(document.len());
+    let mut rest = document;
+    while let Some(open) = rest.find('<') {
+        let tag = &rest[open + 1..];
+        let name_length = tag
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(tag.len());
+        let name = &tag[..name_length];
+        // NOTE; Other markup is copied as is, the document is only searched further for Security elements
+        let end = tag
+            .find('>')
+            .filter(|_| name.rsplit(':').next() == Some("Security"));
+        let Some(end) = end else {
+            redacted.push_str(&rest[..open + 1]);
+            rest = tag;
+            continue;
+        };
+        let content = open + 1 + end + 1;
+        redacted.push_str(&rest[..content]);
+        rest = &rest[content..];
+        // NOTE; A self-closing start tag has no content
+        if tag[..end].ends_with('/') {
+            continue;
+        }
+        redacted.push_str("[REDACTED]");
+        // NOTE; Without close tag the remainder of the document is considered content
+        let close = rest.find(&format!("</{name}>")).unwrap_or(rest.len());
+        rest = &rest[close..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_test() {
+        let security = Security {
+            username_token: Some(UsernameToken {
+                username: "user@mdmwindows.com".into(),
+                password: Some(Password {
+                    password_type: Some(PASSWORD_TEXT.into()),
+                    value: "secret".into(),
+                }),
+            }),
+            binary_security_token: Some(BinarySecurityToken::default()),
+        };
+        assert_eq!(
+            security.credentials(),
+            Some(Credentials::UsernameToken {
+                username: "user@mdmwindows.com",
+                password: "secret",
+            })
+        );
+        let digest = Security {
+            username_token: Some(UsernameToken {
+                username: "user@mdmwindows.com".into(),
+                password: Some(Password {
+                    password_type: Some(
+                        "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest"
+                            .into(),
+                    ),
+                    value: "c2VjcmV0".into(),
+                }),
+            }),
+            binary_security_token: None,
+        };
+        assert_eq!(
+            digest.credentials(),
+            Some(Credentials::UsernameToken {
+                username: "user@mdmwindows.com",
+                password: "",
+            })
+        );
+
+        let security = Security {
+            username_token: None,
+            binary_security_token: Some(BinarySecurityToken {
+                value_type: VALUE_TYPE_USER_TOKEN.into(),
+                encoding_type: ENCODING_TYPE_BASE64.into(),
+                value: "dG9r\n ZW4=".into(),
+            }),
+        };
+        assert_eq!(
+            security.credentials(),
+            Some(Credentials::SecurityToken {
+                value_type: VALUE_TYPE_USER_TOKEN,
+                token: "dG9rZW4=".into(),
+            })
+        );
+        assert_eq!(Security::default().credentials(), None);
+    }
+
+    #[test]
+    fn redact_test() {
+        let document = r#"<s:Envelope><s:Header><a:To>https://mdm.example.com</a:To>
+            <wsse:Security s:mustUnderstand="1"><wsse:UsernameToken><wsse:Username>user</wsse:Username>
+            <wsse:Password>secret</wsse:Password></wsse:UsernameToken></wsse:Security></s:Header>
+            <s:Body><SecurityLevel>1</SecurityLevel></s:Body></s:Envelope>"#;
+        let redacted = redact(document);
+
+        assert!(!redacted.contains("secret"));
+        assert!(
+            redacted.contains(r#"<wsse:Security s:mustUnderstand="1">[REDACTED]</wsse:Security>"#)
+        );
+        assert!(redacted.contains("<a:To>https://mdm.example.com</a:To>"));
+        assert!(redacted.contains("<SecurityLevel>1</SecurityLevel>"));
+        assert_eq!(redact("<a>text</a>"), "<a>text</a>");
+    }
+
+    #[test]
+    fn redact_self_closing_test() {
+        let document = r#"<s:Header><wsse:Security s:mustUnderstand="1" /><a:To>https://mdm.example.com</a:To>
+            <wsse:Security><wsse:Password>secret</wsse:Password></wsse:Security></s:Header>"#;
+        let redacted = redact(document);
+
+        assert!(redacted.contains(
+            r#"<wsse:Security s:mustUnderstand="1" /><a:To>https://mdm.example.com</a:To>"#
+        ));
+        assert!(redacted.contains("<wsse:Security>[REDACTED]</wsse:Security></s:Header>"));
+        assert_eq!(
+            redact("<wsse:Security><wsse:Password>secret"),
+            "<wsse:Security>[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn redact_linear_test() {
+        // NOTE; Searching the close tag of every element is quadratic on these documents
+        let document = "<a:To>".repeat(200_000);
+        assert_eq!(redact(&document), document);
+        let document = format!("<wsse:Security>{}", "<a:To>".repeat(200_000));
+        assert_eq!(redact(&document), "<wsse:Security>[REDACTED]");
+    }
+}
@@ -0,0 +1,421 @@
+//! MS-WSTEP protocol types and implementations
+//!
+//! WSTEP (WS-Trust X.509v3 Token Enrollment Extensions) is the enrollment service, used by MDM clients
+//! to submit a certificate signing request (PKCS#10) and receive the provisioning document that
+//! configures the management client.
+//!
+//! REF; https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-wstep/4766a85d-0d18-4fa1-a51f-e5cb98b752ea
+
+use super::soap::SoapAction;
+pub use super::wsse::{BinarySecurityToken, ENCODING_TYPE_BASE64};
+use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
+
+pub const ACTION_REQUEST_SECURITY_TOKEN: &str =
+    "http://schemas.microsoft.com/windows/pki/2009/01/enrollment/RST/wstep";
+pub const ACTION_REQUEST_SECURITY_TOKEN_RESPONSE_COLLECTION: &str =
+    "http://schemas.microsoft.com/windows/pki/2009/01/enrollment/RSTRC/wstep";
+
+pub const TOKEN_TYPE_DEVICE_ENROLLMENT: &str =
+    "http://schemas.microsoft.com/5.0.0.0/ConfigurationManager/Enrollment/DeviceEnrollmentToken";
+pub const REQUEST_TYPE_ISSUE: &str = "http://docs.oasis-open.org/ws-sx/ws-trust/200512/Issue";
+pub const REQUEST_TYPE_RENEW: &str = "http://docs.oasis-open.org/ws-sx/ws-trust/200512/Renew";
+
+pub const VALUE_TYPE_PKCS10: &str =
+    "http://schemas.microsoft.com/windows/pki/2009/01/enrollment#PKCS10";
+pub const VALUE_TYPE_PKCS7: &str =
+    "http://schemas.microsoft.com/windows/pki/2009/01/enrollment#PKCS7";
+pub const VALUE_TYPE_PROVISIONING_DOCUMENT: &str =
+    "http://schemas.microsoft.com/5.0.0.0/ConfigurationManager/Enrollment/DeviceEnrollmentProvisionDoc";
+
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    namespaces = {
+        "wst" = "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+    },
+)]
+pub struct RequestSecurityTokenBody {
+    #[yaserde(prefix = "wst", rename = "RequestSecurityToken")]
+    pub request_security_token: RequestSecurityToken,
+}
+
+impl SoapAction for RequestSecurityTokenBody {
+    const ACTION: &'static str = ACTION_REQUEST_SECURITY_TOKEN;
+}
+
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wst",
+    default_namespace = "wst",
+    namespaces = {
+        "wst" = "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+        "ac" = "http://schemas.xmlsoap.org/ws/2006/12/authorization",
+    },
+)]
+pub struct RequestSecurityToken {
+    #[yaserde(prefix = "wst", rename = "TokenType")]
+    pub token_type: String,
+
+    #[yaserde(prefix = "wst", rename = "RequestType")]
+    pub request_type: String,
+
+    #[yaserde(prefix = "wsse", rename = "BinarySecurityToken")]
+    pub binary_security_token: BinarySecurityToken,
+
+    #[yaserde(prefix = "ac", rename = "AdditionalContext")]
+    pub additional_context: AdditionalContext,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "ac",
+    default_namespace = "ac",
+    namespaces = {
+        "ac" = "http://schemas.xmlsoap.org/ws/2006/12/authorization",
+    },
+)]
+pub struct AdditionalContext {
+    #[yaserde(prefix = "ac", rename = "ContextItem")]
+    pub context_item: Vec<ContextItem>,
+}
+
+impl AdditionalContext {
+    /// Returns the value of the context item with the provided name.
+    ///
+    /// Item names are matched case-sensitive, as sent by the client.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.context_item
+            .iter()
+            .find(|item| item.name == name)
+            .map(|item| item.value.as_str())
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        self.get("DeviceID")
+    }
+
+    pub fn hw_dev_id(&self) -> Option<&str> {
+        self.get("HWDevID")
+    }
+
+    pub fn os_version(&self) -> Option<&str> {
+        self.get("OSVersion")
+    }
+
+    pub fn device_name(&self) -> Option<&str> {
+        self.get("DeviceName")
+    }
+
+    pub fn enrollment_type(&self) -> Option<EnrollmentType> {
+        self.get("EnrollmentType").map(EnrollmentType::from)
+    }
+}
+
+#[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "ac",
+    default_namespace = "ac",
+    namespaces = {
+        "ac" = "http://schemas.xmlsoap.org/ws/2006/12/authorization",
+    },
+)]
+pub struct ContextItem {
+    #[yaserde(rename = "Name", attribute = true)]
+    pub name: String,
+
+    #[yaserde(prefix = "ac", rename = "Value")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum EnrollmentType {
+    /// Enrollment of the full device, the management client controls the entire machine
+    Device,
+    /// Enrollment of the user account only, eg "bring your own device"
+    User,
+    Unknown(String),
+}
+
+impl From<&str> for EnrollmentType {
+    fn from(value: &str) -> Self {
+        match value {
+            // NOTE; "Full" is sent by the windows enrollment client for device enrollment
+            "Full" | "Device" => Self::Device,
+            "User" => Self::User,
+            other => Self::Unknown(other.into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    namespaces = {
+        "wst" = "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+    },
+)]
+pub struct RequestSecurityTokenResponseCollectionBody {
+    #[yaserde(prefix = "wst", rename = "RequestSecurityTokenResponseCollection")]
+    pub collection: RequestSecurityTokenResponseCollection,
+}
+
+impl SoapAction for RequestSecurityTokenResponseCollectionBody {
+    const ACTION: &'static str = ACTION_REQUEST_SECURITY_TOKEN_RESPONSE_COLLECTION;
+}
+
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wst",
+    default_namespace = "wst",
+    namespaces = {
+        "wst" = "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+    },
+)]
+pub struct RequestSecurityTokenResponseCollection {
+    #[yaserde(prefix = "wst", rename = "RequestSecurityTokenResponse")]
+    pub response: RequestSecurityTokenResponse,
+}
+
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wst",
+    default_namespace = "wst",
+    namespaces = {
+        "wst" = "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+        "wstep" = "http://schemas.microsoft.com/windows/pki/2009/01/enrollment",
+    },
+)]
+pub struct RequestSecurityTokenResponse {
+    #[yaserde(prefix = "wst", rename = "TokenType")]
+    pub token_type: String,
+
+    #[yaserde(prefix = "wstep", rename = "DispositionMessage")]
+    pub disposition_message: Option<String>,
+
+    #[yaserde(prefix = "wst", rename = "RequestedSecurityToken")]
+    pub requested_security_token: RequestedSecurityToken,
+
+    #[yaserde(prefix = "wstep", rename = "RequestID")]
+    pub request_id: u32,
+}
+
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    prefix = "wst",
+    default_namespace = "wst",
+    namespaces = {
+        "wst" = "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+        "wsse" = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd",
+    },
+)]
+pub struct RequestedSecurityToken {
+    #[yaserde(prefix = "wsse", rename = "BinarySecurityToken")]
+    pub binary_security_token: BinarySecurityToken,
+}
+
+/// The OMA client provisioning document, returned (base64 encoded) as the requested security token.
+///
+/// REF; https://learn.microsoft.com/en-us/windows/client-management/mdm-enrollment#enrollment-protocol
+pub mod provisioning {
+    use super::*;
+
+    #[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+    #[yaserde(rename = "wap-provisioningdoc")]
+    pub struct WapProvisioningDoc {
+        #[yaserde(rename = "version", attribute = true)]
+        pub version: String,
+
+        #[yaserde(rename = "characteristic")]
+        pub characteristic: Vec<Characteristic>,
+    }
+
+    #[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+    #[yaserde(rename = "characteristic")]
+    pub struct Characteristic {
+        #[yaserde(rename = "type", attribute = true)]
+        pub characteristic_type: String,
+
+        #[yaserde(rename = "parm")]
+        pub parm: Vec<Parm>,
+
+        #[yaserde(rename = "characteristic")]
+        pub characteristic: Vec<Characteristic>,
+    }
+
+    impl Characteristic {
+        pub fn new(characteristic_type: impl Into<String>) -> Self {
+            Self {
+                characteristic_type: characteristic_type.into(),
+                ..Default::default()
+            }
+        }
+
+        pub fn with_parm(mut self, parm: Parm) -> Self {
+            self.parm.push(parm);
+            self
+        }
+
+        pub fn with_characteristic(mut self, characteristic: Characteristic) -> Self {
+            self.characteristic.push(characteristic);
+            self
+        }
+    }
+
+    #[derive(Default, Clone, PartialEq, Debug, YaSerialize, YaDeserialize)]
+    #[yaserde(rename = "parm")]
+    pub struct Parm {
+        #[yaserde(rename = "name", attribute = true)]
+        pub name: String,
+
+        #[yaserde(rename = "value", attribute = true)]
+        pub value: Option<String>,
+
+        #[yaserde(rename = "datatype", attribute = true)]
+        pub datatype: Option<String>,
+    }
+
+    impl Parm {
+        pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+            Self {
+                name: name.into(),
+                value: Some(value.into()),
+                datatype: None,
+            }
+        }
+
+        /// A parameter without value, its presence is the setting.
+        pub fn flag(name: impl Into<String>) -> Self {
+            Self {
+                name: name.into(),
+                value: None,
+                datatype: None,
+            }
+        }
+
+        pub fn typed(
+            name: impl Into<String>,
+            value: impl Into<String>,
+            datatype: impl Into<String>,
+        ) -> Self {
+            Self {
+                name: name.into(),
+                value: Some(value.into()),
+                datatype: Some(datatype.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microsoft_protocol::{soap::SoapEnvelope, wsa, wsse};
+
+    const ENROLLMENT_REQUEST: &str = r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:a="http://www.w3.org/2005/08/addressing" xmlns:u="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd" xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd" xmlns:wst="http://docs.oasis-open.org/ws-sx/ws-trust/200512" xmlns:ac="http://schemas.xmlsoap.org/ws/2006/12/authorization">
+    <s:Header>
+        <a:Action s:mustUnderstand="1">http://schemas.microsoft.com/windows/pki/2009/01/enrollment/RST/wstep</a:Action>
+        <a:MessageID>urn:uuid:0d5a1441-5891-453b-becf-a2e5f6ea3749</a:MessageID>
+        <a:ReplyTo>
+            <a:Address>http://www.w3.org/2005/08/addressing/anonymous</a:Address>
+        </a:ReplyTo>
+        <a:To s:mustUnderstand="1">https://mdmwindows.com/EnrollmentServer/Enrollment.svc</a:To>
+        <wsse:Security s:mustUnderstand="1">
+            <wsse:UsernameToken u:Id="uuid-cc1ccc1f-2fba-4bcf-b063-ffc0cac77917-4">
+                <wsse:Username>user@mdmwindows.com</wsse:Username>
+                <wsse:Password wsse:Type="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordText">secret</wsse:Password>
+            </wsse:UsernameToken>
+        </wsse:Security>
+    </s:Header>
+    <s:Body>
+        <wst:RequestSecurityToken>
+            <wst:TokenType>http://schemas.microsoft.com/5.0.0.0/ConfigurationManager/Enrollment/DeviceEnrollmentToken</wst:TokenType>
+            <wst:RequestType>http://docs.oasis-open.org/ws-sx/ws-trust/200512/Issue</wst:RequestType>
+            <wsse:BinarySecurityToken ValueType="http://schemas.microsoft.com/windows/pki/2009/01/enrollment#PKCS10" EncodingType="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd#base64binary">MIICzjCCAboCAQAw</wsse:BinarySecurityToken>
+            <ac:AdditionalContext xmlns="http://schemas.xmlsoap.org/ws/2006/12/authorization">
+                <ac:ContextItem Name="OSEdition">
+                    <ac:Value>4</ac:Value>
+                </ac:ContextItem>
+                <ac:ContextItem Name="OSVersion">
+                    <ac:Value>10.0.22631.4460</ac:Value>
+                </ac:ContextItem>
+                <ac:ContextItem Name="DeviceName">
+                    <ac:Value>DESKTOP-TEST</ac:Value>
+                </ac:ContextItem>
+                <ac:ContextItem Name="EnrollmentType">
+                    <ac:Value>Full</ac:Value>
+                </ac:ContextItem>
+                <ac:ContextItem Name="DeviceID">
+                    <ac:Value>AB157C3A18B74A6B9C5DF4E6F5C7A1B2</ac:Value>
+                </ac:ContextItem>
+                <ac:ContextItem Name="HWDevID">
+                    <ac:Value>E8B1C9F1D5A24F2E8D7C6B5A4F3E2D1C0B9A8F7E6D5C4B3A2F1E0D9C8B7A6F5E</ac:Value>
+                </ac:ContextItem>
+            </ac:AdditionalContext>
+        </wst:RequestSecurityToken>
+    </s:Body>
+</s:Envelope>"#;
+
+    #[test]
+    fn enrollment_request_deserialize_test() {
+        let request: SoapEnvelope<RequestSecurityTokenBody, wsa::RequestHeader> =
+            yaserde::de::from_str(ENROLLMENT_REQUEST).unwrap();
+
+        assert_eq!(request.header.action, ACTION_REQUEST_SECURITY_TOKEN);
+        assert_eq!(
+            request.header.message_id.as_deref(),
+            Some("urn:uuid:0d5a1441-5891-453b-becf-a2e5f6ea3749")
+        );
+        let security = request.header.security.unwrap();
+        assert_eq!(
+            security.credentials(),
+            Some(wsse::Credentials::UsernameToken {
+                username: "user@mdmwindows.com",
+                password: "secret",
+            })
+        );
+
+        let token = request.body.request_security_token;
+        assert_eq!(token.request_type, REQUEST_TYPE_ISSUE);
+        assert_eq!(token.binary_security_token.value_type, VALUE_TYPE_PKCS10);
+        assert_eq!(
+            token.binary_security_token.compact_value(),
+            "MIICzjCCAboCAQAw"
+        );
+
+        let context = token.additional_context;
+        assert_eq!(
+            context.device_id(),
+            Some("AB157C3A18B74A6B9C5DF4E6F5C7A1B2")
+        );
+        assert_eq!(context.os_version(), Some("10.0.22631.4460"));
+        assert_eq!(context.enrollment_type(), Some(EnrollmentType::Device));
+        assert!(context.hw_dev_id().is_some());
+        assert_eq!(context.get("MAC"), None);
+    }
+
+    #[test]
+    fn provisioning_document_serialize_test() {
+        use provisioning::*;
+
+        let document = WapProvisioningDoc {
+            version: "1.1".into(),
+            characteristic: vec![Characteristic::new("APPLICATION")
+                .with_parm(Parm::new("APPID", "w7"))
+                .with_parm(Parm::flag("BACKCOMPATRETRYDISABLED"))],
+        };
+
+        let xml = yaserde::ser::to_string_with_config(
+            &document,
+            &yaserde::ser::Config {
+                perform_indent: false,
+                write_document_declaration: false,
+                indent_string: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            xml,
+            r#"<wap-provisioningdoc version="1.1"><characteristic type="APPLICATION"><parm name="APPID" value="w7" /><parm name="BACKCOMPATRETRYDISABLED" /></characteristic></wap-provisioningdoc>"#
+        );
+    }
+}
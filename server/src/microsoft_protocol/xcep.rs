@@ -4,9 +4,15 @@
 //!
 //! Not to be confused with SCEP, which is a platform independent standard for doing the same over an HTTP api.
 
+use super::soap::SoapAction;
 use yaserde::{YaDeserialize, YaSerialize}; // Traits
 use yaserde_derive::{YaDeserialize, YaSerialize}; // Proc-macro's
 
+pub const ACTION_GET_POLICIES: &str =
+    "http://schemas.microsoft.com/windows/pki/2009/01/enrollmentpolicy/IPolicy/GetPolicies";
+pub const ACTION_GET_POLICIES_RESPONSE: &str =
+    "http://schemas.microsoft.com/windows/pki/2009/01/enrollmentpolicy/IPolicy/GetPoliciesResponse";
+
 #[derive(Clone, Debug, YaSerialize, YaDeserialize)]
 #[yaserde(
     prefix = "xcep",
@@ -20,6 +26,10 @@ pub struct GetPoliciesRequestBody {
     pub get_policies: GetPolicies,
 }
 
+impl SoapAction for GetPoliciesRequestBody {
+    const ACTION: &'static str = ACTION_GET_POLICIES;
+}
+
 #[derive(Clone, Debug, YaSerialize, YaDeserialize)]
 #[yaserde(
     prefix = "xcep",
@@ -37,6 +47,21 @@ pub struct GetPolicies {
 
 // impl Validate for GetPolicies {}
 
+#[derive(Clone, Debug, YaSerialize, YaDeserialize)]
+#[yaserde(
+    namespaces = {
+        "xcep" = "http://schemas.microsoft.com/windows/pki/2009/01/enrollmentpolicy",
+    },
+)]
+pub struct GetPoliciesResponseBody {
+    #[yaserde(prefix = "xcep", rename = "GetPoliciesResponse")]
+    pub get_policies_response: GetPoliciesResponse,
+}
+
+impl SoapAction for GetPoliciesResponseBody {
+    const ACTION: &'static str = ACTION_GET_POLICIES_RESPONSE;
+}
+
 #[derive(Clone, Debug, YaSerialize, YaDeserialize)]
 #[yaserde(prefix = "xcep",
 default_namespace = "xcep",
@@ -0,0 +1,344 @@
+//! Environments served by this server
+//!
+//! Every tenant has its own configuration, certificate authority, device registry, policies and credentials.
+//! Requests are assigned to a tenant by their Host header, discovery requests also by the email domain of the
+//! enrolling user. Without configured tenants all requests are served by a single default tenant.
+
+use crate::admin::DashboardSessions;
+use crate::authentication::{self, Authenticators, PasswordFileAuthenticator};
+use crate::certificate_authority::{self, CertificateAuthority};
+use crate::command_queue::{self, CommandQueue, MemoryCommandQueue, SqliteCommandQueue};
+use crate::config::{authority_host, ServerConfig};
+use crate::csp::ddf::{self, Definitions};
+use crate::device_store::{self, DeviceStore, MemoryDeviceStore, SqliteDeviceStore};
+use crate::enrollment_policy::PolicySet;
+use crate::management::{profile, DriftMonitor, ProfileStore, SessionManager};
+use crate::AppState;
+use axum::{
+    extract::FromRequestParts,
+    http::{header::HOST, request::Parts, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Name of the tenant serving all requests when no tenants are configured.
+pub const DEFAULT_TENANT: &str = "default";
+
+#[derive(Debug)]
+pub enum Error {
+    CertificateAuthority(String, certificate_authority::Error),
+    DeviceStore(String, device_store::Error),
+    CommandQueue(String, command_queue::Error),
+    Authentication(String, authentication::Error),
+    Definitions(ddf::Error),
+    Profiles(String, profile::Error),
+    /// A host name or email domain is claimed by multiple tenants
+    Conflict(String),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::CertificateAuthority(tenant, error) => write!(f, "tenant {tenant}: {error}"),
+            Error::DeviceStore(tenant, error) => write!(f, "tenant {tenant}: {error}"),
+            Error::CommandQueue(tenant, error) => write!(f, "tenant {tenant}: {error}"),
+            Error::Authentication(tenant, error) => write!(f, "tenant {tenant}: {error}"),
+            Error::Definitions(error) => write!(f, "CSP definitions: {error}"),
+            Error::Profiles(tenant, error) => write!(f, "tenant {tenant}: {error}"),
+            Error::Conflict(name) => write!(f, "{name} is used by multiple tenants"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tenant {
+    pub name: String,
+    pub config: Arc<ServerConfig>,
+    pub certificate_authority: Arc<CertificateAuthority>,
+    pub enrollment_policy: Arc<PolicySet>,
+    pub management_sessions: Arc<SessionManager>,
+    pub device_store: Arc<dyn DeviceStore>,
+    pub command_queue: Arc<dyn CommandQueue>,
+    pub profiles: Arc<ProfileStore>,
+    pub drift_monitor: Arc<DriftMonitor>,
+    pub authenticator: Arc<Authenticators>,
+    /// Administrators of the admin API, `None` without admin password file
+    pub admin_users: Option<Arc<PasswordFileAuthenticator>>,
+    /// Administrators signed in at the dashboard
+    pub admin_sessions: Arc<DashboardSessions>,
+}
+
+impl Tenant {
+    pub fn open(
+        name: &str,
+        config: ServerConfig,
+        definitions: Option<Arc<Definitions>>,
+    ) -> Result<Self, Error> {
+        let device_store: Arc<dyn DeviceStore> = match &config.database {
+            Some(path) => Arc::new(
+                SqliteDeviceStore::open(path).map_err(|e| Error::DeviceStore(name.into(), e))?,
+            ),
+            None => {
+                warn!("No database configured, the device registry of tenant {name} is lost on restart");
+                Arc::new(MemoryDeviceStore::new())
+            }
+        };
+        let command_queue: Arc<dyn CommandQueue> = match &config.database {
+            Some(path) => Arc::new(
+                SqliteCommandQueue::open(path).map_err(|e| Error::CommandQueue(name.into(), e))?,
+            ),
+            None => Arc::new(MemoryCommandQueue::new()),
+        };
+        let certificate_authority = Arc::new(
+            CertificateAuthority::load_or_generate(&config.authority_options())
+                .map_err(|e| Error::CertificateAuthority(name.into(), e))?,
+        );
+        let authenticator = Authenticators::from_config(&config, certificate_authority.clone())
+            .map_err(|e| Error::Authentication(name.into(), e))?;
+        let management_sessions = match definitions {
+            Some(definitions) => SessionManager::with_definitions(definitions),
+            None => SessionManager::new(),
+        };
+        let admin_users = match PasswordFileAuthenticator::open(&config.admin_password_file) {
+            Ok(admin_users) => Some(Arc::new(admin_users)),
+            Err(authentication::Error::Io(path, error))
+                if error.kind() == std::io::ErrorKind::NotFound =>
+            {
+                warn!(
+                    "No admin password file at {}, tenant {name} doesn't serve the admin API",
+                    path.display()
+                );
+                None
+            }
+            Err(error) => return Err(Error::Authentication(name.into(), error)),
+        };
+        let drift_monitor = DriftMonitor::new(&config.drift);
+        let enrollment_policy = config.enrollment_policy();
+        let profiles =
+            ProfileStore::open(&config.profiles).map_err(|e| Error::Profiles(name.into(), e))?;
+
+        Ok(Self {
+            name: name.into(),
+            config: Arc::new(config),
+            certificate_authority,
+            enrollment_policy: Arc::new(enrollment_policy),
+            management_sessions: Arc::new(management_sessions),
+            device_store,
+            command_queue,
+            profiles: Arc::new(profiles),
+            drift_monitor: Arc::new(drift_monitor),
+            authenticator: Arc::new(authenticator),
+            admin_users,
+            admin_sessions: Arc::new(DashboardSessions::default()),
+        })
+    }
+}
+
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+    /// Tenant index per lowercase host name
+    hosts: HashMap<String, usize>,
+    /// Tenant index per lowercase email domain
+    domains: HashMap<String, usize>,
+    /// Serves requests for unknown hosts, only without configured tenants
+    fallback: Option<usize>,
+}
+
+impl Tenants {
+    pub fn open(config: &ServerConfig) -> Result<Self, Error> {
+        // NOTE; All tenants manage the same kind of devices, the definitions are shared
+        let definitions = match &config.csp_definitions {
+            Some(directory) => Some(Arc::new(
+                Definitions::load_directory(directory).map_err(Error::Definitions)?,
+            )),
+            None => None,
+        };
+        if config.tenants.is_empty() {
+            return Ok(Self {
+                tenants: vec![Tenant::open(DEFAULT_TENANT, config.clone(), definitions)?],
+                hosts: HashMap::new(),
+                domains: HashMap::new(),
+                fallback: Some(0),
+            });
+        }
+
+        let mut tenants = Self {
+            tenants: Vec::new(),
+            hosts: HashMap::new(),
+            domains: HashMap::new(),
+            fallback: None,
+        };
+        for tenant_config in &config.tenants {
+            let index = tenants.tenants.len();
+            let tenant = Tenant::open(
+                &tenant_config.name,
+                config.tenant_config(tenant_config),
+                definitions.clone(),
+            )?;
+
+            // NOTE; Windows discovers the enrollment server at EnterpriseEnrollment.{email domain}
+            let discovery_hosts = tenant_config
+                .domains
+                .iter()
+                .map(|domain| format!("enterpriseenrollment.{domain}"));
+            let hosts = (tenant_config.hosts.iter().cloned())
+                .chain(tenant.config.external_host().map(Into::into))
+                .chain(discovery_hosts);
+            for host in hosts {
+                let previous = tenants.hosts.insert(host.to_lowercase(), index);
+                if previous.is_some_and(|previous| previous != index) {
+                    return Err(Error::Conflict(host));
+                }
+            }
+            for domain in &tenant_config.domains {
+                if tenants
+                    .domains
+                    .insert(domain.to_lowercase(), index)
+                    .is_some()
+                {
+                    return Err(Error::Conflict(domain.clone()));
+                }
+            }
+            tenants.tenants.push(tenant);
+        }
+        Ok(tenants)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        self.tenants.iter()
+    }
+
+    /// Host names assigned to the tenants, lowercase.
+    pub fn hosts(&self) -> impl Iterator<Item = (&str, &Tenant)> {
+        self.hosts
+            .iter()
+            .map(|(host, index)| (host.as_str(), &self.tenants[*index]))
+    }
+
+    pub fn by_host(&self, host: &str) -> Option<&Tenant> {
+        self.hosts
+            .get(&host.to_lowercase())
+            .or(self.fallback.as_ref())
+            .map(|index| &self.tenants[*index])
+    }
+
+    pub fn by_email_domain(&self, domain: &str) -> Option<&Tenant> {
+        self.domains
+            .get(&domain.to_lowercase())
+            .map(|index| &self.tenants[*index])
+    }
+
+    /// Tenant addressed by the request.
+    pub fn by_request(&self, headers: &HeaderMap, uri: &Uri) -> Option<&Tenant> {
+        match request_host(headers, uri) {
+            Some(host) => self.by_host(host),
+            None => self.fallback.map(|index| &self.tenants[index]),
+        }
+    }
+}
+
+/// Host name the request was sent to, HTTP/2 requests carry it within the uri.
+pub fn request_host<'a>(headers: &'a HeaderMap, uri: &'a Uri) -> Option<&'a str> {
+    headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(authority_host)
+        .or_else(|| uri.host())
+}
+
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        match state.tenants.by_request(&parts.headers, &parts.uri) {
+            Some(tenant) => Ok(tenant.clone()),
+            None => {
+                eprintln!(
+                    "Request for unknown host {:?}",
+                    request_host(&parts.headers, &parts.uri)
+                );
+                Err((StatusCode::MISDIRECTED_REQUEST, "Unknown host").into_response())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthPolicy, TenantConfig};
+    use crate::test_support::TempDir;
+
+    fn tenant_config(name: &str) -> TenantConfig {
+        TenantConfig {
+            name: name.into(),
+            hosts: Vec::new(),
+            domains: Vec::new(),
+            directory: None,
+            external_url: None,
+            auth_policies: Some(vec![AuthPolicy::Certificate]),
+            allowed_os_editions: None,
+            authentication_service_url: None,
+            certificate_authority_name: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn resolve_test() {
+        let directory = TempDir::new("tenants");
+        let mut config = ServerConfig {
+            database: None,
+            ..Default::default()
+        };
+        let mut contoso = tenant_config("contoso");
+        contoso.domains = vec!["contoso.com".into()];
+        contoso.external_url = Some("https://mdm.contoso.com".into());
+        contoso.directory = Some(directory.join("contoso"));
+        let mut fabrikam = tenant_config("fabrikam");
+        fabrikam.hosts = vec!["mdm.fabrikam.com".into()];
+        fabrikam.directory = Some(directory.join("fabrikam"));
+        config.tenants = vec![contoso, fabrikam];
+
+        let tenants = Tenants::open(&config).unwrap();
+
+        assert_eq!(tenants.by_host("MDM.contoso.com").unwrap().name, "contoso");
+        assert_eq!(
+            tenants
+                .by_host("enterpriseenrollment.contoso.com")
+                .unwrap()
+                .name,
+            "contoso"
+        );
+        assert_eq!(
+            tenants.by_email_domain("Contoso.com").unwrap().name,
+            "contoso"
+        );
+        assert_eq!(
+            tenants.by_host("mdm.fabrikam.com").unwrap().name,
+            "fabrikam"
+        );
+        assert!(tenants.by_host("mdm.example.com").is_none());
+
+        let contoso = tenants.by_host("mdm.contoso.com").unwrap();
+        let fabrikam = tenants.by_host("mdm.fabrikam.com").unwrap();
+        assert_ne!(
+            contoso.certificate_authority.root_der(),
+            fabrikam.certificate_authority.root_der()
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, "mdm.fabrikam.com:443".parse().unwrap());
+        let tenant = tenants
+            .by_request(&headers, &Uri::from_static("/"))
+            .unwrap();
+        assert_eq!(tenant.name, "fabrikam");
+    }
+}
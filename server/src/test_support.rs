@@ -0,0 +1,106 @@
+//! Fixtures shared by the tests of several modules
+
+use crate::config::{AuthPolicy, ServerConfig};
+use crate::tenant::Tenants;
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::Argon2;
+use std::path::{Path, PathBuf};
+
+/// Directory for the files of a test, removed when dropped so failing tests don't leave it behind.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates an empty directory, named after the test and unique per process.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("simple_mdm_{name}_{}", std::process::id()));
+        // NOTE; Leftover of an aborted run
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Argon2 hash of the password, as listed in the password files.
+pub fn password_hash(password: &str) -> String {
+    // NOTE; A fixed salt keeps the tests deterministic
+    let salt = SaltString::from_b64("c2ltcGxlX21kbV9zYWx0").unwrap();
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Builds a single tenant keeping all its files within a temporary directory, devices are registered in memory.
+///
+/// The tenant accepts the Certificate policy unless configured otherwise.
+pub struct TenantBuilder {
+    directory: TempDir,
+    config: ServerConfig,
+}
+
+impl TenantBuilder {
+    pub fn new(name: &str) -> Self {
+        let directory = TempDir::new(name);
+        let mut config = ServerConfig {
+            database: None,
+            admin_password_file: directory.join("admin_users"),
+            profiles: directory.join("profiles"),
+            ..Default::default()
+        };
+        config.enrollment.auth_policies = vec![AuthPolicy::Certificate];
+        config.enrollment.password_file = directory.join("enrollment_users");
+        config.enrollment.token_file = directory.join("enrollment_tokens");
+        config.certificate_authority.directory = Some(directory.join("certificate_authority"));
+        Self { directory, config }
+    }
+
+    pub fn auth_policies(mut self, auth_policies: &[AuthPolicy]) -> Self {
+        self.config.enrollment.auth_policies = auth_policies.to_vec();
+        self
+    }
+
+    /// Adds the user to the administrators.
+    pub fn admin(self, username: &str, password: &str) -> Self {
+        append_user(&self.config.admin_password_file, username, password);
+        self
+    }
+
+    /// Adds the user to the users allowed to enroll.
+    pub fn enrollment_user(self, username: &str, password: &str) -> Self {
+        append_user(&self.config.enrollment.password_file, username, password);
+        self
+    }
+
+    /// Opens the tenant, its files are removed once the returned directory drops.
+    pub fn build(self) -> (TempDir, Tenants) {
+        // NOTE; The OnPremise and Federated authenticators refuse to start without password file
+        let password_file = &self.config.enrollment.password_file;
+        if !password_file.exists() {
+            std::fs::write(password_file, "").unwrap();
+        }
+        let tenants = Tenants::open(&self.config).unwrap();
+        (self.directory, tenants)
+    }
+}
+
+fn append_user(path: &Path, username: &str, password: &str) {
+    let mut contents = std::fs::read_to_string(path).unwrap_or_default();
+    contents.push_str(&format!("{username}:{}\n", password_hash(password)));
+    std::fs::write(path, contents).unwrap();
+}
@@ -0,0 +1,580 @@
+//! TLS termination of the device and admin listeners
+//!
+//! The server certificate is chosen by the SNI host name, tenants can bring their own certificate for their hosts.
+//! Certificate files are reloaded when they change on disk or the process receives SIGHUP, a failing reload
+//! keeps serving the previous certificates.
+//!
+//! The device listeners ask clients for a certificate issued by one of the tenant authorities. The certificate is
+//! optional at the TLS layer, devices enroll before they have one. The verified certificate of a connection is
+//! attached to each of its requests as [`ClientIdentity`] extension, handlers decide whether they require it.
+
+use crate::certificate_authority::{thumbprint, CertificateAuthority};
+use crate::config::TlsConfig;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::info;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Interval at which the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    /// The certificate file holds no certificates
+    MissingCertificate(PathBuf),
+    Tls(rustls::Error),
+    ClientVerifier(rustls::server::VerifierBuilderError),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(path, error) => write!(f, "{}: {error}", path.display()),
+            Error::Pem(path, error) => write!(f, "{}: {error}", path.display()),
+            Error::MissingCertificate(path) => {
+                write!(f, "{}: no certificate found", path.display())
+            }
+            Error::Tls(error) => write!(f, "TLS configuration: {error}"),
+            Error::ClientVerifier(error) => write!(f, "client verification: {error}"),
+        }
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(value: rustls::Error) -> Self {
+        Error::Tls(value)
+    }
+}
+
+/// Identity of the device presenting a client certificate, verified against the tenant authorities.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    /// Hex encoded SHA-1 hash of the certificate, as recorded by the certificate store
+    pub thumbprint: String,
+    /// Common name of the certificate subject, the DeviceID the certificate was issued to
+    pub device_id: String,
+    /// DER encoding of the client certificate
+    pub der: Vec<u8>,
+}
+
+impl ClientIdentity {
+    /// Identity of a verified certificate, `None` when the subject carries no common name.
+    pub fn from_der(der: Vec<u8>) -> Option<Self> {
+        let (_, certificate) = X509Certificate::from_der(&der).ok()?;
+        let device_id = certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())?
+            .to_string();
+        Some(Self {
+            thumbprint: thumbprint(&der),
+            device_id,
+            der,
+        })
+    }
+}
+
+/// Server certificates per SNI host name, reloadable from their files.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    provider: Arc<CryptoProvider>,
+    /// Certificate files, the first one serves unknown host names and clients without SNI
+    sources: Vec<TlsConfig>,
+    /// Index into `sources` per lowercase host name
+    hosts: HashMap<String, usize>,
+    /// Loaded certificates, in the order of `sources`
+    certificates: RwLock<Vec<Arc<CertifiedKey>>>,
+    /// Modification times of the files at the last load
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl CertificateResolver {
+    /// Loads the default certificate and the certificates of the listed host names.
+    pub fn load(
+        default: &TlsConfig,
+        host_certificates: impl IntoIterator<Item = (String, TlsConfig)>,
+    ) -> Result<Self, Error> {
+        let mut sources = vec![default.clone()];
+        let mut hosts = HashMap::new();
+        for (host, config) in host_certificates {
+            let index = match sources.iter().position(|source| *source == config) {
+                Some(index) => index,
+                None => {
+                    sources.push(config);
+                    sources.len() - 1
+                }
+            };
+            hosts.insert(host.to_lowercase(), index);
+        }
+
+        let resolver = Self {
+            provider: Arc::new(ring::default_provider()),
+            sources,
+            hosts,
+            certificates: RwLock::new(Vec::new()),
+            modified: Mutex::new(Vec::new()),
+        };
+        resolver.reload()?;
+        Ok(resolver)
+    }
+
+    /// Loads all certificate files again, the current certificates stay in use when any file fails to load.
+    pub fn reload(&self) -> Result<(), Error> {
+        let modified = self.modification_times();
+        let certificates = self
+            .sources
+            .iter()
+            .map(|source| self.load_certificate(source).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        *self.certificates.write().unwrap() = certificates;
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Reloads the certificates when any of their files changed since the last load.
+    pub fn reload_if_modified(&self) -> Result<bool, Error> {
+        if *self.modified.lock().unwrap() == self.modification_times() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Reloads the certificates on SIGHUP and when their files change, until the process ends.
+    ///
+    /// NOTE; Platforms without SIGHUP only reload when the files change
+    pub async fn watch(self: Arc<Self>) {
+        let mut hangup = Hangup::listen();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            let reloaded = tokio::select! {
+                () = hangup.recv() => self.reload().map(|()| true),
+                _ = interval.tick() => self.reload_if_modified(),
+            };
+            match reloaded {
+                Ok(true) => info!("Reloaded TLS certificates"),
+                Ok(false) => {}
+                Err(err) => eprintln!("Error reloading TLS certificates: {}", err),
+            }
+        }
+    }
+
+    fn load_certificate(&self, source: &TlsConfig) -> Result<CertifiedKey, Error> {
+        let chain = CertificateDer::pem_file_iter(&source.certificate)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|error| pem_error(&source.certificate, error))?;
+        if chain.is_empty() {
+            return Err(Error::MissingCertificate(source.certificate.clone()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&source.private_key)
+            .map_err(|error| pem_error(&source.private_key, error))?;
+        Ok(CertifiedKey::from_der(chain, key, &self.provider)?)
+    }
+
+    fn modification_times(&self) -> Vec<Option<SystemTime>> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        self.sources
+            .iter()
+            .flat_map(|source| [modified(&source.certificate), modified(&source.private_key)])
+            .collect()
+    }
+}
+
+/// Receiver of SIGHUP, which never arrives on platforms without signals.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn listen() -> Self {
+        #[cfg(unix)]
+        let signal = match signal(SignalKind::hangup()) {
+            Ok(signal) => Some(signal),
+            Err(err) => {
+                eprintln!("Error listening for SIGHUP: {}", err);
+                None
+            }
+        };
+        Self {
+            #[cfg(unix)]
+            signal,
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+            self.signal = None;
+        }
+        std::future::pending().await
+    }
+}
+
+fn pem_error(path: &Path, error: rustls::pki_types::pem::Error) -> Error {
+    match error {
+        rustls::pki_types::pem::Error::Io(error) => Error::Io(path.to_path_buf(), error),
+        error => Error::Pem(path.to_path_buf(), error),
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let index = client_hello
+            .server_name()
+            .and_then(|name| self.hosts.get(&name.to_lowercase()))
+            .copied()
+            .unwrap_or(0);
+        self.certificates.read().unwrap().get(index).cloned()
+    }
+}
+
+/// Accepts device certificates that chain to a tenant authority and were recorded by it.
+struct DeviceCertificateVerifier {
+    chain: Arc<dyn ClientCertVerifier>,
+    authorities: Vec<Arc<CertificateAuthority>>,
+}
+
+impl std::fmt::Debug for DeviceCertificateVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceCertificateVerifier")
+            .field("authorities", &self.authorities.len())
+            .finish()
+    }
+}
+
+impl ClientCertVerifier for DeviceCertificateVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.chain.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.chain
+            .verify_client_cert(end_entity, intermediates, now)?;
+        // NOTE; Revocation is left to the handlers, revoked devices still reach the enrollment endpoints
+        let thumbprint = thumbprint(end_entity);
+        let recorded = self
+            .authorities
+            .iter()
+            .any(|authority| authority.store().find_by_thumbprint(&thumbprint).is_some());
+        if !recorded {
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.chain.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.chain.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.chain.supported_verify_schemes()
+    }
+}
+
+/// Acceptor asking for client certificates issued by any of the authorities.
+pub fn device_acceptor(
+    resolver: Arc<CertificateResolver>,
+    authorities: impl IntoIterator<Item = Arc<CertificateAuthority>>,
+) -> Result<TlsAcceptor, Error> {
+    let authorities: Vec<_> = authorities.into_iter().collect();
+    let mut roots = RootCertStore::empty();
+    for authority in &authorities {
+        // NOTE; Devices only present their own certificate, intermediates are trusted directly
+        let chain = std::iter::once(authority.root_der()).chain(
+            authority
+                .intermediates_der()
+                .iter()
+                .map(|der| der.as_slice()),
+        );
+        for der in chain {
+            roots.add(CertificateDer::from(der.to_vec()))?;
+        }
+    }
+    let chain =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), resolver.provider.clone())
+            .allow_unauthenticated()
+            .build()
+            .map_err(Error::ClientVerifier)?;
+    let verifier = DeviceCertificateVerifier { chain, authorities };
+
+    let config = rustls::ServerConfig::builder_with_provider(resolver.provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(Arc::new(verifier))
+        .with_cert_resolver(resolver);
+    Ok(acceptor(config))
+}
+
+/// Acceptor without client authentication, browsers would otherwise prompt for a certificate.
+pub fn admin_acceptor(resolver: Arc<CertificateResolver>) -> Result<TlsAcceptor, Error> {
+    let config = rustls::ServerConfig::builder_with_provider(resolver.provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    Ok(acceptor(config))
+}
+
+fn acceptor(mut config: rustls::ServerConfig) -> TlsAcceptor {
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Performs the TLS handshake, returns the stream with the verified client identity if one was presented.
+pub async fn accept<S>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+) -> std::io::Result<(TlsStream<S>, Option<ClientIdentity>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = acceptor.accept(stream).await?;
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .and_then(|certificate| ClientIdentity::from_der(certificate.to_vec()));
+    Ok((stream, identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate_authority::{AuthorityOptions, IssuedCertificate};
+    use crate::test_support::TempDir;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    fn issue(authority: &CertificateAuthority, device_id: &str) -> (IssuedCertificate, KeyPair) {
+        let key_pair = KeyPair::generate().unwrap();
+        let request = CertificateParams::default()
+            .serialize_request(&key_pair)
+            .unwrap();
+        let issued = authority.sign_request(request.der(), device_id).unwrap();
+        (issued, key_pair)
+    }
+
+    /// Issuer of the server certificates, trusted by the test clients.
+    fn server_issuer() -> (Certificate, KeyPair) {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Server CA");
+        (params.self_signed(&key_pair).unwrap(), key_pair)
+    }
+
+    /// Writes a server certificate for the host into the directory.
+    fn write_server_certificate(
+        directory: &Path,
+        name: &str,
+        host: &str,
+        issuer: &(Certificate, KeyPair),
+    ) -> TlsConfig {
+        let key_pair = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![host.into()])
+            .unwrap()
+            .signed_by(&key_pair, &issuer.0, &issuer.1)
+            .unwrap();
+        let config = TlsConfig {
+            certificate: directory.join(format!("{name}.crt")),
+            private_key: directory.join(format!("{name}.key")),
+        };
+        std::fs::write(&config.certificate, certificate.pem()).unwrap();
+        std::fs::write(&config.private_key, key_pair.serialize_pem()).unwrap();
+        config
+    }
+
+    /// Outcome of a handshake, the identity the server sees and the server certificate the client got.
+    async fn handshake(
+        acceptor: &TlsAcceptor,
+        issuer: &Certificate,
+        host: &str,
+        client: Option<(&IssuedCertificate, &KeyPair)>,
+    ) -> (std::io::Result<Option<ClientIdentity>>, Vec<u8>) {
+        let mut roots = RootCertStore::empty();
+        roots.add(issuer.der().clone()).unwrap();
+        let builder =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = match client {
+            Some((issued, key_pair)) => builder
+                .with_client_auth_cert(
+                    vec![CertificateDer::from(issued.der.clone())],
+                    PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+        let server_name = ServerName::try_from(host.to_string()).unwrap();
+
+        let (client_stream, server_stream) = tokio::io::duplex(16 * 1024);
+        // NOTE; TLS 1.3 clients finish the handshake before the server judges their certificate
+        let (connected, accepted) = tokio::join!(
+            connector.connect(server_name, client_stream),
+            accept(acceptor, server_stream)
+        );
+        let server_certificate =
+            connected.unwrap().get_ref().1.peer_certificates().unwrap()[0].to_vec();
+        (accepted.map(|(_, identity)| identity), server_certificate)
+    }
+
+    #[tokio::test]
+    async fn device_acceptor_test() {
+        let directory = TempDir::new("tls_device");
+        let issuer = server_issuer();
+        let server = write_server_certificate(directory.path(), "server", "localhost", &issuer);
+        let resolver = Arc::new(CertificateResolver::load(&server, []).unwrap());
+
+        let authority = Arc::new(
+            CertificateAuthority::load_or_generate(&AuthorityOptions {
+                use_intermediate: true,
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let other = CertificateAuthority::ephemeral("Other CA").unwrap();
+        let acceptor = device_acceptor(resolver, [authority.clone()]).unwrap();
+
+        let (issued, key_pair) = issue(&authority, "DEVICE");
+        let (accepted, _) = handshake(
+            &acceptor,
+            &issuer.0,
+            "localhost",
+            Some((&issued, &key_pair)),
+        )
+        .await;
+        let identity = accepted.unwrap().unwrap();
+        assert_eq!(identity.device_id, "DEVICE");
+        assert_eq!(identity.thumbprint, issued.thumbprint);
+
+        let (accepted, _) = handshake(&acceptor, &issuer.0, "localhost", None).await;
+        assert_eq!(accepted.unwrap(), None);
+
+        let (foreign, key_pair) = issue(&other, "DEVICE");
+        let (accepted, _) = handshake(
+            &acceptor,
+            &issuer.0,
+            "localhost",
+            Some((&foreign, &key_pair)),
+        )
+        .await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn certificate_resolver_test() {
+        let directory = TempDir::new("tls_resolver");
+        let issuer = server_issuer();
+        let default =
+            write_server_certificate(directory.path(), "default", "mdm.example.com", &issuer);
+        let contoso = write_server_certificate(
+            directory.path(),
+            "contoso",
+            "enterpriseenrollment.contoso.com",
+            &issuer,
+        );
+        let resolver = Arc::new(
+            CertificateResolver::load(
+                &default,
+                [(
+                    "EnterpriseEnrollment.contoso.com".to_string(),
+                    contoso.clone(),
+                )],
+            )
+            .unwrap(),
+        );
+        let acceptor = admin_acceptor(resolver.clone()).unwrap();
+        let served = |name: &str| {
+            let pem = std::fs::read(directory.join(format!("{name}.crt"))).unwrap();
+            CertificateDer::from_pem_slice(&pem).unwrap().to_vec()
+        };
+        let contoso_host = "enterpriseenrollment.contoso.com";
+
+        let (_, certificate) = handshake(&acceptor, &issuer.0, contoso_host, None).await;
+        assert_eq!(certificate, served("contoso"));
+        let (_, certificate) = handshake(&acceptor, &issuer.0, "mdm.example.com", None).await;
+        assert_eq!(certificate, served("default"));
+
+        assert!(!resolver.reload_if_modified().unwrap());
+        write_server_certificate(directory.path(), "contoso", contoso_host, &issuer);
+        resolver.reload().unwrap();
+        let renewed = served("contoso");
+        let (_, certificate) = handshake(&acceptor, &issuer.0, contoso_host, None).await;
+        assert_eq!(certificate, renewed);
+
+        // NOTE; A broken file keeps the previous certificate in service
+        std::fs::write(&contoso.certificate, "broken").unwrap();
+        assert!(resolver.reload().is_err());
+        let (_, certificate) = handshake(&acceptor, &issuer.0, contoso_host, None).await;
+        assert_eq!(certificate, renewed);
+    }
+
+    #[test]
+    fn client_identity_test() {
+        let authority = CertificateAuthority::ephemeral("Test CA").unwrap();
+        let (issued, _) = issue(&authority, "DEVICE");
+
+        let identity = ClientIdentity::from_der(issued.der.clone()).unwrap();
+        assert_eq!(identity.device_id, "DEVICE");
+        assert_eq!(identity.thumbprint, issued.thumbprint);
+        assert_eq!(ClientIdentity::from_der(b"garbage".to_vec()), None);
+    }
+}